@@ -0,0 +1 @@
+eval('\u0000'); eval(String.fromCharCode(0xD800));
\ No newline at end of file
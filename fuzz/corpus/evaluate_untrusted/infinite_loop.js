@@ -0,0 +1 @@
+while (true) {}
\ No newline at end of file
@@ -0,0 +1 @@
+var s = ''; 1 + 
\ No newline at end of file
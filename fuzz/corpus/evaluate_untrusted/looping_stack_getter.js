@@ -0,0 +1,3 @@
+var e = new Error('x');
+Object.defineProperty(e, 'stack', { get: function () { for (;;) {} } });
+throw e;
\ No newline at end of file
@@ -0,0 +1 @@
+throw { toString: function () { for (;;) {} } };
\ No newline at end of file
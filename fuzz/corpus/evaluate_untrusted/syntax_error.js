@@ -0,0 +1 @@
+var = ;) }{
\ No newline at end of file
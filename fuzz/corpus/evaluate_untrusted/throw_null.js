@@ -0,0 +1 @@
+throw null;
\ No newline at end of file
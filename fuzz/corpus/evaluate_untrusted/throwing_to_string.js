@@ -0,0 +1 @@
+throw { toString: function () { throw this; } };
\ No newline at end of file
@@ -0,0 +1 @@
+var a = []; for (;;) a.push({ x: a.length, y: [a.length] });
\ No newline at end of file
@@ -0,0 +1 @@
+function f() { return f() + 1; } f();
\ No newline at end of file
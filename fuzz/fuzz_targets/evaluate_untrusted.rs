@@ -0,0 +1,32 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Feeds arbitrary bytes to `Runtime::evaluate_untrusted`, each input
+//! against a fresh global. Run with `cargo fuzz run evaluate_untrusted`;
+//! inputs worth keeping go in `corpus/evaluate_untrusted`, which the
+//! crate's tests replay.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate js;
+
+use js::global::{GlobalOptions, new_global_object};
+use js::object::RootedObject;
+use js::rust::{Runtime, RuntimeOptions, NATIVE_STACK_QUOTA};
+
+thread_local!(static RUNTIME: Runtime = {
+    let options = RuntimeOptions {
+        native_stack_quota: Some(NATIVE_STACK_QUOTA),
+        .. RuntimeOptions::default()
+    };
+    Runtime::with_options(&options).unwrap()
+});
+
+fuzz_target!(|data: &[u8]| {
+    RUNTIME.with(|rt| {
+        let global = new_global_object(rt.cx(), None, &GlobalOptions::default()).unwrap();
+        let global = RootedObject::new(rt.cx(), global).unwrap();
+        let _ = rt.evaluate_untrusted(global.get(), data);
+    });
+});
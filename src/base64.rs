@@ -0,0 +1,172 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Base64 as the web platform does it, and the `atob`/`btoa` globals.
+
+use libc::c_char;
+use std::ffi::CString;
+use jsapi::{JSContext, JSObject, JS_ReportError, JS_GetPendingException, JS_DefineProperty};
+use jsval::{JSVal, StringValue, UndefinedValue};
+use closure::define_closure;
+use conversions::to_string;
+use error::JSError;
+use strings::new_string;
+use ERR;
+
+const ALPHABET: &'static [u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes `bytes` as padded base64.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn decode_digit(c: u8) -> Option<u32> {
+    match c {
+        b'A'...b'Z' => Some((c - b'A') as u32),
+        b'a'...b'z' => Some((c - b'a') as u32 + 26),
+        b'0'...b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Decodes base64 the forgiving way `atob` does: ASCII whitespace is
+/// ignored and padding is optional, but if present must be right. Returns
+/// `None` for anything else that isn't base64.
+pub fn decode(s: &str) -> Option<Vec<u8>> {
+    let mut input: Vec<u8> = s.bytes().filter(|&c| !is_ascii_whitespace(c)).collect();
+    if input.len() % 4 == 0 {
+        for _ in 0..2 {
+            if input.last() == Some(&b'=') {
+                input.pop();
+            }
+        }
+    }
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &c in &input {
+        let digit = match decode_digit(c) {
+            Some(digit) => digit,
+            None => return None,
+        };
+        buffer = buffer << 6 | digit;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+fn is_ascii_whitespace(c: u8) -> bool {
+    c == b' ' || c == b'\t' || c == b'\n' || c == b'\x0c' || c == b'\r'
+}
+
+/// Throws an `Error` named `InvalidCharacterError`, as the DOM would, and
+/// returns it.
+fn invalid_character_error(cx: *mut JSContext, message: &str) -> JSError {
+    unsafe {
+        let message = CString::new(message).unwrap();
+        JS_ReportError(cx, b"%s\0".as_ptr() as *const c_char, message.as_ptr());
+        let mut exn = UndefinedValue();
+        if JS_GetPendingException(cx, &mut exn) != ERR && exn.is_object() {
+            if let Ok(name) = new_string(cx, "InvalidCharacterError") {
+                JS_DefineProperty(cx, exn.to_object(), b"name\0".as_ptr() as *const c_char,
+                                  StringValue(&*name), None, None, 0);
+            }
+        }
+        JSError::from_pending_exception(cx)
+    }
+}
+
+/// `btoa(data)`: `data` is a byte string, one byte per character.
+fn btoa(cx: *mut JSContext, data: JSVal) -> Result<JSVal, JSError> {
+    let data = try!(to_string(cx, data));
+    let mut bytes = Vec::with_capacity(data.len());
+    for c in data.chars() {
+        if c as u32 > 0xff {
+            return Err(invalid_character_error(cx, "btoa: string contains characters outside of the Latin1 range"));
+        }
+        bytes.push(c as u8);
+    }
+    let encoded = try!(new_string(cx, &encode(&bytes)));
+    Ok(StringValue(unsafe { &*encoded }))
+}
+
+/// `atob(data)`: returns the decoded bytes as a byte string.
+fn atob(cx: *mut JSContext, data: JSVal) -> Result<JSVal, JSError> {
+    let data = try!(to_string(cx, data));
+    let bytes = match decode(&data) {
+        Some(bytes) => bytes,
+        None => return Err(invalid_character_error(cx, "atob: string is not correctly encoded")),
+    };
+    let decoded: String = bytes.iter().map(|&b| b as char).collect();
+    let decoded = try!(new_string(cx, &decoded));
+    Ok(StringValue(unsafe { &*decoded }))
+}
+
+/// Defines `atob` and `btoa` on `global`.
+pub fn install_base64_globals(cx: *mut JSContext, global: *mut JSObject) -> Result<(), JSError> {
+    try!(define_closure(cx, global, "atob", 1, 0, |call| atob(call.cx(), call.arg(0))));
+    try!(define_closure(cx, global, "btoa", 1, 0, |call| btoa(call.cx(), call.arg(0))));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use conversions::to_string;
+    use testing::{with_test_runtime, evaluate};
+    use super::{encode, decode, install_base64_globals};
+
+    #[test]
+    fn codec_round_trips() {
+        for s in &["", "f", "fo", "foo", "foob", "fooba", "foobar"] {
+            assert_eq!(decode(&encode(s.as_bytes())).unwrap(), s.as_bytes());
+        }
+        assert_eq!(encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(encode(b"fo"), "Zm8=");
+        assert_eq!(decode(" Zm9v\nYg== ").unwrap(), b"foob");
+        assert_eq!(decode("Zm9vYg").unwrap(), b"foob");
+        assert!(decode("Zm9vYg=").is_none());
+        assert!(decode("Zm9vY").is_none());
+        assert!(decode("Zm9v!g==").is_none());
+        assert!(decode("Zm=9").is_none());
+    }
+
+    #[test]
+    fn atob_and_btoa_in_script() {
+        with_test_runtime(|rt, global| {
+            install_base64_globals(rt.cx(), global).unwrap();
+            let s = assert_evaluates!(rt, global, "btoa('\\u00ff\\u00fe\\u0000abc')");
+            assert_eq!(to_string(rt.cx(), s).unwrap(), "//4AYWJj");
+            assert!(evaluate(rt, global, "atob(' //4A\\tYWJj ') === '\\u00ff\\u00fe\\u0000abc'")
+                        .unwrap().to_boolean());
+            let caught = "try { btoa('\\u0100'); } catch (e) { e.name }";
+            let name = to_string(rt.cx(), assert_evaluates!(rt, global, caught)).unwrap();
+            assert_eq!(name, "InvalidCharacterError");
+            let caught = "try { atob('YQ='); } catch (e) { e.name }";
+            let name = to_string(rt.cx(), assert_evaluates!(rt, global, caught)).unwrap();
+            assert_eq!(name, "InvalidCharacterError");
+        });
+    }
+}
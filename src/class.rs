@@ -0,0 +1,453 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Defining `JSClass`es and initializing them on globals.
+
+use libc::{c_char, c_uint, c_void};
+use std::ffi::CString;
+use std::mem;
+use std::ops::BitOr;
+use std::ptr;
+use jsapi::{JSClass, JSFinalizeOp, JSTraceOp, JSNative, JSResolveOp, JSEnumerateOp};
+use jsapi::{JSContext, JSObject, JSTracer, JSFunctionSpec, JSPropertySpec};
+use jsapi::{JS_PropertyStub, JS_StrictPropertyStub, JS_EnumerateStub, JS_ResolveStub};
+use jsapi::{JS_ConvertStub, JS_InitClass, JS_GetProperty, JS_NewObject};
+use jsval::UndefinedValue;
+use error::{JSError, catch_panic};
+use object::RootedObject;
+use private::borrow_private;
+use tracer::{Tracer, Traceable};
+use {JSCLASS_RESERVED_SLOTS_MASK, JSCLASS_RESERVED_SLOTS_SHIFT};
+use {JSCLASS_HAS_PRIVATE, JSCLASS_NEW_ENUMERATE, JSCLASS_NEW_RESOLVE, JSCLASS_IS_GLOBAL};
+use {JSCLASS_IS_DOMJSCLASS, JSCLASS_GLOBAL_SLOT_COUNT, ERR};
+
+/// The `flags` of a `JSClass`: `JSCLASS_*` bits, with the number of
+/// reserved slots packed in among them. The bits are public so that the
+/// constants can be used in `static` classes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ClassFlags(pub u32);
+
+impl ClassFlags {
+    pub const EMPTY: ClassFlags = ClassFlags(0);
+    pub const HAS_PRIVATE: ClassFlags = ClassFlags(JSCLASS_HAS_PRIVATE);
+    pub const NEW_ENUMERATE: ClassFlags = ClassFlags(JSCLASS_NEW_ENUMERATE);
+    pub const NEW_RESOLVE: ClassFlags = ClassFlags(JSCLASS_NEW_RESOLVE);
+    pub const IS_DOMJSCLASS: ClassFlags = ClassFlags(JSCLASS_IS_DOMJSCLASS);
+    /// Marks a global class. Globals also need the engine's reserved
+    /// slots; see `GLOBAL_WITH_SLOTS`.
+    pub const GLOBAL: ClassFlags = ClassFlags(JSCLASS_IS_GLOBAL);
+    /// The flags of a global class: `GLOBAL` with the engine's reserved
+    /// slots.
+    pub const GLOBAL_WITH_SLOTS: ClassFlags = ClassFlags(JSCLASS_IS_GLOBAL |
+        (JSCLASS_GLOBAL_SLOT_COUNT & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT);
+
+    /// `GLOBAL_WITH_SLOTS`.
+    pub fn global() -> ClassFlags {
+        ClassFlags::GLOBAL_WITH_SLOTS
+    }
+
+    pub fn from_bits(bits: u32) -> ClassFlags {
+        ClassFlags(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every bit set in `other` is set here.
+    pub fn contains(self, other: ClassFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// These flags with the reserved slot count replaced by `count`.
+    pub fn with_reserved_slots(self, count: u8) -> ClassFlags {
+        let field = JSCLASS_RESERVED_SLOTS_MASK << JSCLASS_RESERVED_SLOTS_SHIFT;
+        ClassFlags(self.0 & !field | (count as u32 & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT)
+    }
+
+    pub fn reserved_slots(self) -> u8 {
+        (self.0 >> JSCLASS_RESERVED_SLOTS_SHIFT & JSCLASS_RESERVED_SLOTS_MASK) as u8
+    }
+}
+
+impl BitOr for ClassFlags {
+    type Output = ClassFlags;
+
+    fn bitor(self, other: ClassFlags) -> ClassFlags {
+        ClassFlags(self.0 | other.0)
+    }
+}
+
+impl From<ClassFlags> for u32 {
+    fn from(flags: ClassFlags) -> u32 {
+        flags.0
+    }
+}
+
+/// Builds a `JSClass` with the stub hooks filled in.
+///
+/// Classes have to outlive every object created with them, so `build` leaks
+/// the class and its name; build each class once and reuse it.
+pub struct ClassBuilder {
+    name: CString,
+    flags: ClassFlags,
+    enumerate: JSEnumerateOp,
+    resolve: JSResolveOp,
+    finalize: JSFinalizeOp,
+    call: JSNative,
+    construct: JSNative,
+    trace: JSTraceOp,
+}
+
+impl ClassBuilder {
+    /// Starts a new class definition. Interior NUL bytes in `name` are
+    /// rejected by panicking, as class names are always literals.
+    pub fn new(name: &str) -> ClassBuilder {
+        ClassBuilder {
+            name: CString::new(name).unwrap(),
+            flags: ClassFlags::EMPTY,
+            enumerate: Some(JS_EnumerateStub),
+            resolve: Some(JS_ResolveStub),
+            finalize: None,
+            call: None,
+            construct: None,
+            trace: None,
+        }
+    }
+
+    /// Adds `flags` to the class flags. A reserved slot count in them
+    /// replaces the one set so far.
+    pub fn flags(mut self, flags: ClassFlags) -> ClassBuilder {
+        let count = if flags.reserved_slots() != 0 { flags.reserved_slots() } else { self.flags.reserved_slots() };
+        self.flags = (self.flags | flags).with_reserved_slots(count);
+        self
+    }
+
+    /// Sets the number of reserved slots objects of this class get.
+    pub fn reserved_slots(mut self, count: u32) -> ClassBuilder {
+        assert!(count <= JSCLASS_RESERVED_SLOTS_MASK);
+        self.flags = self.flags.with_reserved_slots(count as u8);
+        self
+    }
+
+    /// The number of reserved slots configured so far.
+    pub fn reserved_slot_count(&self) -> u32 {
+        self.flags.reserved_slots() as u32
+    }
+
+    pub fn enumerate(mut self, op: JSEnumerateOp) -> ClassBuilder {
+        self.enumerate = op;
+        self
+    }
+
+    pub fn resolve(mut self, op: JSResolveOp) -> ClassBuilder {
+        self.resolve = op;
+        self
+    }
+
+    pub fn finalize(mut self, op: JSFinalizeOp) -> ClassBuilder {
+        self.finalize = op;
+        self
+    }
+
+    pub fn call(mut self, op: JSNative) -> ClassBuilder {
+        self.call = op;
+        self
+    }
+
+    pub fn construct(mut self, op: JSNative) -> ClassBuilder {
+        self.construct = op;
+        self
+    }
+
+    pub fn trace(mut self, op: JSTraceOp) -> ClassBuilder {
+        self.trace = op;
+        self
+    }
+
+    /// Installs a trace hook that traces the `T` stored in each object's
+    /// private slot by `set_private_boxed`. Objects whose slot is empty or
+    /// holds something else are skipped.
+    pub fn trace_private<T: Traceable + 'static>(self) -> ClassBuilder {
+        self.trace(Some(trace_private::<T>))
+    }
+
+    /// Creates the class, leaking it so it lives as long as the process.
+    pub fn build(self) -> &'static JSClass {
+        let name = self.name.as_ptr();
+        mem::forget(self.name);
+        let class = Box::new(JSClass {
+            name: name,
+            flags: self.flags.bits(),
+            addProperty: Some(JS_PropertyStub),
+            delProperty: Some(JS_PropertyStub),
+            getProperty: Some(JS_PropertyStub),
+            setProperty: Some(JS_StrictPropertyStub),
+            enumerate: self.enumerate,
+            resolve: self.resolve,
+            convert: Some(JS_ConvertStub),
+            finalize: self.finalize,
+            checkAccess: None,
+            call: self.call,
+            hasInstance: None,
+            construct: self.construct,
+            trace: self.trace,
+            reserved: [0 as *mut c_void; 40],
+        });
+        unsafe { &*Box::into_raw(class) }
+    }
+}
+
+unsafe extern "C" fn trace_private<T: Traceable + 'static>(trc: *mut JSTracer, obj: *mut JSObject) {
+    if let Some(private) = borrow_private::<T>(obj) {
+        catch_panic(ptr::null_mut(), || private.trace(&mut Tracer::from_raw(trc)));
+    }
+}
+
+/// Returns the number of reserved slots declared in `class`'s flags.
+pub fn class_reserved_slots(class: &JSClass) -> u32 {
+    ClassFlags::from_bits(class.flags).reserved_slots() as u32
+}
+
+/// Everything `init_class` needs to expose a class to script.
+///
+/// The spec tables must be terminated by an entry with a null name, as
+/// produced by the spec builders.
+pub struct ClassSpec<'a> {
+    pub class: &'static JSClass,
+    /// The prototype's prototype; null means `Object.prototype`.
+    pub parent_proto: *mut JSObject,
+    pub constructor: JSNative,
+    pub constructor_nargs: u32,
+    pub methods: Option<&'a [JSFunctionSpec]>,
+    pub properties: Option<&'a [JSPropertySpec]>,
+    pub static_methods: Option<&'a [JSFunctionSpec]>,
+    pub static_properties: Option<&'a [JSPropertySpec]>,
+}
+
+impl<'a> ClassSpec<'a> {
+    /// A spec for `class` with no constructor, methods or properties.
+    pub fn new(class: &'static JSClass) -> ClassSpec<'a> {
+        ClassSpec {
+            class: class,
+            parent_proto: ptr::null_mut(),
+            constructor: None,
+            constructor_nargs: 0,
+            methods: None,
+            properties: None,
+            static_methods: None,
+            static_properties: None,
+        }
+    }
+}
+
+fn function_specs_ptr(specs: Option<&[JSFunctionSpec]>) -> *mut JSFunctionSpec {
+    match specs {
+        Some(specs) => {
+            assert!(specs.last().map_or(false, |spec| spec.name.is_null()),
+                    "function spec table must end with a null entry");
+            specs.as_ptr() as *mut JSFunctionSpec
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+fn property_specs_ptr(specs: Option<&[JSPropertySpec]>) -> *mut JSPropertySpec {
+    match specs {
+        Some(specs) => {
+            assert!(specs.last().map_or(false, |spec| spec.name.is_null()),
+                    "property spec table must end with a null entry");
+            specs.as_ptr() as *mut JSPropertySpec
+        }
+        None => ptr::null_mut(),
+    }
+}
+
+/// Makes the object a constructor called with `new` returns: an instance of
+/// `class` whose prototype is the `prototype` property of `callee`, the
+/// constructor. If that isn't an object, the class's default prototype is
+/// used, as for a native constructor with no `prototype` of its own.
+pub fn new_object_for_this(cx: *mut JSContext, callee: *mut JSObject, class: &'static JSClass)
+                           -> Result<*mut JSObject, JSError> {
+    let mut proto = UndefinedValue();
+    unsafe {
+        if JS_GetProperty(cx, callee, b"prototype\0".as_ptr() as *const c_char, &mut proto) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let proto = if proto.is_object() { proto.to_object() } else { ptr::null_mut() };
+        let obj = JS_NewObject(cx, class, proto, ptr::null());
+        if obj.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        Ok(obj)
+    }
+}
+
+/// Initializes the class described by `spec` on `global` and returns its
+/// prototype object, rooted.
+///
+/// The constructor (or, for classes without one, the prototype itself) is
+/// defined on `global` under the class name, which is what keeps the
+/// prototype alive. The engine marks the constructor as one; it can tell
+/// a `new` call with `JS_IsConstructing` and should make the instance with
+/// `new_object_for_this`, so it gets the class's prototype.
+pub fn init_class(cx: *mut JSContext, global: *mut JSObject, spec: ClassSpec)
+                  -> Result<RootedObject, JSError> {
+    // Defining the constructor and the spec tables can collect garbage.
+    let _parent_proto = if spec.parent_proto.is_null() {
+        None
+    } else {
+        Some(try!(RootedObject::new(cx, spec.parent_proto)))
+    };
+    unsafe {
+        let proto = JS_InitClass(cx, global, spec.parent_proto,
+                                 spec.class as *const JSClass as *mut JSClass,
+                                 spec.constructor, spec.constructor_nargs as c_uint,
+                                 property_specs_ptr(spec.properties),
+                                 function_specs_ptr(spec.methods),
+                                 property_specs_ptr(spec.static_properties),
+                                 function_specs_ptr(spec.static_methods));
+        if proto.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        RootedObject::new(cx, proto)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::{c_char, c_uint, c_void};
+    use jsapi::*;
+    use jsval::{JSVal, DoubleValue, Int32Value, ObjectValue};
+    use object::{get_reserved_slot, set_reserved_slot};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use specs::FunctionSpecBuilder;
+    use super::{ClassSpec, ClassFlags, ClassBuilder, init_class, class_reserved_slots, new_object_for_this};
+    use {JS_ARGV, JS_SET_RVAL, JS_THIS_OBJECT, JS_CALLEE, JS_IsConstructing};
+    use {JSCLASS_RESERVED_SLOTS_MASK, JSCLASS_RESERVED_SLOTS_SHIFT, JSCLASS_HAS_RESERVED_SLOTS};
+    use {JSCLASS_HAS_PRIVATE, JSCLASS_IS_GLOBAL, JSCLASS_GLOBAL_SLOT_COUNT};
+    use global::{global_class, lazy_global_class};
+
+    static point_class: JSClass = JSClass {
+        name: b"Point\0" as *const u8 as *const c_char,
+        flags: (2 & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT,
+        addProperty: Some(JS_PropertyStub),
+        delProperty: Some(JS_PropertyStub),
+        getProperty: Some(JS_PropertyStub),
+        setProperty: Some(JS_StrictPropertyStub),
+        enumerate: Some(JS_EnumerateStub),
+        resolve: Some(JS_ResolveStub),
+        convert: Some(JS_ConvertStub),
+        finalize: None,
+        checkAccess: None,
+        call: None,
+        hasInstance: None,
+        construct: None,
+        trace: None,
+        reserved: [0 as *mut c_void; 40]
+    };
+
+    unsafe extern "C" fn point_construct(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal) -> JSBool {
+        if !JS_IsConstructing(cx, vp) {
+            JS_ReportError(cx, b"Point must be called with new\0".as_ptr() as *const c_char);
+            return 0;
+        }
+        let obj = match new_object_for_this(cx, JS_CALLEE(cx, vp).to_object(), &point_class) {
+            Ok(obj) => obj,
+            Err(error) => {
+                error.throw(cx);
+                return 0;
+            }
+        };
+        let argv = JS_ARGV(cx, vp);
+        for i in 0..2 {
+            let arg = if i < argc { *argv.offset(i as isize) } else { Int32Value(0) };
+            if !arg.is_number() {
+                JS_ReportError(cx, b"Point coordinates must be numbers\0".as_ptr() as *const c_char);
+                return 0;
+            }
+            set_reserved_slot(obj, i, arg);
+        }
+        JS_SET_RVAL(cx, vp, ObjectValue(&*obj));
+        1
+    }
+
+    unsafe extern "C" fn point_norm(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        let this = JS_THIS_OBJECT(cx, vp);
+        if this.is_null() || JS_GetClass(this) as *const JSClass != &point_class as *const JSClass {
+            JS_ReportError(cx, b"norm called on a non-Point\0".as_ptr() as *const c_char);
+            return 0;
+        }
+        let x = get_reserved_slot(this, 0).to_number();
+        let y = get_reserved_slot(this, 1).to_number();
+        JS_SET_RVAL(cx, vp, DoubleValue((x * x + y * y).sqrt()));
+        1
+    }
+
+    unsafe extern "C" fn point_dimensions(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        JS_SET_RVAL(cx, vp, Int32Value(2));
+        1
+    }
+
+    #[test]
+    fn init_point_class() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let methods = FunctionSpecBuilder::new().function("norm", Some(point_norm), 0).build();
+        let statics = FunctionSpecBuilder::new().function("dimensions", Some(point_dimensions), 0).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let spec = ClassSpec {
+                constructor: Some(point_construct),
+                constructor_nargs: 2,
+                methods: Some(methods.as_slice()),
+                static_methods: Some(statics.as_slice()),
+                .. ClassSpec::new(&point_class)
+            };
+            let proto = init_class(rt.cx(), global, spec).unwrap();
+            unsafe { JS_GC(rt.rt()) };
+            assert_eq!(evaluate(&rt, global, "Point.prototype").unwrap().to_object(), proto.get());
+
+            let norm = evaluate(&rt, global, "var p = new Point(3, 4); p.norm()").unwrap();
+            assert_eq!(norm.to_number(), 5.0);
+            let is_point = evaluate(&rt, global, "p instanceof Point && Point.prototype.norm === p.norm").unwrap();
+            assert!(is_point.to_boolean());
+            assert_eq!(evaluate(&rt, global, "Point.dimensions()").unwrap().to_number(), 2.0);
+            assert!(evaluate(&rt, global, "new Point('a', 'b')").is_err());
+            assert!(evaluate(&rt, global, "Point(1, 2)").is_err());
+            let proto_ok = evaluate(&rt, global, "Object.getPrototypeOf(new Point(1, 2)) === Point.prototype").unwrap();
+            assert!(proto_ok.to_boolean());
+        });
+    }
+
+    #[test]
+    fn class_flags_match_raw_layout() {
+        assert_eq!(ClassFlags::HAS_PRIVATE.bits(), JSCLASS_HAS_PRIVATE);
+        assert_eq!(ClassFlags::EMPTY.with_reserved_slots(2).bits(), JSCLASS_HAS_RESERVED_SLOTS(2));
+        assert_eq!(ClassFlags::EMPTY.with_reserved_slots(255).bits(),
+                   JSCLASS_RESERVED_SLOTS_MASK << JSCLASS_RESERVED_SLOTS_SHIFT);
+        assert_eq!(u32::from(ClassFlags::global()),
+                   JSCLASS_IS_GLOBAL |
+                   ((JSCLASS_GLOBAL_SLOT_COUNT & JSCLASS_RESERVED_SLOTS_MASK) << JSCLASS_RESERVED_SLOTS_SHIFT));
+        assert_eq!(ClassFlags::global().reserved_slots() as u32, JSCLASS_GLOBAL_SLOT_COUNT);
+        assert_eq!(global_class.flags, ClassFlags::GLOBAL_WITH_SLOTS.bits());
+        assert_eq!(lazy_global_class.flags, ClassFlags::GLOBAL_WITH_SLOTS.bits());
+
+        let flags = (ClassFlags::HAS_PRIVATE | ClassFlags::GLOBAL).with_reserved_slots(7);
+        assert_eq!(flags.reserved_slots(), 7);
+        assert!(flags.contains(ClassFlags::HAS_PRIVATE) && flags.contains(ClassFlags::GLOBAL));
+        assert!(!flags.contains(ClassFlags::NEW_RESOLVE));
+        let flags = flags.with_reserved_slots(3);
+        assert_eq!(flags.bits(), JSCLASS_HAS_PRIVATE | JSCLASS_IS_GLOBAL | JSCLASS_HAS_RESERVED_SLOTS(3));
+        assert_eq!(ClassFlags::from_bits(flags.bits()), flags);
+        assert_eq!(ClassFlags::from_bits(point_class.flags).reserved_slots(), 2);
+
+        let class = ClassBuilder::new("Flagged")
+            .reserved_slots(4)
+            .flags(ClassFlags::HAS_PRIVATE)
+            .build();
+        assert_eq!(class.flags, JSCLASS_HAS_PRIVATE | JSCLASS_HAS_RESERVED_SLOTS(4));
+        assert_eq!(class_reserved_slots(class), 4);
+    }
+}
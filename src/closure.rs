@@ -0,0 +1,395 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! JS functions implemented by Rust closures.
+//!
+//! Each closure is owned by a hidden object of a dedicated class, whose
+//! finalizer drops it, and the function keeps that object in a reserved
+//! slot. Nothing is keyed by object addresses, so the closure lives exactly
+//! as long as the function, however the GC treats them.
+
+use libc::{c_char, c_uint};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::ptr;
+use std::sync::{Once, ONCE_INIT};
+use jsapi::{JSBool, JSContext, JSObject, JS_GetFunctionObject, JS_DefineProperty};
+use jsval::{JSVal, ObjectValue, UndefinedValue};
+use glue::{NewFunctionWithReserved, GetFunctionNativeReserved, SetFunctionNativeReserved};
+use error::{JSError, guard_native};
+use private::{NativeClass, new_object_with_native, native_from_object};
+use {JS_ARGV, JS_CALLEE, JS_SET_RVAL, JS_IsConstructing, JSFUN_CONSTRUCTOR, ERR};
+
+/// The arguments of a call to a closure-backed function.
+pub struct CallContext {
+    cx: *mut JSContext,
+    argc: c_uint,
+    vp: *mut JSVal,
+}
+
+impl CallContext {
+    pub fn cx(&self) -> *mut JSContext {
+        self.cx
+    }
+
+    /// The number of arguments actually passed.
+    pub fn argc(&self) -> u32 {
+        self.argc
+    }
+
+    /// Argument `i`, or `undefined` if fewer were passed.
+    pub fn arg(&self, i: u32) -> JSVal {
+        if i < self.argc {
+            unsafe { *JS_ARGV(self.cx, self.vp).offset(i as isize) }
+        } else {
+            UndefinedValue()
+        }
+    }
+
+    /// The `this` value, as passed; primitives aren't boxed. When
+    /// constructing, it's a placeholder, not an object.
+    pub fn this_value(&self) -> JSVal {
+        unsafe { *self.vp.offset(1) }
+    }
+
+    /// Whether the function was called with `new`. Only functions made by
+    /// `new_closure_constructor` can be; they create the new object with
+    /// `class::new_object_for_this` and return it.
+    pub fn is_constructing(&self) -> bool {
+        unsafe { JS_IsConstructing(self.cx, self.vp) }
+    }
+
+    /// The function being called.
+    pub fn callee(&self) -> *mut JSObject {
+        unsafe { JS_CALLEE(self.cx, self.vp).to_object() }
+    }
+}
+
+type Closure = Box<Fn(&CallContext) -> Result<JSVal, JSError>>;
+
+static CLOSURE_CLASS_INIT: Once = ONCE_INIT;
+static mut CLOSURE_CLASS: *const NativeClass<Closure> = 0 as *const NativeClass<Closure>;
+
+/// The class of the objects that own the closures, shared by every runtime.
+fn closure_class() -> &'static NativeClass<Closure> {
+    unsafe {
+        CLOSURE_CLASS_INIT.call_once(|| {
+            CLOSURE_CLASS = Box::into_raw(Box::new(NativeClass::new("RustClosure")));
+        });
+        &*CLOSURE_CLASS
+    }
+}
+
+/// The function's reserved slot holding the closure's owner.
+const CLOSURE_SLOT: usize = 0;
+
+unsafe extern "C" fn call_closure(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal) -> JSBool {
+    guard_native(cx, || {
+        let owner = (*GetFunctionNativeReserved(JS_CALLEE(cx, vp).to_object(), CLOSURE_SLOT)).to_object();
+        let closure = native_from_object::<Closure>(owner).unwrap();
+        let call = CallContext {
+            cx: cx,
+            argc: argc,
+            vp: vp,
+        };
+        match closure(&call) {
+            Ok(value) if call.is_constructing() && !value.is_object() => {
+                JSError::type_error("a constructor must return an object").throw(cx);
+                0
+            }
+            Ok(value) => {
+                JS_SET_RVAL(cx, vp, value);
+                1
+            }
+            Err(error) => {
+                error.throw(cx);
+                0
+            }
+        }
+    })
+}
+
+/// Creates a function named `name` that runs `f`. What `f` returns becomes
+/// the call's result; an `Err` is thrown, rethrowing the original
+/// exception if the error was taken from one, so `try!` can pass script
+/// errors straight through.
+///
+/// The closure lives until the function is collected, and must not call
+/// itself recursively: a call made while it is running throws. See
+/// `new_reentrant_closure_function` for one that can.
+pub fn new_closure_function<F>(cx: *mut JSContext, name: &str, nargs: u32, f: F)
+                               -> Result<*mut JSObject, JSError>
+    where F: FnMut(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    // A closure that calls itself through script finds its `RefCell`
+    // borrowed, and the panic becomes an exception.
+    let f = RefCell::new(f);
+    new_function(cx, name, nargs, 0, move |call: &CallContext| (&mut *f.borrow_mut())(call))
+}
+
+/// Like `new_closure_function`, but script `f` runs may call the function
+/// again, so `f` takes `&self`. Keep mutable state in a `Cell` or
+/// `RefCell`, and don't hold borrows across calls into script.
+pub fn new_reentrant_closure_function<F>(cx: *mut JSContext, name: &str, nargs: u32, f: F)
+                                         -> Result<*mut JSObject, JSError>
+    where F: Fn(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    new_function(cx, name, nargs, 0, f)
+}
+
+/// Like `new_closure_function`, but the function can also be called with
+/// `new`, when `CallContext::is_constructing` is true and `f` must return
+/// the object it made. Returning anything else throws a `TypeError`.
+pub fn new_closure_constructor<F>(cx: *mut JSContext, name: &str, nargs: u32, f: F)
+                                  -> Result<*mut JSObject, JSError>
+    where F: FnMut(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    let f = RefCell::new(f);
+    new_function(cx, name, nargs, JSFUN_CONSTRUCTOR, move |call: &CallContext| (&mut *f.borrow_mut())(call))
+}
+
+fn new_function<F>(cx: *mut JSContext, name: &str, nargs: u32, flags: u32, f: F)
+                   -> Result<*mut JSObject, JSError>
+    where F: Fn(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    let c_name = try!(CString::new(name).map_err(|_| JSError::new(format!("invalid function name {:?}", name))));
+    let closure: Closure = Box::new(f);
+    let owner = try!(new_object_with_native(cx, closure_class(), closure));
+    unsafe {
+        let fun = NewFunctionWithReserved(cx, Some(call_closure), nargs, flags, ptr::null_mut(), c_name.as_ptr());
+        if fun.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let obj = JS_GetFunctionObject(fun);
+        let mut owner = ObjectValue(&*owner);
+        SetFunctionNativeReserved(obj, CLOSURE_SLOT, &mut owner);
+        Ok(obj)
+    }
+}
+
+/// Defines a closure-backed function as property `name` of `obj`, with the
+/// property attributes `attrs`.
+pub fn define_closure<F>(cx: *mut JSContext, obj: *mut JSObject, name: &str, nargs: u32, attrs: u32, f: F)
+                         -> Result<*mut JSObject, JSError>
+    where F: FnMut(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    let fun = try!(new_closure_function(cx, name, nargs, f));
+    define_function(cx, obj, name, fun, attrs)
+}
+
+/// Defines a function made as by `new_reentrant_closure_function` as
+/// property `name` of `obj`.
+pub fn define_reentrant_closure<F>(cx: *mut JSContext, obj: *mut JSObject, name: &str, nargs: u32, attrs: u32,
+                                   f: F) -> Result<*mut JSObject, JSError>
+    where F: Fn(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    let fun = try!(new_reentrant_closure_function(cx, name, nargs, f));
+    define_function(cx, obj, name, fun, attrs)
+}
+
+/// Defines a closure-backed constructor, made as by
+/// `new_closure_constructor`, as property `name` of `obj`.
+pub fn define_closure_constructor<F>(cx: *mut JSContext, obj: *mut JSObject, name: &str, nargs: u32, attrs: u32,
+                                     f: F) -> Result<*mut JSObject, JSError>
+    where F: FnMut(&CallContext) -> Result<JSVal, JSError> + 'static
+{
+    let fun = try!(new_closure_constructor(cx, name, nargs, f));
+    define_function(cx, obj, name, fun, attrs)
+}
+
+fn define_function(cx: *mut JSContext, obj: *mut JSObject, name: &str, fun: *mut JSObject, attrs: u32)
+                   -> Result<*mut JSObject, JSError> {
+    let c_name = CString::new(name).unwrap();
+    unsafe {
+        if JS_DefineProperty(cx, obj, c_name.as_ptr() as *const c_char, ObjectValue(&*fun),
+                             None, None, attrs) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(fun)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use jsapi::{JSContext, JSObject, JS_CallFunctionValue, JS_SetElement};
+    use jsval::{JSVal, Int32Value, ObjectValue, UndefinedValue};
+    use conversions::{to_number, to_string};
+    use error::{JSError, ErrorKind};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, assert_all_collected, object_ptr};
+    use class::{ClassBuilder, new_object_for_this};
+    use object::{get_reserved_slot, set_reserved_slot};
+    use super::{define_closure, define_closure_constructor, define_reentrant_closure, new_closure_function};
+    use ERR;
+
+    /// Calls `f` with `arg` and returns the result, letting a thrown
+    /// exception through as the original value.
+    fn call_function(cx: *mut JSContext, global: *mut JSObject, f: JSVal, arg: JSVal)
+                     -> Result<JSVal, JSError> {
+        let mut arg = arg;
+        let mut rval = UndefinedValue();
+        unsafe {
+            if JS_CallFunctionValue(cx, global, f, 1, &mut arg, &mut rval) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        Ok(rval)
+    }
+
+    #[test]
+    fn closures_return_values_and_throw_errors() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let calls = Rc::new(Cell::new(0));
+        with_compartment(rt.context(), object_ptr(global), || {
+            let counter = calls.clone();
+            define_closure(rt.cx(), global, "half", 1, 0, move |call| {
+                counter.set(counter.get() + 1);
+                let n = try!(to_number(call.cx(), call.arg(0)));
+                if n % 2.0 != 0.0 {
+                    return Err(JSError::new(format!("{} is odd", n)));
+                }
+                Ok(Int32Value((n / 2.0) as i32))
+            }).unwrap();
+            assert_eq!(evaluate(&rt, global, "half(42)").unwrap().to_int32(), 21);
+            let caught = "try { half(3); 'no error' } catch (e) { (e instanceof Error) + ' ' + e.message }";
+            let message = to_string(rt.cx(), evaluate(&rt, global, caught).unwrap()).unwrap();
+            assert_eq!(message, "true 3 is odd");
+        });
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn only_reentrant_closures_can_call_themselves() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut calls = 0;
+            define_closure(rt.cx(), global, "once", 1, 0, move |call| {
+                calls += 1;
+                call_function(call.cx(), global, call.arg(0), Int32Value(calls))
+            }).unwrap();
+            let source = "once(function () { try { once(function () {}); 'no error' } catch (e) { 'threw' } })";
+            assert_eq!(to_string(rt.cx(), evaluate(&rt, global, source).unwrap()).unwrap(), "threw");
+
+            define_reentrant_closure(rt.cx(), global, "twice", 1, 0, move |call| {
+                call_function(call.cx(), global, call.arg(0), Int32Value(1))
+            }).unwrap();
+            let source = "twice(function () { return twice(function (n) { return n + 1; }); })";
+            assert_eq!(evaluate(&rt, global, source).unwrap().to_int32(), 2);
+        });
+    }
+
+    #[test]
+    fn errors_from_exceptions_rethrow_the_original() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_closure(rt.cx(), global, "apply", 2, 0, move |call| {
+                call_function(call.cx(), global, call.arg(0), call.arg(1))
+            }).unwrap();
+            let source = "var original = {code: 7}; \
+                          try { apply(function(x) { throw x; }, original); 'no error' } \
+                          catch (e) { e === original }";
+            assert!(evaluate(&rt, global, source).unwrap().to_boolean());
+            assert_eq!(evaluate(&rt, global, "apply(function(x) { return x + 1; }, 1)").unwrap().to_int32(), 2);
+        });
+    }
+
+    struct DropCounter(Rc<Cell<usize>>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[inline(never)]
+    fn register_closures(rt: &Runtime, registry: *mut JSObject, count: u32, dropped: &Rc<Cell<usize>>) {
+        for i in 0..count {
+            let counter = DropCounter(dropped.clone());
+            let fun = new_closure_function(rt.cx(), "closure", 0, move |_| {
+                let _ = &counter;
+                Ok(Int32Value(i as i32))
+            }).unwrap();
+            let mut value = ObjectValue(unsafe { &*fun });
+            unsafe { assert!(JS_SetElement(rt.cx(), registry, i, &mut value) != ERR) };
+        }
+    }
+
+    #[test]
+    fn closures_live_as_long_as_their_functions() {
+        const COUNT: u32 = 10000;
+        let dropped = Rc::new(Cell::new(0));
+        {
+            let rt = Runtime::new();
+            let global = new_global(&rt);
+            with_compartment(rt.context(), object_ptr(global), || {
+                let registry = evaluate(&rt, global, "var registry = []; registry").unwrap().to_object();
+                register_closures(&rt, registry, COUNT, &dropped);
+                assert!(evaluate(&rt, global, "for (var i = 0; i < registry.length; i += 2) \
+                                               registry[i] = null;").is_ok());
+            });
+            let half = (COUNT / 2) as usize;
+            assert_all_collected(&rt, "dropped closures", || half.saturating_sub(dropped.get()));
+            assert_eq!(dropped.get(), half);
+
+            with_compartment(rt.context(), object_ptr(global), || {
+                let source = "var sum = 0; \
+                              for (var i = 1; i < registry.length; i += 2) sum += registry[i](); \
+                              sum";
+                let expected = (0..COUNT).filter(|i| i % 2 == 1).fold(0.0, |sum, i| sum + i as f64);
+                assert_eq!(evaluate(&rt, global, source).unwrap().to_number(), expected);
+            });
+        }
+        assert_eq!(dropped.get(), COUNT as usize);
+    }
+
+    #[test]
+    fn closures_as_constructors() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let point_class = ClassBuilder::new("Point").reserved_slots(2).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let point = define_closure_constructor(rt.cx(), global, "Point", 2, 0, move |call| {
+                if !call.is_constructing() {
+                    return Err(JSError::type_error("Point must be called with new"));
+                }
+                let obj = try!(new_object_for_this(call.cx(), call.callee(), point_class));
+                for i in 0..2 {
+                    set_reserved_slot(obj, i, call.arg(i));
+                }
+                Ok(ObjectValue(unsafe { &*obj }))
+            }).unwrap();
+            let proto = evaluate(&rt, global, "Point.prototype = {}").unwrap().to_object();
+            define_closure(rt.cx(), proto, "sum", 0, 0, |call| {
+                let this = call.this_value().to_object();
+                let x = try!(to_number(call.cx(), get_reserved_slot(this, 0)));
+                let y = try!(to_number(call.cx(), get_reserved_slot(this, 1)));
+                Ok(Int32Value((x + y) as i32))
+            }).unwrap();
+            assert_eq!(evaluate(&rt, global, "new Point(1, 2).sum()").unwrap().to_int32(), 3);
+            assert!(evaluate(&rt, global, "new Point(1, 2) instanceof Point").unwrap().to_boolean());
+            let error = evaluate(&rt, global, "Point(1, 2)").unwrap_err();
+            assert_eq!(error.message, "TypeError: Point must be called with new");
+            let caught = "try { Point(1, 2) } catch (e) { e instanceof TypeError }";
+            assert!(evaluate(&rt, global, caught).unwrap().to_boolean());
+            assert!(!point.is_null());
+
+            // Plain closures can't be constructed, and constructors must
+            // return objects.
+            define_closure(rt.cx(), global, "plain", 0, 0, |_| Ok(Int32Value(1))).unwrap();
+            let message = evaluate(&rt, global, "new plain()").unwrap_err().message;
+            assert!(message.contains("TypeError"), "{}", message);
+            define_closure_constructor(rt.cx(), global, "Broken", 0, 0, |_| Ok(Int32Value(1))).unwrap();
+            let error = evaluate(&rt, global, "new Broken()").unwrap_err();
+            assert_eq!(error.message, "TypeError: a constructor must return an object");
+            assert_eq!(error.kind, ErrorKind::Type);
+            let caught = "try { new Broken() } catch (e) { e instanceof TypeError }";
+            assert!(evaluate(&rt, global, caught).unwrap().to_boolean());
+        });
+    }
+}
@@ -0,0 +1,210 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A `console` object whose output goes to a Rust sink.
+
+use libc::c_char;
+use std::ffi::CStr;
+use std::ptr;
+use std::rc::Rc;
+use jsapi::{JSContext, JSObject, JS_NewObject, JS_DefineProperty, JS_GetClass, JS_IsArrayObject};
+use jsapi::{JS_GetArrayLength, JS_GetElement, JS_Enumerate, JS_IdArrayLength, JS_IdArrayGet};
+use jsapi::{JS_DestroyIdArray, JS_IdToValue, JS_GetPropertyById, JS_ObjectIsCallable};
+use jsapi::JS_ClearPendingException;
+use jsval::{JSVal, ObjectValue, UndefinedValue};
+use closure::define_closure;
+use conversions::{jsstring_to_string, to_string};
+use error::JSError;
+use function::{function_from_value, function_name};
+use number::js_number_to_string;
+use ERR;
+
+/// Which `console` method produced a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConsoleLevel {
+    Log,
+    Info,
+    Warn,
+    Error,
+    Debug,
+}
+
+/// Receives each line written to `console`.
+pub type ConsoleSink = Fn(ConsoleLevel, String);
+
+/// Objects nested deeper than this are abbreviated.
+const MAX_DEPTH: usize = 2;
+
+/// Defines `console` on `global`, with `log`, `info`, `warn`, `error` and
+/// `debug` methods that format their arguments, separated by spaces, and
+/// pass the line to `sink`.
+pub fn install_console(cx: *mut JSContext, global: *mut JSObject, sink: Box<ConsoleSink>)
+                       -> Result<(), JSError> {
+    let sink: Rc<Box<ConsoleSink>> = Rc::new(sink);
+    unsafe {
+        let console = JS_NewObject(cx, ptr::null(), ptr::null(), ptr::null());
+        if console.is_null() ||
+           JS_DefineProperty(cx, global, b"console\0".as_ptr() as *const c_char, ObjectValue(&*console),
+                             None, None, 0) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let methods = [("log", ConsoleLevel::Log), ("info", ConsoleLevel::Info),
+                       ("warn", ConsoleLevel::Warn), ("error", ConsoleLevel::Error),
+                       ("debug", ConsoleLevel::Debug)];
+        for &(name, level) in &methods {
+            let sink = sink.clone();
+            try!(define_closure(cx, console, name, 0, 0, move |call| {
+                let line: Vec<String> = (0..call.argc()).map(|i| {
+                    let value = call.arg(i);
+                    if value.is_string() {
+                        string_value(call.cx(), value)
+                    } else {
+                        format_value(call.cx(), value, &mut vec![])
+                    }
+                }).collect();
+                sink(level, line.join(" "));
+                Ok(UndefinedValue())
+            }));
+        }
+    }
+    Ok(())
+}
+
+/// Renders `value` for a console line, without throwing: anything that
+/// fails to convert is shown as a placeholder. `stack` holds the objects
+/// being rendered, to spot cycles.
+pub fn format_value(cx: *mut JSContext, value: JSVal, stack: &mut Vec<*mut JSObject>) -> String {
+    if value.is_string() {
+        return format!("{:?}", string_value(cx, value));
+    }
+    if value.is_number() {
+        return js_number_to_string(value.to_number());
+    }
+    if !value.is_object() {
+        return to_string(cx, value).unwrap_or_else(|_| "?".to_string());
+    }
+    let obj = value.to_object();
+    if stack.contains(&obj) {
+        return "[Circular]".to_string();
+    }
+    unsafe {
+        if JS_ObjectIsCallable(cx, obj) != 0 {
+            return match function_from_value(cx, value).and_then(|fun| function_name(cx, fun)) {
+                Some(name) => format!("[Function: {}]", name),
+                None => "[Function]".to_string(),
+            };
+        }
+        let is_array = JS_IsArrayObject(cx, obj) != 0;
+        // Their interesting state isn't in enumerable properties.
+        let class = class_name(obj);
+        if class == "Error" || class == "Date" || class == "RegExp" {
+            return to_string(cx, value).unwrap_or_else(|_| {
+                JS_ClearPendingException(cx);
+                format!("[{}]", class)
+            });
+        }
+        if stack.len() >= MAX_DEPTH {
+            return if is_array { "[Array]" } else { "[Object]" }.to_string();
+        }
+        stack.push(obj);
+        let rendered = if is_array { format_array(cx, obj, stack) } else { format_object(cx, obj, stack) };
+        stack.pop();
+        rendered.unwrap_or_else(|_| {
+            JS_ClearPendingException(cx);
+            "[Object]".to_string()
+        })
+    }
+}
+
+fn string_value(cx: *mut JSContext, value: JSVal) -> String {
+    unsafe { jsstring_to_string(cx, value.to_string()) }.unwrap_or_else(|_| "?".to_string())
+}
+
+unsafe fn class_name(obj: *mut JSObject) -> String {
+    let class = JS_GetClass(obj);
+    if class.is_null() || (*class).name.is_null() {
+        return String::new();
+    }
+    String::from_utf8_lossy(CStr::from_ptr((*class).name).to_bytes()).into_owned()
+}
+
+unsafe fn format_array(cx: *mut JSContext, array: *mut JSObject, stack: &mut Vec<*mut JSObject>)
+                       -> Result<String, ()> {
+    let mut length = 0;
+    if JS_GetArrayLength(cx, array, &mut length) == ERR {
+        return Err(());
+    }
+    let mut elements = vec![];
+    for i in 0..length {
+        let mut element = UndefinedValue();
+        if JS_GetElement(cx, array, i, &mut element) == ERR {
+            return Err(());
+        }
+        elements.push(format_value(cx, element, stack));
+    }
+    Ok(format!("[{}]", elements.join(", ")))
+}
+
+unsafe fn format_object(cx: *mut JSContext, obj: *mut JSObject, stack: &mut Vec<*mut JSObject>)
+                        -> Result<String, ()> {
+    let ids = JS_Enumerate(cx, obj);
+    if ids.is_null() {
+        return Err(());
+    }
+    let mut members = vec![];
+    let mut result = Ok(());
+    for i in 0..JS_IdArrayLength(cx, ids) {
+        let id = JS_IdArrayGet(cx, ids, i);
+        let mut key = UndefinedValue();
+        let mut member = UndefinedValue();
+        if JS_IdToValue(cx, id, &mut key) == ERR || JS_GetPropertyById(cx, obj, id, &mut member) == ERR {
+            result = Err(());
+            break;
+        }
+        let key = if key.is_string() { string_value(cx, key) } else { key.to_int32().to_string() };
+        members.push(format!("{}: {}", key, format_value(cx, member, stack)));
+    }
+    JS_DestroyIdArray(cx, ids);
+    try!(result);
+    if members.is_empty() {
+        Ok("{}".to_string())
+    } else {
+        Ok(format!("{{{}}}", members.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{ConsoleLevel, install_console};
+
+    #[test]
+    fn console_lines_reach_the_sink() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let lines = Rc::new(RefCell::new(vec![]));
+        let sink = lines.clone();
+        with_compartment(rt.context(), object_ptr(global), || {
+            install_console(rt.cx(), global, Box::new(move |level, line| {
+                sink.borrow_mut().push((level, line));
+            })).unwrap();
+            evaluate(&rt, global, "console.log('x', {a: 1}, [1, 2]); \
+                                   var o = {name: 'loop', nested: {deeper: {deepest: 1}}}; o.self = o; \
+                                   console.warn(o); \
+                                   console.error(new TypeError('bad'), null, undefined, function f() {}); \
+                                   console.info(['s', 2.5, true]); \
+                                   console.debug();").unwrap();
+        });
+        assert_eq!(*lines.borrow(), vec![
+            (ConsoleLevel::Log, "x {a: 1} [1, 2]".to_string()),
+            (ConsoleLevel::Warn, "{name: \"loop\", nested: {deeper: [Object]}, self: [Circular]}".to_string()),
+            (ConsoleLevel::Error, "TypeError: bad null undefined [Function: f]".to_string()),
+            (ConsoleLevel::Info, "[\"s\", 2.5, true]".to_string()),
+            (ConsoleLevel::Debug, "".to_string()),
+        ]);
+    }
+}
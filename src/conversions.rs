@@ -0,0 +1,629 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Conversions between JS values and Rust types.
+
+use libc::size_t;
+use std::fmt;
+use std::slice;
+use std::{i32, u32};
+use jsapi::{JSBool, JSContext, JSString, JS_ValueToNumber, JS_ValueToString, JS_ValueToBoolean, JS_TypeOfValue};
+use jsapi::JSType as RawJSType;
+use jsapi::{JS_GetStringCharsAndLength, JS_ClearPendingException};
+use jsval::{JSVal, NullValue, BooleanValue, Int32Value, UInt32Value, DoubleValue, StringValue};
+use error::{JSError, report_allocation_overflow};
+use rust::max_conversion_length;
+use strings::{RootedString, new_string};
+use utf16::from_utf16_lossy;
+use ERR;
+
+/// Copies the contents of a `JSString` into a Rust `String`, replacing
+/// unpaired surrogates with U+FFFD.
+pub unsafe fn jsstring_to_string(cx: *mut JSContext, s: *mut JSString) -> Result<String, JSError> {
+    let mut length: size_t = 0;
+    let chars = JS_GetStringCharsAndLength(cx, s, &mut length);
+    // `s` may not be rooted anywhere but our caller's stack, so nothing may
+    // collect from here until the characters have been copied out.
+    assert_no_gc!({
+        if chars.is_null() {
+            // Flattening a rope can only fail on OOM; don't try to stringify
+            // the resulting exception, that would need more memory.
+            JS_ClearPendingException(cx);
+            Err(JSError::out_of_memory("reading a string"))
+        } else {
+            Ok(from_utf16_lossy(slice::from_raw_parts(chars, length as usize)))
+        }
+    })
+}
+
+/// Checks that a JS array or string of `length` elements may be built from
+/// Rust data. If it's longer than `Runtime::set_max_conversion_length`
+/// allows, throws a catchable `InternalError: allocation size overflow` and
+/// returns false, so a native can simply return false in turn.
+pub fn check_length(cx: *mut JSContext, length: usize) -> bool {
+    if length > max_conversion_length(cx) {
+        report_allocation_overflow(cx);
+        return false;
+    }
+    true
+}
+
+/// Converts `v` to a number following the ECMAScript ToNumber algorithm.
+///
+/// Objects are converted through their `valueOf`/`toString` methods; if one
+/// of those throws the exception is returned as an error.
+pub fn to_number(cx: *mut JSContext, v: JSVal) -> Result<f64, JSError> {
+    if v.is_number() {
+        return Ok(v.to_number());
+    }
+    let mut out = 0.0;
+    unsafe {
+        if JS_ValueToNumber(cx, v, &mut out) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(out)
+}
+
+/// Converts `v` to a string following the ECMAScript ToString algorithm.
+pub fn to_string(cx: *mut JSContext, v: JSVal) -> Result<String, JSError> {
+    unsafe {
+        let s = JS_ValueToString(cx, v);
+        if s.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        jsstring_to_string(cx, s)
+    }
+}
+
+/// Converts `v` to a boolean following the ECMAScript ToBoolean algorithm.
+///
+/// This never runs script, so unlike the other conversions it cannot fail.
+pub fn to_boolean(cx: *mut JSContext, v: JSVal) -> bool {
+    if v.is_boolean() {
+        return v.to_boolean();
+    }
+    let mut b: JSBool = 0;
+    unsafe { JS_ValueToBoolean(cx, v, &mut b) };
+    b != 0
+}
+
+/// The result of the `typeof` operator, with `null` given its own variant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JSType {
+    Undefined,
+    Null,
+    Boolean,
+    Number,
+    String,
+    Object,
+    Function,
+}
+
+impl fmt::Display for JSType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            JSType::Undefined => "undefined",
+            JSType::Null => "null",
+            JSType::Boolean => "boolean",
+            JSType::Number => "number",
+            JSType::String => "string",
+            JSType::Object => "object",
+            JSType::Function => "function",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Returns the type of `v` as reported by `typeof`, except that `null` is
+/// reported as `JSType::Null` rather than `JSType::Object`.
+pub fn type_of(cx: *mut JSContext, v: JSVal) -> JSType {
+    if v.is_null() {
+        return JSType::Null;
+    }
+    match unsafe { JS_TypeOfValue(cx, v) } {
+        RawJSType::JSTYPE_VOID => JSType::Undefined,
+        RawJSType::JSTYPE_FUNCTION => JSType::Function,
+        RawJSType::JSTYPE_STRING => JSType::String,
+        RawJSType::JSTYPE_NUMBER => JSType::Number,
+        RawJSType::JSTYPE_BOOLEAN => JSType::Boolean,
+        RawJSType::JSTYPE_NULL => JSType::Null,
+        RawJSType::JSTYPE_OBJECT |
+        RawJSType::JSTYPE_XML |
+        RawJSType::JSTYPE_LIMIT => JSType::Object,
+    }
+}
+
+/// Rust types that can be made from a JS value.
+pub trait FromJSValConvertible: Sized {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<Self, JSError>;
+}
+
+impl FromJSValConvertible for JSVal {
+    fn from_jsval(_cx: *mut JSContext, v: JSVal) -> Result<JSVal, JSError> {
+        Ok(v)
+    }
+}
+
+impl FromJSValConvertible for bool {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<bool, JSError> {
+        Ok(to_boolean(cx, v))
+    }
+}
+
+impl FromJSValConvertible for f64 {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<f64, JSError> {
+        to_number(cx, v)
+    }
+}
+
+impl FromJSValConvertible for String {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<String, JSError> {
+        to_string(cx, v)
+    }
+}
+
+/// Integers aren't coerced: the value must be a number that is an integer
+/// in range, since truncating or wrapping it would lose information.
+fn to_integer(cx: *mut JSContext, v: JSVal, min: f64, max: f64, name: &str) -> Result<f64, JSError> {
+    if !v.is_number() {
+        return Err(JSError::new(format!("expected {}, got {}", name, type_of(cx, v))));
+    }
+    let n = v.to_number();
+    if n.trunc() != n || n < min || n > max {
+        return Err(JSError::new(format!("{} is not {}", n, name)));
+    }
+    Ok(n)
+}
+
+impl FromJSValConvertible for i32 {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<i32, JSError> {
+        to_integer(cx, v, i32::MIN as f64, i32::MAX as f64, "an i32").map(|n| n as i32)
+    }
+}
+
+impl FromJSValConvertible for u32 {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<u32, JSError> {
+        to_integer(cx, v, 0.0, u32::MAX as f64, "a u32").map(|n| n as u32)
+    }
+}
+
+/// `null` and `undefined` become `None`.
+impl<T: FromJSValConvertible> FromJSValConvertible for Option<T> {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<Option<T>, JSError> {
+        if v.is_null_or_undefined() {
+            Ok(None)
+        } else {
+            T::from_jsval(cx, v).map(Some)
+        }
+    }
+}
+
+/// An integer converted as WebIDL does by default: truncated and wrapped
+/// modulo 2^bits, like ECMAScript's ToInt32 and ToUint32, with NaN and the
+/// infinities becoming 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Wrapping<T>(pub T);
+
+/// An integer converted as for a WebIDL `[Clamp]` argument: clamped to the
+/// type's range and rounded to the nearest integer, ties to even. NaN
+/// becomes 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Clamp<T>(pub T);
+
+/// An integer converted as for a WebIDL `[EnforceRange]` argument:
+/// truncated, with a `TypeError` for NaN, the infinities and anything out
+/// of the type's range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EnforceRange<T>(pub T);
+
+/// The integer types WebIDL converts to, and their ranges. `i64` and `u64`
+/// are WebIDL's `long long` and `unsigned long long`, whose range is only
+/// the integers a double holds exactly.
+pub trait WebIdlInteger: Copy {
+    fn lower_bound() -> f64;
+    fn upper_bound() -> f64;
+    /// WebIDL's name for the type, for errors.
+    fn idl_name() -> &'static str;
+    /// `n`'s low bits, as the type.
+    fn from_wrapped(n: i64) -> Self;
+    /// `n`, an integer already in range.
+    fn from_in_range(n: f64) -> Self;
+}
+
+macro_rules! webidl_integer {
+    ($t:ty, $lower:expr, $upper:expr, $name:expr) => {
+        impl WebIdlInteger for $t {
+            fn lower_bound() -> f64 { $lower }
+            fn upper_bound() -> f64 { $upper }
+            fn idl_name() -> &'static str { $name }
+            fn from_wrapped(n: i64) -> $t { n as $t }
+            fn from_in_range(n: f64) -> $t { n as $t }
+        }
+    }
+}
+
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+webidl_integer!(i8, -128.0, 127.0, "byte");
+webidl_integer!(u8, 0.0, 255.0, "octet");
+webidl_integer!(i16, -32768.0, 32767.0, "short");
+webidl_integer!(u16, 0.0, 65535.0, "unsigned short");
+webidl_integer!(i32, -2147483648.0, 2147483647.0, "long");
+webidl_integer!(u32, 0.0, 4294967295.0, "unsigned long");
+webidl_integer!(i64, -MAX_SAFE_INTEGER, MAX_SAFE_INTEGER, "long long");
+webidl_integer!(u64, 0.0, MAX_SAFE_INTEGER, "unsigned long long");
+
+/// `n` truncated and wrapped modulo 2^bits, where `T` has no more than 64.
+fn wrap_integer<T: WebIdlInteger>(n: f64) -> T {
+    const TWO_63: f64 = 9223372036854775808.0;
+    const TWO_64: f64 = 18446744073709551616.0;
+    if !n.is_finite() {
+        return T::from_wrapped(0);
+    }
+    // Both the remainder and, as the operands are within a factor of two
+    // of each other, the adjustments are exact.
+    let mut n = n.trunc() % TWO_64;
+    if n >= TWO_63 {
+        n -= TWO_64;
+    } else if n < -TWO_63 {
+        n += TWO_64;
+    }
+    T::from_wrapped(n as i64)
+}
+
+fn clamp_integer<T: WebIdlInteger>(n: f64) -> T {
+    if n.is_nan() {
+        return T::from_in_range(0.0);
+    }
+    let n = n.max(T::lower_bound()).min(T::upper_bound());
+    let rounded = if (n - n.trunc()).abs() == 0.5 { 2.0 * (n / 2.0).round() } else { n.round() };
+    T::from_in_range(rounded + 0.0)
+}
+
+fn enforce_range<T: WebIdlInteger>(n: f64) -> Result<T, JSError> {
+    if !n.is_finite() {
+        return Err(JSError::type_error(format!("{} is not a finite {}", n, T::idl_name())));
+    }
+    let n = n.trunc();
+    if n < T::lower_bound() || n > T::upper_bound() {
+        return Err(JSError::type_error(format!("{} is out of range for {}", n, T::idl_name())));
+    }
+    Ok(T::from_in_range(n + 0.0))
+}
+
+impl<T: WebIdlInteger> FromJSValConvertible for Wrapping<T> {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<Wrapping<T>, JSError> {
+        to_number(cx, v).map(|n| Wrapping(wrap_integer(n)))
+    }
+}
+
+impl<T: WebIdlInteger> FromJSValConvertible for Clamp<T> {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<Clamp<T>, JSError> {
+        to_number(cx, v).map(|n| Clamp(clamp_integer(n)))
+    }
+}
+
+impl<T: WebIdlInteger> FromJSValConvertible for EnforceRange<T> {
+    fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<EnforceRange<T>, JSError> {
+        enforce_range(try!(to_number(cx, v))).map(EnforceRange)
+    }
+}
+
+/// Rust values that can be turned into a JS value. Strings become new JS
+/// strings, which aren't rooted.
+pub trait ToJSValConvertible {
+    fn to_jsval(&self, cx: *mut JSContext) -> Result<JSVal, JSError>;
+}
+
+impl ToJSValConvertible for JSVal {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(*self)
+    }
+}
+
+impl ToJSValConvertible for bool {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(BooleanValue(*self))
+    }
+}
+
+impl ToJSValConvertible for i32 {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(Int32Value(*self))
+    }
+}
+
+impl ToJSValConvertible for u32 {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(UInt32Value(*self))
+    }
+}
+
+impl ToJSValConvertible for f64 {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(DoubleValue(*self))
+    }
+}
+
+impl ToJSValConvertible for str {
+    fn to_jsval(&self, cx: *mut JSContext) -> Result<JSVal, JSError> {
+        let string = try!(new_string(cx, self));
+        Ok(StringValue(unsafe { &*string }))
+    }
+}
+
+impl ToJSValConvertible for String {
+    fn to_jsval(&self, cx: *mut JSContext) -> Result<JSVal, JSError> {
+        (**self).to_jsval(cx)
+    }
+}
+
+impl ToJSValConvertible for RootedString {
+    fn to_jsval(&self, _cx: *mut JSContext) -> Result<JSVal, JSError> {
+        Ok(self.value())
+    }
+}
+
+/// `None` becomes `null`.
+impl<T: ToJSValConvertible> ToJSValConvertible for Option<T> {
+    fn to_jsval(&self, cx: *mut JSContext) -> Result<JSVal, JSError> {
+        match *self {
+            Some(ref value) => value.to_jsval(cx),
+            None => Ok(NullValue()),
+        }
+    }
+}
+
+impl<'a, T: ToJSValConvertible + ?Sized> ToJSValConvertible for &'a T {
+    fn to_jsval(&self, cx: *mut JSContext) -> Result<JSVal, JSError> {
+        (**self).to_jsval(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::{c_char, c_uint};
+    use std::ptr;
+    use std::{f64, i32, u32, i64, u64};
+    use super::{to_number, to_string, to_boolean, type_of, JSType, check_length};
+    use super::{FromJSValConvertible, Wrapping, Clamp, EnforceRange};
+    use super::{wrap_integer, clamp_integer, enforce_range};
+    use jsapi::{JSBool, JSContext, JS_WrapValue, JS_NewArrayObject, JS_SetProperty};
+    use jsval::{JSVal, Int32Value, ObjectValue};
+    use error::ErrorKind;
+    use rust::{Runtime, with_compartment};
+    use testing::{with_test_runtime, new_global, evaluate, object_ptr};
+    use specs::{FunctionSpecBuilder, define_functions};
+    use strings::new_string;
+    use {JS_ARGV, JS_SET_RVAL};
+
+    /// `makeArray(n)` returns an array of `n` holes.
+    unsafe extern "C" fn make_array(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        let length = (*JS_ARGV(cx, vp)).to_number() as usize;
+        if !check_length(cx, length) {
+            return 0;
+        }
+        let array = JS_NewArrayObject(cx, 0, ptr::null_mut());
+        if array.is_null() {
+            return 0;
+        }
+        let mut value = Int32Value(length as i32);
+        if JS_SetProperty(cx, array, b"length\0".as_ptr() as *const c_char, &mut value) == 0 {
+            return 0;
+        }
+        JS_SET_RVAL(cx, vp, ObjectValue(&*array));
+        1
+    }
+
+    #[test]
+    fn oversized_conversions_throw() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let specs = FunctionSpecBuilder::new().function("makeArray", Some(make_array), 1).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            let caught = "try { makeArray(Math.pow(2, 32)); 'no error' } \
+                          catch (e) { e.name + ': ' + e.message }";
+            let message = to_string(rt.cx(), assert_evaluates!(rt, global, caught)).unwrap();
+            assert_eq!(message, "InternalError: allocation size overflow");
+
+            rt.set_max_conversion_length(10);
+            assert_eq!(assert_evaluates!(rt, global, "makeArray(10).length").to_number(), 10.0);
+            assert!(evaluate(&rt, global, "makeArray(11)").is_err());
+            assert!(new_string(rt.cx(), "0123456789").is_ok());
+            let err = new_string(rt.cx(), "0123456789a").unwrap_err();
+            assert!(err.message.contains("allocation size overflow"), "{}", err.message);
+        });
+    }
+
+    #[test]
+    fn number_coercions() {
+        with_test_runtime(|rt, global| {
+            let num = |src: &str| to_number(rt.cx(), assert_evaluates!(rt, global, src));
+            assert_eq!(num("'  42  '"), Ok(42.0));
+            assert!(num("({})").unwrap().is_nan());
+            assert_eq!(num("[]"), Ok(0.0));
+            assert_eq!(num("[7]"), Ok(7.0));
+            assert_eq!(num("''"), Ok(0.0));
+            assert_eq!(num("'0x10'"), Ok(16.0));
+            assert_eq!(num("true"), Ok(1.0));
+            assert_eq!(num("null"), Ok(0.0));
+            assert!(num("undefined").unwrap().is_nan());
+            assert!(num("'12px'").unwrap().is_nan());
+        });
+    }
+
+    #[test]
+    fn number_coercion_propagates_exceptions() {
+        with_test_runtime(|rt, global| {
+            let v = assert_evaluates!(rt, global, "({ valueOf: function() { throw new Error('boom'); } })");
+            let err = to_number(rt.cx(), v).unwrap_err();
+            assert!(err.message.contains("boom"));
+        });
+    }
+
+    #[test]
+    fn string_coercions() {
+        with_test_runtime(|rt, global| {
+            let string = |src: &str| to_string(rt.cx(), assert_evaluates!(rt, global, src));
+            assert_eq!(string("1.5"), Ok("1.5".to_string()));
+            assert_eq!(string("[1, [2, 3]]"), Ok("1,2,3".to_string()));
+            assert_eq!(string("null"), Ok("null".to_string()));
+            assert_eq!(string("undefined"), Ok("undefined".to_string()));
+            assert_eq!(string("({})"), Ok("[object Object]".to_string()));
+            assert_eq!(string("'\\u00e9\\u4e2d'"), Ok("\u{e9}\u{4e2d}".to_string()));
+
+            let v = assert_evaluates!(rt, global, "({ toString: function() { throw new TypeError('nope'); } })");
+            let err = to_string(rt.cx(), v).unwrap_err();
+            assert!(err.message.contains("TypeError"));
+        });
+    }
+
+    #[test]
+    fn boolean_coercions() {
+        with_test_runtime(|rt, global| {
+            let boolean = |src: &str| to_boolean(rt.cx(), assert_evaluates!(rt, global, src));
+            assert!(!boolean("''"));
+            assert!(boolean("'0'"));
+            assert!(!boolean("0"));
+            assert!(!boolean("-0"));
+            assert!(!boolean("NaN"));
+            assert!(boolean("0.5"));
+            assert!(!boolean("null"));
+            assert!(!boolean("undefined"));
+            assert!(boolean("({})"));
+            assert!(boolean("[]"));
+            assert!(boolean("new Boolean(false)"));
+            assert!(boolean("' '"));
+            assert!(!boolean("0 / 0"));
+        });
+    }
+
+    #[test]
+    fn type_of_every_variant() {
+        with_test_runtime(|rt, global| {
+            let ty = |src: &str| type_of(rt.cx(), assert_evaluates!(rt, global, src));
+            assert_eq!(ty("undefined"), JSType::Undefined);
+            assert_eq!(ty("null"), JSType::Null);
+            assert_eq!(ty("false"), JSType::Boolean);
+            assert_eq!(ty("1"), JSType::Number);
+            assert_eq!(ty("1.5"), JSType::Number);
+            assert_eq!(ty("'s'"), JSType::String);
+            assert_eq!(ty("({})"), JSType::Object);
+            assert_eq!(ty("[]"), JSType::Object);
+            assert_eq!(ty("new String('boxed')"), JSType::Object);
+            assert_eq!(ty("(function() {})"), JSType::Function);
+            // A native function object.
+            assert_eq!(ty("Math.max"), JSType::Function);
+        });
+        assert_eq!(JSType::Function.to_string(), "function");
+    }
+
+    #[test]
+    fn type_of_cross_compartment_wrappers() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let other = new_global(&rt);
+        let (fun, obj) = with_compartment(rt.context(), object_ptr(other), || {
+            (evaluate(&rt, other, "(function() {})").unwrap(),
+             evaluate(&rt, other, "({})").unwrap())
+        });
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut fun = fun;
+            let mut obj = obj;
+            unsafe {
+                assert!(JS_WrapValue(rt.cx(), &mut fun) != 0);
+                assert!(JS_WrapValue(rt.cx(), &mut obj) != 0);
+            }
+            assert_eq!(type_of(rt.cx(), fun), JSType::Function);
+            assert_eq!(type_of(rt.cx(), obj), JSType::Object);
+        });
+    }
+
+    const TWO_31: f64 = 2147483648.0;
+    const TWO_32: f64 = 4294967296.0;
+
+    #[test]
+    fn wrapping_integers() {
+        assert_eq!(wrap_integer::<i32>(TWO_31), i32::MIN);
+        assert_eq!(wrap_integer::<i32>(-TWO_31 - 1.0), i32::MAX);
+        assert_eq!(wrap_integer::<i32>(TWO_32 + 5.0), 5);
+        assert_eq!(wrap_integer::<i32>(-1.9), -1);
+        assert_eq!(wrap_integer::<u32>(-1.0), u32::MAX);
+        assert_eq!(wrap_integer::<u32>(TWO_32), 0);
+        assert_eq!(wrap_integer::<u16>(65537.5), 1);
+        assert_eq!(wrap_integer::<i8>(128.0), -128);
+        assert_eq!(wrap_integer::<u8>(-0.0), 0);
+        for &n in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert_eq!(wrap_integer::<i32>(n), 0);
+            assert_eq!(wrap_integer::<u64>(n), 0);
+        }
+        // Matches the engine's own ToInt32 on doubles far out of range.
+        assert_eq!(wrap_integer::<i32>(1e20), 1661992960);
+        assert_eq!(wrap_integer::<i64>(9223372036854775808.0), i64::MIN);
+        assert_eq!(wrap_integer::<u64>(-1.0), u64::MAX);
+        assert_eq!(wrap_integer::<u64>(18446744073709551616.0 * 3.0 + 4096.0), 4096);
+        assert_eq!(wrap_integer::<i64>(-9223372036854777856.0), 9223372036854773760);
+    }
+
+    #[test]
+    fn clamping_integers() {
+        assert_eq!(clamp_integer::<i32>(TWO_31), i32::MAX);
+        assert_eq!(clamp_integer::<i32>(-TWO_31 - 1.0), i32::MIN);
+        assert_eq!(clamp_integer::<u8>(300.0), 255);
+        assert_eq!(clamp_integer::<u8>(-5.0), 0);
+        assert_eq!(clamp_integer::<i32>(f64::INFINITY), i32::MAX);
+        assert_eq!(clamp_integer::<i32>(f64::NEG_INFINITY), i32::MIN);
+        assert_eq!(clamp_integer::<u32>(f64::NAN), 0);
+        assert_eq!(clamp_integer::<i32>(-0.0), 0);
+        // Ties go to the even neighbour.
+        assert_eq!(clamp_integer::<i32>(2.5), 2);
+        assert_eq!(clamp_integer::<i32>(3.5), 4);
+        assert_eq!(clamp_integer::<i32>(-2.5), -2);
+        assert_eq!(clamp_integer::<i32>(-0.5), 0);
+        assert_eq!(clamp_integer::<i32>(2.6), 3);
+        assert_eq!(clamp_integer::<u8>(254.5), 254);
+        assert_eq!(clamp_integer::<i64>(1e300), 9007199254740991);
+    }
+
+    #[test]
+    fn enforcing_range() {
+        assert_eq!(enforce_range::<i32>(TWO_31 - 1.0), Ok(i32::MAX));
+        assert!(enforce_range::<i32>(TWO_31).is_err());
+        assert_eq!(enforce_range::<i32>(-TWO_31), Ok(i32::MIN));
+        assert!(enforce_range::<i32>(-TWO_31 - 1.0).is_err());
+        assert_eq!(enforce_range::<i32>(-1.9), Ok(-1));
+        assert_eq!(enforce_range::<u32>(-0.0), Ok(0));
+        assert_eq!(enforce_range::<u32>(-0.5), Ok(0));
+        assert!(enforce_range::<u32>(-1.0).is_err());
+        assert_eq!(enforce_range::<u8>(255.9), Ok(255));
+        assert!(enforce_range::<u64>(9007199254740992.0).is_err());
+        for &n in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let error = enforce_range::<i32>(n).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+            assert!(error.message.starts_with("TypeError"), "{}", error.message);
+        }
+        let error = enforce_range::<u16>(65536.0).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Type);
+        assert_eq!(error.message, "TypeError: 65536 is out of range for unsigned short");
+    }
+
+    #[test]
+    fn webidl_conversions_from_js_values() {
+        with_test_runtime(|rt, global| {
+            let value = |src: &str| assert_evaluates!(rt, global, src);
+            assert_eq!(Wrapping::<i32>::from_jsval(rt.cx(), value("'4294967295'")), Ok(Wrapping(-1)));
+            assert_eq!(Wrapping::<u16>::from_jsval(rt.cx(), value("({ valueOf: function () { return 65536; } })")),
+                       Ok(Wrapping(0)));
+            assert_eq!(Clamp::<u8>::from_jsval(rt.cx(), value("'12px'")), Ok(Clamp(0)));
+            assert_eq!(Clamp::<i8>::from_jsval(rt.cx(), value("[-1000]")), Ok(Clamp(-128)));
+            assert_eq!(EnforceRange::<u32>::from_jsval(rt.cx(), value("Math.pow(2, 32) - 1")),
+                       Ok(EnforceRange(u32::MAX)));
+            assert!(EnforceRange::<i32>::from_jsval(rt.cx(), value("undefined")).is_err());
+            let thrower = value("({ valueOf: function () { throw new Error('boom'); } })");
+            assert!(Clamp::<i32>::from_jsval(rt.cx(), thrower).unwrap_err().message.contains("boom"));
+        });
+    }
+}
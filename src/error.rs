@@ -0,0 +1,887 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Structured errors captured from the JS engine.
+
+use libc::{c_char, c_int};
+use std::any::Any;
+#[cfg(test)]
+use std::cell::Cell;
+use std::error::Error;
+use std::ffi;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, ThreadId};
+use jsapi::{JSBool, JSContext, JSObject, JSErrorReport, JS_IsExceptionPending, JS_GetPendingException};
+use jsapi::{JSEXN_SYNTAXERR, JSEXN_TYPEERR, JSEXN_RANGEERR, JSEXN_REFERENCEERR, JSEXN_INTERNALERR};
+use jsapi::JSMSG_OVER_RECURSED;
+use jsapi::{JS_GetObjectRuntime, JS_GetRuntime, JS_AddNamedValueRoot, JS_RemoveValueRoot};
+use jsapi::{JS_ClearPendingException, JS_ErrorFromException, JS_ValueToString};
+use jsapi::{JS_ReportPendingException, JS_ReportAllocationOverflow, JS_ReportOutOfMemory};
+use jsapi::{JS_GetGlobalForScopeChain, JS_GetProperty, JS_New, JS_SetPendingException, JS_ReportError};
+use jsval::{JSVal, UndefinedValue, ObjectValue, StringValue};
+use conversions::jsstring_to_string;
+use rust::{source_map_url, exception_roots, take_reported_out_of_memory};
+use strings::new_string;
+use ERR;
+
+/// What kind of failure a `JSError` describes.
+///
+/// Exceptions of no more specific kind all share `Error`, rather than a
+/// variant carrying what was thrown: the error keeps that itself, see
+/// `JSError::exception`, and the kind stays `Copy`. Besides the failures
+/// the engine raises there is `RuntimeMismatch`, for the crate's own check
+/// that objects are used with their runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An exception not covered by another kind, e.g. a thrown string, an
+    /// `Error` or a `URIError`, or another failure with a message.
+    Error,
+    /// A `SyntaxError`: the script didn't compile, so none of it ran,
+    /// unless it was thrown by script, e.g. by `eval` or `JSON.parse`.
+    Syntax,
+    /// A `TypeError`, like using `null` as an object.
+    Type,
+    /// A `RangeError`, like `new Array(-1)`.
+    Range,
+    /// A `ReferenceError`: an undeclared name was read.
+    Reference,
+    /// The engine's `InternalError: too much recursion`, for script that
+    /// recursed past the stack limit.
+    Recursion,
+    /// The engine ran out of memory. There is no exception to go with it,
+    /// and retrying later, with less going on, may succeed.
+    OutOfMemory,
+    /// The script was stopped from outside, by the operation callback: it
+    /// was cancelled or exceeded its `Limits`. Script can't catch this.
+    Termination,
+    /// An object from one runtime was passed to an API with another
+    /// runtime's context. Nothing was done with it.
+    RuntimeMismatch,
+}
+
+/// An error raised by the JS engine, usually an exception thrown by script.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JSError {
+    /// What kind of failure this is; usually `Error`.
+    pub kind: ErrorKind,
+    /// The exception converted to a string, e.g. `TypeError: x is null`.
+    pub message: String,
+    /// The file the error was raised in, if the engine reported one.
+    pub filename: Option<String>,
+    /// The line the error was raised on, or 0 if unknown.
+    pub lineno: u32,
+    /// The `sourceMappingURL` of the script in `filename`, if it has one.
+    pub source_map_url: Option<String>,
+    /// The `stack` of the exception, if it was an object with one, as
+    /// `Error`s are.
+    pub js_stack: Option<String>,
+    /// The value that was thrown, if the error came from a JS exception;
+    /// see `exception`.
+    exception: Option<Arc<ExceptionRoot>>,
+}
+
+/// The roots of a runtime's `JSError` exceptions. The runtime removes
+/// them all as it is destroyed, so that errors outliving it can still be
+/// dropped safely.
+pub struct ExceptionRoots {
+    /// The addresses of the rooted values, or `None` once `release` has
+    /// removed them.
+    values: Mutex<Option<HashSet<usize>>>,
+}
+
+impl ExceptionRoots {
+    pub fn new() -> ExceptionRoots {
+        ExceptionRoots { values: Mutex::new(Some(HashSet::new())) }
+    }
+
+    /// How many exceptions are rooted.
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().as_ref().map_or(0, |values| values.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the runtime is gone, taking its roots with it.
+    fn is_released(&self) -> bool {
+        self.values.lock().unwrap().is_none()
+    }
+
+    /// Removes every root from `cx`'s runtime, which is about to be
+    /// destroyed. Errors still holding one then have no exception.
+    pub fn release(&self, cx: *mut JSContext) {
+        if let Some(values) = self.values.lock().unwrap().take() {
+            for value in values {
+                unsafe { JS_RemoveValueRoot(cx, value as *mut JSVal) };
+            }
+        }
+    }
+}
+
+impl Default for ExceptionRoots {
+    fn default() -> ExceptionRoots {
+        ExceptionRoots::new()
+    }
+}
+
+/// The root of a `JSError`'s exception, shared by the error's clones and
+/// removed when the last of them goes, or with the runtime if that goes
+/// first.
+struct ExceptionRoot {
+    cx: *mut JSContext,
+    value: *mut JSVal,
+    thread: ThreadId,
+    /// `None` for a context that doesn't belong to a `Runtime`.
+    roots: Option<Arc<ExceptionRoots>>,
+}
+
+// Only ever used on the runtime's thread; dropping one anywhere else leaks
+// the root instead.
+unsafe impl Send for ExceptionRoot {}
+unsafe impl Sync for ExceptionRoot {}
+
+impl ExceptionRoot {
+    unsafe fn new(cx: *mut JSContext, exn: JSVal) -> ExceptionRoot {
+        let value = Box::into_raw(Box::new(exn));
+        JS_AddNamedValueRoot(cx, value, b"JSError exception\0".as_ptr() as *const c_char);
+        let roots = exception_roots(cx);
+        if let Some(ref roots) = roots {
+            if let Some(ref mut values) = *roots.values.lock().unwrap() {
+                values.insert(value as usize);
+            }
+        }
+        ExceptionRoot { cx: cx, value: value, thread: thread::current().id(), roots: roots }
+    }
+
+    /// The rooted value, unless the runtime is gone.
+    fn get(&self) -> Option<JSVal> {
+        match self.roots {
+            Some(ref roots) if roots.is_released() => None,
+            _ => Some(unsafe { *self.value }),
+        }
+    }
+}
+
+impl Drop for ExceptionRoot {
+    fn drop(&mut self) {
+        if thread::current().id() != self.thread {
+            warn!("a JSError with a rooted exception was dropped off its runtime's thread; leaking the root");
+            return;
+        }
+        let rooted = match self.roots {
+            Some(ref roots) => match *roots.values.lock().unwrap() {
+                Some(ref mut values) => values.remove(&(self.value as usize)),
+                None => false,
+            },
+            None => true,
+        };
+        unsafe {
+            if rooted {
+                JS_RemoveValueRoot(self.cx, self.value);
+            }
+            drop(Box::from_raw(self.value));
+        }
+    }
+}
+
+impl fmt::Debug for ExceptionRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ExceptionRoot")
+    }
+}
+
+impl PartialEq for ExceptionRoot {
+    fn eq(&self, other: &ExceptionRoot) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl JSError {
+    /// Creates an error that did not originate from a JS exception.
+    pub fn new<S: Into<String>>(message: S) -> JSError {
+        JSError {
+            kind: ErrorKind::Error,
+            message: message.into(),
+            filename: None,
+            lineno: 0,
+            source_map_url: None,
+            js_stack: None,
+            exception: None,
+        }
+    }
+
+    /// A `Type` error, for a TypeError the crate raises itself; its message
+    /// reads like the engine's, e.g. `TypeError: f is not a function`.
+    pub fn type_error<S: Into<String>>(message: S) -> JSError {
+        JSError {
+            kind: ErrorKind::Type,
+            ..JSError::new(format!("TypeError: {}", message.into()))
+        }
+    }
+
+    /// An `OutOfMemory` error, with a message saying what was being done,
+    /// e.g. `"reading a string"`.
+    pub fn out_of_memory(doing: &str) -> JSError {
+        JSError {
+            kind: ErrorKind::OutOfMemory,
+            ..JSError::new(format!("out of memory while {}", doing))
+        }
+    }
+
+    pub fn is_out_of_memory(&self) -> bool {
+        self.kind == ErrorKind::OutOfMemory
+    }
+
+    /// A `Termination` error, for a script stopped because `why`, e.g.
+    /// `"script was cancelled"`.
+    pub fn termination<S: Into<String>>(why: S) -> JSError {
+        JSError {
+            kind: ErrorKind::Termination,
+            ..JSError::new(why)
+        }
+    }
+
+    /// A `RuntimeMismatch` error, for an object passed as `what`, e.g.
+    /// `"global"`.
+    pub fn runtime_mismatch(what: &str) -> JSError {
+        JSError {
+            kind: ErrorKind::RuntimeMismatch,
+            ..JSError::new(format!("the {} belongs to a different runtime than the context it was \
+                                    used with", what))
+        }
+    }
+
+    pub fn is_runtime_mismatch(&self) -> bool {
+        self.kind == ErrorKind::RuntimeMismatch
+    }
+
+    /// The error for an engine allocation, made while `doing` something,
+    /// that returned null: the pending exception if there is one, such as
+    /// the `InternalError` for an oversized allocation; `OutOfMemory` if the
+    /// engine reported running out, which it does without an exception;
+    /// and otherwise a plain `Error`, as for `from_pending_exception`.
+    pub unsafe fn from_failed_allocation(cx: *mut JSContext, doing: &str) -> JSError {
+        if JS_IsExceptionPending(cx) != 0 {
+            return JSError::from_pending_exception(cx);
+        }
+        if take_reported_out_of_memory(cx) {
+            return JSError::out_of_memory(doing);
+        }
+        JSError::new(format!("failed without an exception while {}", doing))
+    }
+
+    /// Takes the pending exception off `cx` and converts it into a `JSError`.
+    ///
+    /// If no exception is pending the operation failed without one, e.g.
+    /// by running out of memory, by a native returning false silently, or
+    /// by the operation callback stopping it; this can't tell which, so
+    /// the error is a plain `Error`. `Runtime`'s evaluations tell apart
+    /// the first and last themselves.
+    pub unsafe fn from_pending_exception(cx: *mut JSContext) -> JSError {
+        let mut exn: JSVal = UndefinedValue();
+        if JS_IsExceptionPending(cx) == 0 || JS_GetPendingException(cx, &mut exn) == 0 {
+            return JSError::new("uncatchable exception");
+        }
+        JS_ClearPendingException(cx);
+        JSError::from_exception(cx, exn)
+    }
+
+    /// Converts an exception value that was already taken off the context,
+    /// rooting it for as long as the error lives; see `exception`.
+    pub unsafe fn from_exception(cx: *mut JSContext, exn: JSVal) -> JSError {
+        // Rooted first, as converting it below can collect garbage.
+        let root = Arc::new(ExceptionRoot::new(cx, exn));
+        let report = JS_ErrorFromException(cx, exn);
+        let (filename, lineno) = {
+            if report.is_null() {
+                (None, 0)
+            } else {
+                let fnptr = (*report).filename;
+                let filename = if !fnptr.is_null() {
+                    let c_str = ffi::CStr::from_ptr(fnptr);
+                    Some(String::from_utf8_lossy(c_str.to_bytes()).into_owned())
+                } else {
+                    None
+                };
+                (filename, (*report).lineno as u32)
+            }
+        };
+
+        // Converting the exception can run script (a user-defined toString),
+        // which may itself throw; don't let that escape.
+        let exn_str = JS_ValueToString(cx, exn);
+        let message = if exn_str.is_null() {
+            JS_ClearPendingException(cx);
+            "<exception could not be converted to a string>".to_string()
+        } else {
+            match jsstring_to_string(cx, exn_str) {
+                Ok(s) => s,
+                Err(_) => "<exception could not be converted to a string>".to_string(),
+            }
+        };
+
+        let source_map_url = filename.as_ref().and_then(|filename| source_map_url(cx, filename));
+        JSError {
+            // Only errors the engine made, or script made with one of the
+            // standard constructors, have a report.
+            kind: if report.is_null() { ErrorKind::Error } else { error_kind_for_report(report) },
+            message: message,
+            filename: filename,
+            lineno: lineno,
+            source_map_url: source_map_url,
+            js_stack: exception_stack(cx, exn),
+            exception: Some(root),
+        }
+    }
+
+    /// The value that was thrown, if the error came from a JS exception,
+    /// so that `throw` can rethrow it as is. It stays rooted while the
+    /// error or a clone of it is alive, so the error can be held across
+    /// calls that collect garbage, but no longer than its runtime: once
+    /// that is destroyed there is no exception.
+    ///
+    /// The error must be dropped on its runtime's thread, or the root is
+    /// leaked.
+    pub fn exception(&self) -> Option<JSVal> {
+        self.exception.as_ref().and_then(|root| root.get())
+    }
+
+    /// Releases the exception, e.g. before the error is sent to another
+    /// thread.
+    pub fn drop_exception(&mut self) {
+        self.exception = None;
+    }
+
+    /// Throws this error on `cx` as `throw` does, the original exception
+    /// untouched if there is one, and releases its root. The native should
+    /// then return false.
+    pub fn rethrow(self, cx: *mut JSContext) {
+        // The pending exception is rooted by the context once it's set.
+        self.throw(cx);
+    }
+
+    /// Makes this error the pending exception on `cx`, for a native about
+    /// to return false: the original exception if there is one, otherwise
+    /// one made from this message, of the standard error type matching the
+    /// kind, such as a `TypeError` for `Type`, or else an `Error`.
+    pub fn throw(&self, cx: *mut JSContext) {
+        unsafe {
+            if let Some(exn) = self.exception() {
+                JS_SetPendingException(cx, exn);
+                return;
+            }
+        }
+        let constructor = match self.kind {
+            ErrorKind::Syntax => "SyntaxError",
+            ErrorKind::Type => "TypeError",
+            ErrorKind::Range => "RangeError",
+            ErrorKind::Reference => "ReferenceError",
+            _ => {
+                report_error(cx, &self.message);
+                return;
+            }
+        };
+        // The constructed error adds the name back when it's converted.
+        let prefix = format!("{}: ", constructor);
+        let message = if self.message.starts_with(&prefix) {
+            &self.message[prefix.len()..]
+        } else {
+            &self.message[..]
+        };
+        throw_new_error(cx, constructor, message);
+    }
+}
+
+/// The kind of error `report` describes, from its exception type. The
+/// engine gives over-recursion no type of its own, so it's told apart from
+/// other `InternalError`s by its error number.
+pub unsafe fn error_kind_for_report(report: *const JSErrorReport) -> ErrorKind {
+    match (*report).exnType as c_int {
+        JSEXN_SYNTAXERR => ErrorKind::Syntax,
+        JSEXN_TYPEERR => ErrorKind::Type,
+        JSEXN_RANGEERR => ErrorKind::Range,
+        JSEXN_REFERENCEERR => ErrorKind::Reference,
+        JSEXN_INTERNALERR if (*report).errorNumber == JSMSG_OVER_RECURSED => ErrorKind::Recursion,
+        _ => ErrorKind::Error,
+    }
+}
+
+/// Hands an exception left pending on `cx`, e.g. by a native that returned
+/// success anyway, to the error reporter and clears it, so that it isn't
+/// taken for one thrown by the evaluation about to start. Returns whether
+/// there was one.
+///
+/// Every entry point that runs script on a `Runtime` calls this first.
+pub fn report_stale_exception(cx: *mut JSContext) -> bool {
+    unsafe {
+        if JS_IsExceptionPending(cx) == 0 {
+            return false;
+        }
+        warn!("an exception was left pending before evaluation; reporting it");
+        JS_ReportPendingException(cx);
+        JS_ClearPendingException(cx);
+    }
+    true
+}
+
+/// Throws the engine's `InternalError: allocation size overflow`, for
+/// natives asked to allocate more than they can. The native should then
+/// return false.
+pub fn report_allocation_overflow(cx: *mut JSContext) {
+    unsafe { JS_ReportAllocationOverflow(cx) }
+}
+
+/// Reports that an allocation failed, the way the engine itself does. The
+/// native should then return false; script can't catch this one.
+pub fn report_out_of_memory(cx: *mut JSContext) {
+    unsafe { JS_ReportOutOfMemory(cx) }
+}
+
+/// Whether the engine allocation about to be checked should be taken to
+/// have failed, a seam for testing the out-of-memory paths of the crate's
+/// wrappers; see `fail_next_allocation`. Outside tests, never.
+#[cfg(not(test))]
+#[inline(always)]
+pub fn injected_allocation_failure(_cx: *mut JSContext) -> bool {
+    false
+}
+
+#[cfg(test)]
+thread_local!(static FAIL_NEXT_ALLOCATION: Cell<bool> = Cell::new(false));
+
+/// Makes the next `injected_allocation_failure` check on this thread fail.
+#[cfg(test)]
+pub fn fail_next_allocation() {
+    FAIL_NEXT_ALLOCATION.with(|fail| fail.set(true));
+}
+
+/// Reports the failure the way the engine reports running out of memory,
+/// so the runtime's out-of-memory callback runs too.
+#[cfg(test)]
+pub fn injected_allocation_failure(cx: *mut JSContext) -> bool {
+    if !FAIL_NEXT_ALLOCATION.with(|fail| { let failing = fail.get(); fail.set(false); failing }) {
+        return false;
+    }
+    report_out_of_memory(cx);
+    true
+}
+
+/// Checks that `obj`, passed as `what`, belongs to `cx`'s runtime, before
+/// anything is done with it that would corrupt memory if it didn't. It
+/// only reads the object's compartment, so it's cheap enough for every
+/// entry point taking one.
+pub fn check_same_runtime(cx: *mut JSContext, obj: *mut JSObject, what: &str) -> Result<(), JSError> {
+    unsafe {
+        if JS_GetObjectRuntime(obj) != JS_GetRuntime(cx) {
+            return Err(JSError::runtime_mismatch(what));
+        }
+    }
+    Ok(())
+}
+
+/// Runs `f`, Rust code called by the engine, without letting a panic
+/// unwind into C++, which is undefined behaviour. A panic is logged and, if
+/// `cx` isn't null, left pending on it as an `InternalError` carrying the
+/// panic message; `None` then tells the caller to return failure.
+///
+/// Every callback this crate hands the engine runs its Rust code under
+/// this.
+pub fn catch_panic<R, F: FnOnce() -> R>(cx: *mut JSContext, f: F) -> Option<R> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => Some(result),
+        Err(payload) => {
+            let message = format!("Rust panic: {}", panic_message(&*payload));
+            error!("{}", message);
+            if !cx.is_null() {
+                throw_internal_error(cx, &message);
+            }
+            None
+        }
+    }
+}
+
+/// `catch_panic` for the body of a `JSNative`, which returns false if it
+/// panicked:
+///
+/// ```ignore
+/// unsafe extern "C" fn native(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal) -> JSBool {
+///     guard_native(cx, || { ... })
+/// }
+/// ```
+pub fn guard_native<F: FnOnce() -> JSBool>(cx: *mut JSContext, f: F) -> JSBool {
+    catch_panic(cx, f).unwrap_or(0)
+}
+
+pub fn panic_message(payload: &Any) -> &str {
+    if let Some(message) = payload.downcast_ref::<&'static str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "<non-string panic payload>"
+    }
+}
+
+/// Throws `new InternalError(message)` from the current global, or a plain
+/// error if that can't be created.
+fn throw_internal_error(cx: *mut JSContext, message: &str) {
+    throw_new_error(cx, "InternalError", message)
+}
+
+/// Throws `new constructor(message)`, with `constructor` one of the
+/// global's error constructors, or a plain error if that can't be created.
+fn throw_new_error(cx: *mut JSContext, constructor: &str, message: &str) {
+    unsafe {
+        JS_ClearPendingException(cx);
+        let global = JS_GetGlobalForScopeChain(cx);
+        let name = ffi::CString::new(constructor).unwrap();
+        let mut ctor = UndefinedValue();
+        if !global.is_null() &&
+           JS_GetProperty(cx, global, name.as_ptr(), &mut ctor) != ERR &&
+           ctor.is_object() {
+            if let Ok(string) = new_string(cx, message) {
+                let mut arg = StringValue(&*string);
+                let error = JS_New(cx, ctor.to_object(), 1, &mut arg);
+                if !error.is_null() {
+                    JS_SetPendingException(cx, ObjectValue(&*error));
+                    return;
+                }
+            }
+        }
+        JS_ClearPendingException(cx);
+    }
+    report_error(cx, message);
+}
+
+/// Throws an `Error` with `message` the way `JS_ReportError` does.
+fn report_error(cx: *mut JSContext, message: &str) {
+    let message = ffi::CString::new(message.replace('\0', "")).unwrap();
+    unsafe { JS_ReportError(cx, b"%s\0".as_ptr() as *const c_char, message.as_ptr()) };
+}
+
+/// The `stack` property of `exn`, if it is an object and that is a string.
+/// A getter that throws is treated as no stack.
+unsafe fn exception_stack(cx: *mut JSContext, exn: JSVal) -> Option<String> {
+    if !exn.is_object() {
+        return None;
+    }
+    let mut stack = UndefinedValue();
+    if JS_GetProperty(cx, exn.to_object(), b"stack\0".as_ptr() as *const c_char, &mut stack) == ERR {
+        JS_ClearPendingException(cx);
+        return None;
+    }
+    if !stack.is_string() {
+        return None;
+    }
+    jsstring_to_string(cx, stack.to_string()).ok()
+}
+
+impl fmt::Display for JSError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(match self.filename {
+            Some(ref filename) => write!(f, "{}:{}: {}", filename, self.lineno, self.message),
+            None => write!(f, "{}", self.message),
+        });
+        match self.js_stack {
+            Some(ref stack) if !stack.is_empty() => write!(f, "\n{}", stack.trim_right()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Error for JSError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::{c_char, c_uint};
+    use jsapi::{JSBool, JSContext};
+    use jsval::{JSVal, Int32Value, UndefinedValue};
+    use conversions::to_string;
+    use rust::{Runtime, EvaluateOptions, with_compartment, exception_roots};
+    use testing::{with_test_runtime, new_global, evaluate, object_ptr};
+    use specs::{FunctionSpecBuilder, define_functions};
+    use super::{guard_native, JSError, ErrorKind, fail_next_allocation, report_out_of_memory};
+    use jsapi::{JS_GC, JS_GetRuntime, JS_GetGlobalForScopeChain, JS_GetProperty};
+    use object::call_function;
+    use pointers::{ContextPtr, ObjectPtr};
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+    use object::new_array;
+    use strings::{new_string, concat_strings, substring, RootedString};
+    use structured_clone::StructuredCloneBuffer;
+    use {JS_ARGV, JS_SET_RVAL};
+
+    unsafe extern "C" fn explode(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        guard_native(cx, || {
+            let fuse: Option<u32> = None;
+            JS_SET_RVAL(cx, vp, Int32Value(fuse.expect("fuse is missing") as i32));
+            1
+        })
+    }
+
+    #[test]
+    fn panicking_native_throws_internal_error() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let specs = FunctionSpecBuilder::new().function("explode", Some(explode), 0).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            let caught = "try { explode(); 'no error' } \
+                          catch (e) { (e instanceof InternalError) + ' ' + e.message }";
+            let message = to_string(rt.cx(), assert_evaluates!(rt, global, caught)).unwrap();
+            assert_eq!(message, "true Rust panic: fuse is missing");
+
+            let err = evaluate(&rt, global, "explode()").unwrap_err();
+            assert!(err.message.contains("fuse is missing"), "{}", err.message);
+            assert_eq!(assert_evaluates!(rt, global, "6 * 7").to_int32(), 42);
+        });
+    }
+
+    #[test]
+    fn error_keeps_the_js_stack() {
+        with_test_runtime(|rt, global| {
+            let error = evaluate(rt, global, "function inner() { throw new Error('deep'); } \
+                                               function outer() { inner(); } \
+                                               outer();").unwrap_err();
+            let stack = error.js_stack.clone().unwrap();
+            assert!(stack.contains("inner") && stack.contains("outer"), "{}", stack);
+            assert!(error.to_string().contains(&format!("Error: deep\n{}", stack.trim_right())),
+                    "{}", error);
+
+            assert_eq!(evaluate(rt, global, "throw 'plain';").unwrap_err().js_stack, None);
+            assert_eq!(evaluate(rt, global, "throw 42;").unwrap_err().js_stack, None);
+            let error = evaluate(rt, global, "throw { get stack() { throw 1; } };").unwrap_err();
+            assert_eq!(error.js_stack, None);
+            assert_eq!(JSError::new("rust").to_string(), "rust");
+        });
+    }
+
+    #[test]
+    fn injected_allocation_failures() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let reports = Rc::new(Cell::new(0));
+            let counter = reports.clone();
+            rt.set_oom_callback(Box::new(move || counter.set(counter.get() + 1)));
+
+            let a = new_string(cx, "a").unwrap();
+            let value = assert_evaluates!(rt, global, "({list: [1, 2]})");
+            let failures: Vec<Box<Fn() -> Result<(), JSError>>> = vec![
+                Box::new(|| new_string(cx, "hello").map(|_| ())),
+                Box::new(|| RootedString::from_str(cx, "hello").map(|_| ())),
+                Box::new(|| concat_strings(cx, a, a).map(|_| ())),
+                Box::new(|| substring(cx, a, 0, 1).map(|_| ())),
+                Box::new(|| new_array(cx, &[Int32Value(1)]).map(|_| ())),
+                Box::new(|| StructuredCloneBuffer::write(cx, value).map(|_| ())),
+            ];
+            for (i, failure) in failures.iter().enumerate() {
+                fail_next_allocation();
+                let error = failure().unwrap_err();
+                assert_eq!(error.kind, ErrorKind::OutOfMemory, "case {}: {}", i, error.message);
+                assert!(error.message.starts_with("out of memory while"), "{}", error.message);
+                assert_eq!(reports.get(), i + 1);
+                // The injected failure is one-shot.
+                assert!(failure().is_ok());
+            }
+
+            let mut buffer = StructuredCloneBuffer::write(cx, value).unwrap();
+            fail_next_allocation();
+            assert!(buffer.read(cx).unwrap_err().is_out_of_memory());
+            assert_eq!(reports.get(), failures.len() + 1);
+        });
+    }
+
+    #[test]
+    fn out_of_memory_while_evaluating() {
+        with_test_runtime(|rt, global| {
+            let reports = Rc::new(Cell::new(0));
+            let counter = reports.clone();
+            rt.set_oom_callback(Box::new(move || counter.set(counter.get() + 1)));
+            let specs = FunctionSpecBuilder::new().function("exhaust", Some(exhaust), 0).build();
+            define_functions(rt.cx(), global, &specs).unwrap();
+            let error = rt.evaluate(object_ptr(global), "exhaust()", &EvaluateOptions::new()).unwrap_err();
+            assert!(error.is_out_of_memory(), "{}", error.message);
+            assert_eq!(reports.get(), 1);
+            assert!(!assert_throws!(rt, global, "null.x", "TypeError").is_out_of_memory());
+        });
+    }
+
+    #[test]
+    fn only_reported_allocation_failures_are_out_of_memory() {
+        with_test_runtime(|rt, _global| {
+            let cx = rt.cx();
+            let silent = unsafe { JSError::from_failed_allocation(cx, "testing") };
+            assert_eq!(silent.kind, ErrorKind::Error);
+
+            report_out_of_memory(cx);
+            let reported = unsafe { JSError::from_failed_allocation(cx, "testing") };
+            assert_eq!(reported.kind, ErrorKind::OutOfMemory);
+            // The report is taken along with it.
+            let again = unsafe { JSError::from_failed_allocation(cx, "testing") };
+            assert_eq!(again.kind, ErrorKind::Error);
+            let nothing_pending = unsafe { JSError::from_pending_exception(cx) };
+            assert_eq!(nothing_pending.kind, ErrorKind::Error);
+        });
+    }
+
+    unsafe extern "C" fn exhaust(cx: *mut JSContext, _argc: c_uint, _vp: *mut JSVal) -> JSBool {
+        report_out_of_memory(cx);
+        0
+    }
+
+    unsafe extern "C" fn call_and_rethrow(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        let no_args: &[JSVal] = &[];
+        let global = ObjectPtr::from_raw(JS_GetGlobalForScopeChain(cx));
+        match call_function(ContextPtr::from_raw_nonnull(cx), global, *JS_ARGV(cx, vp), no_args) {
+            Ok(value) => {
+                JS_SET_RVAL(cx, vp, value);
+                1
+            }
+            Err(error) => {
+                assert!(error.exception().is_some());
+                // Cleanup that collects garbage mustn't lose the exception.
+                let copy = error.clone();
+                JS_GC(JS_GetRuntime(cx));
+                drop(copy);
+                JS_GC(JS_GetRuntime(cx));
+                error.rethrow(cx);
+                0
+            }
+        }
+    }
+
+    #[test]
+    fn rethrown_exceptions_are_identical() {
+        with_test_runtime(|rt, global| {
+            let functions = FunctionSpecBuilder::new().function("callAndRethrow", Some(call_and_rethrow), 1).build();
+            define_functions(rt.cx(), global, &functions).unwrap();
+            let seen = assert_evaluates!(rt, global, "
+                var thrown;
+                try {
+                    callAndRethrow(function () {
+                        thrown = { marker: 'original' };
+                        throw thrown;
+                    });
+                    'not thrown';
+                } catch (e) {
+                    String(e === thrown && e.marker);
+                }");
+            assert_eq!(to_string(rt.cx(), seen).unwrap(), "original");
+
+            // A primitive thrown, then collected garbage, survives as well.
+            let seen = assert_evaluates!(rt, global, "
+                try { callAndRethrow(function () { throw ['a', 'b', 'c'].join('-'); }) } catch (e) { e }");
+            assert_eq!(to_string(rt.cx(), seen).unwrap(), "a-b-c");
+        });
+    }
+
+    #[test]
+    fn errors_without_exceptions_rethrow_as_errors() {
+        with_test_runtime(|rt, global| {
+            let error = JSError::new("made in Rust");
+            assert_eq!(error.exception(), None);
+            error.rethrow(rt.cx());
+            let error = unsafe { JSError::from_pending_exception(rt.cx()) };
+            assert!(error.message.contains("made in Rust"), "{}", error.message);
+
+            // Dropping an error without rethrowing releases the root.
+            let roots = exception_roots(rt.cx()).unwrap();
+            drop(error);
+            let error = evaluate(rt, global, "throw { dropped: true }").unwrap_err();
+            assert_eq!(roots.len(), 1);
+            drop(error);
+            assert_eq!(roots.len(), 0);
+        });
+    }
+
+    #[test]
+    fn exceptions_are_rooted_until_their_runtime_goes() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let roots = exception_roots(rt.cx()).unwrap();
+        let error = with_compartment(rt.context(), object_ptr(global), || {
+            let error = evaluate(&rt, global, "throw { marker: 'kept' }").unwrap_err();
+            let copy = error.clone();
+            assert_eq!(roots.len(), 1);
+            drop(copy);
+            unsafe { JS_GC(rt.rt()) };
+            let mut marker = UndefinedValue();
+            let exception = error.exception().unwrap().to_object();
+            assert!(unsafe {
+                JS_GetProperty(rt.cx(), exception, b"marker\0".as_ptr() as *const c_char, &mut marker)
+            } != 0);
+            assert_eq!(to_string(rt.cx(), marker).unwrap(), "kept");
+            error
+        });
+        drop(rt);
+        assert_eq!(roots.len(), 0);
+        assert_eq!(error.exception(), None);
+        assert_eq!(error.message, "[object Object]");
+    }
+
+    #[test]
+    fn errors_are_classified() {
+        with_test_runtime(|rt, global| {
+            let cases = [("(", ErrorKind::Syntax),
+                         ("null.x", ErrorKind::Type),
+                         ("new Array(-1)", ErrorKind::Range),
+                         ("undeclaredName", ErrorKind::Reference),
+                         ("function deeper() { return deeper() + 1; } deeper()", ErrorKind::Recursion),
+                         ("throw new Error('plain')", ErrorKind::Error),
+                         ("throw 'a string'", ErrorKind::Error),
+                         ("decodeURI('%')", ErrorKind::Error),
+                         // Thrown by script rather than the compiler.
+                         ("JSON.parse('{')", ErrorKind::Syntax),
+                         ("throw new TypeError('by hand')", ErrorKind::Type)];
+            for &(source, kind) in &cases {
+                let error = evaluate(rt, global, source).unwrap_err();
+                assert_eq!(error.kind, kind, "{}: {}", source, error.message);
+            }
+        });
+    }
+
+    #[test]
+    fn over_recursion_is_told_apart_by_its_error_number() {
+        with_test_runtime(|rt, global| {
+            let source = "function f() { f() } f()";
+            let error = evaluate(rt, global, source).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Recursion, "{}", error.message);
+            // JSMSG_OVER_RECURSED is the linked engine's number for this.
+            assert!(error.message.contains("too much recursion"), "{}", error.message);
+            let error = evaluate(rt, global, "throw new InternalError('too much recursion')").unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Error);
+        });
+    }
+
+    #[test]
+    fn interrupted_scripts_are_terminated() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let token = rt.cancellation_token();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                token.cancel();
+            })
+        };
+        with_compartment(rt.context(), object_ptr(global), || {
+            let options = EvaluateOptions::new();
+            let error = rt.evaluate(object_ptr(global), "while (true) {}", &options).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Termination, "{}", error.message);
+        });
+        canceller.join().unwrap();
+        assert_eq!(JSError::termination("stopped").kind, ErrorKind::Termination);
+    }
+}
@@ -0,0 +1,263 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A thread owning a `Runtime`, for using one from a multithreaded program.
+//! Any thread can send it script to evaluate; results come back as plain
+//! data, since JS values can't leave the runtime's thread.
+
+use libc::c_void;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use jsapi::{JSObject, JSBool, jschar, JS_Stringify};
+use jsval::{JSVal, UndefinedValue};
+use conversions::to_string;
+use error::JSError;
+use pointers::ObjectPtr;
+use rust::{Runtime, EvaluateOptions, CancellationToken, with_compartment};
+use utf16::from_utf16_lossy;
+use ERR;
+
+/// The completion value of a script run by an `EvalThread`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalOutput {
+    Undefined,
+    Null,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    /// An object, as `JSON.stringify` would write it. Objects it skips,
+    /// like functions, are `Undefined` instead.
+    Json(String),
+}
+
+struct Request {
+    source: String,
+    filename: String,
+    reply: Sender<Result<EvalOutput, JSError>>,
+}
+
+/// A thread with its own `Runtime` and default global, evaluating the
+/// scripts sent to it one at a time, in the order they arrive. It can be
+/// shared between threads, e.g. in an `Arc`.
+///
+/// Dropping it stops the script that's running, if any, fails every request
+/// still waiting with an error saying the thread shut down, and waits for
+/// the thread to exit. `finish` runs them instead.
+pub struct EvalThread {
+    requests: Mutex<Option<Sender<Request>>>,
+    shutting_down: Arc<AtomicBool>,
+    cancellation: CancellationToken,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl EvalThread {
+    /// Starts the thread, calling `setup` there with the runtime and its
+    /// default global, inside the global's compartment, before anything is
+    /// evaluated.
+    pub fn spawn<F>(setup: F) -> EvalThread
+        where F: FnOnce(&Runtime, *mut JSObject) + Send + 'static
+    {
+        let (request_sender, request_receiver) = channel::<Request>();
+        let (token_sender, token_receiver) = channel();
+        let shutting_down = Arc::new(AtomicBool::new(false));
+        let thread_shutting_down = shutting_down.clone();
+        let thread = thread::spawn(move || {
+            let mut rt = Runtime::new();
+            rt.init_default_global(None).unwrap();
+            let global = rt.global();
+            let object = unsafe { ObjectPtr::from_raw_nonnull(global) };
+            let mut setup = Some(setup);
+            with_compartment(rt.context(), object, || {
+                (setup.take().unwrap())(&rt, global);
+                token_sender.send(rt.cancellation_token()).unwrap();
+                for request in request_receiver.iter() {
+                    let result = if thread_shutting_down.load(Ordering::SeqCst) {
+                        Err(JSError::new("the evaluation thread shut down before running this script"))
+                    } else {
+                        evaluate(&rt, object, &request.source, &request.filename)
+                    };
+                    // The requester may have stopped waiting.
+                    let _ = request.reply.send(result);
+                }
+            });
+        });
+        let cancellation = token_receiver.recv().expect("the evaluation thread failed to start");
+        EvalThread {
+            requests: Mutex::new(Some(request_sender)),
+            shutting_down: shutting_down,
+            cancellation: cancellation,
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues `source` for evaluation against the default global. The
+    /// result arrives on the returned receiver.
+    pub fn evaluate(&self, source: String, filename: String) -> Receiver<Result<EvalOutput, JSError>> {
+        let (reply, result) = channel();
+        let request = Request { source: source, filename: filename, reply: reply };
+        let requests = self.requests.lock().unwrap();
+        if let Err(unsent) = requests.as_ref().unwrap().send(request) {
+            let _ = unsent.0.reply.send(Err(JSError::new("the evaluation thread has exited")));
+        }
+        result
+    }
+
+    /// Waits for every queued request to be evaluated, then stops the
+    /// thread.
+    pub fn finish(mut self) {
+        self.stop();
+    }
+
+    fn stop(&mut self) {
+        self.requests.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            // A panic on the thread has already failed its requests.
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for EvalThread {
+    fn drop(&mut self) {
+        if self.thread.is_some() {
+            self.shutting_down.store(true, Ordering::SeqCst);
+            self.cancellation.cancel();
+            self.stop();
+        }
+    }
+}
+
+/// Evaluates `source` and converts the result to plain data. The error's
+/// exception is dropped, as it can't be used on another thread.
+fn evaluate(rt: &Runtime, global: ObjectPtr, source: &str, filename: &str)
+            -> Result<EvalOutput, JSError> {
+    let options = EvaluateOptions::new().filename(filename);
+    rt.evaluate(global, source, &options).and_then(|value| to_output(rt, value)).map_err(|mut error| {
+        error.drop_exception();
+        error
+    })
+}
+
+fn to_output(rt: &Runtime, mut value: JSVal) -> Result<EvalOutput, JSError> {
+    if value.is_undefined() {
+        return Ok(EvalOutput::Undefined);
+    }
+    if value.is_null() {
+        return Ok(EvalOutput::Null);
+    }
+    if value.is_boolean() {
+        return Ok(EvalOutput::Boolean(value.to_boolean()));
+    }
+    if value.is_number() {
+        return Ok(EvalOutput::Number(value.to_number()));
+    }
+    if value.is_string() {
+        return to_string(rt.cx(), value).map(EvalOutput::String);
+    }
+    let mut json: Vec<u16> = vec!();
+    unsafe {
+        if JS_Stringify(rt.cx(), &mut value, ptr::null_mut(), UndefinedValue(), Some(write_json),
+                        &mut json as *mut Vec<u16> as *mut c_void) == ERR {
+            return Err(JSError::from_pending_exception(rt.cx()));
+        }
+    }
+    if json.is_empty() {
+        return Ok(EvalOutput::Undefined);
+    }
+    Ok(EvalOutput::Json(from_utf16_lossy(&json)))
+}
+
+extern "C" fn write_json(chars: *const jschar, length: u32, data: *mut c_void) -> JSBool {
+    unsafe {
+        let json = &mut *(data as *mut Vec<u16>);
+        json.extend_from_slice(slice::from_raw_parts(chars, length as usize));
+    }
+    1
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::thread;
+    use rust::EvaluateOptions;
+    use testing::object_ptr;
+    use super::{EvalThread, EvalOutput};
+
+    fn evaluate(thread: &EvalThread, source: &str) -> EvalOutput {
+        thread.evaluate(source.to_string(), "test.js".to_string()).recv().unwrap().unwrap()
+    }
+
+    #[test]
+    fn results_are_plain_data() {
+        let thread = EvalThread::spawn(|rt, global| {
+            rt.evaluate(object_ptr(global), "var greeting = 'hello';", &EvaluateOptions::new()).unwrap();
+        });
+        assert_eq!(evaluate(&thread, "undefined"), EvalOutput::Undefined);
+        assert_eq!(evaluate(&thread, "null"), EvalOutput::Null);
+        assert_eq!(evaluate(&thread, "1 < 2"), EvalOutput::Boolean(true));
+        assert_eq!(evaluate(&thread, "6 * 7"), EvalOutput::Number(42.0));
+        assert_eq!(evaluate(&thread, "greeting + ' \u{1F600}'"), EvalOutput::String("hello \u{1F600}".to_string()));
+        assert_eq!(evaluate(&thread, "({ list: [1, 'two', null], nested: { ok: true } })"),
+                   EvalOutput::Json("{\"list\":[1,\"two\",null],\"nested\":{\"ok\":true}}".to_string()));
+        assert_eq!(evaluate(&thread, "(function () {})"), EvalOutput::Undefined);
+
+        let error = thread.evaluate("null.x".to_string(), "broken.js".to_string()).recv().unwrap().unwrap_err();
+        assert!(error.message.starts_with("TypeError"), "{}", error.message);
+        assert_eq!(error.filename, Some("broken.js".to_string()));
+        assert_eq!(error.exception(), None);
+        thread.finish();
+    }
+
+    #[test]
+    fn requests_from_each_sender_run_in_order() {
+        const SENDERS: usize = 4;
+        const REQUESTS: usize = 100;
+        let thread = Arc::new(EvalThread::spawn(|_, _| {}));
+        let senders: Vec<_> = (0..SENDERS).map(|sender| {
+            let thread = thread.clone();
+            thread::spawn(move || {
+                let source = format!("this.count{0} = (this.count{0} || 0) + 1", sender);
+                let replies: Vec<_> = (0..REQUESTS).map(|_| {
+                    thread.evaluate(source.clone(), format!("sender{}.js", sender))
+                }).collect();
+                for (i, reply) in replies.into_iter().enumerate() {
+                    assert_eq!(reply.recv().unwrap(), Ok(EvalOutput::Number((i + 1) as f64)));
+                }
+            })
+        }).collect();
+        for sender in senders {
+            sender.join().unwrap();
+        }
+        assert_eq!(evaluate(&thread, "count0 + count1 + count2 + count3"),
+                   EvalOutput::Number((SENDERS * REQUESTS) as f64));
+    }
+
+    #[test]
+    fn dropping_cancels_pending_requests() {
+        let thread = EvalThread::spawn(|_, _| {});
+        let running = thread.evaluate("while (true) {}".to_string(), "loop.js".to_string());
+        let pending: Vec<_> = (0..10).map(|_| thread.evaluate("1".to_string(), "pending.js".to_string())).collect();
+        drop(thread);
+        assert!(running.recv().unwrap().is_err());
+        for reply in pending {
+            let error = reply.recv().unwrap().unwrap_err();
+            assert!(error.message.contains("shut down"), "{}", error.message);
+        }
+    }
+
+    #[test]
+    fn finishing_drains_pending_requests() {
+        let thread = EvalThread::spawn(|_, _| {});
+        let replies: Vec<_> = (0..10).map(|i| thread.evaluate(format!("{} * 2", i), "drain.js".to_string()))
+                                     .collect();
+        thread.finish();
+        for (i, reply) in replies.into_iter().enumerate() {
+            assert_eq!(reply.recv().unwrap(), Ok(EvalOutput::Number((i * 2) as f64)));
+        }
+    }
+}
@@ -0,0 +1,209 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Inspecting JS functions.
+
+use jsapi::{JSContext, JSFunction, JSObject, JS_GetFunctionId, JS_GetFunctionArity};
+use jsapi::{JS_ObjectIsFunction, JS_ValueToFunction, JS_DecompileFunction};
+use jsapi::{JS_CloneFunctionObject, JS_GetParent, JS_GetGlobalForObject};
+use jsdbgapi::JS_GetFunctionScript;
+use jsval::JSVal;
+use conversions::jsstring_to_string;
+use error::JSError;
+use pointers::{ContextPtr, ObjectPtr};
+use rust::with_compartment;
+
+/// Returns the function `v` holds, or `None` if it isn't a function.
+/// Callable objects that aren't functions, like some proxies, give `None`.
+pub fn function_from_value(cx: *mut JSContext, v: JSVal) -> Option<*mut JSFunction> {
+    if !v.is_object() {
+        return None;
+    }
+    unsafe {
+        if JS_ObjectIsFunction(cx, v.to_object()) == 0 {
+            return None;
+        }
+        let fun = JS_ValueToFunction(cx, v);
+        if fun.is_null() { None } else { Some(fun) }
+    }
+}
+
+/// The function's name, or `None` for anonymous functions.
+pub fn function_name(cx: *mut JSContext, fun: *mut JSFunction) -> Option<String> {
+    unsafe {
+        let id = JS_GetFunctionId(fun);
+        if id.is_null() {
+            return None;
+        }
+        jsstring_to_string(cx, id).ok()
+    }
+}
+
+/// The number of declared arguments, i.e. the function's `length`.
+pub fn function_arity(fun: *mut JSFunction) -> u16 {
+    unsafe { JS_GetFunctionArity(fun) }
+}
+
+/// Whether `fun` is implemented natively rather than in script.
+///
+/// Unlike `function_arity` this needs a context: the public API only tells
+/// the two kinds apart through `JS_GetFunctionScript`, which takes one, and
+/// the function flags that record it aren't part of the API.
+pub fn is_native_function(cx: *mut JSContext, fun: *mut JSFunction) -> bool {
+    unsafe { JS_GetFunctionScript(cx, fun).is_null() }
+}
+
+/// Returns the source of `fun`, as `Function.prototype.toString` would.
+pub fn decompile_function(cx: *mut JSContext, fun: *mut JSFunction) -> Result<String, JSError> {
+    unsafe {
+        let source = JS_DecompileFunction(cx, fun, 0);
+        if source.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        jsstring_to_string(cx, source)
+    }
+}
+
+/// Clones the function object `fun` into `target_global`'s compartment,
+/// reparented to that global, and returns the clone, which isn't rooted.
+/// The function's script is copied rather than recompiled.
+///
+/// A clone looks up free variables in `target_global`, not in the global
+/// it was cloned from: cloning `function f() { return typeof document }`
+/// gives a function that sees the target's `document`. Functions that
+/// close over an enclosing function's variables would lose them, so
+/// cloning them is an error, as is cloning a compile-and-go function,
+/// whose script is bound to its global; compile the source with
+/// `EvaluateOptions::compile_and_go(false)` to get clonable functions.
+pub fn clone_function_into(cx: *mut JSContext, fun: *mut JSObject, target_global: *mut JSObject)
+                           -> Result<*mut JSObject, JSError> {
+    unsafe {
+        if JS_ObjectIsFunction(cx, fun) == 0 {
+            return Err(JSError::type_error("only functions can be cloned"));
+        }
+        let parent = JS_GetParent(fun);
+        if parent != JS_GetGlobalForObject(cx, fun) {
+            return Err(JSError::type_error("can't clone a function that closes over \
+                                            an enclosing function's variables"));
+        }
+    }
+    let (context, target) = unsafe {
+        (ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(target_global))
+    };
+    with_compartment(context, target, || unsafe {
+        let clone = JS_CloneFunctionObject(cx, fun, target_global);
+        if clone.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        Ok(clone)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_uint;
+    use std::ptr;
+    use jsapi::{JSBool, JSContext, JSObject, JS_CallFunctionValue, JS_GetGlobalForObject};
+    use jsval::{JSVal, UndefinedValue, ObjectValue};
+    use conversions::to_string;
+    use error::ErrorKind;
+    use rust::{Runtime, EvaluateOptions, with_compartment};
+    use testing::{with_test_runtime, new_global, object_ptr};
+    use specs::{FunctionSpecBuilder, define_functions};
+    use super::{function_from_value, function_name, function_arity};
+    use super::{is_native_function, decompile_function, clone_function_into};
+
+    unsafe extern "C" fn noop(_cx: *mut JSContext, _argc: c_uint, _vp: *mut JSVal) -> JSBool {
+        1
+    }
+
+    #[test]
+    fn script_functions() {
+        with_test_runtime(|rt, global| {
+            let named = assert_evaluates!(rt, global, "(function add(a, b) { return a + b; })");
+            let named = function_from_value(rt.cx(), named).unwrap();
+            assert_eq!(function_name(rt.cx(), named), Some("add".to_string()));
+            assert_eq!(function_arity(named), 2);
+            assert!(!is_native_function(rt.cx(), named));
+            let source = decompile_function(rt.cx(), named).unwrap();
+            assert!(source.contains("return a + b"));
+
+            let anonymous = assert_evaluates!(rt, global, "(function() {})");
+            let anonymous = function_from_value(rt.cx(), anonymous).unwrap();
+            assert_eq!(function_name(rt.cx(), anonymous), None);
+            assert_eq!(function_arity(anonymous), 0);
+
+            assert!(function_from_value(rt.cx(), assert_evaluates!(rt, global, "({})")).is_none());
+            assert!(function_from_value(rt.cx(), assert_evaluates!(rt, global, "3")).is_none());
+        });
+    }
+
+    #[test]
+    fn native_functions() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let specs = FunctionSpecBuilder::new().function("noop", Some(noop), 3).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            let fun = function_from_value(rt.cx(), assert_evaluates!(rt, global, "noop")).unwrap();
+            assert_eq!(function_name(rt.cx(), fun), Some("noop".to_string()));
+            assert_eq!(function_arity(fun), 3);
+            assert!(is_native_function(rt.cx(), fun));
+            assert!(decompile_function(rt.cx(), fun).unwrap().contains("[native code]"));
+        });
+    }
+
+    #[test]
+    fn cloned_functions_see_the_target_global() {
+        let rt = Runtime::new();
+        let (first, second) = (new_global(&rt), new_global(&rt));
+        let options = EvaluateOptions::new().compile_and_go(false);
+        let fun = with_compartment(rt.context(), object_ptr(first), || {
+            let source = "(function f() { return typeof document; })";
+            rt.evaluate(object_ptr(first), source, &options).unwrap().to_object()
+        });
+        with_compartment(rt.context(), object_ptr(second), || {
+            assert!(rt.evaluate(object_ptr(second), "var document = {};", &options).is_ok());
+            let clone = clone_function_into(rt.cx(), fun, second).unwrap();
+            assert!(clone != fun);
+            unsafe { assert_eq!(JS_GetGlobalForObject(rt.cx(), clone), second) };
+            let result = call_function(&rt, second, clone);
+            assert_eq!(to_string(rt.cx(), result).unwrap(), "object");
+        });
+        with_compartment(rt.context(), object_ptr(first), || {
+            let result = call_function(&rt, first, fun);
+            assert_eq!(to_string(rt.cx(), result).unwrap(), "undefined");
+        });
+    }
+
+    #[test]
+    fn unclonable_functions() {
+        let rt = Runtime::new();
+        let (first, second) = (new_global(&rt), new_global(&rt));
+        let options = EvaluateOptions::new().compile_and_go(false);
+        with_compartment(rt.context(), object_ptr(first), || {
+            let source = "(function() { var n = 0; return function() { return ++n; }; })()";
+            let closure = rt.evaluate(object_ptr(first), source, &options).unwrap().to_object();
+            let error = clone_function_into(rt.cx(), closure, second).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+
+            let compile_and_go = assert_evaluates!(rt, first, "(function() {})").to_object();
+            assert!(clone_function_into(rt.cx(), compile_and_go, second).is_err());
+
+            let object = assert_evaluates!(rt, first, "({})").to_object();
+            let error = clone_function_into(rt.cx(), object, second).unwrap_err();
+            assert_eq!(error.message, "TypeError: only functions can be cloned");
+            assert_eq!(error.kind, ErrorKind::Type);
+        });
+    }
+
+    fn call_function(rt: &Runtime, global: *mut JSObject, fun: *mut JSObject) -> JSVal {
+        let mut rval = UndefinedValue();
+        unsafe {
+            assert!(JS_CallFunctionValue(rt.cx(), global, ObjectValue(&*fun), 0, ptr::null_mut(),
+                                         &mut rval) != 0);
+        }
+        rval
+    }
+}
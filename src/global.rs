@@ -0,0 +1,193 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Creating global objects.
+
+use libc::{c_char, c_void};
+use std::ptr;
+use jsapi::{JSBool, JSClass, JSContext, JSObject, JSHandleObject, JSHandleId};
+use jsapi::{JS_NewGlobalObject, JS_InitStandardClasses};
+use jsapi::{JS_ResolveStandardClass, JS_EnumerateStandardClasses};
+use jsapi::{JS_PropertyStub, JS_StrictPropertyStub, JS_EnumerateStub, JS_ResolveStub, JS_ConvertStub};
+use class::ClassFlags;
+use error::JSError;
+use pointers::{ContextPtr, ObjectPtr};
+use rust::with_compartment;
+
+/// The class literal behind `global_class` and `lazy_global_class`.
+const GLOBAL_CLASS: JSClass = JSClass {
+    name: b"global\0" as *const u8 as *const c_char,
+    flags: ClassFlags::GLOBAL_WITH_SLOTS.0,
+    addProperty: Some(JS_PropertyStub),
+    delProperty: Some(JS_PropertyStub),
+    getProperty: Some(JS_PropertyStub),
+    setProperty: Some(JS_StrictPropertyStub),
+    enumerate: Some(JS_EnumerateStub),
+    resolve: Some(JS_ResolveStub),
+    convert: Some(JS_ConvertStub),
+    finalize: None,
+    checkAccess: None,
+    call: None,
+    hasInstance: None,
+    construct: None,
+    trace: None,
+    reserved: [0 as *mut c_void; 40]
+};
+
+/// A plain global class with the stub hooks.
+pub static global_class: JSClass = GLOBAL_CLASS;
+
+/// A resolve hook that creates standard classes the first time script
+/// looks them up. Use it with `lazy_standard_classes_enumerate` in a global
+/// class (e.g. through `ClassBuilder::resolve`), and don't initialize the
+/// standard classes eagerly on such globals.
+pub unsafe extern "C" fn lazy_standard_classes_resolve(cx: *mut JSContext, obj: JSHandleObject,
+                                                       id: JSHandleId) -> JSBool {
+    let mut resolved = 0;
+    JS_ResolveStandardClass(cx, *obj.unnamed_field1, *id.unnamed_field1, &mut resolved)
+}
+
+/// The enumerate hook to go with `lazy_standard_classes_resolve`: creates
+/// every standard class, so enumerating the global sees them all.
+pub unsafe extern "C" fn lazy_standard_classes_enumerate(cx: *mut JSContext, obj: JSHandleObject) -> JSBool {
+    JS_EnumerateStandardClasses(cx, *obj.unnamed_field1)
+}
+
+/// `global_class` with lazy standard class resolution: only the resolve
+/// hook, and the enumerate hook that goes with it, differ.
+pub static lazy_global_class: JSClass = JSClass {
+    enumerate: Some(lazy_standard_classes_enumerate),
+    resolve: Some(lazy_standard_classes_resolve),
+    .. GLOBAL_CLASS
+};
+
+/// How a new global gets `Object`, `Array`, `Math`, `JSON` and the rest of
+/// the standard classes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StandardClasses {
+    /// All defined when the global is created.
+    Eager,
+    /// Each created when first used. Without a class, `lazy_global_class` is
+    /// used; a custom class has to install the lazy hooks itself.
+    Lazy,
+    /// Not defined at all, for a stripped-down global.
+    None,
+}
+
+/// How `new_global_object` sets up a global.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GlobalOptions {
+    /// Defaults to `Eager`.
+    pub standard_classes: StandardClasses,
+}
+
+impl Default for GlobalOptions {
+    fn default() -> GlobalOptions {
+        GlobalOptions {
+            standard_classes: StandardClasses::Eager,
+        }
+    }
+}
+
+/// Creates a global object, in a new compartment, of class `class` or, if
+/// `None`, a plain global class. The global isn't rooted.
+pub fn new_global_object(cx: *mut JSContext, class: Option<&'static JSClass>, options: &GlobalOptions)
+                         -> Result<*mut JSObject, JSError> {
+    let class = class.unwrap_or(match options.standard_classes {
+        StandardClasses::Lazy => &lazy_global_class,
+        _ => &global_class,
+    });
+    unsafe {
+        let global = JS_NewGlobalObject(cx, class, ptr::null_mut());
+        if global.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        if options.standard_classes == StandardClasses::Eager {
+            let (context, object) = (ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(global));
+            let ok = with_compartment(context, object, || JS_InitStandardClasses(cx, global));
+            if ok == 0 {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        Ok(global)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_char;
+    use jsapi::{JSContext, JSObject, JS_AlreadyHasOwnProperty};
+    use rust::{Runtime, with_compartment};
+    use testing::{evaluate, object_ptr};
+    use super::{GlobalOptions, StandardClasses, new_global_object};
+
+    fn has_own(cx: *mut JSContext, global: *mut JSObject, name: &[u8]) -> bool {
+        let mut found = 0;
+        unsafe {
+            assert!(JS_AlreadyHasOwnProperty(cx, global, name.as_ptr() as *const c_char, &mut found) != 0);
+        }
+        found != 0
+    }
+
+    #[test]
+    fn standard_classes_by_default() {
+        let rt = Runtime::new();
+        let global = new_global_object(rt.cx(), None, &GlobalOptions::default()).unwrap();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let json = evaluate(&rt, global, "JSON.stringify([1,2,3]) === '[1,2,3]'").unwrap();
+            assert!(json.to_boolean());
+            assert_eq!(evaluate(&rt, global, "Math.max(1, 2)").unwrap().to_number(), 2.0);
+        });
+    }
+
+    #[test]
+    fn stripped_down_global() {
+        let rt = Runtime::new();
+        let options = GlobalOptions { standard_classes: StandardClasses::None };
+        let global = new_global_object(rt.cx(), None, &options).unwrap();
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert!(evaluate(&rt, global, "1 + 1").is_ok());
+            assert!(evaluate(&rt, global, "JSON").is_err());
+        });
+    }
+
+    #[test]
+    fn lazy_standard_classes() {
+        let rt = Runtime::new();
+        let options = GlobalOptions { standard_classes: StandardClasses::Lazy };
+        let global = new_global_object(rt.cx(), None, &options).unwrap();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let names: [&[u8]; 4] = [b"Math\0", b"JSON\0", b"Array\0", b"Date\0"];
+            assert!(names.iter().all(|name| !has_own(rt.cx(), global, name)));
+            assert_eq!(evaluate(&rt, global, "Math.max(1, 2)").unwrap().to_number(), 2.0);
+            // Only what was used has been created.
+            assert!(has_own(rt.cx(), global, b"Math\0"));
+            assert!(names[1..].iter().all(|name| !has_own(rt.cx(), global, name)));
+        });
+
+        // The standard classes aren't enumerable, but they're own properties.
+        let global = new_global_object(rt.cx(), None, &options).unwrap();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let names = evaluate(&rt, global, "var names = Object.getOwnPropertyNames(this); \
+                                               names.indexOf('JSON') >= 0 && names.indexOf('Array') >= 0")
+                .unwrap();
+            assert!(names.to_boolean());
+        });
+    }
+
+    #[test]
+    fn creating_many_globals() {
+        const COUNT: usize = 500;
+        let rt = Runtime::new();
+        for &standard_classes in [StandardClasses::Eager, StandardClasses::Lazy].iter() {
+            let options = GlobalOptions { standard_classes: standard_classes };
+            for _ in 0..COUNT {
+                let global = new_global_object(rt.cx(), None, &options).unwrap();
+                with_compartment(rt.context(), object_ptr(global), || {
+                    assert!(evaluate(&rt, global, "typeof JSON.parse").is_ok());
+                });
+            }
+        }
+    }
+}
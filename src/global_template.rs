@@ -0,0 +1,256 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Templates for stamping out many identically set up globals, e.g. one
+//! sandbox per request, without redoing the setup work each time.
+
+use libc::c_void;
+use std::ffi::CString;
+use std::ptr;
+use std::slice;
+use jsapi::{JSClass, JSConstDoubleSpec, JSObject};
+use jsapi::{JS_DefineConstDoubles, JS_EncodeScript, JS_DecodeScript, JS_ExecuteScript, JS_free};
+use jsval::UndefinedValue;
+use error::JSError;
+use global::{GlobalOptions, StandardClasses, new_global_object};
+use object::RootedObject;
+use rust::{Runtime, EvaluateOptions, with_compartment, check_source_length};
+use specs::{FunctionSpecs, define_functions};
+use ERR;
+
+/// Everything needed to set up a global: its class, host functions,
+/// constants and prelude scripts, the preludes already compiled to XDR
+/// bytecode. Made with `GlobalTemplateBuilder`; see
+/// `Runtime::instantiate_global`.
+pub struct GlobalTemplate {
+    class: Option<&'static JSClass>,
+    options: GlobalOptions,
+    functions: Vec<FunctionSpecs>,
+    constants: Vec<JSConstDoubleSpec>,
+    // The constants point into these, so they must not be dropped before them.
+    #[allow(dead_code)]
+    constant_names: Vec<CString>,
+    preludes: Vec<Vec<u8>>,
+}
+
+impl GlobalTemplate {
+    /// Creates a global in a new compartment and sets it up as described.
+    /// Each prelude runs afresh in every instance, so nothing it creates is
+    /// shared between them; only the compilation is. The global isn't
+    /// rooted.
+    pub fn instantiate(&self, rt: &Runtime) -> Result<*mut JSObject, JSError> {
+        let cx = rt.cx();
+        let global = try!(RootedObject::new(cx, try!(new_global_object(cx, self.class, &self.options))));
+        try!(with_compartment(rt, &global, || {
+            for specs in &self.functions {
+                try!(define_functions(cx, global.get(), specs));
+            }
+            unsafe {
+                if JS_DefineConstDoubles(cx, global.get(), self.constants.as_ptr() as *mut _) == ERR {
+                    return Err(JSError::from_pending_exception(cx));
+                }
+                for bytecode in &self.preludes {
+                    let length = try!(check_source_length(bytecode.len()));
+                    let script = JS_DecodeScript(cx, bytecode.as_ptr() as *const c_void, length,
+                                                 ptr::null_mut(), ptr::null_mut());
+                    if script.is_null() {
+                        return Err(JSError::from_pending_exception(cx));
+                    }
+                    let mut rval = UndefinedValue();
+                    if JS_ExecuteScript(cx, global.get(), script, &mut rval) == ERR {
+                        return Err(JSError::from_pending_exception(cx));
+                    }
+                }
+            }
+            Ok(())
+        }));
+        Ok(global.get())
+    }
+}
+
+/// Builds a `GlobalTemplate`.
+pub struct GlobalTemplateBuilder {
+    class: Option<&'static JSClass>,
+    options: GlobalOptions,
+    functions: Vec<FunctionSpecs>,
+    constants: Vec<(CString, f64)>,
+    preludes: Vec<(String, String)>,
+}
+
+impl GlobalTemplateBuilder {
+    /// A template for a plain global with lazily resolved standard classes,
+    /// which are the slowest part of setting one up eagerly.
+    pub fn new() -> GlobalTemplateBuilder {
+        GlobalTemplateBuilder {
+            class: None,
+            options: GlobalOptions { standard_classes: StandardClasses::Lazy },
+            functions: vec!(),
+            constants: vec!(),
+            preludes: vec!(),
+        }
+    }
+
+    /// Uses `class` for the global, as `new_global_object` does.
+    pub fn class(mut self, class: &'static JSClass) -> GlobalTemplateBuilder {
+        self.class = Some(class);
+        self
+    }
+
+    pub fn standard_classes(mut self, standard_classes: StandardClasses) -> GlobalTemplateBuilder {
+        self.options.standard_classes = standard_classes;
+        self
+    }
+
+    /// Defines the functions in `specs` on the global.
+    pub fn functions(mut self, specs: FunctionSpecs) -> GlobalTemplateBuilder {
+        self.functions.push(specs);
+        self
+    }
+
+    /// Defines a read-only, permanent number `name` on the global.
+    pub fn constant(mut self, name: &str, value: f64) -> GlobalTemplateBuilder {
+        self.constants.push((CString::new(name).unwrap(), value));
+        self
+    }
+
+    /// Runs `source` against the global once the functions and constants
+    /// are defined, after the preludes added before it.
+    pub fn prelude(mut self, source: &str, filename: &str) -> GlobalTemplateBuilder {
+        self.preludes.push((source.to_string(), filename.to_string()));
+        self
+    }
+
+    /// Compiles the preludes with `rt` and creates the template. The
+    /// template can be instantiated on any runtime.
+    pub fn build(self, rt: &Runtime) -> Result<GlobalTemplate, JSError> {
+        let cx = rt.cx();
+        let options = GlobalOptions { standard_classes: StandardClasses::None };
+        let scratch = try!(RootedObject::new(cx, try!(new_global_object(cx, None, &options))));
+        let preludes = try!(with_compartment(rt, &scratch, || {
+            let mut preludes = vec!();
+            for &(ref source, ref filename) in &self.preludes {
+                // Compile-and-go scripts can't be XDR-encoded.
+                let options = EvaluateOptions::new().filename(filename).compile_and_go(false);
+                let script = try!(rt.compile_script(scratch.get(), source, &options));
+                let mut length = 0;
+                let data = unsafe { JS_EncodeScript(cx, script.raw(), &mut length) };
+                if data.is_null() {
+                    return Err(unsafe { JSError::from_pending_exception(cx) });
+                }
+                preludes.push(unsafe { slice::from_raw_parts(data as *const u8, length as usize).to_vec() });
+                unsafe { JS_free(cx, data) };
+            }
+            Ok(preludes)
+        }));
+
+        let mut constants = vec!();
+        let mut constant_names = vec!();
+        for (name, value) in self.constants.into_iter() {
+            constants.push(JSConstDoubleSpec { dval: value, name: name.as_ptr(), flags: 0, spare: [0; 3] });
+            constant_names.push(name);
+        }
+        constants.push(JSConstDoubleSpec { dval: 0.0, name: ptr::null(), flags: 0, spare: [0; 3] });
+        Ok(GlobalTemplate {
+            class: self.class,
+            options: self.options,
+            functions: self.functions,
+            constants: constants,
+            constant_names: constant_names,
+            preludes: preludes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_uint;
+    use jsapi::{JSBool, JSContext};
+    use jsval::{JSVal, Int32Value};
+    use rust::{Runtime, with_compartment};
+    use specs::{FunctionSpecBuilder, define_functions};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::GlobalTemplateBuilder;
+    use JS_SET_RVAL;
+
+    unsafe extern "C" fn answer(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        JS_SET_RVAL(cx, vp, Int32Value(42));
+        1
+    }
+
+    const PRELUDE: &'static str = "var config = { count: 0 }; \
+                                   function bump() { return ++config.count; } \
+                                   var table = []; \
+                                   for (var i = 0; i < 200; i++) table.push({ key: 'k' + i, value: i * LIMIT });";
+
+    fn template_builder() -> GlobalTemplateBuilder {
+        GlobalTemplateBuilder::new()
+            .functions(FunctionSpecBuilder::new().function("answer", Some(answer), 0).build())
+            .constant("LIMIT", 16.0)
+            .prelude(PRELUDE, "prelude.js")
+    }
+
+    #[test]
+    fn instances_are_set_up_alike() {
+        let rt = Runtime::new();
+        let template = template_builder().build(&rt).unwrap();
+        let global = rt.instantiate_global(&template).unwrap();
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert_eq!(evaluate(&rt, global, "answer() + LIMIT").unwrap().to_number(), 58.0);
+            assert_eq!(evaluate(&rt, global, "table[3].value").unwrap().to_number(), 48.0);
+            assert!(evaluate(&rt, global, "JSON.stringify(table[0]) == '{\"key\":\"k0\",\"value\":0}'")
+                .unwrap().to_boolean());
+            // Constants can't be changed.
+            assert_eq!(evaluate(&rt, global, "LIMIT = 1; LIMIT").unwrap().to_number(), 16.0);
+        });
+    }
+
+    #[test]
+    fn instances_share_no_state() {
+        let rt = Runtime::new();
+        let template = template_builder().build(&rt).unwrap();
+        let first = rt.instantiate_global(&template).unwrap();
+        let second = rt.instantiate_global(&template).unwrap();
+        with_compartment(rt.context(), object_ptr(first), || {
+            assert!(evaluate(&rt, first, "bump(); bump(); table[0].value = -1; Array.prototype.mine = 1").is_ok());
+            assert_eq!(evaluate(&rt, first, "bump()").unwrap().to_number(), 3.0);
+        });
+        with_compartment(rt.context(), object_ptr(second), || {
+            assert_eq!(evaluate(&rt, second, "bump()").unwrap().to_number(), 1.0);
+            assert_eq!(evaluate(&rt, second, "table[0].value").unwrap().to_number(), 0.0);
+            assert!(evaluate(&rt, second, "[].mine === undefined").unwrap().to_boolean());
+        });
+    }
+
+    #[test]
+    fn broken_prelude() {
+        let rt = Runtime::new();
+        assert!(GlobalTemplateBuilder::new().prelude("var = ;", "broken.js").build(&rt).is_err());
+        let template = GlobalTemplateBuilder::new().prelude("undefinedFunction()", "throws.js")
+            .build(&rt).unwrap();
+        assert!(rt.instantiate_global(&template).is_err());
+    }
+
+    #[test]
+    fn instances_match_globals_set_up_by_hand() {
+        let rt = Runtime::new();
+        let specs = FunctionSpecBuilder::new().function("answer", Some(answer), 0).build();
+        let check = "answer() + table[199].value + bump() * 1000";
+
+        let global = new_global(&rt);
+        let by_hand = with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            evaluate(&rt, global, "var LIMIT = 16;").unwrap();
+            evaluate(&rt, global, PRELUDE).unwrap();
+            evaluate(&rt, global, check).unwrap().to_number()
+        });
+
+        let template = template_builder().build(&rt).unwrap();
+        for _ in 0..10 {
+            let global = rt.instantiate_global(&template).unwrap();
+            with_compartment(rt.context(), object_ptr(global), || {
+                assert_eq!(evaluate(&rt, global, check).unwrap().to_number(), by_hand);
+            });
+        }
+    }
+}
@@ -73,6 +73,8 @@ pub fn RUST_FUNCTION_VALUE_TO_JITINFO(v: JSVal) -> *const JSJitInfo;
 
 pub fn SetFunctionNativeReserved(fun: *mut JSObject, which: libc::size_t, val: *mut JSVal);
 pub fn GetFunctionNativeReserved(fun: *mut JSObject, which: libc::size_t) -> *mut JSVal;
+pub fn NewFunctionWithReserved(cx: *mut JSContext, call: JSNative, nargs: libc::c_uint, flags: libc::c_uint,
+                               parent: *mut JSObject, name: *const libc::c_char) -> *mut JSFunction;
 
 pub fn CreateProxyHandler(traps: *const ProxyTraps, extra: *const libc::c_void) -> *const libc::c_void;
 pub fn CreateWrapperProxyHandler(traps: *const ProxyTraps) -> *const libc::c_void;
@@ -115,4 +117,17 @@ pub fn IsWrapper(obj: *mut JSObject) -> JSBool;
 pub fn UnwrapObject(obj: *mut JSObject, stopAtOuter: JSBool, flags: *mut libc::c_uint) -> *mut JSObject;
 
 pub fn AppendToAutoIdVector(v: *mut AutoIdVector, id: jsid) -> bool;
+pub fn SealObject(cx: *mut JSContext, obj: *mut JSObject) -> JSBool;
+
+pub fn ShrinkingGC(rt: *mut JSRuntime);
+pub fn IncrementalGCSlice(rt: *mut JSRuntime, millis: i64);
+pub fn FinishIncrementalGC(rt: *mut JSRuntime);
+pub fn IsIncrementalGCInProgress(rt: *mut JSRuntime) -> bool;
+pub fn IsIncrementalGCEnabled(rt: *mut JSRuntime) -> bool;
+pub fn GetObjectCompartment(obj: *mut JSObject) -> *mut JSCompartment;
+pub fn CompartmentGC(rt: *mut JSRuntime, obj: *mut JSObject) -> bool;
+pub fn IsIncrementalBarrierNeeded(rt: *mut JSRuntime) -> bool;
+pub fn IncrementalReferenceBarrier(ptr: *mut libc::c_void);
+pub fn IncrementalValueBarrier(v: JSVal);
+pub fn DumpHeap(rt: *mut JSRuntime, fp: *mut libc::FILE, start: *mut JSObject) -> bool;
 }
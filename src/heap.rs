@@ -0,0 +1,192 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! GC pointers stored in Rust data that the GC traces.
+//!
+//! An object whose class has a trace hook may keep GC pointers in its
+//! private data instead of rooting them, as long as the hook reports them.
+//! While an incremental collection is under way, overwriting such a pointer
+//! has to tell the engine about the old value, or the collector may miss it
+//! and free something still in use; `Heap` does that on every `set`. This
+//! engine's collector doesn't move or age objects, so no post-write
+//! barrier is needed.
+
+use libc::c_void;
+use std::cell::Cell;
+use std::ptr;
+use jsapi::{JSObject, JSString, JSGCTraceKind};
+use jsapi::JSGCTraceKind::{JSTRACE_OBJECT, JSTRACE_STRING};
+use glue::{IsIncrementalBarrierNeeded, IncrementalReferenceBarrier, IncrementalValueBarrier};
+use jsval::JSVal;
+use rust::Runtime;
+
+/// A GC pointer type that can live in a `Heap`.
+pub trait GCPointer: Copy {
+    /// Tells an incremental collection in progress that `self` is about to
+    /// be overwritten.
+    unsafe fn pre_barrier(self);
+
+    /// The GC thing `self` points at and its kind, if any.
+    fn gc_thing(self) -> Option<(*mut c_void, JSGCTraceKind)>;
+}
+
+impl GCPointer for *mut JSObject {
+    unsafe fn pre_barrier(self) {
+        if !self.is_null() {
+            IncrementalReferenceBarrier(self as *mut c_void);
+        }
+    }
+
+    fn gc_thing(self) -> Option<(*mut c_void, JSGCTraceKind)> {
+        if self.is_null() { None } else { Some((self as *mut c_void, JSTRACE_OBJECT)) }
+    }
+}
+
+impl GCPointer for *mut JSString {
+    unsafe fn pre_barrier(self) {
+        if !self.is_null() {
+            IncrementalReferenceBarrier(self as *mut c_void);
+        }
+    }
+
+    fn gc_thing(self) -> Option<(*mut c_void, JSGCTraceKind)> {
+        if self.is_null() { None } else { Some((self as *mut c_void, JSTRACE_STRING)) }
+    }
+}
+
+impl GCPointer for JSVal {
+    unsafe fn pre_barrier(self) {
+        if self.is_markable() {
+            IncrementalValueBarrier(self);
+        }
+    }
+
+    fn gc_thing(self) -> Option<(*mut c_void, JSGCTraceKind)> {
+        if self.is_markable() { Some((self.to_gcthing(), self.trace_kind())) } else { None }
+    }
+}
+
+/// A GC pointer kept in Rust data owned by a traced object, e.g. a field of
+/// a `NativeClass::new_traced` object's native.
+///
+/// The pointer is neither rooted nor kept alive by the `Heap` itself: it is
+/// only safe to keep as long as the owner's trace hook reports it, e.g.
+/// through its `Traceable` implementation. Writes go through `set`, which
+/// needs the runtime whose collection may need a barrier.
+pub struct Heap<T: GCPointer> {
+    value: Cell<T>,
+}
+
+impl<T: GCPointer> Heap<T> {
+    /// Creates a cell holding `value`. A new location has no old value for
+    /// the collector to miss, so this needs no barrier.
+    pub fn new(value: T) -> Heap<T> {
+        Heap {
+            value: Cell::new(value),
+        }
+    }
+
+    pub fn get(&self) -> T {
+        self.value.get()
+    }
+
+    /// Stores `value`, running the write barrier for the old value if an
+    /// incremental collection is in progress on `rt`.
+    pub fn set(&self, rt: &Runtime, value: T) {
+        unsafe {
+            if IsIncrementalBarrierNeeded(rt.rt()) {
+                self.value.get().pre_barrier();
+            }
+        }
+        self.value.set(value);
+    }
+}
+
+impl Heap<*mut JSObject> {
+    /// A cell holding no object.
+    pub fn null() -> Heap<*mut JSObject> {
+        Heap::new(ptr::null_mut())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_char;
+    use std::ptr;
+    use jsapi::{JSObject, JS_GC, JS_DefineProperty, JS_GetProperty};
+    use jsval::{JSVal, UndefinedValue, ObjectValue};
+    use private::{NativeClass, new_object_with_native, native_from_object};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use strings::JSStringView;
+    use tracer::{Tracer, Traceable};
+    use super::Heap;
+
+    struct Holder {
+        object: Heap<*mut JSObject>,
+        value: Heap<JSVal>,
+    }
+
+    impl Traceable for Holder {
+        fn trace(&self, trc: &mut Tracer) {
+            trc.trace_object(&self.object, "object");
+            trc.trace_value(&self.value, "value");
+        }
+    }
+
+    /// Leaves the stored object and string reachable only through the
+    /// holder's trace hook.
+    #[inline(never)]
+    fn fill_holder(rt: &Runtime, global: *mut JSObject, class: &NativeClass<Holder>) {
+        let cx = rt.cx();
+        let holder = new_object_with_native(cx, class, Holder {
+            object: Heap::null(),
+            value: Heap::new(UndefinedValue()),
+        }).unwrap();
+        unsafe {
+            assert!(JS_DefineProperty(cx, global, b"holder\0".as_ptr() as *const c_char,
+                                      ObjectValue(&*holder), None, None, 0) != 0);
+            let native = native_from_object::<Holder>(holder).unwrap();
+            native.object.set(rt, evaluate(rt, global, "({answer: 42})").unwrap().to_object());
+            native.value.set(rt, evaluate(rt, global, "['kept', 'alive'].join(' ')").unwrap());
+        }
+    }
+
+    #[test]
+    fn heap_fields_survive_gc() {
+        let class = NativeClass::<Holder>::new_traced("Holder");
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            fill_holder(&rt, global, &class);
+            unsafe {
+                JS_GC(rt.rt());
+                JS_GC(rt.rt());
+
+                let holder = evaluate(&rt, global, "holder").unwrap().to_object();
+                let native = native_from_object::<Holder>(holder).unwrap();
+                let mut answer = UndefinedValue();
+                assert!(JS_GetProperty(rt.cx(), native.object.get(),
+                                       b"answer\0".as_ptr() as *const c_char, &mut answer) != 0);
+                assert_eq!(answer.to_int32(), 42);
+                let view = JSStringView::new(rt.cx(), native.value.get().to_string()).unwrap();
+                assert!(view.eq_str("kept alive"));
+            }
+        });
+    }
+
+    #[test]
+    fn set_replaces_the_value() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let cell = Heap::null();
+            assert!(cell.get().is_null());
+            cell.set(&rt, global);
+            assert_eq!(cell.get(), global);
+            cell.set(&rt, ptr::null_mut());
+            assert!(cell.get().is_null());
+        });
+    }
+}
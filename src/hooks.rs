@@ -0,0 +1,178 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Hooks the engine calls as script runs, installed through `Runtime`.
+
+use libc::{c_char, c_uint, c_void};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::CStr;
+use std::ptr;
+use std::rc::Rc;
+use jsapi::{JSBool, JSContext, JSFreeOp, JSFunction, JSScript, JS_GetFunctionId};
+use jsdbgapi::{JSStackFrame, JSTrapStatus, jsbytecode, JS_GetFrameFunction, JS_GetFrameScript};
+use jsdbgapi::{JS_GetScriptFilename, JS_GetScriptBaseLineNumber, JS_PCToLineNumber};
+use jsval::JSVal;
+use conversions::jsstring_to_string;
+use error::catch_panic;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallEventKind {
+    Enter,
+    Leave,
+}
+
+/// A scripted function being entered or left.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionCallEvent {
+    /// `None` for anonymous functions.
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    /// The line the function starts on.
+    pub line: u32,
+    pub kind: CallEventKind,
+}
+
+/// The hook behind `Runtime::set_call_hook`. The closure handed to
+/// `JS_SetCallHook` points at an `Rc<CallHook>`, and each hooked frame holds
+/// a reference of its own until it is left, so replacing the hook mid-frame
+/// doesn't free it under the frame.
+pub struct CallHook {
+    hook: RefCell<Box<FnMut(FunctionCallEvent)>>,
+    /// Set while the hook runs; calls made by the hook itself aren't reported.
+    running: Cell<bool>,
+}
+
+impl CallHook {
+    pub fn new(hook: Box<FnMut(FunctionCallEvent)>) -> Box<Rc<CallHook>> {
+        Box::new(Rc::new(CallHook {
+            hook: RefCell::new(hook),
+            running: Cell::new(false),
+        }))
+    }
+
+    /// Calls the hook, unless it is already running.
+    fn report(&self, event: FunctionCallEvent) {
+        if self.running.get() {
+            return;
+        }
+        self.running.set(true);
+        // A call hook can't fail the call, so a panic is only logged.
+        catch_panic(ptr::null_mut(), || (&mut *self.hook.borrow_mut())(event));
+        self.running.set(false);
+    }
+}
+
+/// Returns the filename and base line number of `script`.
+pub unsafe fn script_location(cx: *mut JSContext, script: *mut JSScript) -> (Option<String>, u32) {
+    if script.is_null() {
+        return (None, 0);
+    }
+    let filename = JS_GetScriptFilename(cx, script);
+    let filename = if filename.is_null() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(CStr::from_ptr(filename).to_bytes()).into_owned())
+    };
+    (filename, JS_GetScriptBaseLineNumber(cx, script))
+}
+
+/// The `JSInterpreterHook` behind `Runtime::set_call_hook`.
+///
+/// Before a call `closure` is the runtime's `Rc<CallHook>`, or null once the
+/// hook has been cleared. The value returned then is passed back as
+/// `closure` after the call, if it isn't null: a boxed reference to the same
+/// hook, owned by the frame and released here.
+pub unsafe extern "C" fn call_hook(cx: *mut JSContext, fp: *mut JSStackFrame, before: JSBool,
+                                   _ok: *mut JSBool, closure: *mut c_void) -> *mut c_void {
+    if before == 0 {
+        let hook = Box::from_raw(closure as *mut Rc<CallHook>);
+        hook.report(frame_event(cx, fp, CallEventKind::Leave));
+        return ptr::null_mut();
+    }
+    if closure.is_null() {
+        return ptr::null_mut();
+    }
+    let hook = (*(closure as *const Rc<CallHook>)).clone();
+    if hook.running.get() || JS_GetFrameFunction(cx, fp).is_null() {
+        return ptr::null_mut();
+    }
+    hook.report(frame_event(cx, fp, CallEventKind::Enter));
+    Box::into_raw(Box::new(hook)) as *mut c_void
+}
+
+/// The event for entering or leaving the function running in `fp`.
+unsafe fn frame_event(cx: *mut JSContext, fp: *mut JSStackFrame, kind: CallEventKind) -> FunctionCallEvent {
+    let fun = JS_GetFrameFunction(cx, fp);
+    let id = JS_GetFunctionId(fun);
+    let name = if id.is_null() { None } else { jsstring_to_string(cx, id).ok() };
+    let (filename, line) = script_location(cx, JS_GetFrameScript(cx, fp));
+    FunctionCallEvent {
+        name: name,
+        filename: filename,
+        line: line,
+        kind: kind,
+    }
+}
+
+/// Line coverage, per filename, of the scripts compiled while it's attached.
+pub struct CoverageCollector {
+    /// The scripts to cover and their filenames.
+    scripts: RefCell<HashMap<*mut JSScript, String>>,
+    lines: RefCell<HashMap<String, BTreeSet<u32>>>,
+}
+
+impl CoverageCollector {
+    pub fn new() -> Box<CoverageCollector> {
+        Box::new(CoverageCollector {
+            scripts: RefCell::new(HashMap::new()),
+            lines: RefCell::new(HashMap::new()),
+        })
+    }
+
+    pub fn as_closure(&self) -> *mut c_void {
+        self as *const CoverageCollector as *mut c_void
+    }
+
+    /// The lines executed so far.
+    pub fn into_lines(self) -> HashMap<String, BTreeSet<u32>> {
+        self.lines.into_inner()
+    }
+}
+
+/// Registers each new script with the `CoverageCollector` in `data`.
+pub unsafe extern "C" fn coverage_new_script(_cx: *mut JSContext, filename: *const c_char,
+                                             _lineno: c_uint, script: *mut JSScript,
+                                             _fun: *mut JSFunction, data: *mut c_void) {
+    let collector = &*(data as *const CoverageCollector);
+    let filename = if filename.is_null() {
+        String::new()
+    } else {
+        String::from_utf8_lossy(CStr::from_ptr(filename).to_bytes()).into_owned()
+    };
+    collector.scripts.borrow_mut().insert(script, filename);
+}
+
+/// Forgets finalized scripts, whose addresses may be reused.
+pub unsafe extern "C" fn coverage_destroy_script(_fop: *mut JSFreeOp, script: *mut JSScript,
+                                                 data: *mut c_void) {
+    let collector = &*(data as *const CoverageCollector);
+    collector.scripts.borrow_mut().remove(&script);
+}
+
+/// The single-step hook: records the line of every bytecode executed in a
+/// script the collector knows about.
+pub unsafe extern "C" fn coverage_step(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                                       _rval: *mut JSVal, data: *mut c_void) -> JSTrapStatus {
+    let collector = &*(data as *const CoverageCollector);
+    if let Some(filename) = collector.scripts.borrow().get(&script) {
+        let line = JS_PCToLineNumber(cx, script, pc);
+        let mut lines = collector.lines.borrow_mut();
+        if !lines.contains_key(filename) {
+            lines.insert(filename.clone(), BTreeSet::new());
+        }
+        lines.get_mut(filename).unwrap().insert(line);
+    }
+    JSTrapStatus::JSTRAP_CONTINUE
+}
@@ -27,8 +27,13 @@ pub type JSProtoKey = c_uint;
 pub const JSProto_LIMIT: JSProtoKey = 41;
 
 #[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum JSVersion {
     JSVERSION_DEFAULT = 0,
+    JSVERSION_ECMA_3  = 148,
+    JSVERSION_1_6     = 160,
+    JSVERSION_1_7     = 170,
+    JSVERSION_1_8     = 180,
     JSVERSION_LATEST  = 185
 }
 
@@ -362,6 +367,8 @@ pub struct JSPropertyDescriptor {
 }
 pub struct JSPrincipals {
     pub refcount: c_int,
+    #[cfg(feature = "debugmozjs")]
+    pub debugToken: u32,
 }
 pub struct JSSecurityCallbacks {
     pub checkObjectAccess: JSCheckAccessOp,
@@ -410,6 +417,10 @@ pub const JSREPORT_EXCEPTION: c_uint = 0x2;
 pub const JSREPORT_STRICT: c_uint = 0x4;
 pub const JSREPORT_STRICT_MODE_ERROR: c_uint = 0x8;
 
+// Error numbers from js.msg.
+pub const JSMSG_OVER_RECURSED: c_uint = 25;
+pub const JSMSG_OUT_OF_MEMORY: c_uint = 137;
+
 extern "C" {
 pub fn JS_CreateHandleObject(cx: *mut JSContext, obj: *mut JSObject, phandle: *mut JSHandleObject) -> JSBool;
 
@@ -703,6 +714,8 @@ pub fn JS_GC(rt: *mut JSRuntime);
 
 pub fn JS_MaybeGC(cx: *mut JSContext);
 
+// Only exported by debug builds of SpiderMonkey.
+#[cfg(feature = "debugmozjs")]
 pub fn JS_SetGCZeal(cx: *mut JSContext, zeal: uint8_t, frequency: uint32_t);
 
 pub fn JS_InhibitGC(cx: *mut JSContext);
@@ -0,0 +1,78 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Bindings for the parts of `jsdbgapi.h` used by the crate.
+
+use libc::{c_char, c_uint, c_void};
+use jsapi::{JSBool, jschar, JSContext, JSFreeOp, JSFunction, JSObject, JSRuntime, JSScript};
+use jsval::JSVal;
+
+pub enum JSStackFrame {}
+
+pub type jsbytecode = u8;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JSTrapStatus {
+    JSTRAP_ERROR,
+    JSTRAP_CONTINUE,
+    JSTRAP_RETURN,
+    JSTRAP_THROW,
+    JSTRAP_LIMIT,
+}
+
+pub type JSInterpreterHook =
+    Option<unsafe extern "C" fn(cx: *mut JSContext, fp: *mut JSStackFrame, before: JSBool,
+                                ok: *mut JSBool, closure: *mut c_void) -> *mut c_void>;
+
+pub type JSTrapHandler =
+    Option<unsafe extern "C" fn(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                                rval: *mut JSVal, closure: JSVal) -> JSTrapStatus>;
+
+pub type JSInterruptHook =
+    Option<unsafe extern "C" fn(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                                rval: *mut JSVal, closure: *mut c_void) -> JSTrapStatus>;
+
+pub type JSNewScriptHook =
+    Option<unsafe extern "C" fn(cx: *mut JSContext, filename: *const c_char, lineno: c_uint,
+                                script: *mut JSScript, fun: *mut JSFunction, callerdata: *mut c_void)>;
+
+pub type JSDestroyScriptHook =
+    Option<unsafe extern "C" fn(fop: *mut JSFreeOp, script: *mut JSScript, callerdata: *mut c_void)>;
+
+extern {
+pub fn JS_SetDebugMode(cx: *mut JSContext, debug: JSBool) -> JSBool;
+pub fn JS_SetRuntimeDebugMode(rt: *mut JSRuntime, debug: JSBool);
+pub fn JS_GetDebugMode(cx: *mut JSContext) -> JSBool;
+
+pub fn JS_SetTrap(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                  handler: JSTrapHandler, closure: JSVal) -> JSBool;
+pub fn JS_ClearTrap(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                    handlerp: *mut JSTrapHandler, closurep: *mut JSVal);
+pub fn JS_ClearScriptTraps(cx: *mut JSContext, script: *mut JSScript);
+pub fn JS_LineNumberToPC(cx: *mut JSContext, script: *mut JSScript, lineno: c_uint) -> *mut jsbytecode;
+
+pub fn JS_SetInterrupt(rt: *mut JSRuntime, handler: JSInterruptHook, closure: *mut c_void) -> JSBool;
+pub fn JS_ClearInterrupt(rt: *mut JSRuntime, handlerp: *mut JSInterruptHook,
+                         closurep: *mut *mut c_void) -> JSBool;
+
+pub fn JS_SetCallHook(rt: *mut JSRuntime, hook: JSInterpreterHook, closure: *mut c_void) -> JSBool;
+pub fn JS_SetNewScriptHook(rt: *mut JSRuntime, hook: JSNewScriptHook, callerdata: *mut c_void);
+pub fn JS_SetDestroyScriptHook(rt: *mut JSRuntime, hook: JSDestroyScriptHook, callerdata: *mut c_void);
+
+pub fn JS_GetFrameScript(cx: *mut JSContext, fp: *mut JSStackFrame) -> *mut JSScript;
+pub fn JS_GetFramePC(cx: *mut JSContext, fp: *mut JSStackFrame) -> *mut jsbytecode;
+pub fn JS_GetFrameFunction(cx: *mut JSContext, fp: *mut JSStackFrame) -> *mut JSFunction;
+pub fn JS_GetFrameFunctionObject(cx: *mut JSContext, fp: *mut JSStackFrame) -> *mut JSObject;
+pub fn JS_GetFrameThis(cx: *mut JSContext, fp: *mut JSStackFrame, thisv: *mut JSVal) -> JSBool;
+pub fn JS_FrameIterator(cx: *mut JSContext, iteratorp: *mut *mut JSStackFrame) -> *mut JSStackFrame;
+pub fn JS_EvaluateUCInStackFrame(cx: *mut JSContext, fp: *mut JSStackFrame, chars: *const jschar,
+                                 length: c_uint, filename: *const c_char, lineno: c_uint,
+                                 rval: *mut JSVal) -> JSBool;
+
+pub fn JS_GetFunctionScript(cx: *mut JSContext, fun: *mut JSFunction) -> *mut JSScript;
+pub fn JS_GetScriptFilename(cx: *mut JSContext, script: *mut JSScript) -> *const c_char;
+pub fn JS_GetScriptBaseLineNumber(cx: *mut JSContext, script: *mut JSScript) -> c_uint;
+pub fn JS_PCToLineNumber(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode) -> c_uint;
+}
@@ -384,6 +384,13 @@ GetFunctionNativeReserved(JSObject* fun, size_t which)
     return &js::GetFunctionNativeReserved(fun, which);
 }
 
+JSFunction*
+NewFunctionWithReserved(JSContext* cx, JSNative call, unsigned nargs, unsigned flags,
+                        JSObject* parent, const char* name)
+{
+    return js::NewFunctionWithReserved(cx, call, nargs, flags, parent, name);
+}
+
 const void*
 CreateProxyHandler(const ProxyTraps* aTraps, void* aExtra)
 {
@@ -564,4 +571,132 @@ AppendToAutoIdVector(JS::AutoIdVector* v, jsid id)
     return v->append(id);
 }
 
+JSBool
+SealObject(JSContext* cx, JSObject* obj)
+{
+    // There's no JSAPI entry point for Object.seal. Freezing makes the
+    // object non-extensible and its properties permanent, as sealing does,
+    // but also read-only; JS_DefinePropertyById, which skips the checks
+    // script-level redefinition makes, then makes the data properties that
+    // were writable writable again.
+    JS::AutoIdVector ids(cx);
+    if (!js::GetPropertyNames(cx, obj, JSITER_OWNONLY | JSITER_HIDDEN, &ids)) {
+        return false;
+    }
+    JS::AutoIdVector writable(cx);
+    for (size_t i = 0; i < ids.length(); i++) {
+        JSPropertyDescriptor desc;
+        if (!JS_GetPropertyDescriptorById(cx, obj, ids[i], 0, &desc)) {
+            return false;
+        }
+        if (desc.obj == obj && !(desc.attrs & (JSPROP_READONLY | JSPROP_GETTER | JSPROP_SETTER)) &&
+            !writable.append(ids[i])) {
+            return false;
+        }
+    }
+    if (!JS_FreezeObject(cx, obj)) {
+        return false;
+    }
+    for (size_t i = 0; i < writable.length(); i++) {
+        JSPropertyDescriptor desc;
+        if (!JS_GetPropertyDescriptorById(cx, obj, writable[i], 0, &desc) ||
+            !JS_DefinePropertyById(cx, obj, writable[i], desc.value, desc.getter, desc.setter,
+                                   desc.attrs & ~JSPROP_READONLY)) {
+            return false;
+        }
+    }
+    return true;
+}
+
+void
+ShrinkingGC(JSRuntime* rt)
+{
+    JS::PrepareForFullGC(rt);
+    JS::ShrinkingGC(rt, JS::gcreason::API);
+}
+
+void
+IncrementalGCSlice(JSRuntime* rt, int64_t millis)
+{
+    // Preparing a different set of zones than the running collection's
+    // would reset it.
+    if (JS::IsIncrementalGCInProgress(rt)) {
+        JS::PrepareForIncrementalGC(rt);
+    } else {
+        JS::PrepareForFullGC(rt);
+    }
+    JS::IncrementalGC(rt, JS::gcreason::API, millis);
+}
+
+void
+FinishIncrementalGC(JSRuntime* rt)
+{
+    JS::PrepareForIncrementalGC(rt);
+    JS::FinishIncrementalGC(rt, JS::gcreason::API);
+}
+
+bool
+IsIncrementalGCEnabled(JSRuntime* rt)
+{
+    return JS::IsIncrementalGCEnabled(rt);
+}
+
+JSCompartment*
+GetObjectCompartment(JSObject* obj)
+{
+    return js::GetObjectCompartment(obj);
+}
+
+bool
+CompartmentGC(JSRuntime* rt, JSObject* obj)
+{
+    // Scheduling only some compartments would reset a collection that is
+    // already marking others.
+    if (JS::IsIncrementalGCInProgress(rt)) {
+        return false;
+    }
+    JS::PrepareCompartmentForGC(js::GetObjectCompartment(obj));
+    JS::GCForReason(rt, JS::gcreason::API);
+    return true;
+}
+
+bool
+IsIncrementalGCInProgress(JSRuntime* rt)
+{
+    return JS::IsIncrementalGCInProgress(rt);
+}
+
+bool
+IsIncrementalBarrierNeeded(JSRuntime* rt)
+{
+    return js::IsIncrementalBarrierNeeded(rt);
+}
+
+void
+IncrementalReferenceBarrier(void* ptr)
+{
+    js::IncrementalReferenceBarrier(ptr);
+}
+
+void
+IncrementalValueBarrier(jsval v)
+{
+    js::IncrementalValueBarrier(v);
+}
+
+bool
+DumpHeap(JSRuntime* rt, FILE* fp, JSObject* start)
+{
+#ifdef DEBUG
+    return JS_DumpHeap(rt, fp, start, JSTRACE_OBJECT, NULL, size_t(-1), NULL);
+#else
+    // Only debug builds can dump from a given object.
+    if (start) {
+        return false;
+    }
+    js::DumpHeapComplete(rt, fp);
+    return true;
+#endif
+}
+
 } // extern "C"
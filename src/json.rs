@@ -0,0 +1,238 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Converting between rustc-serialize's `Json` and JS values directly,
+//! without going through JSON text. Behind the `rustc-serialize` feature.
+
+use libc::size_t;
+use std::collections::BTreeMap;
+use std::ptr;
+use serialize::json::Json;
+use jsapi::{JSContext, JSObject, JS_NewObject, JS_NewArrayObject, JS_IsArrayObject};
+use jsapi::{JS_GetArrayLength, JS_GetElement, JS_SetElement, JS_DefineUCProperty};
+use jsapi::{JS_Enumerate, JS_IdArrayLength, JS_IdArrayGet, JS_DestroyIdArray};
+use jsapi::{JS_IdToValue, JS_GetPropertyById, JS_ObjectIsCallable};
+use jsval::{JSVal, NullValue, BooleanValue, DoubleValue, NumberValue, StringValue, UndefinedValue};
+use conversions::{jsstring_to_string, check_length};
+use error::JSError;
+use object::RootedObject;
+use strings::new_string;
+use utf16::to_utf16;
+use {ERR, JSPROP_ENUMERATE};
+
+/// Builds the JS value `json` describes in `cx`'s current compartment.
+pub fn json_to_jsval(cx: *mut JSContext, json: &Json) -> Result<JSVal, JSError> {
+    match *json {
+        Json::I64(n) => Ok(NumberValue(n as f64)),
+        Json::U64(n) => Ok(NumberValue(n as f64)),
+        Json::F64(n) => Ok(DoubleValue(n)),
+        Json::String(ref s) => {
+            let string = try!(new_string(cx, s));
+            Ok(StringValue(unsafe { &*string }))
+        }
+        Json::Boolean(b) => Ok(BooleanValue(b)),
+        Json::Null => Ok(NullValue()),
+        Json::Array(ref elements) => {
+            if !check_length(cx, elements.len()) {
+                return Err(unsafe { JSError::from_pending_exception(cx) });
+            }
+            let array = try!(RootedObject::new(cx, unsafe { JS_NewArrayObject(cx, 0, ptr::null_mut()) }));
+            for (i, element) in elements.iter().enumerate() {
+                let mut value = try!(json_to_jsval(cx, element));
+                unsafe {
+                    if JS_SetElement(cx, array.get(), i as u32, &mut value) == ERR {
+                        return Err(JSError::from_pending_exception(cx));
+                    }
+                }
+            }
+            Ok(array.value())
+        }
+        Json::Object(ref members) => {
+            let object = try!(RootedObject::new(cx, unsafe {
+                JS_NewObject(cx, ptr::null(), ptr::null(), ptr::null())
+            }));
+            for (name, member) in members {
+                let value = try!(json_to_jsval(cx, member));
+                let name = to_utf16(name);
+                unsafe {
+                    if JS_DefineUCProperty(cx, object.get(), name.as_ptr(), name.len() as size_t, value,
+                                           None, None, JSPROP_ENUMERATE) == ERR {
+                        return Err(JSError::from_pending_exception(cx));
+                    }
+                }
+            }
+            Ok(object.value())
+        }
+    }
+}
+
+/// Converts `value` the way `JSON.stringify` would see it: non-finite
+/// numbers become `null`, as do `undefined` and functions inside arrays,
+/// while object properties holding them are left out. Fails if `value`
+/// itself has no JSON form or contains a cycle. `toJSON` methods are not
+/// called.
+pub fn jsval_to_json(cx: *mut JSContext, value: JSVal) -> Result<Json, JSError> {
+    let mut stack = vec![];
+    match try!(convert(cx, value, &mut stack)) {
+        Some(json) => Ok(json),
+        None => Err(JSError::new("value has no JSON representation")),
+    }
+}
+
+/// Numbers come back the way rustc-serialize parses them: integers as
+/// `U64` or `I64`, everything else as `F64`.
+fn number_json(n: f64) -> Json {
+    if !n.is_finite() {
+        Json::Null
+    } else if n.fract() == 0.0 && n.abs() < 9007199254740992.0 {
+        if n >= 0.0 { Json::U64(n as u64) } else { Json::I64(n as i64) }
+    } else {
+        Json::F64(n)
+    }
+}
+
+/// `None` for values `JSON.stringify` skips. `stack` holds the objects
+/// being converted, to detect cycles.
+fn convert(cx: *mut JSContext, value: JSVal, stack: &mut Vec<RootedObject>) -> Result<Option<Json>, JSError> {
+    if value.is_undefined() {
+        return Ok(None);
+    }
+    if value.is_null() {
+        return Ok(Some(Json::Null));
+    }
+    if value.is_boolean() {
+        return Ok(Some(Json::Boolean(value.to_boolean())));
+    }
+    if value.is_number() {
+        return Ok(Some(number_json(value.to_number())));
+    }
+    if value.is_string() {
+        return Ok(Some(Json::String(try!(unsafe { jsstring_to_string(cx, value.to_string()) }))));
+    }
+    let obj = value.to_object();
+    if unsafe { JS_ObjectIsCallable(cx, obj) } != 0 {
+        return Ok(None);
+    }
+    if stack.iter().any(|ancestor| ancestor.get() == obj) {
+        return Err(JSError::new("cyclic object value"));
+    }
+    stack.push(try!(RootedObject::new(cx, obj)));
+    let result = if unsafe { JS_IsArrayObject(cx, obj) } != 0 {
+        convert_array(cx, obj, stack)
+    } else {
+        convert_object(cx, obj, stack)
+    };
+    stack.pop();
+    result.map(Some)
+}
+
+fn convert_array(cx: *mut JSContext, array: *mut JSObject, stack: &mut Vec<RootedObject>) -> Result<Json, JSError> {
+    let mut length = 0;
+    unsafe {
+        if JS_GetArrayLength(cx, array, &mut length) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    let mut elements = Vec::with_capacity(length as usize);
+    for i in 0..length {
+        let mut element = UndefinedValue();
+        unsafe {
+            if JS_GetElement(cx, array, i, &mut element) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        elements.push(try!(convert(cx, element, stack)).unwrap_or(Json::Null));
+    }
+    Ok(Json::Array(elements))
+}
+
+fn convert_object(cx: *mut JSContext, obj: *mut JSObject, stack: &mut Vec<RootedObject>) -> Result<Json, JSError> {
+    let mut ids = vec![];
+    unsafe {
+        let array = JS_Enumerate(cx, obj);
+        if array.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        for i in 0..JS_IdArrayLength(cx, array) {
+            ids.push(JS_IdArrayGet(cx, array, i));
+        }
+        JS_DestroyIdArray(cx, array);
+    }
+    let mut members = BTreeMap::new();
+    for id in ids {
+        let mut key = UndefinedValue();
+        let mut member = UndefinedValue();
+        unsafe {
+            if JS_IdToValue(cx, id, &mut key) == ERR ||
+               JS_GetPropertyById(cx, obj, id, &mut member) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        let key = if key.is_string() {
+            try!(unsafe { jsstring_to_string(cx, key.to_string()) })
+        } else {
+            // Index properties have integer ids.
+            key.to_int32().to_string()
+        };
+        if let Some(member) = try!(convert(cx, member, stack)) {
+            members.insert(key, member);
+        }
+    }
+    Ok(Json::Object(members))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use serialize::json::Json;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{json_to_jsval, jsval_to_json};
+
+    fn nested(depth: usize) -> Json {
+        let mut json = Json::Array(vec![Json::Null, Json::Boolean(true)]);
+        for i in 0..depth {
+            let mut members = BTreeMap::new();
+            members.insert("level".to_string(), Json::U64(i as u64));
+            members.insert("child".to_string(), json);
+            members.insert("name \u{e9}".to_string(), Json::String(format!("n{}", i)));
+            members.insert("offsets".to_string(), Json::Array(vec![Json::I64(-1), Json::F64(0.5)]));
+            json = Json::Object(members);
+        }
+        json
+    }
+
+    #[test]
+    fn round_trip() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let original = nested(200);
+            let value = json_to_jsval(rt.cx(), &original).unwrap();
+            assert_eq!(jsval_to_json(rt.cx(), value).unwrap(), original);
+        });
+    }
+
+    #[test]
+    fn follows_stringify_rules() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let value = evaluate(&rt, global, "({a: [undefined, function() {}, NaN, -Infinity, 1.5], \
+                                                 b: undefined, c: function() {}, 3: 'three'})").unwrap();
+            let json = jsval_to_json(rt.cx(), value).unwrap();
+            assert_eq!(json, Json::from_str("{\"a\": [null, null, null, null, 1.5], \"3\": \"three\"}").unwrap());
+
+            let value = evaluate(&rt, global, "undefined").unwrap();
+            assert!(jsval_to_json(rt.cx(), value).is_err());
+            let value = evaluate(&rt, global, "(function() {})").unwrap();
+            assert!(jsval_to_json(rt.cx(), value).is_err());
+            let value = evaluate(&rt, global, "var o = {}; o.self = [o]; o").unwrap();
+            assert!(jsval_to_json(rt.cx(), value).is_err());
+            // Shared references that aren't cycles are fine.
+            let value = evaluate(&rt, global, "var s = {}; [s, s]").unwrap();
+            assert!(jsval_to_json(rt.cx(), value).is_ok());
+        });
+    }
+}
@@ -6,7 +6,9 @@ use jsapi::{JSObject, JSString, JSGCTraceKind};
 use jsapi::JSGCTraceKind::{JSTRACE_OBJECT, JSTRACE_STRING};
 
 use libc::c_void;
+use std::fmt;
 use std::mem;
+use number::js_number_to_string;
 
 #[cfg(target_pointer_width = "64")]
 const JSVAL_TAG_SHIFT: u32 = 47;
@@ -89,6 +91,16 @@ pub struct JSVal {
     pub v: u64
 }
 
+impl fmt::Debug for JSVal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_number() {
+            write!(f, "JSVal({})", js_number_to_string(self.to_number()))
+        } else {
+            write!(f, "JSVal({:#x})", self.v)
+        }
+    }
+}
+
 #[cfg(target_pointer_width = "64")]
 #[inline(always)]
 fn BuildJSVal(tag: u32, payload: u64) -> JSVal {
@@ -150,6 +162,17 @@ pub fn UInt32Value(ui: u32) -> JSVal {
     }
 }
 
+/// An int32 value if `f` is one exactly, as the engine itself stores
+/// integral numbers, and a double otherwise.
+#[inline(always)]
+pub fn NumberValue(f: f64) -> JSVal {
+    if f == (f as i32) as f64 && !(f == 0.0 && f.is_sign_negative()) {
+        Int32Value(f as i32)
+    } else {
+        DoubleValue(f)
+    }
+}
+
 #[cfg(target_pointer_width = "64")]
 #[inline(always)]
 pub fn StringValue(s: &JSString) -> JSVal {
@@ -247,6 +270,18 @@ impl JSVal {
         (self.v >> 32) == ValueTag::BOOLEAN as u64
     }
 
+    /// Whether this is one of the engine's internal placeholder values,
+    /// like the `this` of a native called as a constructor.
+    #[cfg(target_pointer_width = "64")]
+    pub fn is_magic(&self) -> bool {
+        (self.v >> JSVAL_TAG_SHIFT) == ValueTag::MAGIC as u64
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    pub fn is_magic(&self) -> bool {
+        (self.v >> 32) == ValueTag::MAGIC as u64
+    }
+
     #[cfg(target_pointer_width = "64")]
     pub fn is_double(&self) -> bool {
         self.v <= ValueShiftedTag::MAX_DOUBLE as u64
@@ -279,6 +314,28 @@ impl JSVal {
         (self.v >> 32) == ValueTag::STRING as u64
     }
 
+    #[cfg(target_pointer_width = "64")]
+    pub fn is_int32(&self) -> bool {
+        (self.v >> JSVAL_TAG_SHIFT) == ValueTag::INT32 as u64
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    pub fn is_int32(&self) -> bool {
+        (self.v >> 32) == ValueTag::INT32 as u64
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    pub fn is_number(&self) -> bool {
+        const JSVAL_UPPER_EXCL_SHIFTED_TAG_OF_NUMBER_SET: u64 = ValueShiftedTag::UNDEFINED as u64;
+        self.v < JSVAL_UPPER_EXCL_SHIFTED_TAG_OF_NUMBER_SET
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    pub fn is_number(&self) -> bool {
+        const JSVAL_UPPER_INCL_TAG_OF_NUMBER_SET: u64 = ValueTag::INT32 as u64;
+        (self.v >> 32) <= JSVAL_UPPER_INCL_TAG_OF_NUMBER_SET
+    }
+
     #[cfg(target_pointer_width = "64")]
     pub fn is_object(&self) -> bool {
         assert!((self.v >> JSVAL_TAG_SHIFT) <= ValueTag::OBJECT as u64);
@@ -301,6 +358,39 @@ impl JSVal {
         (self.v & 0x00000000FFFFFFFF) != 0
     }
 
+    pub fn to_int32(&self) -> i32 {
+        assert!(self.is_int32());
+        (self.v & 0x00000000FFFFFFFF) as u32 as i32
+    }
+
+    pub fn to_double(&self) -> f64 {
+        assert!(self.is_double());
+        unsafe { mem::transmute(self.v) }
+    }
+
+    pub fn to_number(&self) -> f64 {
+        assert!(self.is_number());
+        if self.is_int32() {
+            self.to_int32() as f64
+        } else {
+            self.to_double()
+        }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    pub fn to_string(&self) -> *mut JSString {
+        assert!(self.is_string());
+        let ptrBits = self.v & JSVAL_PAYLOAD_MASK;
+        ptrBits as usize as *mut JSString
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    pub fn to_string(&self) -> *mut JSString {
+        assert!(self.is_string());
+        let ptrBits: u32 = (self.v & 0x00000000FFFFFFFF) as u32;
+        ptrBits as *mut JSString
+    }
+
     pub fn to_object(&self) -> *mut JSObject {
         assert!(self.is_object());
         self.to_object_or_null()
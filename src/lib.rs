@@ -12,7 +12,14 @@
 extern crate libc;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "rustc-serialize")]
 extern crate rustc_serialize as serialize;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
 
 use libc::c_uint;
 use libc::types::common::c99::uint32_t;
@@ -33,12 +40,57 @@ pub use jsapi::bindgen::JS_Unlock as JS_UnlockRuntime;
 
 pub use jsfriendapi::JSJitInfo;
 
+#[macro_use]
+pub mod rooting;
 pub mod jsapi;
 pub mod linkhack;
+#[macro_use]
 pub mod rust;
+#[cfg(any(test, feature = "test-utils"))]
+#[macro_use]
+pub mod testing;
 pub mod glue;
 pub mod jsval;
 pub mod jsfriendapi;
+pub mod jsdbgapi;
+pub mod conversions;
+pub mod error;
+pub mod object;
+pub mod class;
+pub mod global;
+pub mod global_template;
+pub mod specs;
+pub mod private;
+pub mod heap;
+pub mod tracer;
+pub mod object_key;
+pub mod weak_map;
+pub mod script_cache;
+pub mod locale;
+pub mod hooks;
+pub mod script;
+pub mod stack;
+pub mod function;
+pub mod closure;
+pub mod loader;
+pub mod timers;
+pub mod console;
+pub mod base64;
+pub mod shell;
+pub mod memory;
+pub mod regexp;
+pub mod strings;
+pub mod utf16;
+pub mod number;
+pub mod structured_clone;
+pub mod pointers;
+pub mod principals;
+pub mod eval_thread;
+pub mod profiler;
+#[cfg(feature = "serde")]
+pub mod serde_jsval;
+#[cfg(feature = "rustc-serialize")]
+pub mod json;
 
 // FIXME: Add the remaining options
 pub const JSOPTION_STRICT: uint32_t =    0b00000000000001u32;
@@ -46,6 +98,7 @@ pub const JSOPTION_WERROR: uint32_t =    0b00000000000010u32;
 pub const JSOPTION_VAROBJFIX: uint32_t = 0b00000000000100u32;
 pub const JSOPTION_DONT_REPORT_UNCAUGHT: uint32_t = (1u32 << 8) as u32;
 pub const JSOPTION_AUTOJSAPI_OWNS_ERROR_REPORTING: uint32_t = (1u32 << 10) as u32;
+pub const JSOPTION_COMPILE_N_GO: uint32_t = (1u32 << 12) as u32;
 pub const JSOPTION_METHODJIT: uint32_t = (1u32 << 14) as u32;
 pub const JSOPTION_TYPE_INFERENCE: uint32_t = (1u32 << 18) as u32;
 
@@ -72,6 +125,15 @@ pub const JSPROP_SETTER: c_uint = 0x20;
 pub const JSPROP_SHARED: c_uint =    0x40;
 pub const JSPROP_NATIVE_ACCESSORS: c_uint = 0x08;
 
+pub const JSREG_FOLD: c_uint = 0x01;
+pub const JSREG_GLOB: c_uint = 0x02;
+pub const JSREG_MULTILINE: c_uint = 0x04;
+pub const JSREG_STICKY: c_uint = 0x08;
+
+pub const JSCLASS_HAS_PRIVATE: c_uint = 1 << 0;
+pub const JSCLASS_NEW_ENUMERATE: c_uint = 1 << 1;
+pub const JSCLASS_NEW_RESOLVE: c_uint = 1 << 2;
+
 pub const JSCLASS_RESERVED_SLOTS_SHIFT: c_uint = 8;
 pub const JSCLASS_RESERVED_SLOTS_WIDTH: c_uint = 8;
 pub const JSCLASS_RESERVED_SLOTS_MASK: c_uint = ((1 << JSCLASS_RESERVED_SLOTS_WIDTH) - 1) as c_uint;
@@ -122,6 +184,13 @@ pub unsafe fn JS_THIS_OBJECT(cx: *mut JSContext, vp: *mut JSVal) -> *mut JSObjec
     r.to_object_or_null()
 }
 
+/// Whether the native was called with `new`, in which case its `this` is a
+/// placeholder and it must make the new object itself.
+#[inline(always)]
+pub unsafe fn JS_IsConstructing(_cx: *mut JSContext, vp: *const JSVal) -> bool {
+    (*vp.offset(1)).is_magic()
+}
+
 #[inline(always)]
 pub unsafe fn JS_CALLEE(_cx: *mut JSContext, vp: *mut JSVal) -> JSVal {
     *vp
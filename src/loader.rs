@@ -0,0 +1,280 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A CommonJS-style `require` for scripts split across files.
+//!
+//! The embedder supplies the source of each module through a loader set
+//! with `Runtime::set_module_loader`, and `Runtime::define_require` gives a
+//! global a `require(specifier)` function. A module runs once per global,
+//! as the body of a function taking `module`, `exports` and `require`; what
+//! it leaves in `module.exports` is what `require` returns.
+
+use libc::{c_char, c_uint, size_t};
+use std::cell::RefCell;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+use jsapi::{JSContext, JSObject, JS_NewObject, JS_GetGlobalForScopeChain, JS_GetFunctionObject};
+use jsapi::{JS_GetProperty, JS_DefineProperty, JS_AlreadyHasOwnUCProperty, JS_GetUCProperty};
+use jsapi::{JS_DefineUCProperty, JS_DeleteUCProperty2, JS_CompileUCFunction, JS_CallFunctionValue};
+use jsval::{JSVal, ObjectValue, UndefinedValue};
+use closure::{CallContext, define_reentrant_closure};
+use conversions::to_string;
+use error::JSError;
+use utf16::to_utf16;
+use {ERR, JSPROP_ENUMERATE};
+
+/// Why a loader couldn't provide a module. Script sees it as an `Error`
+/// thrown by `require`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadError {
+    pub message: String,
+}
+
+impl LoadError {
+    pub fn new<S: Into<String>>(message: S) -> LoadError {
+        LoadError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Returns the source of the module `specifier` names, as required by the
+/// module `referrer` (empty at top level). Modules are cached by
+/// specifier, so each module should have a single canonical one.
+pub type ModuleLoader = Fn(&str, &str) -> Result<String, LoadError>;
+
+/// The loader and the chain of modules being loaded, shared by the
+/// runtime and every `require` it defines.
+pub struct ModuleRegistry {
+    loader: RefCell<Option<Rc<Box<ModuleLoader>>>>,
+    loading: RefCell<Vec<String>>,
+}
+
+impl ModuleRegistry {
+    pub fn new() -> Rc<ModuleRegistry> {
+        Rc::new(ModuleRegistry {
+            loader: RefCell::new(None),
+            loading: RefCell::new(vec![]),
+        })
+    }
+
+    pub fn set_loader(&self, loader: Box<ModuleLoader>) {
+        *self.loader.borrow_mut() = Some(Rc::new(loader));
+    }
+}
+
+/// Defines `require` on `global`, loading modules through `registry`. The
+/// cache of loaded modules is the function's `cache` property.
+pub fn define_require(cx: *mut JSContext, global: *mut JSObject, registry: Rc<ModuleRegistry>)
+                      -> Result<(), JSError> {
+    // Modules `require` others while it is running.
+    let require = try!(define_reentrant_closure(cx, global, "require", 1, 0, move |call| {
+        require(call, &registry)
+    }));
+    let cache = try!(new_object(cx));
+    unsafe {
+        if JS_DefineProperty(cx, require, b"cache\0".as_ptr() as *const c_char, ObjectValue(&*cache),
+                             None, None, JSPROP_ENUMERATE) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+fn require(call: &CallContext, registry: &ModuleRegistry) -> Result<JSVal, JSError> {
+    let cx = call.cx();
+    let specifier = try!(to_string(cx, call.arg(0)));
+    let name = to_utf16(specifier);
+    let cache = try!(get_property(cx, call.callee(), b"cache\0"));
+    if !cache.is_object() {
+        return Err(JSError::type_error("require.cache is not an object"));
+    }
+    let cache = cache.to_object();
+
+    // A module that is still loading, in a cycle, gives its exports so far.
+    let mut found = 0;
+    unsafe {
+        if JS_AlreadyHasOwnUCProperty(cx, cache, name.as_ptr(), name.len() as size_t, &mut found) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        if found != 0 {
+            let mut module = UndefinedValue();
+            if JS_GetUCProperty(cx, cache, name.as_ptr(), name.len() as size_t, &mut module) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            if !module.is_object() {
+                return Err(JSError::type_error(format!("require.cache['{}'] is not an object", specifier)));
+            }
+            return get_property(cx, module.to_object(), b"exports\0");
+        }
+    }
+
+    let loader = match *registry.loader.borrow() {
+        Some(ref loader) => loader.clone(),
+        None => return Err(JSError::new("no module loader has been set")),
+    };
+    let referrer = registry.loading.borrow().last().cloned().unwrap_or(String::new());
+    let source = try!(loader(&specifier, &referrer).map_err(|error| {
+        JSError::new(format!("cannot load module '{}': {}", specifier, error))
+    }));
+
+    let module = try!(new_object(cx));
+    let exports = try!(new_object(cx));
+    unsafe {
+        if JS_DefineProperty(cx, module, b"exports\0".as_ptr() as *const c_char, ObjectValue(&*exports),
+                             None, None, JSPROP_ENUMERATE) == ERR ||
+           JS_DefineUCProperty(cx, cache, name.as_ptr(), name.len() as size_t, ObjectValue(&*module),
+                               None, None, JSPROP_ENUMERATE) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+
+    registry.loading.borrow_mut().push(specifier.clone());
+    let result = run_module(cx, &specifier, &source, module, exports, call.callee());
+    registry.loading.borrow_mut().pop();
+    if let Err(error) = result {
+        // Let a later `require` try again.
+        let mut ignored = UndefinedValue();
+        unsafe { JS_DeleteUCProperty2(cx, cache, name.as_ptr(), name.len() as size_t, &mut ignored) };
+        return Err(error);
+    }
+    get_property(cx, module, b"exports\0")
+}
+
+/// Runs `source` as the body of `function (module, exports, require)`.
+fn run_module(cx: *mut JSContext, specifier: &str, source: &str, module: *mut JSObject,
+              exports: *mut JSObject, require: *mut JSObject) -> Result<(), JSError> {
+    let filename = try!(CString::new(specifier).map_err(|_| {
+        JSError::new(format!("invalid module specifier {:?}", specifier))
+    }));
+    let chars = to_utf16(source);
+    let mut argnames = [b"module\0".as_ptr() as *const c_char,
+                        b"exports\0".as_ptr() as *const c_char,
+                        b"require\0".as_ptr() as *const c_char];
+    unsafe {
+        let global = JS_GetGlobalForScopeChain(cx);
+        let fun = JS_CompileUCFunction(cx, global, ptr::null(), argnames.len() as c_uint,
+                                       argnames.as_mut_ptr(), chars.as_ptr(), chars.len() as size_t,
+                                       filename.as_ptr(), 1);
+        if fun.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let mut args = [ObjectValue(&*module), ObjectValue(&*exports), ObjectValue(&*require)];
+        let mut rval = UndefinedValue();
+        if JS_CallFunctionValue(cx, global, ObjectValue(&*JS_GetFunctionObject(fun)),
+                                args.len() as c_uint, args.as_mut_ptr(), &mut rval) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+fn new_object(cx: *mut JSContext) -> Result<*mut JSObject, JSError> {
+    unsafe {
+        let obj = JS_NewObject(cx, ptr::null(), ptr::null(), ptr::null());
+        if obj.is_null() {
+            return Err(JSError::from_failed_allocation(cx, "creating an object"));
+        }
+        Ok(obj)
+    }
+}
+
+/// Reads the property named by the nul-terminated `name`.
+fn get_property(cx: *mut JSContext, obj: *mut JSObject, name: &[u8]) -> Result<JSVal, JSError> {
+    let mut value = UndefinedValue();
+    unsafe {
+        if JS_GetProperty(cx, obj, name.as_ptr() as *const c_char, &mut value) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use conversions::to_string;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::LoadError;
+
+    fn fixtures() -> HashMap<&'static str, &'static str> {
+        let mut files = HashMap::new();
+        files.insert("a", "exports.name = 'a'; \
+                           var b = require('b'); \
+                           exports.fromB = b.name + ' saw ' + b.sawA; \
+                           exports.done = true;");
+        files.insert("b", "var a = require('a'); \
+                           exports.name = 'b'; \
+                           exports.sawA = a.name + (a.done ? ' done' : ' partial');");
+        files.insert("broken", "throw new TypeError('broken module');");
+        files
+    }
+
+    #[test]
+    fn cyclic_modules_see_partial_exports() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let files = fixtures();
+        let requests = Rc::new(RefCell::new(vec![]));
+        let log = requests.clone();
+        rt.set_module_loader(Box::new(move |specifier: &str, referrer: &str| {
+            log.borrow_mut().push(format!("{} <- {}", specifier, referrer));
+            files.get(specifier).map(|s| s.to_string())
+                 .ok_or_else(|| LoadError::new("no such file"))
+        }));
+        with_compartment(rt.context(), object_ptr(global), || {
+            rt.define_require(global).unwrap();
+            let a = evaluate(&rt, global, "var a = require('a'); a.fromB").unwrap();
+            assert_eq!(to_string(rt.cx(), a).unwrap(), "b saw a partial");
+            assert!(evaluate(&rt, global, "require('a') === a && require('b').sawA == 'a partial'")
+                        .unwrap().to_boolean());
+            assert_eq!(*requests.borrow(), vec!["a <- ".to_string(), "b <- a".to_string()]);
+
+            let caught = "try { require('missing'); } catch (e) { e.message }";
+            let message = to_string(rt.cx(), evaluate(&rt, global, caught).unwrap()).unwrap();
+            assert!(message.contains("cannot load module 'missing': no such file"), "{}", message);
+            let caught = "try { require('broken'); } catch (e) { (e instanceof TypeError) + ' ' + \
+                          ('broken' in require.cache) }";
+            let message = to_string(rt.cx(), evaluate(&rt, global, caught).unwrap()).unwrap();
+            assert_eq!(message, "true false");
+        });
+    }
+
+    #[test]
+    fn tampered_caches_are_type_errors() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        rt.set_module_loader(Box::new(|_: &str, _: &str| Ok("exports.loaded = true;".to_string())));
+        with_compartment(rt.context(), object_ptr(global), || {
+            rt.define_require(global).unwrap();
+            let caught = "require.cache.a = 5; \
+                          try { require('a'); } catch (e) { (e instanceof TypeError) + ' ' + e.message }";
+            let message = to_string(rt.cx(), evaluate(&rt, global, caught).unwrap()).unwrap();
+            assert_eq!(message, "true require.cache['a'] is not an object");
+
+            let caught = "require.cache = null; \
+                          try { require('b'); } catch (e) { (e instanceof TypeError) + ' ' + e.message }";
+            let message = to_string(rt.cx(), evaluate(&rt, global, caught).unwrap()).unwrap();
+            assert_eq!(message, "true require.cache is not an object");
+        });
+    }
+}
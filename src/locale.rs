@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Locale-sensitive string operations supplied by the embedder, installed
+//! with `Runtime::set_locale_provider`.
+
+use libc::{c_char, size_t};
+use std::cmp::Ordering;
+use std::ffi::CStr;
+use jsapi::{JSBool, JSContext, JSString, JSLocaleCallbacks};
+use jsapi::{JS_GetLocaleCallbacks, JS_NewUCStringCopyN};
+use jsval::{JSVal, Int32Value, StringValue};
+use conversions::jsstring_to_string;
+use error::guard_native;
+use utf16::to_utf16;
+
+/// Implements `String.prototype.toLocaleUpperCase`, `toLocaleLowerCase`
+/// and `localeCompare`, and the conversion of locale-encoded C strings such
+/// as those produced by `Date.prototype.toLocaleString`.
+pub trait LocaleProvider {
+    fn to_upper_case(&self, s: &str) -> String;
+    fn to_lower_case(&self, s: &str) -> String;
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+    /// Decodes a string in the C library's locale encoding.
+    fn to_unicode(&self, s: &[u8]) -> String;
+}
+
+/// The callback table handed to `JS_SetLocaleCallbacks`, followed by the
+/// provider the callbacks forward to.
+///
+/// The engine keeps a pointer to `raw` and gives it back from
+/// `JS_GetLocaleCallbacks`, which is how the callbacks find the provider, so
+/// this has to outlive the context it's installed on. Every string passed to
+/// the callbacks is borrowed from the engine; results are returned as new
+/// GC strings, so no buffer changes hands.
+#[repr(C)]
+pub struct LocaleCallbacks {
+    raw: JSLocaleCallbacks,
+    provider: Box<LocaleProvider>,
+}
+
+impl LocaleCallbacks {
+    pub fn new(provider: Box<LocaleProvider>) -> Box<LocaleCallbacks> {
+        Box::new(LocaleCallbacks {
+            raw: JSLocaleCallbacks {
+                localeToUpperCase: Some(locale_to_upper_case),
+                localeToLowerCase: Some(locale_to_lower_case),
+                localeCompare: Some(locale_compare),
+                localeToUnicode: Some(locale_to_unicode),
+                localeGetErrorMessage: None,
+            },
+            provider: provider,
+        })
+    }
+
+    /// The table to pass to `JS_SetLocaleCallbacks`.
+    pub fn as_raw(&self) -> *mut JSLocaleCallbacks {
+        &self.raw as *const JSLocaleCallbacks as *mut JSLocaleCallbacks
+    }
+}
+
+unsafe fn provider<'a>(cx: *mut JSContext) -> &'a LocaleProvider {
+    let callbacks = JS_GetLocaleCallbacks(cx) as *const LocaleCallbacks;
+    assert!(!callbacks.is_null());
+    &*(*callbacks).provider
+}
+
+unsafe fn return_string(cx: *mut JSContext, s: &str, rval: *mut JSVal) -> JSBool {
+    let chars = to_utf16(s);
+    let s = JS_NewUCStringCopyN(cx, chars.as_ptr(), chars.len() as size_t);
+    if s.is_null() {
+        return 0;
+    }
+    *rval = StringValue(&*s);
+    1
+}
+
+unsafe fn change_case<F>(cx: *mut JSContext, s: *mut JSString, rval: *mut JSVal, f: F) -> JSBool
+    where F: Fn(&LocaleProvider, &str) -> String
+{
+    match jsstring_to_string(cx, s) {
+        Ok(s) => guard_native(cx, || return_string(cx, &f(provider(cx), &s), rval)),
+        Err(_) => 0,
+    }
+}
+
+unsafe extern "C" fn locale_to_upper_case(cx: *mut JSContext, s: *mut JSString, rval: *mut JSVal)
+                                          -> JSBool {
+    change_case(cx, s, rval, |provider, s| provider.to_upper_case(s))
+}
+
+unsafe extern "C" fn locale_to_lower_case(cx: *mut JSContext, s: *mut JSString, rval: *mut JSVal)
+                                          -> JSBool {
+    change_case(cx, s, rval, |provider, s| provider.to_lower_case(s))
+}
+
+unsafe extern "C" fn locale_compare(cx: *mut JSContext, a: *mut JSString, b: *mut JSString,
+                                    rval: *mut JSVal) -> JSBool {
+    let (a, b) = match (jsstring_to_string(cx, a), jsstring_to_string(cx, b)) {
+        (Ok(a), Ok(b)) => (a, b),
+        _ => return 0,
+    };
+    guard_native(cx, || {
+        *rval = Int32Value(match provider(cx).compare(&a, &b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        });
+        1
+    })
+}
+
+unsafe extern "C" fn locale_to_unicode(cx: *mut JSContext, src: *const c_char, rval: *mut JSVal)
+                                       -> JSBool {
+    let bytes = CStr::from_ptr(src).to_bytes();
+    guard_native(cx, || return_string(cx, &provider(cx).to_unicode(bytes), rval))
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::str;
+    use super::LocaleProvider;
+    use conversions::to_string;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+
+    /// Turkish casing of dotted and dotless i, and reversed collation.
+    struct Turkish;
+
+    impl LocaleProvider for Turkish {
+        fn to_upper_case(&self, s: &str) -> String {
+            s.chars().map(|c| if c == 'i' { '\u{130}' } else { c })
+                     .collect::<String>().to_uppercase()
+        }
+
+        fn to_lower_case(&self, s: &str) -> String {
+            s.chars().map(|c| if c == 'I' { '\u{131}' } else { c })
+                     .collect::<String>().to_lowercase()
+        }
+
+        fn compare(&self, a: &str, b: &str) -> Ordering {
+            b.cmp(a)
+        }
+
+        fn to_unicode(&self, s: &[u8]) -> String {
+            str::from_utf8(s).unwrap().to_string()
+        }
+    }
+
+    #[test]
+    fn locale_provider_is_used() {
+        let rt = Runtime::new();
+        rt.set_locale_provider(Box::new(Turkish));
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let string = |src: &str| to_string(rt.cx(), evaluate(&rt, global, src).unwrap()).unwrap();
+            assert_eq!(string("'i'.toLocaleUpperCase()"), "\u{130}");
+            assert_eq!(string("'i'.toUpperCase()"), "I");
+            assert_eq!(string("'TITLE'.toLocaleLowerCase()"), "t\u{131}tle");
+            assert_eq!(evaluate(&rt, global, "'a'.localeCompare('b')").unwrap().to_number(), 1.0);
+        });
+    }
+}
@@ -0,0 +1,219 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A breakdown of a runtime's memory, for diagnostics pages.
+//!
+//! The engine's detailed memory reporters aren't bound here, so the report
+//! is built from coarser numbers: the GC's own counters, for how the
+//! heap's chunks divide into arenas in use, free space in used chunks, and
+//! empty chunks kept for reuse; and, for each compartment
+//! `JS_IterateCompartments` finds, how many objects in it are reachable,
+//! counted by walking the heap from its roots with a tracer.
+
+use libc::c_void;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use jsapi::{JSRuntime, JSCompartment, JSObject, JSTracer, JSGCTraceKind, JS_GetGCParameter};
+use jsapi::{JS_IterateCompartments, JS_TracerInit, JS_TraceRuntime, JS_TraceChildren};
+use jsapi::{JSGC_BYTES, JSGC_TOTAL_CHUNKS, JSGC_UNUSED_CHUNKS};
+use glue::GetObjectCompartment;
+
+/// The size of a GC chunk, the unit the heap grows by.
+const CHUNK_BYTES: usize = 1 << 20;
+
+/// A node of a memory report. A node's size is the sum of its children's,
+/// if it has any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryReport {
+    pub name: String,
+    pub bytes: usize,
+    /// How many things the node stands for, where only they could be
+    /// counted and not their size, like a compartment's objects.
+    pub count: Option<usize>,
+    pub children: Vec<MemoryReport>,
+}
+
+impl MemoryReport {
+    fn leaf(name: &str, bytes: usize) -> MemoryReport {
+        MemoryReport {
+            name: name.to_string(),
+            bytes: bytes,
+            count: None,
+            children: vec![],
+        }
+    }
+
+    fn counted(name: &str, count: usize) -> MemoryReport {
+        MemoryReport {
+            count: Some(count),
+            ..MemoryReport::leaf(name, 0)
+        }
+    }
+
+    fn node(name: &str, children: Vec<MemoryReport>) -> MemoryReport {
+        MemoryReport {
+            name: name.to_string(),
+            bytes: children.iter().map(|child| child.bytes).sum(),
+            count: None,
+            children: children,
+        }
+    }
+
+    /// The descendant at `path`, a `/`-separated list of names below this
+    /// node, like `"gc-heap/arenas"`.
+    pub fn find(&self, path: &str) -> Option<&MemoryReport> {
+        path.split('/').fold(Some(self), |node, name| {
+            node.and_then(|node| node.children.iter().find(|child| child.name == name))
+        })
+    }
+
+    /// The report as JSON: `{"name": ..., "bytes": ..., "children": [...]}`,
+    /// with a `"count"` after `"bytes"` for counted nodes.
+    pub fn to_json_string(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        out.push_str("{\"name\":\"");
+        for c in self.name.chars() {
+            match c {
+                '"' => out.push_str("\\\""),
+                '\\' => out.push_str("\\\\"),
+                c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                c => out.push(c),
+            }
+        }
+        out.push_str(&format!("\",\"bytes\":{},", self.bytes));
+        if let Some(count) = self.count {
+            out.push_str(&format!("\"count\":{},", count));
+        }
+        out.push_str("\"children\":[");
+        for (i, child) in self.children.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            child.write_json(out);
+        }
+        out.push_str("]}");
+    }
+}
+
+/// A tracer that visits everything reachable from the runtime's roots.
+/// `tracer` comes first, so the callback can find the rest from it.
+#[repr(C)]
+struct HeapWalk {
+    tracer: JSTracer,
+    seen: HashSet<usize>,
+    pending: Vec<(*mut c_void, JSGCTraceKind)>,
+}
+
+unsafe extern "C" fn visit_edge(trc: *mut JSTracer, thingp: *mut *mut c_void, kind: JSGCTraceKind) {
+    let walk = &mut *(trc as *mut HeapWalk);
+    let thing = *thingp;
+    if walk.seen.insert(thing as usize) {
+        walk.pending.push((thing, kind));
+    }
+}
+
+unsafe extern "C" fn push_compartment(_rt: *mut JSRuntime, data: *mut c_void, compartment: *mut JSCompartment) {
+    (*(data as *mut Vec<*mut JSCompartment>)).push(compartment);
+}
+
+/// How many objects reachable from `rt`'s roots each compartment has, in
+/// the order `JS_IterateCompartments` lists them.
+fn count_objects(rt: *mut JSRuntime) -> Vec<usize> {
+    let mut compartments: Vec<*mut JSCompartment> = vec![];
+    let mut objects: HashMap<*mut JSCompartment, usize> = HashMap::new();
+    unsafe {
+        JS_IterateCompartments(rt, &mut compartments as *mut _ as *mut c_void, Some(push_compartment));
+        let mut walk = HeapWalk {
+            tracer: mem::zeroed(),
+            seen: HashSet::new(),
+            pending: vec![],
+        };
+        JS_TracerInit(&mut walk.tracer, rt, Some(visit_edge));
+        JS_TraceRuntime(&mut walk.tracer);
+        while let Some((thing, kind)) = walk.pending.pop() {
+            if let JSGCTraceKind::JSTRACE_OBJECT = kind {
+                *objects.entry(GetObjectCompartment(thing as *mut JSObject)).or_insert(0) += 1;
+            }
+            JS_TraceChildren(&mut walk.tracer, thing, kind);
+        }
+    }
+    compartments.iter().map(|compartment| objects.get(compartment).cloned().unwrap_or(0)).collect()
+}
+
+/// Builds the report for `rt`. Compartments have no names to go by, so
+/// they are numbered in the order the engine lists them:
+/// `"compartments/compartment(0)/objects"`.
+pub fn collect_memory_report(rt: *mut JSRuntime) -> MemoryReport {
+    let (arenas, total_chunks, unused_chunks) = unsafe {
+        (JS_GetGCParameter(rt, JSGC_BYTES) as usize,
+         JS_GetGCParameter(rt, JSGC_TOTAL_CHUNKS) as usize,
+         JS_GetGCParameter(rt, JSGC_UNUSED_CHUNKS) as usize)
+    };
+    let used_chunk_bytes = total_chunks.saturating_sub(unused_chunks) * CHUNK_BYTES;
+    MemoryReport::node("js-runtime", vec![
+        MemoryReport::node("gc-heap", vec![
+            MemoryReport::leaf("arenas", arenas),
+            MemoryReport::leaf("unused-arenas", used_chunk_bytes.saturating_sub(arenas)),
+            MemoryReport::leaf("unused-chunks", unused_chunks * CHUNK_BYTES),
+        ]),
+        MemoryReport::node("compartments", count_objects(rt).into_iter().enumerate().map(|(i, objects)| {
+            MemoryReport::node(&format!("compartment({})", i), vec![MemoryReport::counted("objects", objects)])
+        }).collect()),
+    ])
+}
+
+#[cfg(test)]
+mod test {
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use global::{GlobalOptions, new_global_object};
+    use object::RootedObject;
+
+    #[test]
+    fn report_matches_gc_counters() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            evaluate(&rt, global, "var keep = []; for (var i = 0; i < 10000; i++) keep.push({i: i});").unwrap();
+        });
+        let report = rt.collect_memory_report();
+        assert_eq!(report.find("gc-heap/arenas").unwrap().bytes, rt.gc_bytes());
+        assert_eq!(report.find("compartments").unwrap().bytes, 0);
+        let heap = report.find("gc-heap").unwrap();
+        assert_eq!(heap.bytes, report.bytes);
+        assert!(heap.bytes >= rt.gc_bytes());
+        assert_eq!(heap.bytes % (1 << 20), 0);
+        assert!(report.find("gc-heap/nothing").is_none());
+
+        let json = report.to_json_string();
+        assert!(json.starts_with("{\"name\":\"js-runtime\",\"bytes\":"), "{}", json);
+        assert!(json.contains(&format!("{{\"name\":\"arenas\",\"bytes\":{},\"children\":[]}}", rt.gc_bytes())),
+                "{}", json);
+        assert!(json.contains("{\"name\":\"objects\",\"bytes\":0,\"count\":"), "{}", json);
+    }
+
+    #[test]
+    fn objects_are_counted_by_compartment() {
+        let rt = Runtime::new();
+        let busy = new_global(&rt);
+        let idle = new_global_object(rt.cx(), None, &GlobalOptions::default()).unwrap();
+        let _idle = RootedObject::new(rt.cx(), idle).unwrap();
+        with_compartment(rt.context(), object_ptr(busy), || {
+            evaluate(&rt, busy, "var keep = []; for (var i = 0; i < 5000; i++) keep.push({i: i});").unwrap();
+        });
+        let report = rt.collect_memory_report();
+        let counts: Vec<usize> = report.find("compartments").unwrap().children.iter().map(|compartment| {
+            compartment.find("objects").unwrap().count.unwrap()
+        }).collect();
+        // Both globals' compartments, and the engine's own atoms compartment.
+        assert!(counts.len() >= 3, "{:?}", counts);
+        assert_eq!(counts.iter().filter(|&&count| count >= 5000).count(), 1, "{:?}", counts);
+        assert!(counts.iter().filter(|&&count| count > 0 && count < 5000).count() >= 1, "{:?}", counts);
+    }
+}
@@ -0,0 +1,167 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Numbers formatted and parsed the way JS does, without a context.
+
+use std::f64;
+use std::iter;
+
+/// Formats `n` as `Number.prototype.toString()` would: the shortest digits
+/// that round-trip, in exponent notation only outside `1e-7 < |n| < 1e21`,
+/// and with no `.0` on integers.
+pub fn js_number_to_string(n: f64) -> String {
+    if n.is_nan() {
+        return "NaN".to_string();
+    }
+    if n == 0.0 {
+        return "0".to_string();
+    }
+    if n.is_infinite() {
+        return if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    if n < 0.0 {
+        return format!("-{}", js_number_to_string(-n));
+    }
+
+    // Rust's `{:e}` gives the shortest round-tripping digits, d.ddde-x.
+    let exponential = format!("{:e}", n);
+    let (mantissa, exponent) = exponential.split_at(exponential.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    let k = digits.len() as i32;
+    // The decimal point goes after the first `point` digits.
+    let point = exponent[1..].parse::<i32>().unwrap() + 1;
+
+    if k <= point && point <= 21 {
+        digits + &zeros(point - k)
+    } else if 0 < point && point <= 21 {
+        format!("{}.{}", &digits[..point as usize], &digits[point as usize..])
+    } else if -6 < point && point <= 0 {
+        format!("0.{}{}", zeros(-point), digits)
+    } else {
+        let sign = if point > 0 { "+" } else { "-" };
+        if k == 1 {
+            format!("{}e{}{}", digits, sign, (point - 1).abs())
+        } else {
+            format!("{}.{}e{}{}", &digits[..1], &digits[1..], sign, (point - 1).abs())
+        }
+    }
+}
+
+fn zeros(n: i32) -> String {
+    iter::repeat('0').take(n as usize).collect()
+}
+
+/// Whitespace and line terminators, which `Number(s)` ignores around a
+/// number. Unicode's White_Space also has U+0085 (NEL), which JS doesn't
+/// count as either.
+fn is_js_whitespace(c: char) -> bool {
+    (c.is_whitespace() && c != '\u{85}') || c == '\u{feff}'
+}
+
+/// Parses `s` as `Number(s)` would: surrounding whitespace is ignored, an
+/// empty string is 0, `0x` introduces a hexadecimal integer, and anything
+/// that isn't a number is NaN.
+pub fn js_string_to_number(s: &str) -> f64 {
+    let s = s.trim_matches(is_js_whitespace);
+    if s.is_empty() {
+        return 0.0;
+    }
+    if s.starts_with("0x") || s.starts_with("0X") {
+        let hex = &s[2..];
+        if hex.is_empty() {
+            return f64::NAN;
+        }
+        let mut n = 0.0;
+        for c in hex.chars() {
+            match c.to_digit(16) {
+                Some(digit) => n = n * 16.0 + digit as f64,
+                None => return f64::NAN,
+            }
+        }
+        return n;
+    }
+    let (negative, unsigned) = if s.starts_with('-') {
+        (true, &s[1..])
+    } else if s.starts_with('+') {
+        (false, &s[1..])
+    } else {
+        (false, s)
+    };
+    let magnitude = if unsigned == "Infinity" {
+        f64::INFINITY
+    } else {
+        match parse_decimal(unsigned) {
+            Some(n) => n,
+            None => return f64::NAN,
+        }
+    };
+    if negative { -magnitude } else { magnitude }
+}
+
+/// Parses `digits[.digits][(e|E)[sign]digits]`, where either the integer
+/// or the fraction digits may be missing but not both.
+fn parse_decimal(s: &str) -> Option<f64> {
+    let (significand, exponent) = match s.find(|c| c == 'e' || c == 'E') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (integer, fraction) = match significand.find('.') {
+        Some(i) => (&significand[..i], &significand[i + 1..]),
+        None => (significand, ""),
+    };
+    let all_digits = |s: &str| s.chars().all(|c| c.is_digit(10));
+    if (integer.is_empty() && fraction.is_empty()) || !all_digits(integer) || !all_digits(fraction) {
+        return None;
+    }
+    let exponent = match exponent {
+        Some(exponent) => {
+            let unsigned = exponent.trim_left_matches(|c| c == '+' || c == '-');
+            if unsigned.is_empty() || !all_digits(unsigned) || exponent.len() - unsigned.len() > 1 {
+                return None;
+            }
+            exponent
+        }
+        None => "0",
+    };
+    // Rust's parser wants digits on both sides of the point.
+    let integer = if integer.is_empty() { "0" } else { integer };
+    let fraction = if fraction.is_empty() { "0" } else { fraction };
+    format!("{}.{}e{}", integer, fraction, exponent).parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64;
+    use super::{js_number_to_string, js_string_to_number};
+
+    #[test]
+    fn numbers_format_like_js() {
+        let cases = [
+            (0.0, "0"), (-0.0, "0"), (1.0, "1"), (-1.5, "-1.5"), (0.1 + 0.2, "0.30000000000000004"),
+            (1e21, "1e+21"), (1e20, "100000000000000000000"), (123e18, "123000000000000000000"),
+            (1.5e300, "1.5e+300"), (0.000001, "0.000001"), (1e-7, "1e-7"), (1.25e-7, "1.25e-7"),
+            (f64::NAN, "NaN"), (f64::INFINITY, "Infinity"), (f64::NEG_INFINITY, "-Infinity"),
+            (5e-324, "5e-324"), (f64::MAX, "1.7976931348623157e+308"), (100.0, "100"), (0.5, "0.5"),
+        ];
+        for &(n, s) in &cases {
+            assert_eq!(js_number_to_string(n), s, "formatting {:e}", n);
+        }
+    }
+
+    #[test]
+    fn strings_parse_like_js() {
+        let cases = [
+            ("", 0.0), ("  12  ", 12.0), ("\n\t-3.5e2 ", -350.0), ("0x1f", 31.0), ("0X1F", 31.0),
+            (".5", 0.5), ("5.", 5.0), ("+1e3", 1000.0), ("1E-2", 0.01), ("-Infinity", f64::NEG_INFINITY),
+            ("007", 7.0), ("\u{feff}1", 1.0),
+        ];
+        for &(s, n) in &cases {
+            assert_eq!(js_string_to_number(s), n, "parsing {:?}", s);
+        }
+        for s in &["abc", "0x", "-0x10", "1e", "1e+-2", ".", "1.2.3", "infinity", "12px", "0x1g", "\u{85}1"] {
+            assert!(js_string_to_number(s).is_nan(), "parsing {:?}", s);
+        }
+        assert!(js_string_to_number("-0").is_sign_negative());
+    }
+}
@@ -0,0 +1,1122 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for inspecting and manipulating JS objects.
+
+use libc::{c_char, c_int};
+use std::cmp;
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::ptr;
+use jsapi::{JSBool, JSContext, JSObject, JS_HasInstance, JS_GetProperty};
+use jsapi::{JS_GetPrototype, JS_SetPrototype, JS_IsExtensible, JS_FreezeObject};
+use jsapi::{JS_Enumerate, JS_IdArrayLength, JS_IdArrayGet, JS_DestroyIdArray};
+use jsapi::{JSPropertyDescriptor, JS_GetPropertyDescriptorById};
+use jsapi::{JS_CallFunctionValue, JS_ObjectIsCallable, JS_WrapValue};
+use jsapi::{JS_AddNamedValueRoot, JS_RemoveValueRoot, JS_NewArrayObject};
+use jsapi::{JS_AddNamedObjectRoot, JS_RemoveObjectRoot};
+use jsapi::{JS_GetGlobalForScopeChain, JS_GetClass, JS_GetReservedSlot, JS_SetReservedSlot};
+use jsapi::{JS_IsArrayObject, JS_ObjectIsFunction, JS_ObjectIsDate, JS_ObjectIsRegExp};
+use jsapi::{JS_ValueToId, JS_GetOwnPropertyDescriptor, JS_AlreadyHasOwnProperty};
+use jsval::{JSVal, UndefinedValue, Int32Value, ObjectValue, ObjectOrNullValue, StringValue};
+use class::class_reserved_slots;
+use conversions::{type_of, check_length, FromJSValConvertible, ToJSValConvertible};
+use error::{JSError, check_same_runtime, injected_allocation_failure, report_stale_exception};
+use glue::{UnwrapObject, SealObject};
+use pointers::{ContextPtr, ObjectPtr, AsContextPtr, AsObjectPtr};
+use rust::{with_compartment, check_argument_count};
+use strings::new_string;
+use structured_clone::StructuredCloneBuffer;
+use {JSID_VOID, ERR, JSPROP_GETTER, JSPROP_SETTER};
+
+/// Keeps an object alive while nothing the GC scans refers to it, e.g.
+/// while it is being built or walked from Rust heap data. The root goes
+/// away when this is dropped.
+pub struct RootedObject {
+    cx: *mut JSContext,
+    // Boxed so the root's address stays put.
+    object: Box<*mut JSObject>,
+}
+
+impl RootedObject {
+    /// Roots `object`. A null `object` is taken to come from a failed
+    /// allocation, and the pending exception is returned instead.
+    pub fn new(cx: *mut JSContext, object: *mut JSObject) -> Result<RootedObject, JSError> {
+        if object.is_null() {
+            return Err(unsafe { JSError::from_failed_allocation(cx, "creating an object") });
+        }
+        let mut root = Box::new(object);
+        unsafe {
+            JS_AddNamedObjectRoot(cx, &mut *root, b"RootedObject\0".as_ptr() as *const c_char);
+        }
+        Ok(RootedObject {
+            cx: cx,
+            object: root,
+        })
+    }
+
+    pub fn cx(&self) -> *mut JSContext {
+        self.cx
+    }
+
+    pub fn get(&self) -> *mut JSObject {
+        *self.object
+    }
+
+    pub fn ptr(&self) -> ObjectPtr {
+        unsafe { ObjectPtr::from_raw_nonnull(self.get()) }
+    }
+
+    pub fn value(&self) -> JSVal {
+        ObjectValue(unsafe { &*self.get() })
+    }
+}
+
+impl Drop for RootedObject {
+    fn drop(&mut self) {
+        unsafe { JS_RemoveObjectRoot(self.cx, &mut *self.object) };
+    }
+}
+
+/// Creates an array holding `elements`. The array isn't rooted.
+pub fn new_array(cx: *mut JSContext, elements: &[JSVal]) -> Result<*mut JSObject, JSError> {
+    if !check_length(cx, elements.len()) {
+        return Err(unsafe { JSError::from_pending_exception(cx) });
+    }
+    unsafe {
+        let array = JS_NewArrayObject(cx, elements.len() as c_int, elements.as_ptr() as *mut JSVal);
+        if array.is_null() || injected_allocation_failure(cx) {
+            return Err(JSError::from_failed_allocation(cx, "creating an array"));
+        }
+        Ok(array)
+    }
+}
+
+/// Implements `val instanceof ctor`.
+///
+/// If `ctor` is not callable the engine throws a TypeError, which is returned
+/// as an error rather than as `Ok(false)`.
+pub fn has_instance(cx: *mut JSContext, ctor: *mut JSObject, val: JSVal) -> Result<bool, JSError> {
+    let mut result: JSBool = 0;
+    unsafe {
+        if JS_HasInstance(cx, ctor, val, &mut result) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(result != 0)
+}
+
+/// Implements `val instanceof <name>`, looking the constructor up on the
+/// current global; `name` would typically be a standard class like `"Date"`.
+pub fn is_instance_of_global_class(cx: *mut JSContext, val: JSVal, name: &str) -> Result<bool, JSError> {
+    let name_cstr = match CString::new(name) {
+        Ok(s) => s,
+        Err(_) => return Err(JSError::new(format!("invalid class name {:?}", name))),
+    };
+    let mut ctor: JSVal = UndefinedValue();
+    unsafe {
+        let global = JS_GetGlobalForScopeChain(cx);
+        if global.is_null() {
+            return Err(JSError::new("no global object for the current scope"));
+        }
+        if JS_GetProperty(cx, global, name_cstr.as_ptr(), &mut ctor) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    if !ctor.is_object() {
+        return Err(JSError::new(format!("{} is not an object on the global", name)));
+    }
+    has_instance(cx, ctor.to_object(), val)
+}
+
+// The class predicates below see through cross-compartment wrappers, so
+// they describe the object a wrapper stands for, and ask about it from its
+// own compartment.
+fn unwrap(obj: *mut JSObject) -> *mut JSObject {
+    unsafe { UnwrapObject(obj, 1, ptr::null_mut()) }
+}
+
+fn class_is(cx: *mut JSContext, obj: *mut JSObject,
+            predicate: unsafe extern "C" fn(*mut JSContext, *mut JSObject) -> JSBool) -> bool {
+    let target = unwrap(obj);
+    let (context, object) = unsafe {
+        (ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(target))
+    };
+    with_compartment(context, object, || unsafe { predicate(cx, target) != ERR })
+}
+
+/// The name of `obj`'s class, like `"Array"`, `"Function"` or the name a
+/// `ClassBuilder` was given.
+pub fn object_class_name(obj: *mut JSObject) -> &'static str {
+    unsafe {
+        let class = JS_GetClass(unwrap(obj));
+        if class.is_null() || (*class).name.is_null() {
+            return "";
+        }
+        CStr::from_ptr((*class).name).to_str().unwrap_or("")
+    }
+}
+
+/// Whether `obj` is an array.
+pub fn is_array_object(cx: *mut JSContext, obj: *mut JSObject) -> bool {
+    class_is(cx, obj, JS_IsArrayObject)
+}
+
+/// Whether `obj` is a function, scripted or native. Callable objects of
+/// other classes aren't; see `is_callable`.
+pub fn is_function_object(obj: *mut JSObject) -> bool {
+    // Checking the class needs no context.
+    unsafe { JS_ObjectIsFunction(ptr::null_mut(), unwrap(obj)) != ERR }
+}
+
+/// Whether `obj` can be called: a function, or an object whose class has a
+/// call hook.
+pub fn is_callable(cx: *mut JSContext, obj: *mut JSObject) -> bool {
+    class_is(cx, obj, JS_ObjectIsCallable)
+}
+
+/// Whether `obj` is a `Date`.
+pub fn is_date_object(cx: *mut JSContext, obj: *mut JSObject) -> bool {
+    class_is(cx, obj, JS_ObjectIsDate)
+}
+
+/// Whether `obj` is a `RegExp`.
+pub fn is_regexp_object(cx: *mut JSContext, obj: *mut JSObject) -> bool {
+    class_is(cx, obj, JS_ObjectIsRegExp)
+}
+
+fn debug_check_slot(obj: *mut JSObject, index: u32) {
+    if cfg!(debug_assertions) {
+        let count = unsafe { class_reserved_slots(&*JS_GetClass(obj)) };
+        assert!(index < count, "reserved slot {} out of range (class has {})", index, count);
+    }
+}
+
+/// Stores `val` in reserved slot `index` of `obj`. The engine performs the
+/// write barriers. In debug builds, panics if the object's class doesn't
+/// declare that many reserved slots.
+pub fn set_reserved_slot(obj: *mut JSObject, index: u32, val: JSVal) {
+    debug_check_slot(obj, index);
+    unsafe { JS_SetReservedSlot(obj, index, val) }
+}
+
+/// Reads reserved slot `index` of `obj`; slots that were never set read as
+/// `undefined`. In debug builds, panics if the index is out of range.
+pub fn get_reserved_slot(obj: *mut JSObject, index: u32) -> JSVal {
+    debug_check_slot(obj, index);
+    unsafe { JS_GetReservedSlot(obj, index) }
+}
+
+/// Stores an object pointer (or null) in a reserved slot.
+pub fn set_reserved_slot_object(obj: *mut JSObject, index: u32, val: *mut JSObject) {
+    set_reserved_slot(obj, index, ObjectOrNullValue(val))
+}
+
+/// Reads an object pointer stored with `set_reserved_slot_object`, or
+/// `None` if the slot holds null or a non-object value.
+pub fn get_reserved_slot_object(obj: *mut JSObject, index: u32) -> Option<*mut JSObject> {
+    let val = get_reserved_slot(obj, index);
+    if val.is_object() {
+        Some(val.to_object())
+    } else {
+        None
+    }
+}
+
+/// Stores a `u32` in a reserved slot, bit-for-bit as an int32 value.
+pub fn set_reserved_slot_u32(obj: *mut JSObject, index: u32, val: u32) {
+    set_reserved_slot(obj, index, Int32Value(val as i32))
+}
+
+/// Reads a `u32` stored with `set_reserved_slot_u32`, or `None` if the slot
+/// holds something else.
+pub fn get_reserved_slot_u32(obj: *mut JSObject, index: u32) -> Option<u32> {
+    let val = get_reserved_slot(obj, index);
+    if val.is_int32() {
+        Some(val.to_int32() as u32)
+    } else {
+        None
+    }
+}
+
+/// Returns `obj`'s `[[Prototype]]`, `None` at the end of the chain.
+pub fn get_prototype(_cx: *mut JSContext, obj: *mut JSObject) -> Result<Option<*mut JSObject>, JSError> {
+    let proto = unsafe { JS_GetPrototype(obj) };
+    Ok(if proto.is_null() { None } else { Some(proto) })
+}
+
+/// Sets `obj`'s `[[Prototype]]`, like assigning `__proto__`. Fails for
+/// non-extensible objects and when the change would create a cycle.
+pub fn set_prototype(cx: *mut JSContext, obj: *mut JSObject, proto: Option<*mut JSObject>)
+                     -> Result<(), JSError> {
+    unsafe {
+        // Unlike assigning `__proto__`, `JS_SetPrototype` doesn't check this
+        // itself, and only asserts it in debug builds.
+        if JS_IsExtensible(obj) == 0 {
+            return Err(JSError::type_error("can't set the prototype of a non-extensible object"));
+        }
+        if JS_SetPrototype(cx, obj, proto.unwrap_or(ptr::null_mut())) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+/// Returns `obj`'s prototypes, nearest first, not including `obj` itself.
+/// Stops with an error rather than looping if the chain has a cycle, which
+/// the engine normally prevents but proxies can fake.
+pub fn prototype_chain(cx: *mut JSContext, obj: *mut JSObject) -> Result<Vec<*mut JSObject>, JSError> {
+    let mut chain = vec!();
+    let mut current = obj;
+    while let Some(proto) = try!(get_prototype(cx, current)) {
+        if proto == obj || chain.contains(&proto) {
+            return Err(JSError::new("cyclic prototype chain"));
+        }
+        chain.push(proto);
+        current = proto;
+    }
+    Ok(chain)
+}
+
+/// Implements `Object.seal(obj)`: no properties can be added or deleted,
+/// but existing data properties stay writable. Nothing script can replace,
+/// like the global's `Object.seal`, is involved.
+pub fn seal_object(cx: *mut JSContext, obj: *mut JSObject) -> Result<(), JSError> {
+    unsafe {
+        if SealObject(cx, obj) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+/// Implements `Object.freeze(obj)`. Objects `obj` refers to are unaffected;
+/// see `deep_freeze`.
+pub fn freeze_object(cx: *mut JSContext, obj: *mut JSObject) -> Result<(), JSError> {
+    unsafe {
+        if JS_FreezeObject(cx, obj) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+/// Freezes `obj` and, recursively, every object held in its own enumerable
+/// data properties. Each object is visited once, so cycles are fine.
+/// Accessor properties are left alone rather than run, so objects only
+/// reachable through a getter aren't frozen.
+pub fn deep_freeze(cx: *mut JSContext, obj: *mut JSObject) -> Result<(), JSError> {
+    let mut visited = HashSet::new();
+    // `pending` lives on the heap, where the conservative scanner doesn't
+    // look, and freezing can collect, so every object found is rooted until
+    // the walk is over.
+    let mut roots = vec!(try!(RootedObject::new(cx, obj)));
+    let mut pending = vec!(obj);
+    visited.insert(obj);
+    while let Some(obj) = pending.pop() {
+        try!(freeze_object(cx, obj));
+        unsafe {
+            let ids = JS_Enumerate(cx, obj);
+            if ids.is_null() {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            for i in 0..JS_IdArrayLength(cx, ids) {
+                let mut desc = JSPropertyDescriptor {
+                    obj: ptr::null_mut(),
+                    attrs: 0,
+                    shortid: 0,
+                    getter: None,
+                    setter: None,
+                    value: UndefinedValue(),
+                };
+                if JS_GetPropertyDescriptorById(cx, obj, JS_IdArrayGet(cx, ids, i), 0, &mut desc) == ERR {
+                    JS_DestroyIdArray(cx, ids);
+                    return Err(JSError::from_pending_exception(cx));
+                }
+                if desc.attrs & (JSPROP_GETTER | JSPROP_SETTER) != 0 || !desc.value.is_object() {
+                    continue;
+                }
+                let value = desc.value.to_object();
+                if visited.insert(value) {
+                    match RootedObject::new(cx, value) {
+                        Ok(root) => roots.push(root),
+                        Err(error) => {
+                            JS_DestroyIdArray(cx, ids);
+                            return Err(error);
+                        }
+                    }
+                    pending.push(value);
+                }
+            }
+            JS_DestroyIdArray(cx, ids);
+        }
+    }
+    Ok(())
+}
+
+/// What `Object.getOwnPropertyDescriptor` says about a property.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PropertyDescriptor {
+    /// The value of a data property; `None` for an accessor property.
+    pub value: Option<JSVal>,
+    /// Always false for accessor properties.
+    pub writable: bool,
+    pub enumerable: bool,
+    pub configurable: bool,
+    /// The accessor functions, where the property has them.
+    pub getter: Option<*mut JSObject>,
+    pub setter: Option<*mut JSObject>,
+}
+
+impl PropertyDescriptor {
+    pub fn is_accessor(&self) -> bool {
+        self.value.is_none()
+    }
+}
+
+/// Describes `obj`'s own property `name`, or returns `None` if it has no
+/// such property; inherited properties aren't looked at. A proxy is asked
+/// through its trap, and an exception the trap throws is the error.
+pub fn get_own_property_descriptor<C, O>(cx: C, obj: O, name: &str)
+                                         -> Result<Option<PropertyDescriptor>, JSError>
+    where C: AsContextPtr, O: AsObjectPtr {
+    let (cx, obj) = (cx.context_ptr().as_raw(), obj.object_ptr().as_raw());
+    let name = try!(new_string(cx, name));
+    let mut descriptor = UndefinedValue();
+    unsafe {
+        let mut id = JSID_VOID;
+        if JS_ValueToId(cx, StringValue(&*name), &mut id) == ERR ||
+           JS_GetOwnPropertyDescriptor(cx, obj, id, &mut descriptor) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    if !descriptor.is_object() {
+        return Ok(None);
+    }
+    let descriptor = descriptor.to_object();
+    let field = |name: &[u8]| -> Result<JSVal, JSError> {
+        let mut value = UndefinedValue();
+        unsafe {
+            if JS_GetProperty(cx, descriptor, name.as_ptr() as *const c_char, &mut value) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        Ok(value)
+    };
+    let is_true = |value: JSVal| value.is_boolean() && value.to_boolean();
+    let as_function = |value: JSVal| if value.is_object() { Some(value.to_object()) } else { None };
+    let mut is_data = 0;
+    unsafe {
+        if JS_AlreadyHasOwnProperty(cx, descriptor, b"writable\0".as_ptr() as *const c_char, &mut is_data) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(Some(PropertyDescriptor {
+        value: if is_data != 0 { Some(try!(field(b"value\0"))) } else { None },
+        writable: is_true(try!(field(b"writable\0"))),
+        enumerable: is_true(try!(field(b"enumerable\0"))),
+        configurable: is_true(try!(field(b"configurable\0"))),
+        getter: as_function(try!(field(b"get\0"))),
+        setter: as_function(try!(field(b"set\0"))),
+    }))
+}
+
+/// Looks up a `.`-separated path of properties, such as
+/// `"network.proxy.port"`, starting from `obj`. Array elements are
+/// reached by index, as in `"servers.0.host"`. If a segment before the
+/// last isn't an object, the error names it: `network.proxy is undefined`.
+pub fn get_path<C: AsContextPtr, O: AsObjectPtr>(cx: C, obj: O, path: &str) -> Result<JSVal, JSError> {
+    let (cx, obj) = (cx.context_ptr().as_raw(), obj.object_ptr().as_raw());
+    try!(check_same_runtime(cx, obj, "object"));
+    let mut value = ObjectValue(unsafe { &*obj });
+    let mut walked = 0;
+    for segment in path.split('.') {
+        if !value.is_object() {
+            return Err(JSError::new(format!("{} is {}", &path[..walked - 1], type_of(cx, value))));
+        }
+        let name = match CString::new(segment) {
+            Ok(s) => s,
+            Err(_) => return Err(JSError::new(format!("invalid property name {:?}", segment))),
+        };
+        let current = value.to_object();
+        unsafe {
+            if JS_GetProperty(cx, current, name.as_ptr(), &mut value) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        walked += segment.len() + 1;
+    }
+    Ok(value)
+}
+
+/// Like `get_path`, converting the value found to `T`.
+pub fn get_path_into<T, C, O>(cx: C, obj: O, path: &str) -> Result<T, JSError>
+    where T: FromJSValConvertible, C: AsContextPtr, O: AsObjectPtr {
+    let cx = cx.context_ptr();
+    let value = try!(get_path(cx, obj, path));
+    T::from_jsval(cx.as_raw(), value).map_err(|error| JSError::new(format!("{}: {}", path, error.message)))
+}
+
+/// `get_own_property_descriptor` for raw pointers, which must be live.
+#[deprecated(note = "use get_own_property_descriptor with typed pointers")]
+pub unsafe fn get_own_property_descriptor_raw(cx: *mut JSContext, obj: *mut JSObject, name: &str)
+                                              -> Result<Option<PropertyDescriptor>, JSError> {
+    get_own_property_descriptor(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(obj), name)
+}
+
+/// `get_path` for raw pointers, which must be live.
+#[deprecated(note = "use get_path with typed pointers")]
+pub unsafe fn get_path_raw(cx: *mut JSContext, obj: *mut JSObject, path: &str) -> Result<JSVal, JSError> {
+    get_path(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(obj), path)
+}
+
+/// `get_path_into` for raw pointers, which must be live.
+#[deprecated(note = "use get_path_into with typed pointers")]
+pub unsafe fn get_path_into_raw<T: FromJSValConvertible>(cx: *mut JSContext, obj: *mut JSObject, path: &str)
+                                                         -> Result<T, JSError> {
+    get_path_into(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(obj), path)
+}
+
+/// Values rooted in place until dropped.
+struct RootedValues {
+    cx: *mut JSContext,
+    values: Vec<JSVal>,
+}
+
+impl RootedValues {
+    fn new(cx: *mut JSContext, values: &[JSVal]) -> RootedValues {
+        let mut rooted = RootedValues { cx: cx, values: values.to_vec() };
+        for value in &mut rooted.values {
+            unsafe { JS_AddNamedValueRoot(cx, value, b"call_method argument\0".as_ptr() as *const c_char) };
+        }
+        rooted
+    }
+}
+
+impl Drop for RootedValues {
+    fn drop(&mut self) {
+        for value in &mut self.values {
+            unsafe { JS_RemoveValueRoot(self.cx, value) };
+        }
+    }
+}
+
+/// The number of arguments a `CallArgsBuilder` holds without allocating.
+pub const INLINE_ARGS: usize = 8;
+
+/// Builds the arguments for a call without allocating for up to
+/// `INLINE_ARGS` of them, converting Rust values as they're pushed:
+///
+/// ```ignore
+/// let mut args = CallArgsBuilder::new();
+/// try!(args.push(cx, 42));
+/// try!(args.push(cx, "hi"));
+/// let result = try!(call_function(cx, this, fun, &args));
+/// ```
+///
+/// Inline arguments live in the builder, which must stay on the stack where
+/// the conservative scanner finds them. Past `INLINE_ARGS`, they all move to
+/// the heap and are rooted there.
+pub struct CallArgsBuilder {
+    cx: *mut JSContext,
+    inline: [JSVal; INLINE_ARGS],
+    len: usize,
+    spilled: Vec<JSVal>,
+}
+
+impl CallArgsBuilder {
+    pub fn new() -> CallArgsBuilder {
+        CallArgsBuilder {
+            cx: ptr::null_mut(),
+            inline: [UndefinedValue(); INLINE_ARGS],
+            len: 0,
+            spilled: vec!(),
+        }
+    }
+
+    /// Converts `value` and appends it.
+    pub fn push<T: ToJSValConvertible>(&mut self, cx: *mut JSContext, value: T) -> Result<(), JSError> {
+        let value = try!(value.to_jsval(cx));
+        if self.len < INLINE_ARGS {
+            self.inline[self.len] = value;
+        } else {
+            self.spill(cx, value);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    fn spill(&mut self, cx: *mut JSContext, value: JSVal) {
+        // Roots point into the vector, so they have to move with it when it
+        // grows.
+        if self.spilled.is_empty() || self.spilled.len() == self.spilled.capacity() {
+            self.unroot_spilled();
+            let mut grown = Vec::with_capacity(cmp::max(2 * self.len, 2 * INLINE_ARGS));
+            grown.extend_from_slice(if self.spilled.is_empty() { &self.inline } else { &self.spilled });
+            self.spilled = grown;
+            self.cx = cx;
+            for value in &mut self.spilled {
+                unsafe { JS_AddNamedValueRoot(cx, value, b"CallArgsBuilder\0".as_ptr() as *const c_char) };
+            }
+        }
+        self.spilled.push(value);
+        let value = self.spilled.last_mut().unwrap();
+        unsafe { JS_AddNamedValueRoot(cx, value, b"CallArgsBuilder\0".as_ptr() as *const c_char) };
+    }
+
+    fn unroot_spilled(&mut self) {
+        for value in &mut self.spilled {
+            unsafe { JS_RemoveValueRoot(self.cx, value) };
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_slice(&self) -> &[JSVal] {
+        if self.len > INLINE_ARGS { &self.spilled } else { &self.inline[..self.len] }
+    }
+
+    /// Empties the builder for reuse.
+    pub fn clear(&mut self) {
+        self.unroot_spilled();
+        self.spilled.clear();
+        self.len = 0;
+    }
+}
+
+impl Drop for CallArgsBuilder {
+    fn drop(&mut self) {
+        self.unroot_spilled();
+    }
+}
+
+/// Arguments `call_function` takes: a slice of values, which is copied
+/// and rooted for the call, or a `CallArgsBuilder`, which is already safe
+/// to use as it is.
+pub trait CallArgs {
+    fn with_rooted<R, F: FnOnce(&[JSVal]) -> R>(&self, cx: *mut JSContext, f: F) -> R;
+}
+
+impl<'a> CallArgs for &'a [JSVal] {
+    fn with_rooted<R, F: FnOnce(&[JSVal]) -> R>(&self, cx: *mut JSContext, f: F) -> R {
+        let args = RootedValues::new(cx, self);
+        f(&args.values)
+    }
+}
+
+impl<'a> CallArgs for &'a CallArgsBuilder {
+    fn with_rooted<R, F: FnOnce(&[JSVal]) -> R>(&self, _cx: *mut JSContext, f: F) -> R {
+        f(self.as_slice())
+    }
+}
+
+/// Calls `fun` with `this`, or the global if `None`, and `args` and returns
+/// the result. Everything must be in the current compartment. An exception
+/// thrown by the call is returned as the error; one already pending is
+/// reported and cleared first, so it isn't taken for the call's.
+pub fn call_function<C: AsContextPtr, A: CallArgs>(cx: C, this: Option<ObjectPtr>, fun: JSVal, args: A)
+                                                   -> Result<JSVal, JSError> {
+    let cx = cx.context_ptr().as_raw();
+    report_stale_exception(cx);
+    let this = match this {
+        Some(this) => {
+            try!(check_same_runtime(cx, this.as_raw(), "`this` object"));
+            this.as_raw()
+        }
+        None => ptr::null_mut(),
+    };
+    if fun.is_object() {
+        try!(check_same_runtime(cx, fun.to_object(), "function"));
+    }
+    let mut rval = UndefinedValue();
+    let ok = try!(args.with_rooted(cx, |args| {
+        let argc = try!(check_argument_count(args.len()));
+        Ok(unsafe { JS_CallFunctionValue(cx, this, fun, argc, args.as_ptr() as *mut JSVal, &mut rval) })
+    }));
+    if ok == ERR {
+        return Err(unsafe { JSError::from_pending_exception(cx) });
+    }
+    Ok(rval)
+}
+
+/// Calls `obj[name](args...)` in `obj`'s compartment and returns the result,
+/// wrapped for the caller's. Fails with a `TypeError`-style error if the
+/// property isn't callable; an exception thrown by a getter for it or by
+/// the method itself is returned as the error. As with `call_function`, an
+/// exception already pending is reported and cleared first.
+pub fn call_method<C: AsContextPtr, O: AsObjectPtr>(cx: C, obj: O, name: &str, args: &[JSVal])
+                                                    -> Result<JSVal, JSError> {
+    let (context, object) = (cx.context_ptr(), obj.object_ptr());
+    let (cx, obj) = (context.as_raw(), object.as_raw());
+    report_stale_exception(cx);
+    try!(check_same_runtime(cx, obj, "object"));
+    let name_cstr = match CString::new(name) {
+        Ok(s) => s,
+        Err(_) => return Err(JSError::new(format!("invalid method name {:?}", name))),
+    };
+    let mut args = RootedValues::new(cx, args);
+    let mut rval = try!(with_compartment(context, object, || unsafe {
+        for arg in &mut args.values {
+            if JS_WrapValue(cx, arg) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        let mut method = UndefinedValue();
+        if JS_GetProperty(cx, obj, name_cstr.as_ptr(), &mut method) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        if !method.is_object() || JS_ObjectIsCallable(cx, method.to_object()) == 0 {
+            return Err(JSError::type_error(format!("{} is not a function, it is {}",
+                                                   name, type_of(cx, method))));
+        }
+        let argc = try!(check_argument_count(args.values.len()));
+        let mut rval = UndefinedValue();
+        if JS_CallFunctionValue(cx, obj, method, argc, args.values.as_mut_ptr(), &mut rval) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        Ok(rval)
+    }));
+    unsafe {
+        if JS_WrapValue(cx, &mut rval) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(rval)
+}
+
+/// `call_function` for raw pointers, which must be live; `this` may be
+/// null.
+#[deprecated(note = "use call_function with typed pointers")]
+pub unsafe fn call_function_raw<A: CallArgs>(cx: *mut JSContext, this: *mut JSObject, fun: JSVal, args: A)
+                                             -> Result<JSVal, JSError> {
+    call_function(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw(this), fun, args)
+}
+
+/// `call_method` for raw pointers, which must be live.
+#[deprecated(note = "use call_method with typed pointers")]
+pub unsafe fn call_method_raw(cx: *mut JSContext, obj: *mut JSObject, name: &str, args: &[JSVal])
+                              -> Result<JSVal, JSError> {
+    call_method(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(obj), name, args)
+}
+
+/// Copies `value`, which must be in the current compartment, into
+/// `target_global`'s compartment with the structured clone algorithm: plain
+/// objects, arrays, dates, regexps and typed arrays are copied deeply, and
+/// anything else, like a function, is a `DataCloneError`. The copy isn't
+/// rooted and shares nothing with the original.
+pub fn clone_value_into(cx: *mut JSContext, value: JSVal, target_global: *mut JSObject)
+                        -> Result<JSVal, JSError> {
+    let mut buffer = try!(StructuredCloneBuffer::write(cx, value));
+    let (context, target) = unsafe {
+        (ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(target_global))
+    };
+    with_compartment(context, target, || buffer.read(cx))
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use jsapi::{JSObject, JS_NewObject, JS_GetGlobalForObject, JS_GC, JS_WrapValue};
+    use jsapi::{JS_SetPendingException, JS_IsExceptionPending};
+    use glue::IsWrapper;
+    use conversions::to_string;
+    use class::ClassBuilder;
+    use jsval::{Int32Value, NullValue};
+    use super::{has_instance, is_instance_of_global_class};
+    use super::{object_class_name, is_array_object, is_function_object, is_callable};
+    use super::{is_date_object, is_regexp_object};
+    use super::get_own_property_descriptor;
+    use super::{set_reserved_slot, get_reserved_slot, set_reserved_slot_object};
+    use super::{get_reserved_slot_object, set_reserved_slot_u32, get_reserved_slot_u32};
+    use super::{get_prototype, set_prototype, prototype_chain};
+    use super::{seal_object, freeze_object, deep_freeze, call_method, get_path, get_path_into};
+    use super::{clone_value_into, call_function, CallArgsBuilder, INLINE_ARGS};
+    use error::ErrorKind;
+    use rust::{Runtime, with_compartment};
+    use testing::{with_test_runtime, new_global, evaluate, object_ptr};
+
+    #[test]
+    fn instance_of_builtins() {
+        with_test_runtime(|rt, global| {
+            let date = assert_evaluates!(rt, global, "new Date()");
+            assert_eq!(is_instance_of_global_class(rt.cx(), date, "Date"), Ok(true));
+            assert_eq!(is_instance_of_global_class(rt.cx(), date, "Object"), Ok(true));
+
+            let plain = assert_evaluates!(rt, global, "({})");
+            assert_eq!(is_instance_of_global_class(rt.cx(), plain, "Array"), Ok(false));
+
+            let number = assert_evaluates!(rt, global, "5");
+            assert_eq!(is_instance_of_global_class(rt.cx(), number, "Number"), Ok(false));
+
+            assert!(is_instance_of_global_class(rt.cx(), plain, "NoSuchClass").is_err());
+        });
+    }
+
+    #[test]
+    fn class_predicates() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let kinds = [("[1, 2]", "Array"), ("(function () {})", "Function"), ("Math.max", "Function"),
+                         ("new Date(0)", "Date"), ("/a+/g", "RegExp"), ("({})", "Object")];
+            for &(source, class) in &kinds {
+                let obj = assert_evaluates!(rt, global, source).to_object();
+                assert_eq!(object_class_name(obj), class, "{}", source);
+                assert_eq!(is_array_object(cx, obj), class == "Array", "{}", source);
+                assert_eq!(is_function_object(obj), class == "Function", "{}", source);
+                assert_eq!(is_callable(cx, obj), class == "Function", "{}", source);
+                assert_eq!(is_date_object(cx, obj), class == "Date", "{}", source);
+                assert_eq!(is_regexp_object(cx, obj), class == "RegExp", "{}", source);
+            }
+        });
+    }
+
+    #[test]
+    fn class_predicates_see_through_wrappers() {
+        let rt = Runtime::new();
+        let (first, second) = (new_global(&rt), new_global(&rt));
+        let cx = rt.cx();
+        let kinds = [("[]", "Array"), ("(function () {})", "Function"), ("new Date(0)", "Date"),
+                     ("/a/", "RegExp")];
+        for &(source, class) in &kinds {
+            let mut value = with_compartment(&rt, object_ptr(second), || {
+                evaluate(&rt, second, source).unwrap()
+            });
+            with_compartment(&rt, object_ptr(first), || {
+                assert!(unsafe { JS_WrapValue(cx, &mut value) } != 0);
+                let wrapper = value.to_object();
+                assert!(unsafe { IsWrapper(wrapper) } != 0, "{}", source);
+                assert_eq!(object_class_name(wrapper), class);
+                assert_eq!(is_array_object(cx, wrapper), class == "Array");
+                assert_eq!(is_function_object(wrapper), class == "Function");
+                assert_eq!(is_callable(cx, wrapper), class == "Function");
+                assert_eq!(is_date_object(cx, wrapper), class == "Date");
+                assert_eq!(is_regexp_object(cx, wrapper), class == "RegExp");
+            });
+        }
+    }
+
+    #[test]
+    fn own_property_descriptors() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let obj = assert_evaluates!(rt, global, "var getter = function () { return 1; }; \
+                                                     var o = Object.create({ inherited: 1 }, { \
+                                                       data: { value: 42, writable: true, enumerable: true }, \
+                                                       accessor: { get: getter, configurable: true } \
+                                                     }); o").to_object();
+            let obj = object_ptr(obj);
+            let data = get_own_property_descriptor(rt, obj, "data").unwrap().unwrap();
+            assert_eq!(data.value.map(|v| v.to_int32()), Some(42));
+            assert!(!data.is_accessor());
+            assert!(data.writable && data.enumerable && !data.configurable);
+            assert_eq!((data.getter, data.setter), (None, None));
+
+            let accessor = get_own_property_descriptor(rt, obj, "accessor").unwrap().unwrap();
+            assert!(accessor.is_accessor());
+            assert_eq!(accessor.value, None);
+            assert!(!accessor.writable && !accessor.enumerable && accessor.configurable);
+            assert_eq!(accessor.getter, Some(assert_evaluates!(rt, global, "getter").to_object()));
+            assert_eq!(accessor.setter, None);
+
+            assert_eq!(get_own_property_descriptor(rt, obj, "missing"), Ok(None));
+            assert_eq!(get_own_property_descriptor(rt, obj, "inherited"), Ok(None));
+
+            let frozen = assert_evaluates!(rt, global, "Object.freeze({ name: 'x', 0: true })").to_object();
+            let frozen = object_ptr(frozen);
+            let name = get_own_property_descriptor(rt, frozen, "name").unwrap().unwrap();
+            assert_eq!(to_string(cx, name.value.unwrap()).unwrap(), "x");
+            assert!(!name.writable && name.enumerable && !name.configurable);
+            let index = get_own_property_descriptor(rt, frozen, "0").unwrap().unwrap();
+            assert_eq!(index.value.map(|v| v.to_boolean()), Some(true));
+        });
+    }
+
+    #[test]
+    fn property_descriptor_traps_can_throw() {
+        with_test_runtime(|rt, global| {
+            let proxy = assert_evaluates!(rt, global, "Proxy.create({ \
+                                                         getOwnPropertyDescriptor: function (name) { \
+                                                           throw new Error('no ' + name); \
+                                                         } \
+                                                       })").to_object();
+            let error = get_own_property_descriptor(rt, object_ptr(proxy), "secret").unwrap_err();
+            assert!(error.message.contains("no secret"), "{}", error.message);
+        });
+    }
+
+    #[test]
+    fn instance_of_script_constructor() {
+        with_test_runtime(|rt, global| {
+            let ctor = assert_evaluates!(rt, global, "function Point() {}; Point").to_object();
+            let point = assert_evaluates!(rt, global, "new Point()");
+            let other = assert_evaluates!(rt, global, "[]");
+            assert_eq!(has_instance(rt.cx(), ctor, point), Ok(true));
+            assert_eq!(has_instance(rt.cx(), ctor, other), Ok(false));
+        });
+    }
+
+    #[test]
+    fn instance_of_non_callable_is_type_error() {
+        with_test_runtime(|rt, global| {
+            let not_ctor = assert_evaluates!(rt, global, "({})").to_object();
+            let val = assert_evaluates!(rt, global, "({})");
+            let err = has_instance(rt.cx(), not_ctor, val).unwrap_err();
+            assert!(err.message.contains("TypeError"));
+        });
+    }
+
+    #[test]
+    fn reparenting_makes_methods_callable() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let host = evaluate(rt, global, "var host = { greet: function() { return 'hi ' + this.name; } }; host")
+                .unwrap().to_object();
+            let obj = assert_evaluates!(rt, global, "var obj = { name: 'bob' }; obj").to_object();
+            let object_proto = get_prototype(cx, obj).unwrap().unwrap();
+            assert_eq!(prototype_chain(cx, obj).unwrap(), vec!(object_proto));
+            assert!(assert_evaluates!(rt, global, "obj.greet").is_undefined());
+
+            set_prototype(cx, obj, Some(host)).unwrap();
+            assert_eq!(prototype_chain(cx, obj).unwrap(), vec!(host, object_proto));
+            let greeting = assert_evaluates!(rt, global, "obj.greet() === 'hi bob'");
+            assert!(greeting.to_boolean());
+
+            assert!(set_prototype(cx, host, Some(obj)).is_err());
+            set_prototype(cx, obj, None).unwrap();
+            assert_eq!(get_prototype(cx, obj).unwrap(), None);
+            assert!(prototype_chain(cx, obj).unwrap().is_empty());
+
+            let sealed = assert_evaluates!(rt, global, "Object.preventExtensions({})").to_object();
+            let error = set_prototype(cx, sealed, Some(host)).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+        });
+    }
+
+    const CONFIG: &'static str =
+        "var config = { name: 'app', limits: { depth: 3, tags: ['a'] } }; config";
+
+    #[test]
+    fn sealing_and_shallow_freezing() {
+        with_test_runtime(|rt, global| {
+            let config = assert_evaluates!(rt, global, CONFIG).to_object();
+            // Script can't get in the way.
+            assert_evaluates!(rt, global, "Object.seal = function() {}; \
+                                           Object.defineProperty(config, 'hidden', \
+                                               { value: 1, writable: true, configurable: true })");
+            seal_object(rt.cx(), config).unwrap();
+            assert!(assert_evaluates!(rt, global, "Object.isSealed(config) && !Object.isFrozen(config)")
+                        .to_boolean());
+            assert!(evaluate(rt, global, "'use strict'; config.name = 'changed'").is_ok());
+            assert!(evaluate(rt, global, "'use strict'; config.hidden = 2").is_ok());
+            assert_throws!(rt, global, "'use strict'; config.extra = 1", "TypeError");
+            assert_throws!(rt, global, "'use strict'; delete config.hidden", "TypeError");
+
+            freeze_object(rt.cx(), config).unwrap();
+            assert_throws!(rt, global, "'use strict'; config.name = 'again'", "TypeError");
+            // Shallow: nested objects can still be changed.
+            assert!(evaluate(rt, global, "'use strict'; config.limits.depth = 4").is_ok());
+        });
+    }
+
+    #[test]
+    fn deep_freezing() {
+        with_test_runtime(|rt, global| {
+            let config = assert_evaluates!(rt, global, CONFIG).to_object();
+            deep_freeze(rt.cx(), config).unwrap();
+            assert_throws!(rt, global, "'use strict'; config.limits.depth = 4", "TypeError");
+            assert_throws!(rt, global, "'use strict'; config.limits.tags.push('b')", "TypeError");
+            assert!(assert_evaluates!(rt, global, "config.limits.depth === 3").to_boolean());
+
+            let cyclic = evaluate(rt, global, "var a = {}; a.self = a; a.b = { a: a }; a")
+                .unwrap().to_object();
+            deep_freeze(rt.cx(), cyclic).unwrap();
+            assert!(assert_evaluates!(rt, global, "Object.isFrozen(a) && Object.isFrozen(a.b)").to_boolean());
+
+            let lazy = evaluate(rt, global, "var ran = false; \
+                                             ({ get value() { ran = true; return {}; } })")
+                .unwrap().to_object();
+            deep_freeze(rt.cx(), lazy).unwrap();
+            assert!(!assert_evaluates!(rt, global, "ran").to_boolean());
+        });
+    }
+
+    fn new_two_slot_object(rt: &Runtime) -> *mut JSObject {
+        let class = ClassBuilder::new("TwoSlots").reserved_slots(2);
+        assert_eq!(class.reserved_slot_count(), 2);
+        let class = class.build();
+        let obj = unsafe { JS_NewObject(rt.cx(), class, ptr::null(), ptr::null()) };
+        assert!(!obj.is_null());
+        obj
+    }
+
+    #[test]
+    fn reserved_slots_round_trip() {
+        with_test_runtime(|rt, global| {
+            let obj = new_two_slot_object(rt);
+            assert!(get_reserved_slot(obj, 0).is_undefined());
+
+            let target = assert_evaluates!(rt, global, "({})").to_object();
+            set_reserved_slot_object(obj, 0, target);
+            set_reserved_slot_u32(obj, 1, 0xdeadbeef);
+            assert_eq!(get_reserved_slot_object(obj, 0), Some(target));
+            assert_eq!(get_reserved_slot_u32(obj, 1), Some(0xdeadbeef));
+            assert_eq!(get_reserved_slot_u32(obj, 0), None);
+
+            set_reserved_slot_object(obj, 0, ptr::null_mut());
+            assert!(get_reserved_slot(obj, 0).is_null());
+            assert_eq!(get_reserved_slot_object(obj, 0), None);
+
+            let five = assert_evaluates!(rt, global, "5");
+            set_reserved_slot(obj, 1, five);
+            assert!(get_reserved_slot(obj, 1) == five);
+        });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "out of range")]
+    fn reserved_slot_out_of_range() {
+        with_test_runtime(|rt, _| {
+            let obj = new_two_slot_object(rt);
+            get_reserved_slot(obj, 2);
+        });
+    }
+
+    #[test]
+    fn values_cloned_into_another_global() {
+        let rt = Runtime::new();
+        let (first, second) = (new_global(&rt), new_global(&rt));
+        let clone = with_compartment(rt.context(), object_ptr(first), || {
+            let value = evaluate(&rt, first, "var original = {list: [1, {n: 2}]}; original").unwrap();
+            let clone = clone_value_into(rt.cx(), value, second).unwrap();
+            let function = evaluate(&rt, first, "(function() {})").unwrap();
+            assert!(clone_value_into(rt.cx(), function, second).is_err());
+            clone
+        });
+        with_compartment(rt.context(), object_ptr(second), || {
+            let clone = object_ptr(clone.to_object());
+            assert_eq!(get_path_into::<f64, _, _>(&rt, clone, "list.1.n"), Ok(2.0));
+            unsafe { assert_eq!(JS_GetGlobalForObject(rt.cx(), clone.as_raw()), second) };
+        });
+    }
+
+    #[test]
+    fn calling_with_inline_args() {
+        const CALLS: i32 = 100000;
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let this = Some(object_ptr(global));
+            let fun = assert_evaluates!(rt, global, "(function(n, s) { return n + s.length; })");
+            let mut total = 0.0;
+            let mut args = CallArgsBuilder::new();
+            for i in 0..CALLS {
+                args.clear();
+                args.push(cx, i).unwrap();
+                args.push(cx, "ab").unwrap();
+                total += call_function(rt, this, fun, &args).unwrap().to_number();
+            }
+            let calls = CALLS as f64;
+            assert_eq!(total, calls * (calls - 1.0) / 2.0 + 2.0 * calls);
+
+            let values = [Int32Value(1), NullValue()];
+            let error = call_function(rt, this, fun, &values[..]).unwrap_err();
+            assert!(error.message.starts_with("TypeError"), "{}", error.message);
+
+            // An exception some native left pending isn't the call's.
+            unsafe { JS_SetPendingException(cx, Int32Value(7)) };
+            let values = [Int32Value(1), assert_evaluates!(rt, global, "'abc'")];
+            assert_eq!(call_function(rt, this, fun, &values[..]).unwrap().to_int32(), 4);
+            unsafe { JS_SetPendingException(cx, Int32Value(7)) };
+            let source = assert_evaluates!(rt, global, "'1 + 1'");
+            let sum = call_method(rt, object_ptr(global), "eval", &[source]).unwrap();
+            assert_eq!(sum.to_int32(), 2);
+            assert!(unsafe { JS_IsExceptionPending(cx) } == 0);
+        });
+    }
+
+    #[test]
+    fn calling_with_spilled_args() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let fun = assert_evaluates!(rt, global,
+                                        "(function() { return Array.prototype.join.call(arguments, ','); })");
+            let this = Some(object_ptr(global));
+            let mut args = CallArgsBuilder::new();
+            for i in 0..(INLINE_ARGS + 5) {
+                args.push(cx, format!("arg{}", i)).unwrap();
+                unsafe { JS_GC(rt.rt()) };
+            }
+            args.push(cx, Some(true)).unwrap();
+            args.push(cx, None::<f64>).unwrap();
+            assert_eq!(args.len(), INLINE_ARGS + 7);
+            let joined = to_string(cx, call_function(rt, this, fun, &args).unwrap()).unwrap();
+            let expected: Vec<String> = (0..(INLINE_ARGS + 5)).map(|i| format!("arg{}", i)).collect();
+            assert_eq!(joined, format!("{},true,", expected.join(",")));
+
+            args.clear();
+            assert!(args.is_empty());
+            args.push(cx, 1.5).unwrap();
+            assert_eq!(to_string(cx, call_function(rt, this, fun, &args).unwrap()).unwrap(), "1.5");
+        });
+    }
+
+    #[test]
+    fn call_method_by_name() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(&rt, object_ptr(global), || {
+            let counter = evaluate(&rt, global, "({ count: 1, \
+                                                    add: function(a, b) { return this.count += a + b; }, \
+                                                    get broken() { throw new Error('getter'); } })").unwrap();
+            let counter = object_ptr(counter.to_object());
+            let result = call_method(&rt, counter, "add", &[Int32Value(2), Int32Value(3)]).unwrap();
+            assert_eq!(result.to_number(), 6.0);
+
+            let error = call_method(&rt, counter, "count", &[]).unwrap_err();
+            assert_eq!(error.message, "TypeError: count is not a function, it is number");
+            assert_eq!(error.kind, ErrorKind::Type);
+            let error = call_method(&rt, counter, "missing", &[]).unwrap_err();
+            assert_eq!(error.message, "TypeError: missing is not a function, it is undefined");
+            assert_eq!(error.kind, ErrorKind::Type);
+            let error = call_method(&rt, counter, "broken", &[]).unwrap_err();
+            assert_eq!(error.message, "Error: getter");
+        });
+
+        // A method of an object in another compartment.
+        let other = new_global(&rt);
+        let object = with_compartment(&rt, object_ptr(other), || {
+            evaluate(&rt, other, "({ twice: function(x) { return [x, x]; } })").unwrap().to_object()
+        });
+        with_compartment(&rt, object_ptr(global), || {
+            let result = call_method(&rt, object_ptr(object), "twice", &[Int32Value(4)]).unwrap();
+            assert!(result.is_object());
+        });
+    }
+
+    #[test]
+    fn get_path_walks_properties_and_indices() {
+        with_test_runtime(|rt, global| {
+            let config = evaluate(rt, global, "({ network: { proxy: { port: 8080 } }, \
+                                                  servers: [{ host: 'a' }, { host: 'b' }] })").unwrap();
+            let config = object_ptr(config.to_object());
+            assert_eq!(get_path(rt, config, "network.proxy.port").unwrap().to_number(), 8080.0);
+            assert_eq!(get_path_into::<i32, _, _>(rt, config, "network.proxy.port").unwrap(), 8080);
+            assert_eq!(get_path_into::<String, _, _>(rt, config, "servers.1.host").unwrap(), "b");
+            assert!(get_path(rt, config, "network.socks").unwrap().is_undefined());
+
+            let error = get_path(rt, config, "network.socks.port").unwrap_err();
+            assert_eq!(error.message, "network.socks is undefined");
+            let error = get_path(rt, config, "servers.5.host").unwrap_err();
+            assert_eq!(error.message, "servers.5 is undefined");
+            let error = get_path_into::<i32, _, _>(rt, config, "servers.0.host").unwrap_err();
+            assert_eq!(error.message, "servers.0.host: expected an i32, got string");
+        });
+    }
+}
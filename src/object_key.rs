@@ -0,0 +1,107 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Using JS objects as keys in Rust collections.
+
+use libc::c_char;
+use std::hash::{Hash, Hasher};
+use jsapi::{JSContext, JSObject, JS_AddNamedObjectRoot, JS_RemoveObjectRoot};
+
+/// A rooted JS object that hashes and compares by identity, for keying
+/// `HashMap`s and `HashSet`s by object.
+///
+/// The key keeps its object alive until it is dropped. Comparisons read the
+/// rooted slot, so they follow the object wherever the GC keeps it; this
+/// engine's collector doesn't move objects, so the hash of that address
+/// doesn't change either.
+pub struct JsObjectKey {
+    cx: *mut JSContext,
+    // Boxed so the root's address stays put.
+    object: Box<*mut JSObject>,
+}
+
+impl JsObjectKey {
+    /// Roots `object`, which must not be null, until the key is dropped.
+    pub fn new(cx: *mut JSContext, object: *mut JSObject) -> JsObjectKey {
+        assert!(!object.is_null());
+        let mut root = Box::new(object);
+        unsafe {
+            JS_AddNamedObjectRoot(cx, &mut *root, b"JsObjectKey\0".as_ptr() as *const c_char);
+        }
+        JsObjectKey {
+            cx: cx,
+            object: root,
+        }
+    }
+
+    pub fn get(&self) -> *mut JSObject {
+        *self.object
+    }
+}
+
+impl Clone for JsObjectKey {
+    fn clone(&self) -> JsObjectKey {
+        JsObjectKey::new(self.cx, self.get())
+    }
+}
+
+impl PartialEq for JsObjectKey {
+    fn eq(&self, other: &JsObjectKey) -> bool {
+        self.get() == other.get()
+    }
+}
+
+impl Eq for JsObjectKey {}
+
+impl Hash for JsObjectKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.get() as usize).hash(state);
+    }
+}
+
+impl Drop for JsObjectKey {
+    fn drop(&mut self) {
+        unsafe { JS_RemoveObjectRoot(self.cx, &mut *self.object) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use jsapi::{JSObject, JS_GC};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::JsObjectKey;
+
+    /// Creates an object that only `map`'s key keeps alive.
+    #[inline(never)]
+    fn insert_fresh_object(rt: &Runtime, global: *mut JSObject,
+                           map: &mut HashMap<JsObjectKey, &'static str>) {
+        let object = evaluate(rt, global, "({name: 'fresh'})").unwrap().to_object();
+        map.insert(JsObjectKey::new(rt.cx(), object), "fresh");
+    }
+
+    #[test]
+    fn lookup_by_another_key_after_gc() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut map = HashMap::new();
+            insert_fresh_object(&rt, global, &mut map);
+            let other = evaluate(&rt, global, "({})").unwrap().to_object();
+            map.insert(JsObjectKey::new(rt.cx(), other), "other");
+            unsafe {
+                JS_GC(rt.rt());
+                JS_GC(rt.rt());
+            }
+
+            let fresh = map.keys().find(|key| map[*key] == "fresh").unwrap().get();
+            let second = JsObjectKey::new(rt.cx(), fresh);
+            assert_eq!(map.get(&second), Some(&"fresh"));
+            assert_eq!(map.get(&second.clone()), Some(&"fresh"));
+            assert_eq!(map.get(&JsObjectKey::new(rt.cx(), other)), Some(&"other"));
+            assert_eq!(map.get(&JsObjectKey::new(rt.cx(), global)), None);
+        });
+    }
+}
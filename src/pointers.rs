@@ -0,0 +1,151 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Non-null, typed engine pointers.
+//!
+//! `ContextPtr`, `ObjectPtr` and `StringPtr` can't be null, and can't be
+//! passed where another kind of pointer is expected, so checking for null
+//! happens once, where a raw pointer comes in, and swapped arguments don't
+//! compile. APIs that take `C: AsContextPtr` or `O: AsObjectPtr` accept
+//! these, a `Runtime` and a `RootedObject`, which can't be null either.
+//! A raw pointer has to go through the unsafe `from_raw` first; the
+//! deprecated `*_raw` functions do that for callers not yet migrated.
+
+use std::ptr::NonNull;
+use jsapi::{JSContext, JSObject, JSString};
+use object::RootedObject;
+use rust::Runtime;
+
+/// A `JSContext` pointer that isn't null.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ContextPtr(NonNull<JSContext>);
+
+/// A `JSObject` pointer that isn't null. Like a raw pointer, it doesn't
+/// keep the object alive; see `object::RootedObject`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObjectPtr(NonNull<JSObject>);
+
+/// A `JSString` pointer that isn't null. Like a raw pointer, it doesn't
+/// keep the string alive; see `strings::RootedString`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StringPtr(NonNull<JSString>);
+
+macro_rules! typed_pointer {
+    ($name:ident, $target:ty) => {
+        impl $name {
+            /// Wraps `ptr`, or returns `None` if it's null. Unsafe because
+            /// the pointer is trusted to point at a live engine thing.
+            pub unsafe fn from_raw(ptr: *mut $target) -> Option<$name> {
+                NonNull::new(ptr).map($name)
+            }
+
+            /// `from_raw` for a pointer known not to be null, such as one
+            /// the engine passed to a callback. Panics if it is null.
+            pub unsafe fn from_raw_nonnull(ptr: *mut $target) -> $name {
+                $name::from_raw(ptr).expect(concat!("null ", stringify!($target)))
+            }
+
+            /// The raw pointer, for the engine APIs that take one.
+            pub fn as_raw(self) -> *mut $target {
+                self.0.as_ptr()
+            }
+        }
+    }
+}
+
+typed_pointer!(ContextPtr, JSContext);
+typed_pointer!(ObjectPtr, JSObject);
+typed_pointer!(StringPtr, JSString);
+
+/// Things that name a context: `ContextPtr` or a `Runtime`.
+pub trait AsContextPtr {
+    fn context_ptr(self) -> ContextPtr;
+}
+
+impl AsContextPtr for ContextPtr {
+    fn context_ptr(self) -> ContextPtr {
+        self
+    }
+}
+
+impl<'a> AsContextPtr for &'a Runtime {
+    fn context_ptr(self) -> ContextPtr {
+        self.context()
+    }
+}
+
+/// Things that name an object: `ObjectPtr` or a `RootedObject`.
+pub trait AsObjectPtr {
+    fn object_ptr(self) -> ObjectPtr;
+}
+
+impl AsObjectPtr for ObjectPtr {
+    fn object_ptr(self) -> ObjectPtr {
+        self
+    }
+}
+
+impl<'a> AsObjectPtr for &'a RootedObject {
+    fn object_ptr(self) -> ObjectPtr {
+        self.ptr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use jsapi::{JSContext, JSObject, JSString};
+    use object::{RootedObject, get_path, get_path_into};
+    use rust::with_compartment;
+    use testing::with_test_runtime;
+    use super::{ContextPtr, ObjectPtr, StringPtr};
+
+    #[test]
+    fn null_is_checked_at_the_boundary() {
+        unsafe {
+            assert_eq!(ContextPtr::from_raw(ptr::null_mut::<JSContext>()), None);
+            assert_eq!(ObjectPtr::from_raw(ptr::null_mut::<JSObject>()), None);
+            assert_eq!(StringPtr::from_raw(ptr::null_mut::<JSString>()), None);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "null JSObject")]
+    fn null_nonnull_objects_panic() {
+        unsafe { ObjectPtr::from_raw_nonnull(ptr::null_mut::<JSObject>()) };
+    }
+
+    #[test]
+    fn typed_pointers_name_the_same_things() {
+        with_test_runtime(|rt, global| {
+            let context = rt.context();
+            assert_eq!(context.as_raw(), rt.cx());
+            let typed_global = unsafe { ObjectPtr::from_raw(global) }.unwrap();
+            assert_eq!(typed_global.as_raw(), global);
+
+            let config = assert_evaluates!(rt, global, "({ port: 8080 })").to_object();
+            let rooted = RootedObject::new(rt.cx(), config).unwrap();
+            let config = rooted.ptr();
+            with_compartment(context, typed_global, || {
+                assert_eq!(get_path(context, config, "port").unwrap().to_int32(), 8080);
+                assert_eq!(get_path(rt, &rooted, "port").unwrap().to_int32(), 8080);
+                assert_eq!(get_path_into::<i32, _, _>(rt, config, "port"), Ok(8080));
+            });
+        });
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn raw_pointers_go_through_the_deprecated_wrappers() {
+        use object::get_path_raw;
+        use rust::with_compartment_raw;
+        with_test_runtime(|rt, global| {
+            let config = assert_evaluates!(rt, global, "({ port: 8080 })").to_object();
+            let port = unsafe {
+                with_compartment_raw(rt.cx(), global, || get_path_raw(rt.cx(), config, "port"))
+            };
+            assert_eq!(port.unwrap().to_int32(), 8080);
+        });
+    }
+}
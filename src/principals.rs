@@ -0,0 +1,142 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Principals, the engine's record of whose code a script is, so scripts
+//! from different origins can share a compartment and still be told apart.
+//!
+//! The engine reference counts principals: every script compiled with them
+//! holds a reference until it's finalized. A `Principals` handle holds one
+//! more, and the last one to go frees them through a callback every
+//! `Runtime` installs, so the engine must never be given principals that
+//! didn't come from `Principals::new`.
+
+use jsapi::{JSPrincipals, JS_HoldPrincipals, JS_DropPrincipals};
+use rust::Runtime;
+
+// `raw` must come first, so a pointer to it is a pointer to the whole.
+#[repr(C)]
+struct PrincipalsData {
+    raw: JSPrincipals,
+    name: String,
+}
+
+/// A counted reference to principals made for `rt`. Cloning takes another
+/// reference rather than making new principals.
+pub struct Principals<'a> {
+    rt: &'a Runtime,
+    raw: *mut JSPrincipals,
+}
+
+impl<'a> Principals<'a> {
+    /// Makes new principals, called `name` for the embedding's own use.
+    pub fn new(rt: &'a Runtime, name: &str) -> Principals<'a> {
+        let data = Box::new(PrincipalsData {
+            raw: JSPrincipals {
+                refcount: 1,
+                #[cfg(feature = "debugmozjs")]
+                debugToken: 0,
+            },
+            name: name.to_string(),
+        });
+        Principals { rt: rt, raw: Box::into_raw(data) as *mut JSPrincipals }
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { &(*(self.raw as *const PrincipalsData)).name }
+    }
+
+    /// The principals, for engine APIs that take them.
+    /// They stay alive while this handle does.
+    pub fn raw(&self) -> *mut JSPrincipals {
+        self.raw
+    }
+}
+
+impl<'a> Clone for Principals<'a> {
+    fn clone(&self) -> Principals<'a> {
+        unsafe { JS_HoldPrincipals(self.raw) };
+        Principals { rt: self.rt, raw: self.raw }
+    }
+}
+
+impl<'a> Drop for Principals<'a> {
+    fn drop(&mut self) {
+        unsafe { JS_DropPrincipals(self.rt.rt(), self.raw) };
+    }
+}
+
+impl<'a> PartialEq for Principals<'a> {
+    fn eq(&self, other: &Principals<'a>) -> bool {
+        self.raw == other.raw
+    }
+}
+
+/// The runtime's `JSDestroyPrincipalsOp`, called once the last reference
+/// to principals is dropped.
+pub unsafe extern "C" fn destroy_principals(principals: *mut JSPrincipals) {
+    drop(Box::from_raw(principals as *mut PrincipalsData));
+}
+
+#[cfg(test)]
+mod test {
+    use jsapi::JS_GC;
+    use jsval::{Int32Value, ObjectValue};
+    use object::call_function;
+    use rust::{Runtime, EvaluateOptions, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::Principals;
+
+    fn refcount(principals: &Principals) -> i32 {
+        unsafe { (*principals.raw()).refcount as i32 }
+    }
+
+    #[test]
+    fn clones_share_a_count() {
+        let rt = Runtime::new();
+        let principals = Principals::new(&rt, "https://example.com");
+        assert_eq!(refcount(&principals), 1);
+        let other = principals.clone();
+        assert_eq!(refcount(&principals), 2);
+        assert!(other == principals);
+        assert_eq!(other.name(), "https://example.com");
+        drop(other);
+        assert_eq!(refcount(&principals), 1);
+        assert!(Principals::new(&rt, "https://example.com") != principals);
+    }
+
+    #[test]
+    fn scripts_with_different_principals_share_a_compartment() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let first = Principals::new(&rt, "https://first.example");
+            let second = Principals::new(&rt, "https://second.example");
+            let options = EvaluateOptions::new().filename("mixed.js");
+            let a = rt.compile_script_for_principals(global, "var a = 1; a", &options, &first).unwrap();
+            let b = rt.compile_script_for_principals(global, "var b = a + 1; b", &options, &second).unwrap();
+            assert!(a.principals() == Some(&first));
+            assert!(b.principals() == Some(&second));
+            drop(first);
+            drop(second);
+
+            unsafe { JS_GC(rt.rt()) };
+            assert_eq!(a.execute().unwrap().to_int32(), 1);
+            assert_eq!(b.execute().unwrap().to_int32(), 2);
+            assert_eq!(a.principals().unwrap().name(), "https://first.example");
+
+            let third = Principals::new(&rt, "https://third.example");
+            let value = rt.evaluate_for_principals(global, "a + b", &options, &third).unwrap();
+            assert_eq!(value.to_int32(), 3);
+            let fun = rt.compile_function_for_principals(global, Some("add"), &["x", "y"], "return x + y + b;",
+                                                         &options, &third).unwrap();
+            let args = [Int32Value(3), Int32Value(4)];
+            let fun = ObjectValue(unsafe { &*fun });
+            assert_eq!(call_function(&rt, Some(object_ptr(global)), fun, &args[..]).unwrap().to_int32(), 9);
+            assert!(evaluate(&rt, global, "a + b").is_ok());
+        });
+        // Destroying the runtime finalizes the scripts, dropping their
+        // references last.
+        drop(rt);
+    }
+}
@@ -0,0 +1,306 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! JS objects that own Rust data through their private slot.
+
+use libc::c_void;
+use std::any::TypeId;
+use std::marker::PhantomData;
+use std::ptr;
+use jsapi::{JSClass, JSContext, JSFreeOp, JSObject};
+use jsapi::{JS_NewObject, JS_GetPrivate, JS_SetPrivate, JS_GetClass};
+use class::{ClassBuilder, ClassFlags};
+use error::{JSError, catch_panic};
+use rust::update_malloc_counter;
+use tracer::Traceable;
+use JSCLASS_HAS_PRIVATE;
+
+const PRIVATE_MAGIC: u32 = 0x4d4f5a52; // "MOZR"
+
+/// Precedes every value stored by `set_private_boxed`, so that reads can
+/// tell our allocations (and their types) apart from foreign private data.
+#[repr(C)]
+struct PrivateHeader {
+    magic: u32,
+    type_id: TypeId,
+}
+
+#[repr(C)]
+struct PrivateBox<T> {
+    header: PrivateHeader,
+    value: T,
+}
+
+unsafe fn class_has_private(obj: *mut JSObject) -> bool {
+    let class = JS_GetClass(obj);
+    !class.is_null() && (*class).flags & JSCLASS_HAS_PRIVATE != 0
+}
+
+unsafe fn private_box<T: 'static>(obj: *mut JSObject) -> *mut PrivateBox<T> {
+    if !class_has_private(obj) {
+        return ptr::null_mut();
+    }
+    let private = JS_GetPrivate(obj) as *mut PrivateBox<T>;
+    if private.is_null() {
+        return ptr::null_mut();
+    }
+    let header = &(*private).header;
+    if header.magic != PRIVATE_MAGIC || header.type_id != TypeId::of::<T>() {
+        return ptr::null_mut();
+    }
+    private
+}
+
+/// Moves `value` into the private slot of `obj`.
+///
+/// Fails if `obj`'s class doesn't have `JSCLASS_HAS_PRIVATE` or the slot is
+/// already in use. The value is not freed automatically: the class's
+/// finalizer should call `take_private`.
+pub unsafe fn set_private_boxed<T: 'static>(obj: *mut JSObject, value: Box<T>) -> Result<(), JSError> {
+    if !class_has_private(obj) {
+        return Err(JSError::new("object's class does not have JSCLASS_HAS_PRIVATE"));
+    }
+    if !JS_GetPrivate(obj).is_null() {
+        return Err(JSError::new("object's private slot is already in use"));
+    }
+    let private = Box::new(PrivateBox {
+        header: PrivateHeader {
+            magic: PRIVATE_MAGIC,
+            type_id: TypeId::of::<T>(),
+        },
+        value: *value,
+    });
+    JS_SetPrivate(obj, Box::into_raw(private) as *mut c_void);
+    Ok(())
+}
+
+/// Borrows the `T` stored in `obj`'s private slot, or returns `None` if the
+/// class has no private slot or it holds anything other than a `T` stored
+/// by `set_private_boxed`.
+///
+/// The caller must make sure `obj` stays alive while the reference is used.
+pub unsafe fn borrow_private<'a, T: 'static>(obj: *mut JSObject) -> Option<&'a T> {
+    let private = private_box::<T>(obj);
+    if private.is_null() {
+        None
+    } else {
+        Some(&(*private).value)
+    }
+}
+
+/// Removes the `T` stored in `obj`'s private slot and returns it, leaving
+/// the slot empty.
+pub unsafe fn take_private<T: 'static>(obj: *mut JSObject) -> Option<Box<T>> {
+    let private = private_box::<T>(obj);
+    if private.is_null() {
+        return None;
+    }
+    JS_SetPrivate(obj, ptr::null_mut());
+    let private = *Box::from_raw(private);
+    Some(Box::new(private.value))
+}
+
+/// A `JSClass` whose objects own a `T`, dropped when the object is finalized.
+///
+/// The finalizer runs whenever the GC gets to it, possibly long after the
+/// last reference went away and on no particular stack, so `T` has to be
+/// `'static` and its `Drop` implementation must not panic or touch the JS
+/// engine.
+pub struct NativeClass<T> {
+    class: &'static JSClass,
+    marker: PhantomData<T>,
+}
+
+impl<T: 'static> NativeClass<T> {
+    /// Defines a new class named `name`. Do this once per type and reuse it.
+    pub fn new(name: &str) -> NativeClass<T> {
+        let class = ClassBuilder::new(name)
+            .flags(ClassFlags::HAS_PRIVATE)
+            .finalize(Some(finalize_native::<T>))
+            .build();
+        NativeClass {
+            class: class,
+            marker: PhantomData,
+        }
+    }
+
+    /// Like `new`, but the class's trace hook reports the GC pointers the
+    /// native holds, typically in `Heap` fields.
+    pub fn new_traced(name: &str) -> NativeClass<T> where T: Traceable {
+        let class = ClassBuilder::new(name)
+            .flags(ClassFlags::HAS_PRIVATE)
+            .finalize(Some(finalize_native::<T>))
+            .trace_private::<T>()
+            .build();
+        NativeClass {
+            class: class,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the underlying `JSClass`.
+    pub fn class(&self) -> &'static JSClass {
+        self.class
+    }
+}
+
+unsafe extern "C" fn finalize_native<T: 'static>(_fop: *mut JSFreeOp, obj: *mut JSObject) {
+    let private = take_private::<T>(obj);
+    catch_panic(ptr::null_mut(), || drop(private));
+}
+
+/// Creates an object of `class` that owns `value`.
+pub fn new_object_with_native<T: 'static>(cx: *mut JSContext, class: &NativeClass<T>, value: T)
+                                          -> Result<*mut JSObject, JSError> {
+    unsafe {
+        let obj = JS_NewObject(cx, class.class, ptr::null(), ptr::null());
+        if obj.is_null() {
+            return Err(JSError::from_failed_allocation(cx, "creating an object"));
+        }
+        try!(set_private_boxed(obj, Box::new(value)));
+        Ok(obj)
+    }
+}
+
+/// Like `new_object_with_native`, for a `value` that owns `external_bytes`
+/// of memory the GC can't see, such as a decoded image. Declaring them with
+/// `update_malloc_counter` makes the GC run sooner, and so finalize objects
+/// like this one sooner, when many of them are garbage.
+pub fn new_object_with_native_sized<T: 'static>(cx: *mut JSContext, class: &NativeClass<T>, value: T,
+                                                external_bytes: usize) -> Result<*mut JSObject, JSError> {
+    let obj = try!(new_object_with_native(cx, class, value));
+    update_malloc_counter(cx, external_bytes);
+    Ok(obj)
+}
+
+/// Returns the `T` owned by `obj`, or `None` if `obj` was not created by
+/// `new_object_with_native::<T>`.
+///
+/// The caller must make sure `obj` stays alive while the reference is used.
+pub unsafe fn native_from_object<'a, T: 'static>(obj: *mut JSObject) -> Option<&'a T> {
+    borrow_private::<T>(obj)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    use jsapi::{JSContext, JS_NewObject, JS_MaybeGC, JS_GetGCParameter, JS_SetGCParameter};
+    use jsapi::{JSGC_NUMBER, JSGC_MAX_MALLOC_BYTES};
+    use class::{ClassBuilder, ClassFlags};
+    use super::{NativeClass, new_object_with_native, new_object_with_native_sized, native_from_object};
+    use super::{set_private_boxed, borrow_private, take_private};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, assert_all_collected, object_ptr};
+
+    static DROPS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    struct Counted(u32);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[inline(never)]
+    fn make_garbage(cx: *mut JSContext, class: &NativeClass<Counted>, count: u32) {
+        for i in 0..count {
+            let obj = new_object_with_native(cx, class, Counted(i)).unwrap();
+            assert_eq!(unsafe { native_from_object::<Counted>(obj) }.map(|c| c.0), Some(i));
+        }
+    }
+
+    #[test]
+    fn natives_are_dropped_by_the_gc() {
+        const COUNT: usize = 1000;
+        let class = NativeClass::<Counted>::new("Counted");
+        {
+            let rt = Runtime::new();
+            let global = new_global(&rt);
+            with_compartment(rt.context(), object_ptr(global), || {
+                make_garbage(rt.cx(), &class, COUNT as u32);
+            });
+            assert_all_collected(&rt, "natives", || COUNT - DROPS.load(Ordering::SeqCst));
+        }
+        assert_eq!(DROPS.load(Ordering::SeqCst), COUNT);
+    }
+
+    /// Attaches 50MB of buffers to garbage objects, declaring their size or
+    /// not, and returns how many collections that set off.
+    fn collections_while_attaching(declare: bool) -> u32 {
+        let class = NativeClass::<Vec<u8>>::new("Buffer");
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        unsafe { JS_SetGCParameter(rt.rt(), JSGC_MAX_MALLOC_BYTES, 16 << 20) };
+        with_compartment(rt.context(), object_ptr(global), || {
+            let before = unsafe { JS_GetGCParameter(rt.rt(), JSGC_NUMBER) };
+            for _ in 0..50 {
+                let buffer = vec![0u8; 1 << 20];
+                let size = if declare { buffer.len() } else { 0 };
+                new_object_with_native_sized(rt.cx(), &class, buffer, size).unwrap();
+                unsafe { JS_MaybeGC(rt.cx()) };
+            }
+            unsafe { JS_GetGCParameter(rt.rt(), JSGC_NUMBER) - before }
+        })
+    }
+
+    #[test]
+    fn declared_external_memory_triggers_gc() {
+        let undeclared = collections_while_attaching(false);
+        let declared = collections_while_attaching(true);
+        assert!(declared > undeclared, "{} collections with declared sizes, {} without",
+                declared, undeclared);
+    }
+
+    #[test]
+    fn native_type_is_checked() {
+        let counted = NativeClass::<u32>::new("U32");
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let obj = new_object_with_native(rt.cx(), &counted, 7u32).unwrap();
+            unsafe {
+                assert_eq!(native_from_object::<u32>(obj), Some(&7));
+                assert_eq!(native_from_object::<i32>(obj), None);
+                let plain = evaluate(&rt, global, "({})").unwrap().to_object();
+                assert_eq!(native_from_object::<u32>(plain), None);
+            }
+        });
+    }
+
+    #[test]
+    fn private_boxed_round_trip() {
+        let class = ClassBuilder::new("Private").flags(ClassFlags::HAS_PRIVATE).build();
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || unsafe {
+            let obj = JS_NewObject(rt.cx(), class, ptr::null(), ptr::null());
+            assert!(borrow_private::<String>(obj).is_none());
+            set_private_boxed(obj, Box::new("hello".to_string())).unwrap();
+            assert_eq!(borrow_private::<String>(obj).map(|s| &**s), Some("hello"));
+            // The slot is taken until the value is removed again.
+            assert!(set_private_boxed(obj, Box::new(1u8)).is_err());
+
+            // Reading with the wrong type fails cleanly.
+            assert!(borrow_private::<Vec<u8>>(obj).is_none());
+            assert!(take_private::<u8>(obj).is_none());
+
+            assert_eq!(take_private::<String>(obj).map(|s| *s), Some("hello".to_string()));
+            assert!(borrow_private::<String>(obj).is_none());
+        });
+    }
+
+    #[test]
+    fn private_boxed_requires_private_class() {
+        let class = ClassBuilder::new("NoPrivate").build();
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || unsafe {
+            let obj = JS_NewObject(rt.cx(), class, ptr::null(), ptr::null());
+            assert!(set_private_boxed(obj, Box::new(5u32)).is_err());
+            assert!(borrow_private::<u32>(obj).is_none());
+        });
+    }
+}
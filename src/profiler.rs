@@ -0,0 +1,126 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A simple function profiler built on `Runtime::set_call_hook`.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use hooks::{CallEventKind, FunctionCallEvent};
+use rust::Runtime;
+
+/// What the profiler recorded for one function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    /// Time spent in the function, including the functions it called.
+    pub inclusive: Duration,
+    /// Time spent in the function itself.
+    pub exclusive: Duration,
+}
+
+struct Frame {
+    name: String,
+    entered: Instant,
+    /// Inclusive time of the calls made from this frame.
+    children: Duration,
+}
+
+/// Aggregates call events into per-function statistics.
+///
+/// Functions are keyed by name; anonymous ones by `<anonymous>@file:line`.
+/// Recursive calls count their time once per active frame.
+pub struct Profiler {
+    stats: HashMap<String, CallStats>,
+    stack: Vec<Frame>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            stats: HashMap::new(),
+            stack: vec!(),
+        }
+    }
+
+    /// Creates a profiler and installs it as `rt`'s call hook.
+    pub fn attach(rt: &Runtime) -> Rc<RefCell<Profiler>> {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let hook = profiler.clone();
+        rt.set_call_hook(Box::new(move |event| hook.borrow_mut().record(&event)));
+        profiler
+    }
+
+    pub fn record(&mut self, event: &FunctionCallEvent) {
+        let now = Instant::now();
+        match event.kind {
+            CallEventKind::Enter => {
+                let name = match event.name {
+                    Some(ref name) => name.clone(),
+                    None => format!("<anonymous>@{}:{}",
+                                    event.filename.as_ref().map_or("", |s| &**s), event.line),
+                };
+                self.stats.entry(name.clone()).or_insert(CallStats::default()).calls += 1;
+                self.stack.push(Frame {
+                    name: name,
+                    entered: now,
+                    children: Duration::new(0, 0),
+                });
+            }
+            CallEventKind::Leave => {
+                let frame = match self.stack.pop() {
+                    Some(frame) => frame,
+                    // Entered before the profiler was attached.
+                    None => return,
+                };
+                let elapsed = now.duration_since(frame.entered);
+                if let Some(parent) = self.stack.last_mut() {
+                    parent.children = parent.children + elapsed;
+                }
+                let stats = self.stats.entry(frame.name).or_insert(CallStats::default());
+                stats.inclusive = stats.inclusive + elapsed;
+                if elapsed > frame.children {
+                    stats.exclusive = stats.exclusive + (elapsed - frame.children);
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> &HashMap<String, CallStats> {
+        &self.stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Profiler;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+
+    #[test]
+    fn nested_calls_are_counted() {
+        let rt = Runtime::new();
+        let profiler = Profiler::attach(&rt);
+        // Globals created after the hook is installed run in debug mode.
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            evaluate(&rt, global, "function leaf() { return 1; } \
+                                   function middle() { return leaf() + leaf(); } \
+                                   function outer() { var t = 0; \
+                                                      for (var i = 0; i < 3; i++) t += middle(); \
+                                                      return t; } \
+                                   outer();").unwrap();
+        });
+        rt.clear_call_hook();
+
+        let profiler = profiler.borrow();
+        let stats = profiler.stats();
+        assert_eq!(stats["outer"].calls, 1);
+        assert_eq!(stats["middle"].calls, 3);
+        assert_eq!(stats["leaf"].calls, 6);
+        assert!(stats["outer"].inclusive >= stats["middle"].inclusive);
+        assert!(stats["outer"].inclusive >= stats["outer"].exclusive);
+    }
+}
@@ -0,0 +1,164 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Creating and running RegExp objects from Rust.
+//!
+//! Positions are in UTF-16 code units, which is how JS indexes strings:
+//! in `"é1"` matched against `/\d/` the match is at `(1, 2)`, not at the
+//! UTF-8 byte offset 2. Convert before slicing a Rust `&str` with them.
+
+use libc::{c_char, c_uint, size_t};
+use jsapi::{JSContext, JSObject, JS_NewUCRegExpObjectNoStatics, JS_ObjectIsRegExp};
+use jsapi::{JS_ExecuteRegExpNoStatics, JS_GetArrayLength, JS_GetElement, JS_GetProperty};
+use jsapi::JS_GetStringLength;
+use jsval::{JSVal, UndefinedValue};
+use conversions::jsstring_to_string;
+use error::JSError;
+use utf16::to_utf16;
+use {ERR, JSREG_FOLD, JSREG_GLOB, JSREG_MULTILINE, JSREG_STICKY};
+
+/// The flags a RegExp is created with, as in `/.../gim`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegExpFlags {
+    /// `i`
+    pub ignore_case: bool,
+    /// `g`
+    pub global: bool,
+    /// `m`
+    pub multiline: bool,
+    /// `y`
+    pub sticky: bool,
+}
+
+impl RegExpFlags {
+    fn bits(&self) -> c_uint {
+        let mut bits = 0;
+        if self.ignore_case { bits |= JSREG_FOLD; }
+        if self.global { bits |= JSREG_GLOB; }
+        if self.multiline { bits |= JSREG_MULTILINE; }
+        if self.sticky { bits |= JSREG_STICKY; }
+        bits
+    }
+}
+
+/// A successful match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MatchResult {
+    /// The whole match, as a `(start, end)` pair of UTF-16 code unit indices
+    /// into the input, end exclusive.
+    pub range: (usize, usize),
+    /// The text each capture group matched, `None` for groups that didn't
+    /// take part in the match. The engine reports no positions for groups,
+    /// only for the whole match.
+    pub captures: Vec<Option<String>>,
+}
+
+/// Compiles `pattern`, failing with the engine's SyntaxError if it's invalid.
+/// The object isn't rooted.
+pub fn new_regexp(cx: *mut JSContext, pattern: &str, flags: RegExpFlags) -> Result<*mut JSObject, JSError> {
+    let mut pattern = to_utf16(pattern);
+    unsafe {
+        let re = JS_NewUCRegExpObjectNoStatics(cx, pattern.as_mut_ptr(), pattern.len() as size_t,
+                                               flags.bits());
+        if re.is_null() {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        Ok(re)
+    }
+}
+
+/// Whether `obj` is a RegExp, e.g. to validate a value from script before
+/// passing it to `regexp_exec`.
+pub fn is_regexp_object(cx: *mut JSContext, obj: *mut JSObject) -> bool {
+    unsafe { JS_ObjectIsRegExp(cx, obj) != 0 }
+}
+
+/// Runs `re` against `input` from the start, like `re.exec(input)` but
+/// without reading or updating `lastIndex` or the `RegExp` statics.
+/// Returns `Ok(None)` if there's no match.
+pub fn regexp_exec(cx: *mut JSContext, re: *mut JSObject, input: &str) -> Result<Option<MatchResult>, JSError> {
+    if !is_regexp_object(cx, re) {
+        return Err(JSError::new("regexp_exec called on an object that isn't a RegExp"));
+    }
+    let mut input = to_utf16(input);
+    let mut index: size_t = 0;
+    let mut rval = UndefinedValue();
+    unsafe {
+        if JS_ExecuteRegExpNoStatics(cx, re, input.as_mut_ptr(), input.len() as size_t,
+                                     &mut index, 0, &mut rval) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        if rval.is_null() {
+            return Ok(None);
+        }
+        let array = rval.to_object();
+        let mut length = 0;
+        let mut start = UndefinedValue();
+        if JS_GetArrayLength(cx, array, &mut length) == ERR ||
+           JS_GetProperty(cx, array, b"index\0".as_ptr() as *const c_char, &mut start) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let start = start.to_number() as usize;
+        let mut element = UndefinedValue();
+        let mut get = |i: u32| -> Result<JSVal, JSError> {
+            if JS_GetElement(cx, array, i, &mut element) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            Ok(element)
+        };
+        let whole = try!(get(0));
+        let end = start + JS_GetStringLength(whole.to_string()) as usize;
+        let mut captures = Vec::with_capacity(length.saturating_sub(1) as usize);
+        for i in 1..length {
+            let capture = try!(get(i));
+            captures.push(if capture.is_undefined() {
+                None
+            } else {
+                Some(try!(jsstring_to_string(cx, capture.to_string())))
+            });
+        }
+        Ok(Some(MatchResult { range: (start, end), captures: captures }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use testing::with_test_runtime;
+    use super::{RegExpFlags, MatchResult, new_regexp, regexp_exec, is_regexp_object};
+
+    #[test]
+    fn optional_groups() {
+        with_test_runtime(|rt, _| {
+            let cx = rt.cx();
+            let re = new_regexp(cx, r"(\d+)-(\d+)(?:-(\d+))?", RegExpFlags::default()).unwrap();
+            assert!(is_regexp_object(cx, re));
+
+            // "é" is one UTF-16 code unit, two UTF-8 bytes.
+            assert_eq!(regexp_exec(cx, re, "é 10-20!").unwrap(), Some(MatchResult {
+                range: (2, 7),
+                captures: vec!(Some("10".to_string()), Some("20".to_string()), None),
+            }));
+            assert_eq!(regexp_exec(cx, re, "1-1-1").unwrap(), Some(MatchResult {
+                range: (0, 5),
+                captures: vec!(Some("1".to_string()), Some("1".to_string()), Some("1".to_string())),
+            }));
+            assert_eq!(regexp_exec(cx, re, "no digits").unwrap(), None);
+        });
+    }
+
+    #[test]
+    fn flags_and_validation() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let flags = RegExpFlags { ignore_case: true, .. RegExpFlags::default() };
+            let re = new_regexp(cx, "abc", flags).unwrap();
+            assert_eq!(regexp_exec(cx, re, "xABC").unwrap().unwrap().range, (1, 4));
+
+            assert!(new_regexp(cx, "(", RegExpFlags::default()).is_err());
+            let not_regexp = assert_evaluates!(rt, global, "({})").to_object();
+            assert!(!is_regexp_object(cx, not_regexp));
+            assert!(regexp_exec(cx, not_regexp, "abc").is_err());
+        });
+    }
+}
@@ -0,0 +1,114 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Debug-build checks for GC pointers held unrooted.
+//!
+//! A raw `JSVal`, object or string pointer kept somewhere the conservative
+//! stack scanner doesn't look (the heap, a `Vec`, a struct behind a `Box`)
+//! dangles as soon as a GC runs. Code that holds such a value across calls
+//! that shouldn't collect can say so with an `UnrootedGuard` or
+//! `assert_no_gc!`; in debug builds, or with the `debugmozjs` feature, they
+//! panic when a GC did in fact happen on the current thread in the
+//! meantime, and otherwise they cost nothing. The `debugmozjs` feature also
+//! turns on GC zeal, which makes such GCs likely.
+
+#[cfg(any(debug_assertions, feature = "debugmozjs"))]
+use std::cell::Cell;
+
+#[cfg(any(debug_assertions, feature = "debugmozjs"))]
+thread_local!(static GC_EPOCH: Cell<u64> = Cell::new(0));
+
+/// Called by the runtime's GC callback when a collection starts.
+pub fn note_gc() {
+    if cfg!(any(debug_assertions, feature = "debugmozjs")) {
+        bump_epoch();
+    }
+}
+
+#[cfg(any(debug_assertions, feature = "debugmozjs"))]
+fn bump_epoch() {
+    GC_EPOCH.with(|epoch| epoch.set(epoch.get() + 1));
+}
+
+#[cfg(not(any(debug_assertions, feature = "debugmozjs")))]
+fn bump_epoch() {}
+
+#[cfg(any(debug_assertions, feature = "debugmozjs"))]
+fn current_epoch() -> u64 {
+    GC_EPOCH.with(|epoch| epoch.get())
+}
+
+#[cfg(not(any(debug_assertions, feature = "debugmozjs")))]
+fn current_epoch() -> u64 {
+    0
+}
+
+/// Asserts that no GC happens on this thread between its creation and each
+/// call to `check`.
+pub struct UnrootedGuard {
+    epoch: u64,
+    file: &'static str,
+    line: u32,
+}
+
+impl UnrootedGuard {
+    /// `file` and `line` identify the guarded code in the panic message;
+    /// `assert_no_gc!` fills them in.
+    pub fn new(file: &'static str, line: u32) -> UnrootedGuard {
+        UnrootedGuard {
+            epoch: current_epoch(),
+            file: file,
+            line: line,
+        }
+    }
+
+    /// Panics, in debug builds, if a GC has run since the guard was created.
+    pub fn check(&self) {
+        if cfg!(any(debug_assertions, feature = "debugmozjs")) && current_epoch() != self.epoch {
+            panic!("GC happened while unrooted values were held (guard created at {}:{})",
+                   self.file, self.line);
+        }
+    }
+}
+
+/// Evaluates `$body` and, in debug builds, panics if it triggered a GC.
+/// For code that keeps unrooted GC pointers across the calls it makes:
+///
+/// ```ignore
+/// let chars = assert_no_gc!(copy_chars(cx, s));
+/// ```
+#[macro_export]
+macro_rules! assert_no_gc {
+    ($body:expr) => {{
+        let guard = $crate::rooting::UnrootedGuard::new(file!(), line!());
+        let result = $body;
+        guard.check();
+        result
+    }}
+}
+
+#[cfg(test)]
+mod test {
+    use jsapi::JS_GC;
+    use rust::Runtime;
+    use super::UnrootedGuard;
+
+    #[test]
+    fn no_gc_passes() {
+        let rt = Runtime::new();
+        let guard = UnrootedGuard::new(file!(), line!());
+        let sum = assert_no_gc!(1 + 1);
+        assert_eq!(sum, 2);
+        guard.check();
+        drop(rt);
+    }
+
+    #[test]
+    #[cfg(any(debug_assertions, feature = "debugmozjs"))]
+    #[should_panic(expected = "GC happened while unrooted values were held")]
+    fn gc_while_guarded_panics() {
+        let rt = Runtime::new();
+        assert_no_gc!(unsafe { JS_GC(rt.rt()) });
+    }
+}
@@ -5,36 +5,950 @@
 //! Rust wrappers around the raw JS apis
 
 use libc::types::os::arch::c95::{size_t, c_uint};
-use libc::c_char;
+use libc::{c_char, c_void, fclose, fflush, fread, fseek, tmpfile, SEEK_SET};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::error::Error;
 use std::ffi;
-use std::str;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::ops::BitOr;
+use std::ptr;
+use std::rc::{Rc, Weak};
+use std::slice;
+use std::sync::{Arc, Condvar, Mutex, Once, ONCE_INIT};
+use std::sync::atomic::{AtomicBool, AtomicUsize, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::u32;
 use jsapi::*;
 use jsapi::JSVersion::JSVERSION_LATEST;
-use jsval::{JSVal, NullValue};
+use jsval::{JSVal, UndefinedValue};
+use conversions::FromJSValConvertible;
+use error::{JSError, ErrorKind, report_stale_exception, catch_panic, panic_message, check_same_runtime};
+use error::{error_kind_for_report, ExceptionRoots};
+use global::{GlobalOptions, StandardClasses, new_global_object};
+use global_template::GlobalTemplate;
+use glue::{ShrinkingGC, IncrementalGCSlice, IsIncrementalGCInProgress, DumpHeap};
+use glue::{FinishIncrementalGC, IsIncrementalGCEnabled, CompartmentGC};
+use hooks::{CallHook, FunctionCallEvent, call_hook};
+use hooks::{CoverageCollector, coverage_new_script, coverage_destroy_script, coverage_step};
+use jsdbgapi::{JS_SetCallHook, JS_SetRuntimeDebugMode, JS_SetInterrupt, JS_ClearInterrupt};
+use jsdbgapi::{JS_SetNewScriptHook, JS_SetDestroyScriptHook};
+use loader::{ModuleLoader, ModuleRegistry, define_require};
+use locale::{LocaleProvider, LocaleCallbacks};
+use memory::{MemoryReport, collect_memory_report};
+use object::{RootedObject, clone_value_into};
+use pointers::{ContextPtr, ObjectPtr, AsContextPtr, AsObjectPtr};
+use principals::{Principals, destroy_principals};
+use rooting;
+use script::{CompiledScript, find_source_map_url};
+use script_cache::{ScriptCache, ScriptCacheStats};
+use stack::SavedFrameChain;
+use utf16::to_utf16_into;
+use weak_map::Sweep;
 use default_stacksize;
 use default_heapsize;
-use JSOPTION_VAROBJFIX;
-use JSOPTION_METHODJIT;
-use JSOPTION_TYPE_INFERENCE;
-use JSOPTION_DONT_REPORT_UNCAUGHT;
-use JSOPTION_AUTOJSAPI_OWNS_ERROR_REPORTING;
+use {JSOPTION_STRICT, JSOPTION_WERROR, JSOPTION_VAROBJFIX};
+use {JSOPTION_DONT_REPORT_UNCAUGHT, JSOPTION_AUTOJSAPI_OWNS_ERROR_REPORTING, JSOPTION_COMPILE_N_GO};
+use {JSOPTION_METHODJIT, JSOPTION_TYPE_INFERENCE};
 use ERR;
 
 // ___________________________________________________________________________
 // friendly Rustic API to runtimes
 
 /// A wrapper for the `JSRuntime` and `JSContext` structures in SpiderMonkey.
+///
+/// Any number of runtimes may exist at once, for instance one per worker
+/// thread. Each is independent: at this API level `JS_Init` takes no parent
+/// runtime and runtimes share nothing but the engine's process-wide state,
+/// which `init_engine` sets up race-free. A runtime is bound to the thread
+/// that created it, which the raw pointers enforce by keeping `Runtime`
+/// neither `Send` nor `Sync`; all of its contexts and objects must be used
+/// from that thread only.
+///
+/// A `Runtime` is the engine's only owner and is deliberately not `Clone`:
+/// dropping it destroys the context and runtime. The callbacks it installs
+/// hold no reference to it; they find its state through
+/// `JS_GetRuntimePrivate`, so nothing they keep can hold the runtime alive.
+///
+/// The runtime takes over these engine hooks, and setting them through the
+/// raw API breaks the crate: the runtime private, the GC and finalize
+/// callbacks, the destroy-principals callback, and the context's error
+/// reporter and operation callback. An embedder's own GC and finalize
+/// callbacks go through `set_gc_callback` and `set_finalize_callback`
+/// instead, and run after the crate's.
 pub struct Runtime {
     rt: *mut JSRuntime,
     cx: *mut JSContext,
+    state: Box<RuntimeState>,
+    script_cache: RefCell<Option<ScriptCache>>,
+    locale: RefCell<Option<Box<LocaleCallbacks>>>,
+    watchdog: RefCell<Option<(Arc<Watchdog>, thread::JoinHandle<()>)>>,
+    /// Boxed so the address handed to the engine stays put.
+    call_hook: RefCell<Option<Box<Rc<CallHook>>>>,
+    coverage: RefCell<Option<Box<CoverageCollector>>>,
+    modules: Rc<ModuleRegistry>,
+    /// How many limited evaluations are on the stack.
+    evaluation_depth: Cell<u32>,
+    /// Reused by `evaluate` and `compile_script` for the UTF-16 source and
+    /// the C filename.
+    scratch_chars: RefCell<Vec<u16>>,
+    scratch_filename: RefCell<Vec<u8>>,
+    /// The creating thread, as returned by `current_thread`.
+    thread: usize,
+    /// Boxed so the roots' addresses stay put.
+    default_global: Option<Box<*mut JSObject>>,
+    named_globals: HashMap<String, Box<*mut JSObject>>,
+}
+
+/// Restores `Runtime::evaluation_depth` when dropped, and stops the
+/// watchdog's clock once the outermost evaluation is over, even if the
+/// evaluation panicked.
+struct EvaluationDepth<'a> {
+    rt: &'a Runtime,
+    depth: u32,
+}
+
+impl<'a> Drop for EvaluationDepth<'a> {
+    fn drop(&mut self) {
+        self.rt.evaluation_depth.set(self.depth);
+        if self.depth == 0 {
+            if let Some((ref watchdog, _)) = *self.rt.watchdog.borrow() {
+                watchdog.state.lock().unwrap().started = None;
+            }
+        }
+    }
+}
+
+/// Restores a context's options when dropped.
+struct SavedOptions {
+    cx: *mut JSContext,
+    options: u32,
+}
+
+impl Drop for SavedOptions {
+    fn drop(&mut self) {
+        unsafe { JS_SetOptions(self.cx, self.options) };
+    }
+}
+
+/// A request on a context, begun by `new` and ended when dropped, so that
+/// it ends even if the code run inside it panics.
+struct Request {
+    cx: *mut JSContext,
+}
+
+impl Request {
+    fn new(cx: *mut JSContext) -> Request {
+        unsafe { JS_BeginRequest(cx) };
+        Request { cx: cx }
+    }
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        unsafe { JS_EndRequest(self.cx) };
+    }
+}
+
+/// Rust-side state of a runtime, reachable from engine callbacks through
+/// `JS_GetRuntimePrivate`.
+struct RuntimeState {
+    /// Whether a collection is under way, as seen by `gc_callback`.
+    in_gc: Cell<bool>,
+    /// `JSGC_BYTES` when the last collection finished.
+    bytes_after_gc: Cell<u32>,
+    /// Shared with the runtime's `CancellationToken`s.
+    interrupt: Arc<Interrupt>,
+    /// Swept by `finalize_callback`; entries are dropped once dead.
+    weak_references: RefCell<Vec<Weak<Sweep>>>,
+    /// The last error `reportError` saw since the outermost evaluation
+    /// started, for evaluations whose exception it consumed.
+    reported_error: RefCell<Option<JSError>>,
+    /// See `Runtime::set_max_conversion_length`.
+    max_conversion_length: Cell<usize>,
+    /// See `SourceMaps`.
+    source_maps: RefCell<SourceMaps>,
+    /// See `Runtime::set_oom_callback`.
+    oom_callback: RefCell<Option<Box<FnMut()>>>,
+    /// Each live `ErrorReporterGuard`, innermost last.
+    error_captures: RefCell<Vec<ErrorCapture>>,
+    /// What script run by `Runtime::evaluate_untrusted` has left.
+    fuel: Cell<Option<Fuel>>,
+    /// The `JSGC_MODE` `begin_incremental_gc` replaced, restored by
+    /// `gc_callback` once the incremental collection is over.
+    saved_gc_mode: Cell<Option<u32>>,
+    /// The embedder's callbacks; see `Runtime::set_gc_callback`.
+    gc_callback: Cell<JSGCCallback>,
+    finalize_callback: Cell<JSFinalizeCallback>,
+    /// The roots of the runtime's `JSError`s' exceptions.
+    exception_roots: Arc<ExceptionRoots>,
+}
+
+/// Returns the state of the `Runtime` wrapping `rt`.
+///
+/// `rt` must belong to a live `Runtime`.
+unsafe fn runtime_state<'a>(rt: *mut JSRuntime) -> &'a RuntimeState {
+    let state = JS_GetRuntimePrivate(rt) as *const RuntimeState;
+    assert!(!state.is_null());
+    &*state
+}
+
+/// The cross-thread half of a runtime's cancellation support.
+struct Interrupt {
+    cancelled: AtomicBool,
+    /// Set by the watchdog or `operation_callback`, cleared when the next
+    /// evaluation starts.
+    limit_exceeded: Mutex<Option<LimitExceeded>>,
+    /// `JSGC_BYTES` as last sampled on the runtime's thread, by
+    /// `operation_callback` or `gc_callback`, for the watchdog to read.
+    heap_bytes: AtomicUsize,
+    /// Null once the runtime is gone, so late tokens don't touch it.
+    rt: Mutex<*mut JSRuntime>,
+}
+
+unsafe impl Send for Interrupt {}
+unsafe impl Sync for Interrupt {}
+
+/// A handle that stops script running on a `Runtime`, from any thread.
+///
+/// Cancelling is sticky: once `cancel` has been called every evaluation on
+/// the runtime is aborted, including ones that start later, until `reset`
+/// is called. Script stops at the next point where the engine checks for
+/// interrupts (loop iterations and function calls) and the evaluation fails
+/// with an uncatchable error.
+#[derive(Clone)]
+pub struct CancellationToken {
+    interrupt: Arc<Interrupt>,
+}
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.interrupt.cancelled.store(true, Ordering::SeqCst);
+        let rt = self.interrupt.rt.lock().unwrap();
+        if !rt.is_null() {
+            unsafe { JS_TriggerOperationCallback(*rt) };
+        }
+    }
+
+    /// Lets script run again.
+    pub fn reset(&self) {
+        self.interrupt.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.interrupt.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// How `Runtime::with_options` sets up a runtime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuntimeOptions {
+    /// Whether exceptions that escape an evaluation are passed to the error
+    /// reporter; see `Runtime::set_report_uncaught`. Defaults to false.
+    pub report_uncaught: bool,
+    /// How much of its thread's native stack the engine may use before
+    /// failing with "too much recursion", rather than overflowing the stack
+    /// on deeply nested source or recursion through natives. None, the
+    /// default, leaves the engine unlimited; `NATIVE_STACK_QUOTA` suits
+    /// threads Rust spawns.
+    pub native_stack_quota: Option<usize>,
+}
+
+impl Default for RuntimeOptions {
+    fn default() -> RuntimeOptions {
+        RuntimeOptions {
+            report_uncaught: false,
+            native_stack_quota: None,
+        }
+    }
+}
+
+/// Resource limits enforced on every evaluation; see `Runtime::set_limits`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// The longest an evaluation may run.
+    pub max_duration: Option<Duration>,
+    /// The largest the GC heap (`Runtime::gc_bytes`) may grow.
+    pub max_heap_bytes: Option<usize>,
+}
+
+impl Limits {
+    /// Limits for script from untrusted sources: a second and the heap
+    /// `Runtime::evaluate_untrusted` allows, enough for anything but
+    /// hostile input.
+    pub fn untrusted() -> Limits {
+        Limits {
+            max_duration: Some(Duration::from_secs(1)),
+            max_heap_bytes: Some(UNTRUSTED_MAX_HEAP_BYTES),
+        }
+    }
+}
+
+/// Evaluates a snippet of JS embedded in Rust with `Runtime::evaluate`,
+/// naming the Rust file and line as its location, so that errors point at
+/// the code that embedded it. The filename, and the line, can be given
+/// instead:
+///
+/// ```ignore
+/// let v = try!(evaluate_script!(rt, global, "1 + 1"));
+/// let v = try!(evaluate_script!(rt, global, source, "config.js"));
+/// let v = try!(evaluate_script!(rt, global, source, "config.js", 10));
+/// ```
+#[macro_export]
+macro_rules! evaluate_script {
+    ($rt:expr, $global:expr, $source:expr) => {
+        evaluate_script!($rt, $global, $source, file!(), line!())
+    };
+    ($rt:expr, $global:expr, $source:expr, $filename:expr) => {
+        evaluate_script!($rt, $global, $source, $filename, 1)
+    };
+    ($rt:expr, $global:expr, $source:expr, $filename:expr, $line:expr) => {
+        $rt.evaluate($global, $source, &$crate::rust::EvaluateOptions::new().filename($filename).line($line))
+    };
+}
+
+/// A context's options (`JSOPTION_*`). Bits without a name here are kept
+/// as they are.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct ContextFlags(u32);
+
+impl ContextFlags {
+    pub const EMPTY: ContextFlags = ContextFlags(0);
+    pub const STRICT: ContextFlags = ContextFlags(JSOPTION_STRICT);
+    pub const WERROR: ContextFlags = ContextFlags(JSOPTION_WERROR);
+    pub const VAROBJFIX: ContextFlags = ContextFlags(JSOPTION_VAROBJFIX);
+    pub const DONT_REPORT_UNCAUGHT: ContextFlags = ContextFlags(JSOPTION_DONT_REPORT_UNCAUGHT);
+    pub const AUTOJSAPI_OWNS_ERROR_REPORTING: ContextFlags =
+        ContextFlags(JSOPTION_AUTOJSAPI_OWNS_ERROR_REPORTING);
+    pub const COMPILE_N_GO: ContextFlags = ContextFlags(JSOPTION_COMPILE_N_GO);
+    pub const METHODJIT: ContextFlags = ContextFlags(JSOPTION_METHODJIT);
+    pub const TYPE_INFERENCE: ContextFlags = ContextFlags(JSOPTION_TYPE_INFERENCE);
+
+    pub fn from_bits(bits: u32) -> ContextFlags {
+        ContextFlags(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether every flag in `other` is set here.
+    pub fn contains(self, other: ContextFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: ContextFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: ContextFlags) {
+        self.0 &= !other.0;
+    }
+
+    /// Inserts or removes `other`.
+    pub fn set(&mut self, other: ContextFlags, enable: bool) {
+        if enable { self.insert(other) } else { self.remove(other) }
+    }
+}
+
+impl BitOr for ContextFlags {
+    type Output = ContextFlags;
+
+    fn bitor(self, other: ContextFlags) -> ContextFlags {
+        ContextFlags(self.0 | other.0)
+    }
+}
+
+/// How `Runtime::evaluate` compiles and runs a script. Anything left unset
+/// behaves as it always has for `evaluate_script`.
+///
+/// There is no option to compile without keeping the source. The engine
+/// this crate binds holds on to every script's source for
+/// `Function.prototype.toString` and error reports, and has no source hook
+/// to load it back on demand, so a `Runtime::set_source_hook` can't be
+/// offered until the bindings move to an engine that has one.
+#[derive(Clone, Debug)]
+pub struct EvaluateOptions {
+    filename: Option<String>,
+    line: u32,
+    version: Option<JSVersion>,
+    utf8: bool,
+    scope: Option<*mut JSObject>,
+    principals: *mut JSPrincipals,
+    no_rval: bool,
+    compile_and_go: bool,
+    save_frame_chain: bool,
+}
+
+impl EvaluateOptions {
+    pub fn new() -> EvaluateOptions {
+        EvaluateOptions {
+            filename: None,
+            line: 1,
+            version: None,
+            utf8: false,
+            scope: None,
+            principals: ptr::null_mut(),
+            no_rval: false,
+            compile_and_go: true,
+            save_frame_chain: false,
+        }
+    }
+
+    /// The file name error reports and stacks show. None by default.
+    pub fn filename(mut self, filename: &str) -> EvaluateOptions {
+        self.filename = Some(filename.to_string());
+        self
+    }
+
+    /// The line number of the source's first line. Defaults to 1.
+    pub fn line(mut self, line: u32) -> EvaluateOptions {
+        self.line = line;
+        self
+    }
+
+    /// The language version to parse with, instead of the context's.
+    pub fn version(mut self, version: JSVersion) -> EvaluateOptions {
+        self.version = Some(version);
+        self
+    }
+
+    /// Hands the source to the engine as bytes instead of converting it to
+    /// UTF-16. The engine only decodes them as UTF-8 after
+    /// `JS_SetCStringsAreUTF8`; otherwise this is for ASCII sources only.
+    pub fn utf8(mut self, utf8: bool) -> EvaluateOptions {
+        self.utf8 = utf8;
+        self
+    }
+
+    /// Evaluates against `scope` instead of the global. It has to be in the
+    /// global's compartment.
+    pub fn scope(mut self, scope: *mut JSObject) -> EvaluateOptions {
+        self.scope = Some(scope);
+        self
+    }
+
+    /// Sets the JS stack aside while `evaluate` runs the script, so that a
+    /// native evaluating script on behalf of its caller doesn't leak the
+    /// caller's frames into the script's error stacks; see
+    /// `stack::SavedFrameChain`. Off by default.
+    pub fn save_frame_chain(mut self, save_frame_chain: bool) -> EvaluateOptions {
+        self.save_frame_chain = save_frame_chain;
+        self
+    }
+
+    /// The principals the script runs with. None by default. They must
+    /// come from `Principals::raw`, and outlive the call this is passed to,
+    /// so this is only for the `_for_principals` methods, which ensure both.
+    fn principals(mut self, principals: *mut JSPrincipals) -> EvaluateOptions {
+        self.principals = principals;
+        self
+    }
+
+    /// Skips computing the completion value, which `evaluate` then returns
+    /// as `undefined`.
+    pub fn no_rval(mut self, no_rval: bool) -> EvaluateOptions {
+        self.no_rval = no_rval;
+        self
+    }
+
+    /// Whether the script is compiled for the one global it is evaluated or
+    /// compiled against, which makes it faster. On by default; a script
+    /// from `Runtime::compile_script` that should run against other globals
+    /// too needs it off.
+    pub fn compile_and_go(mut self, compile_and_go: bool) -> EvaluateOptions {
+        self.compile_and_go = compile_and_go;
+        self
+    }
+
+    fn filename_str(&self) -> Option<&str> {
+        self.filename.as_ref().map(|s| &**s)
+    }
+}
+
+/// Writes `filename` into `buf` as a C string and points at it, or returns
+/// null if there is none.
+fn c_filename(filename: Option<&str>, buf: &mut Vec<u8>) -> Result<*const c_char, JSError> {
+    match filename {
+        Some(filename) => {
+            if filename.as_bytes().contains(&0) {
+                return Err(JSError::new(format!("invalid filename {:?}", filename)));
+            }
+            buf.extend(filename.as_bytes().iter().cloned());
+            buf.push(0);
+            Ok(buf.as_ptr() as *const c_char)
+        }
+        None => Ok(ptr::null()),
+    }
+}
+
+impl Default for EvaluateOptions {
+    fn default() -> EvaluateOptions {
+        EvaluateOptions::new()
+    }
+}
+
+/// What `Runtime::evaluate_batch` does when a script fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Skip the scripts after the failing one.
+    StopOnError,
+    /// Run every script regardless.
+    ContinueOnError,
+}
+
+/// How far a collection driven by `Runtime::begin_incremental_gc` or
+/// `Runtime::incremental_gc_slice` got.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IncrementalGCProgress {
+    /// The collection needs more slices.
+    InProgress,
+    /// The collection is complete.
+    Finished,
+    /// Incremental GC isn't compiled into the engine, or it was turned off,
+    /// so a full, non-incremental collection ran instead.
+    NotIncremental,
+}
+
+/// Which of the `Limits` stopped an evaluation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Time,
+    Heap,
+    /// Script run by `Runtime::evaluate_untrusted` used up its fuel.
+    Fuel,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitExceeded::Time => f.write_str("script exceeded its time limit"),
+            LimitExceeded::Heap => f.write_str("script exceeded its heap limit"),
+            LimitExceeded::Fuel => f.write_str("script ran out of fuel"),
+        }
+    }
+}
+
+/// How often the watchdog samples the clock and the heap size.
+const WATCHDOG_INTERVAL_MS: u64 = 5;
+
+/// How many interrupt checks script run by `Runtime::evaluate_untrusted`
+/// may reach.
+const UNTRUSTED_FUEL: u64 = 1000000;
+
+/// The largest the GC heap may grow while `Runtime::evaluate_untrusted`
+/// runs script.
+const UNTRUSTED_MAX_HEAP_BYTES: usize = 64 << 20;
+
+/// A budget counted down by `operation_callback`, which keeps itself
+/// triggered while one is set so that the engine calls it at every
+/// interrupt check: each loop iteration. Unlike the watchdog's limits it
+/// stops a given script at the same point every time.
+#[derive(Clone, Copy)]
+struct Fuel {
+    remaining: u64,
+    max_heap_bytes: usize,
+}
+
+/// A `RuntimeOptions::native_stack_quota` well under the 2 MiB Rust gives
+/// spawned threads.
+pub const NATIVE_STACK_QUOTA: usize = 512 * 1024;
+
+/// The default of `Runtime::set_max_conversion_length`: the longest
+/// possible JS array.
+pub const DEFAULT_MAX_CONVERSION_LENGTH: usize = u32::MAX as usize;
+
+/// How many files `SourceMaps` remembers the source map URL of.
+const MAX_SOURCE_MAPS: usize = 256;
+
+/// The `sourceMappingURL`s of the files evaluated without a
+/// `CompiledScript` to hold them, by filename, so that errors raised later
+/// by code they defined can report them. Only the `MAX_SOURCE_MAPS` most
+/// recently recorded are kept.
+struct SourceMaps {
+    urls: HashMap<String, String>,
+    /// The keys of `urls`, oldest first.
+    order: VecDeque<String>,
+}
+
+impl SourceMaps {
+    fn new() -> SourceMaps {
+        SourceMaps {
+            urls: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn get(&self, filename: &str) -> Option<&String> {
+        self.urls.get(filename)
+    }
+
+    /// Records `url` for `filename`, or forgets it with `None`.
+    fn set(&mut self, filename: &str, url: Option<String>) {
+        if self.urls.remove(filename).is_some() {
+            self.order.retain(|recorded| recorded != filename);
+        }
+        if let Some(url) = url {
+            if self.order.len() == MAX_SOURCE_MAPS {
+                let oldest = self.order.pop_front().unwrap();
+                self.urls.remove(&oldest);
+            }
+            self.urls.insert(filename.to_string(), url);
+            self.order.push_back(filename.to_string());
+        }
+    }
+}
+
+/// Scratch buffers that have grown past this many elements are freed after
+/// use rather than kept, so one huge script doesn't pin its memory.
+const MAX_SCRATCH_CAPACITY: usize = 256 * 1024;
+
+/// Takes the buffer out of `cell`, empty but with its capacity. A nested
+/// evaluation finds the cell empty and allocates its own.
+fn take_scratch<T>(cell: &RefCell<Vec<T>>) -> Vec<T> {
+    let mut buf = mem::replace(&mut *cell.borrow_mut(), vec![]);
+    buf.clear();
+    buf
+}
+
+/// Puts `buf` back for the next call, unless it has grown too big.
+fn return_scratch<T>(cell: &RefCell<Vec<T>>, buf: Vec<T>) {
+    if buf.capacity() <= MAX_SCRATCH_CAPACITY {
+        *cell.borrow_mut() = buf;
+    }
+}
+
+struct WatchdogState {
+    limits: Limits,
+    /// When the current evaluation started, or `None` between evaluations.
+    started: Option<Instant>,
+    shutdown: bool,
+}
+
+struct Watchdog {
+    state: Mutex<WatchdogState>,
+    wakeup: Condvar,
+}
+
+/// The body of a runtime's watchdog thread: sleeps between evaluations and
+/// interrupts script that overruns the limits.
+///
+/// The heap size can only be read on the runtime's thread, so while a heap
+/// limit is set each tick also triggers the operation callback, which
+/// samples it into `Interrupt::heap_bytes`. The value checked is thus up to
+/// a tick stale, which is fine for a limit.
+fn run_watchdog(watchdog: Arc<Watchdog>, interrupt: Arc<Interrupt>) {
+    let mut state = watchdog.state.lock().unwrap();
+    while !state.shutdown {
+        let started = match state.started {
+            Some(started) => started,
+            None => {
+                state = watchdog.wakeup.wait(state).unwrap();
+                continue;
+            }
+        };
+        let exceeded = {
+            let rt = interrupt.rt.lock().unwrap();
+            let heap = interrupt.heap_bytes.load(Ordering::SeqCst);
+            let exceeded = match (state.limits.max_duration, state.limits.max_heap_bytes) {
+                (Some(max), _) if started.elapsed() > max => Some(LimitExceeded::Time),
+                (_, Some(max)) if heap > max => Some(LimitExceeded::Heap),
+                _ => None,
+            };
+            if exceeded.is_some() {
+                *interrupt.limit_exceeded.lock().unwrap() = exceeded;
+            }
+            if !rt.is_null() && (exceeded.is_some() || state.limits.max_heap_bytes.is_some()) {
+                unsafe { JS_TriggerOperationCallback(*rt) };
+            }
+            exceeded
+        };
+        if exceeded.is_some() {
+            state.started = None;
+            continue;
+        }
+        state = watchdog.wakeup.wait_timeout(state, Duration::from_millis(WATCHDOG_INTERVAL_MS))
+                               .unwrap().0;
+    }
+}
+
+unsafe extern "C" fn operation_callback(cx: *mut JSContext) -> JSBool {
+    catch_panic(cx, || {
+        let rt = JS_GetRuntime(cx);
+        let state = runtime_state(rt);
+        let heap = JS_GetGCParameter(rt, JSGC_BYTES) as usize;
+        state.interrupt.heap_bytes.store(heap, Ordering::SeqCst);
+        if let Some(fuel) = state.fuel.get() {
+            JS_TriggerOperationCallback(rt);
+            if fuel.remaining == 0 {
+                *state.interrupt.limit_exceeded.lock().unwrap() = Some(LimitExceeded::Fuel);
+            } else if heap > fuel.max_heap_bytes {
+                *state.interrupt.limit_exceeded.lock().unwrap() = Some(LimitExceeded::Heap);
+            } else {
+                state.fuel.set(Some(Fuel { remaining: fuel.remaining - 1, .. fuel }));
+            }
+        }
+        if state.interrupt.cancelled.load(Ordering::SeqCst) ||
+           state.interrupt.limit_exceeded.lock().unwrap().is_some() {
+            // The engine disarms the interrupt before calling us; re-arm it
+            // so that later script is stopped too.
+            JS_TriggerOperationCallback(rt);
+            return 0;
+        }
+        1
+    }).unwrap_or(0)
+}
+
+unsafe extern "C" fn gc_callback(rt: *mut JSRuntime, status: JSGCStatus) {
+    catch_panic(ptr::null_mut(), || {
+        let state = runtime_state(rt);
+        match status {
+            JSGC_BEGIN => {
+                state.in_gc.set(true);
+                rooting::note_gc();
+            }
+            _ => {
+                let bytes = JS_GetGCParameter(rt, JSGC_BYTES);
+                state.in_gc.set(false);
+                state.bytes_after_gc.set(bytes);
+                state.interrupt.heap_bytes.store(bytes as usize, Ordering::SeqCst);
+                if !IsIncrementalGCInProgress(rt) {
+                    if let Some(mode) = state.saved_gc_mode.get() {
+                        state.saved_gc_mode.set(None);
+                        JS_SetGCParameter(rt, JSGC_MODE, mode);
+                    }
+                }
+            }
+        }
+    });
+    if !JS_GetRuntimePrivate(rt).is_null() {
+        if let Some(callback) = runtime_state(rt).gc_callback.get() {
+            callback(rt, status);
+        }
+    }
+}
+
+unsafe extern "C" fn finalize_callback(fop: *mut JSFreeOp, status: JSFinalizeStatus, is_compartment: JSBool) {
+    let rt = (*fop).runtime;
+    if status == JSFINALIZE_START {
+        catch_panic(ptr::null_mut(), || {
+            let state = runtime_state(rt);
+            state.weak_references.borrow_mut().retain(|weak| {
+                match weak.upgrade() {
+                    Some(references) => {
+                        references.sweep();
+                        true
+                    }
+                    None => false,
+                }
+            });
+        });
+    }
+    if !JS_GetRuntimePrivate(rt).is_null() {
+        if let Some(callback) = runtime_state(rt).finalize_callback.get() {
+            callback(fop, status, is_compartment);
+        }
+    }
+}
+
+// ___________________________________________________________________________
+// process-wide engine state
+
+static ENGINE_INIT: Once = ONCE_INIT;
+static LIVE_RUNTIMES: AtomicUsize = ATOMIC_USIZE_INIT;
+static ENGINE_SHUT_DOWN: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// SpiderMonkey sets up its process-wide state the first time a runtime is
+/// created, and that path isn't safe to run on several threads at once.
+/// Create and destroy one runtime under a `Once` so that later creations,
+/// from any thread, find the engine already initialized.
+fn init_engine() {
+    ENGINE_INIT.call_once(|| {
+        unsafe {
+            let rt = JS_Init(default_heapsize);
+            if !rt.is_null() {
+                JS_Finish(rt);
+            }
+        }
+    });
+}
+
+/// The error returned by `shutdown_engine` while runtimes are still alive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RuntimesStillAlive(pub usize);
+
+impl fmt::Display for RuntimesStillAlive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot shut down the JS engine: {} runtime(s) still alive", self.0)
+    }
+}
+
+impl Error for RuntimesStillAlive {
+    fn description(&self) -> &str {
+        "cannot shut down the JS engine while runtimes are alive"
+    }
+}
+
+/// Releases the engine's process-wide resources (`JS_ShutDown`).
+///
+/// Every `Runtime` must have been dropped first, and no new runtime can be
+/// created afterwards.
+pub fn shutdown_engine() -> Result<(), RuntimesStillAlive> {
+    let live = LIVE_RUNTIMES.load(Ordering::SeqCst);
+    if live != 0 {
+        return Err(RuntimesStillAlive(live));
+    }
+    if ENGINE_SHUT_DOWN.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    // `try_new` registers itself before checking the flag, so a runtime
+    // created concurrently is either seen here or refuses to start.
+    let live = LIVE_RUNTIMES.load(Ordering::SeqCst);
+    if live != 0 {
+        ENGINE_SHUT_DOWN.store(false, Ordering::SeqCst);
+        return Err(RuntimesStillAlive(live));
+    }
+    unsafe { JS_ShutDown() };
+    Ok(())
+}
+
+/// The reasons `Runtime::try_new` can fail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RuntimeCreationError {
+    /// `JS_NewRuntime` returned null.
+    RuntimeCreationFailed,
+    /// `JS_NewContext` returned null; the runtime was destroyed again.
+    ContextCreationFailed,
+    /// `shutdown_engine` has already been called.
+    EngineShutDown,
+}
+
+impl fmt::Display for RuntimeCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+impl Error for RuntimeCreationError {
+    fn description(&self) -> &str {
+        match *self {
+            RuntimeCreationError::RuntimeCreationFailed => "failed to create a JSRuntime",
+            RuntimeCreationError::ContextCreationFailed => "failed to create a JSContext",
+            RuntimeCreationError::EngineShutDown => "the JS engine has been shut down",
+        }
+    }
+}
+
+/// Why `Runtime::evaluate_into` failed.
+#[derive(Debug)]
+pub enum EvalError {
+    /// The script failed to compile or threw.
+    Script(JSError),
+    /// The script's result couldn't be converted to the type asked for.
+    Conversion(JSError),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EvalError::Script(ref error) => write!(f, "{}", error),
+            EvalError::Conversion(ref error) => write!(f, "could not convert the result: {}", error),
+        }
+    }
+}
+
+impl Error for EvalError {
+    fn description(&self) -> &str {
+        match *self {
+            EvalError::Script(ref error) => error.description(),
+            EvalError::Conversion(ref error) => error.description(),
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            EvalError::Script(ref error) => Some(error),
+            EvalError::Conversion(ref error) => Some(error),
+        }
+    }
+}
+
+/// The completion value of `Runtime::evaluate_isolated`. Neither variant
+/// can refer to anything in the throwaway compartment.
+#[derive(Copy, Clone, Debug)]
+pub enum IsolatedResult {
+    /// A number, boolean, `null` or `undefined`, which belong to no
+    /// compartment.
+    Primitive(JSVal),
+    /// A string or object, structured-cloned into the target global's
+    /// compartment. Like `evaluate`'s result it isn't rooted.
+    Cloned(JSVal),
+}
+
+impl IsolatedResult {
+    pub fn value(&self) -> JSVal {
+        match *self {
+            IsolatedResult::Primitive(value) | IsolatedResult::Cloned(value) => value,
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn new_context(rt: *mut JSRuntime) -> *mut JSContext {
+    unsafe { JS_NewContext(rt, default_stacksize as size_t) }
+}
+
+#[cfg(test)]
+thread_local!(static FAIL_NEXT_CONTEXT: Cell<bool> = Cell::new(false));
+
+/// Lets tests simulate `JS_NewContext` failing.
+#[cfg(test)]
+fn new_context(rt: *mut JSRuntime) -> *mut JSContext {
+    if FAIL_NEXT_CONTEXT.with(|fail| { let failing = fail.get(); fail.set(false); failing }) {
+        return ptr::null_mut();
+    }
+    unsafe { JS_NewContext(rt, default_stacksize as size_t) }
 }
 
 impl Runtime {
-    /// Creates a new `JSRuntime` and `JSContext`.
+    /// Creates a new `JSRuntime` and `JSContext`, panicking on failure.
     pub fn new() -> Runtime {
+        match Runtime::try_new() {
+            Ok(rt) => rt,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Creates a new `JSRuntime` and `JSContext`.
+    pub fn try_new() -> Result<Runtime, RuntimeCreationError> {
+        Runtime::with_options(&RuntimeOptions::default())
+    }
+
+    /// Creates a new `JSRuntime` and `JSContext` set up as `options` says.
+    pub fn with_options(options: &RuntimeOptions) -> Result<Runtime, RuntimeCreationError> {
+        LIVE_RUNTIMES.fetch_add(1, Ordering::SeqCst);
+        let result = Runtime::create(options);
+        if result.is_err() {
+            LIVE_RUNTIMES.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+
+    fn create(options: &RuntimeOptions) -> Result<Runtime, RuntimeCreationError> {
+        if ENGINE_SHUT_DOWN.load(Ordering::SeqCst) {
+            return Err(RuntimeCreationError::EngineShutDown);
+        }
+        init_engine();
+
         let js_runtime = unsafe { JS_Init(default_heapsize) };
-        assert!(!js_runtime.is_null());
+        if js_runtime.is_null() {
+            return Err(RuntimeCreationError::RuntimeCreationFailed);
+        }
 
         // Unconstrain the runtime's threshold on nominal heap size, to avoid
         // triggering GC too often if operating continuously near an arbitrary
@@ -43,118 +957,2559 @@ impl Runtime {
         // last-ditch GCs from within the GC's allocator.
         unsafe {
             JS_SetGCParameter(js_runtime, JSGC_MAX_BYTES, u32::MAX);
+            if let Some(quota) = options.native_stack_quota {
+                JS_SetNativeStackQuota(js_runtime, quota as size_t);
+            }
         }
 
-        let js_context = unsafe {
-            JS_NewContext(js_runtime, default_stacksize as size_t)
-        };
-        assert!(!js_context.is_null());
+        let js_context = new_context(js_runtime);
+        if js_context.is_null() {
+            unsafe { JS_Finish(js_runtime) };
+            return Err(RuntimeCreationError::ContextCreationFailed);
+        }
 
         unsafe {
-            JS_SetOptions(js_context,
-                          JSOPTION_VAROBJFIX |
-                          JSOPTION_METHODJIT |
-                          JSOPTION_TYPE_INFERENCE |
-                          JSOPTION_DONT_REPORT_UNCAUGHT |
-                          JSOPTION_AUTOJSAPI_OWNS_ERROR_REPORTING);
+            let mut flags = ContextFlags::VAROBJFIX |
+                            ContextFlags::METHODJIT |
+                            ContextFlags::TYPE_INFERENCE |
+                            ContextFlags::DONT_REPORT_UNCAUGHT |
+                            ContextFlags::AUTOJSAPI_OWNS_ERROR_REPORTING;
+            flags.set(ContextFlags::DONT_REPORT_UNCAUGHT, !options.report_uncaught);
+            JS_SetOptions(js_context, flags.bits());
 
             JS_SetVersion(js_context, JSVERSION_LATEST);
             JS_SetErrorReporter(js_context,
                                 Some(reportError as unsafe extern "C"
                                      fn(*mut JSContext, *const c_char, *mut JSErrorReport)));
-            JS_SetGCZeal(js_context, 0, JS_DEFAULT_ZEAL_FREQ);
+        }
+        set_debug_gc_zeal(js_context);
+
+        let state = Box::new(RuntimeState {
+            in_gc: Cell::new(false),
+            bytes_after_gc: Cell::new(0),
+            interrupt: Arc::new(Interrupt {
+                cancelled: AtomicBool::new(false),
+                limit_exceeded: Mutex::new(None),
+                heap_bytes: AtomicUsize::new(0),
+                rt: Mutex::new(js_runtime),
+            }),
+            weak_references: RefCell::new(vec![]),
+            reported_error: RefCell::new(None),
+            max_conversion_length: Cell::new(DEFAULT_MAX_CONVERSION_LENGTH),
+            source_maps: RefCell::new(SourceMaps::new()),
+            oom_callback: RefCell::new(None),
+            error_captures: RefCell::new(vec![]),
+            fuel: Cell::new(None),
+            saved_gc_mode: Cell::new(None),
+            gc_callback: Cell::new(None),
+            finalize_callback: Cell::new(None),
+            exception_roots: Arc::new(ExceptionRoots::new()),
+        });
+        unsafe {
+            JS_SetRuntimePrivate(js_runtime, &*state as *const RuntimeState as *mut c_void);
+            JS_SetGCCallback(js_runtime, Some(gc_callback));
+            JS_SetFinalizeCallback(js_runtime, Some(finalize_callback));
+            JS_InitDestroyPrincipalsCallback(js_runtime, Some(destroy_principals));
+            JS_SetOperationCallback(js_context, Some(operation_callback));
         }
 
-        Runtime {
+        Ok(Runtime {
             rt: js_runtime,
             cx: js_context,
-        }
+            state: state,
+            script_cache: RefCell::new(None),
+            locale: RefCell::new(None),
+            watchdog: RefCell::new(None),
+            call_hook: RefCell::new(None),
+            coverage: RefCell::new(None),
+            modules: ModuleRegistry::new(),
+            evaluation_depth: Cell::new(0),
+            scratch_chars: RefCell::new(vec![]),
+            scratch_filename: RefCell::new(vec![]),
+            thread: current_thread(),
+            default_global: None,
+            named_globals: HashMap::new(),
+        })
     }
 
     /// Returns the `JSRuntime` object.
     pub fn rt(&self) -> *mut JSRuntime {
+        self.check_thread();
         self.rt
     }
 
     /// Returns the `JSContext` object.
     pub fn cx(&self) -> *mut JSContext {
+        self.check_thread();
         self.cx
     }
 
-    pub fn evaluate_script(&self, global: *mut JSObject, script: String,
-                           filename: String, line_num: usize)
-                           -> Result<(), ()> {
-        let script_utf16: Vec<u16> = script.utf16_units().collect();
-        let filename_cstr = ffi::CString::new(filename.as_bytes()).unwrap();
-        debug!("Evaluating script from {} with content {}", filename, script);
-
-        // SpiderMonkey does not approve of null pointers.
-        let (ptr, len) = if script_utf16.len() == 0 {
-            static empty: &'static [u16] = &[];
-            (empty.as_ptr(), 0)
-        } else {
-            (script_utf16.as_ptr(), script_utf16.len() as c_uint)
-        };
-        assert!(!ptr.is_null());
-
-        let mut rval: JSVal = NullValue();
-        let result = unsafe {
-            JS_EvaluateUCScript(self.cx(), global, ptr, len,
-                                filename_cstr.as_ptr(), line_num as c_uint,
-                                &mut rval)
-        };
+    /// Returns the `JSContext` object as a `ContextPtr`.
+    pub fn context(&self) -> ContextPtr {
+        unsafe { ContextPtr::from_raw(self.cx()) }.expect("runtime has no context")
+    }
 
-        if result == ERR {
-            debug!("...err!");
-            Err(())
-        } else {
-            // we could return the script result but then we'd have
-            // to root it and so forth and, really, who cares?
-            debug!("...ok!");
-            Ok(())
+    /// Panics, in debug builds or with the `debugmozjs` feature, if called
+    /// from a thread other than the one that created the runtime.
+    fn check_thread(&self) {
+        if cfg!(any(debug_assertions, feature = "debugmozjs")) {
+            assert!(current_thread() == self.thread, "runtime used from a thread other than its own");
         }
     }
-}
+
+    /// Creates a global of class `class` (by default a plain global class)
+    /// with the standard classes, and keeps it rooted as the runtime's
+    /// default global until the runtime is dropped. Calling this again
+    /// replaces the previous default global, which then becomes garbage.
+    pub fn init_default_global(&mut self, class: Option<&'static JSClass>) -> Result<(), JSError> {
+        let global = try!(new_global_object(self.cx, class, &GlobalOptions::default()));
+        self.clear_default_global();
+        let mut root = Box::new(global);
+        unsafe {
+            JS_AddNamedObjectRoot(self.cx, &mut *root, b"default global\0".as_ptr() as *const c_char);
+        }
+        self.default_global = Some(root);
+        Ok(())
+    }
+
+    /// The global created by `init_default_global`.
+    ///
+    /// Panics if there is none.
+    pub fn global(&self) -> *mut JSObject {
+        match self.default_global {
+            Some(ref root) => **root,
+            None => panic!("Runtime::global called before init_default_global"),
+        }
+    }
+
+    /// Creates a global that the runtime keeps rooted under `name` until
+    /// `drop_global` or the runtime is dropped. Fails if `name` is taken.
+    pub fn create_named_global(&mut self, name: &str, options: &GlobalOptions) -> Result<(), JSError> {
+        if self.named_globals.contains_key(name) {
+            return Err(JSError::new(format!("a global named {:?} already exists", name)));
+        }
+        let global = try!(new_global_object(self.cx, None, options));
+        let mut root = Box::new(global);
+        unsafe {
+            JS_AddNamedObjectRoot(self.cx, &mut *root, b"named global\0".as_ptr() as *const c_char);
+        }
+        self.named_globals.insert(name.to_string(), root);
+        Ok(())
+    }
+
+    /// The global created under `name`, if any.
+    pub fn global_named(&self, name: &str) -> Option<*mut JSObject> {
+        self.named_globals.get(name).map(|root| **root)
+    }
+
+    /// Unroots the global created under `name`, so that it and its
+    /// compartment can be collected once nothing else refers to them.
+    /// Returns whether there was such a global.
+    pub fn drop_global(&mut self, name: &str) -> bool {
+        self.remove_named_global(name).is_some()
+    }
+
+    /// Like `drop_global`, then collects the global's compartment right
+    /// away with `gc_compartment`.
+    pub fn drop_global_and_gc(&mut self, name: &str) -> bool {
+        match self.remove_named_global(name) {
+            Some(global) => {
+                gc_compartment(self.cx, global);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unroots the global created under `name` and returns it. It stays
+    /// valid until the next collection.
+    fn remove_named_global(&mut self, name: &str) -> Option<*mut JSObject> {
+        self.named_globals.remove(name).map(|mut root| {
+            unsafe { JS_RemoveObjectRoot(self.cx, &mut *root) };
+            *root
+        })
+    }
+
+    fn clear_default_global(&mut self) {
+        if let Some(mut root) = self.default_global.take() {
+            unsafe { JS_RemoveObjectRoot(self.cx, &mut *root) };
+        }
+    }
+
+    /// Has `references` swept at the start of every finalization on this
+    /// runtime, for as long as it is alive. See `WeakObjectMap`.
+    pub fn register_weak_references(&self, references: Weak<Sweep>) {
+        self.state.weak_references.borrow_mut().push(references);
+    }
+
+    /// The number of bytes currently allocated on the GC heap.
+    pub fn gc_bytes(&self) -> usize {
+        gc_bytes(self.cx)
+    }
+
+    /// A breakdown of the GC heap; see `memory::MemoryReport`.
+    pub fn collect_memory_report(&self) -> MemoryReport {
+        collect_memory_report(self.rt)
+    }
+
+    /// Writes the engine's description of the GC heap to `out`: every
+    /// live thing with the edges between them, in the engine's own text
+    /// format. With `start`, only what is reachable from that object is
+    /// dumped, which needs a debug build of SpiderMonkey (the
+    /// `debugmozjs` feature); otherwise this fails.
+    pub fn dump_heap(&self, out: &mut Write, start: Option<*mut JSObject>) -> io::Result<()> {
+        // The engine writes to a `FILE*`; go through an anonymous temporary
+        // file and copy that over.
+        unsafe {
+            let fp = tmpfile();
+            if fp.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let start = start.unwrap_or(ptr::null_mut());
+            if !DumpHeap(self.rt, fp, start) {
+                fclose(fp);
+                return Err(io::Error::new(io::ErrorKind::Other,
+                                          "dumping from an object needs a debug SpiderMonkey"));
+            }
+            if fflush(fp) != 0 || fseek(fp, 0, SEEK_SET) != 0 {
+                let error = io::Error::last_os_error();
+                fclose(fp);
+                return Err(error);
+            }
+            let mut buffer = [0u8; 8192];
+            let mut result = Ok(());
+            loop {
+                let read = fread(buffer.as_mut_ptr() as *mut c_void, 1, buffer.len() as size_t, fp);
+                if read == 0 {
+                    break;
+                }
+                result = out.write_all(&buffer[..read as usize]);
+                if result.is_err() {
+                    break;
+                }
+            }
+            fclose(fp);
+            result
+        }
+    }
+
+    /// Tells the engine the system is short of memory: runs a shrinking
+    /// collection, which also returns free GC chunks to the OS.
+    ///
+    /// Does nothing when called from inside a collection, e.g. from a GC or
+    /// finalize callback.
+    pub fn notify_low_memory(&self) {
+        if self.state.in_gc.get() {
+            return;
+        }
+        unsafe { ShrinkingGC(self.rt) }
+    }
+
+    /// Tells the engine the embedder is idle for roughly `budget`.
+    ///
+    /// Continues an unfinished incremental collection, or starts one if the
+    /// heap has grown by half since the last collection; otherwise does
+    /// nothing. Unless the runtime's `JSGC_MODE` is `JSGC_MODE_INCREMENTAL`
+    /// the collection runs to completion regardless of the budget. Like
+    /// `notify_low_memory`, this does nothing from inside a collection.
+    pub fn notify_idle(&self, budget: Duration) {
+        if self.state.in_gc.get() {
+            return;
+        }
+        let millis = budget.as_secs() as i64 * 1000 + (budget.subsec_nanos() / 1000000) as i64;
+        unsafe {
+            let grown = self.gc_bytes() > self.state.bytes_after_gc.get() as usize * 3 / 2;
+            if IsIncrementalGCInProgress(self.rt) || grown {
+                IncrementalGCSlice(self.rt, cmp::max(millis, 1));
+            }
+        }
+    }
+
+    /// Starts an incremental collection of the whole heap and runs its first
+    /// slice, of the budget set with `set_gc_slice_budget`; later slices are
+    /// run by `incremental_gc_slice`. Switches the runtime to
+    /// `JSGC_MODE_INCREMENTAL` first, and back to its previous mode once the
+    /// collection is over. Continues a collection already under way instead
+    /// of starting another.
+    ///
+    /// Between slices script may run as usual: the engine's write barriers
+    /// keep the collection consistent, and values held outside its heap
+    /// should go through `Heap` for the same reason.
+    ///
+    /// Does nothing from inside a collection; returns `Finished` then.
+    pub fn begin_incremental_gc(&self) -> IncrementalGCProgress {
+        if !self.state.in_gc.get() {
+            unsafe {
+                let mode = JS_GetGCParameter(self.rt, JSGC_MODE);
+                if mode != JSGC_MODE_INCREMENTAL {
+                    if self.state.saved_gc_mode.get().is_none() {
+                        self.state.saved_gc_mode.set(Some(mode));
+                    }
+                    JS_SetGCParameter(self.rt, JSGC_MODE, JSGC_MODE_INCREMENTAL);
+                }
+            }
+        }
+        self.run_gc_slice(0)
+    }
+
+    /// Runs a slice of the current incremental collection taking about
+    /// `budget_ms` milliseconds, or of a new one if none is under way.
+    pub fn incremental_gc_slice(&self, budget_ms: u32) -> IncrementalGCProgress {
+        self.run_gc_slice(cmp::max(budget_ms, 1) as i64)
+    }
+
+    /// Runs the current incremental collection, if any, to completion.
+    pub fn finish_incremental_gc(&self) {
+        if self.state.in_gc.get() {
+            return;
+        }
+        unsafe {
+            if IsIncrementalGCInProgress(self.rt) {
+                FinishIncrementalGC(self.rt);
+            }
+        }
+    }
+
+    /// Sets the default time budget of an incremental slice, used by
+    /// `begin_incremental_gc` and by slices the engine runs by itself.
+    pub fn set_gc_slice_budget(&self, ms: u32) {
+        unsafe { JS_SetGCParameter(self.rt, JSGC_SLICE_TIME_BUDGET, ms) }
+    }
+
+    /// Runs a slice of `millis`, or of the configured budget if 0.
+    fn run_gc_slice(&self, millis: i64) -> IncrementalGCProgress {
+        if self.state.in_gc.get() {
+            return IncrementalGCProgress::Finished;
+        }
+        unsafe {
+            if !IsIncrementalGCEnabled(self.rt) {
+                JS_GC(self.rt);
+                return IncrementalGCProgress::NotIncremental;
+            }
+            IncrementalGCSlice(self.rt, millis);
+            if IsIncrementalGCInProgress(self.rt) {
+                IncrementalGCProgress::InProgress
+            } else {
+                IncrementalGCProgress::Finished
+            }
+        }
+    }
+
+    /// Makes the engine collect every `frequency` allocations (`zeal` 1 or
+    /// 2; see `JS_SetGCZeal`), or turns that off again with `zeal` 0.
+    /// Combined with `assert_no_gc!` this makes rooting mistakes show up in
+    /// tests. Runtimes start out at `DEBUG_GC_ZEAL`.
+    #[cfg(feature = "debugmozjs")]
+    pub fn set_gc_zeal(&self, zeal: u8, frequency: u32) {
+        set_gc_zeal(self.cx, zeal, frequency)
+    }
+
+    /// The JS version scripts are compiled for unless `EvaluateOptions`
+    /// says otherwise; `JSVERSION_LATEST` to begin with.
+    pub fn version(&self) -> JSVersion {
+        unsafe { JS_GetVersion(self.cx) }
+    }
+
+    /// Describes the engine build, as far as it can be found out; see
+    /// `BuildInfo`. Thread safety is found out by starting a request and
+    /// asking whether the runtime saw it, which only a threadsafe build
+    /// records.
+    pub fn build_info(&self) -> BuildInfo {
+        let threadsafe = {
+            let _request = Request::new(self.cx);
+            unsafe { JS_IsInRequest(self.rt) != 0 }
+        };
+        BuildInfo {
+            debug: cfg!(feature = "debugmozjs"),
+            gc_zeal: cfg!(feature = "debugmozjs"),
+            threadsafe: threadsafe,
+        }
+    }
+
+    /// Returns a token that can stop script on this runtime from another
+    /// thread.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        CancellationToken {
+            interrupt: self.state.interrupt.clone(),
+        }
+    }
+
+    /// Enforces `limits` on every later evaluation through `run_script`
+    /// or `evaluate_cached`, timing and measuring each one from its start.
+    /// Nested evaluations count towards the outermost one.
+    ///
+    /// The limits are watched by a thread owned by this runtime, started
+    /// when limits are first set and stopped when `Limits::default()`
+    /// clears them. An evaluation that exceeds a limit fails with an error
+    /// naming it.
+    pub fn set_limits(&self, limits: Limits) {
+        if limits == Limits::default() {
+            self.stop_watchdog();
+            return;
+        }
+        let mut watchdog = self.watchdog.borrow_mut();
+        if let Some((ref watchdog, _)) = *watchdog {
+            watchdog.state.lock().unwrap().limits = limits;
+            return;
+        }
+        let shared = Arc::new(Watchdog {
+            state: Mutex::new(WatchdogState {
+                limits: limits,
+                started: None,
+                shutdown: false,
+            }),
+            wakeup: Condvar::new(),
+        });
+        let thread = {
+            let shared = shared.clone();
+            let interrupt = self.state.interrupt.clone();
+            thread::spawn(move || run_watchdog(shared, interrupt))
+        };
+        *watchdog = Some((shared, thread));
+    }
+
+    fn stop_watchdog(&self) {
+        if let Some((watchdog, thread)) = self.watchdog.borrow_mut().take() {
+            watchdog.state.lock().unwrap().shutdown = true;
+            watchdog.wakeup.notify_one();
+            thread.join().unwrap();
+        }
+    }
+
+    /// The limits set with `set_limits`.
+    pub fn limits(&self) -> Limits {
+        match *self.watchdog.borrow() {
+            Some((ref watchdog, _)) => watchdog.state.lock().unwrap().limits,
+            None => Limits::default(),
+        }
+    }
+
+    /// Runs `f`, an evaluation, under the limits set with `set_limits`.
+    fn run_limited<F: FnOnce() -> JSBool>(&self, f: F) -> JSBool {
+        let depth = self.evaluation_depth.get();
+        if depth == 0 {
+            *self.state.reported_error.borrow_mut() = None;
+            *self.state.interrupt.limit_exceeded.lock().unwrap() = None;
+            self.state.interrupt.heap_bytes.store(self.gc_bytes(), Ordering::SeqCst);
+            if let Some((ref watchdog, _)) = *self.watchdog.borrow() {
+                watchdog.state.lock().unwrap().started = Some(Instant::now());
+                watchdog.wakeup.notify_one();
+            }
+        }
+        self.evaluation_depth.set(depth + 1);
+        let _depth = EvaluationDepth {
+            rt: self,
+            depth: depth,
+        };
+        f()
+    }
+
+    /// The error for an evaluation that returned false.
+    fn evaluation_error(&self) -> JSError {
+        if let Some(limit) = *self.state.interrupt.limit_exceeded.lock().unwrap() {
+            return JSError::termination(limit.to_string());
+        }
+        if self.state.interrupt.cancelled.load(Ordering::SeqCst) {
+            return JSError::termination("script was cancelled");
+        }
+        self.take_exception()
+    }
+
+    /// Reports and clears an exception left pending before an evaluation
+    /// starts, keeping its report from being mistaken for the evaluation's.
+    fn clear_stale_exception(&self) {
+        if report_stale_exception(self.cx) {
+            *self.state.reported_error.borrow_mut() = None;
+        }
+    }
+
+    /// Remembers the source map URL of `source`, which was evaluated from
+    /// `filename`, so that errors raised there later can report it, and
+    /// gives it to `error`, the evaluation's own, if that was raised there.
+    /// A `SyntaxError` raised in `filename` means `source` didn't compile,
+    /// and nothing is remembered.
+    fn record_source_map(&self, filename: Option<&str>, source: &str, error: Option<&mut JSError>) {
+        let filename = match filename {
+            Some(filename) => filename,
+            None => return,
+        };
+        let url = find_source_map_url(source);
+        if let Some(error) = error {
+            if error.filename.as_ref().map(|s| &**s) == Some(filename) {
+                if error.kind == ErrorKind::Syntax {
+                    return;
+                }
+                error.source_map_url = url.clone();
+            }
+        }
+        self.state.source_maps.borrow_mut().set(filename, url);
+    }
+
+    /// Takes the exception that made an evaluation or compilation fail.
+    fn take_exception(&self) -> JSError {
+        unsafe {
+            // With `set_report_uncaught(true)` the engine has already handed
+            // the exception to `reportError` and cleared it.
+            if JS_IsExceptionPending(self.cx) == 0 {
+                if let Some(error) = self.state.reported_error.borrow_mut().take() {
+                    return error;
+                }
+            }
+            JSError::from_pending_exception(self.cx)
+        }
+    }
+
+    /// Whether exceptions that escape an evaluation are passed to the error
+    /// reporter, which logs them (`JSOPTION_DONT_REPORT_UNCAUGHT` unset).
+    /// Either way the evaluation's error describes the exception, and no
+    /// exception is left pending on the context. Off by default.
+    pub fn set_report_uncaught(&self, report: bool) {
+        self.set_options(ContextFlags::DONT_REPORT_UNCAUGHT, !report);
+    }
+
+    pub fn reports_uncaught(&self) -> bool {
+        !self.context_flags().contains(ContextFlags::DONT_REPORT_UNCAUGHT)
+    }
+
+    /// Limits the length of the arrays and strings the crate's conversions
+    /// build from Rust data; see `conversions::check_length`. Defaults to
+    /// `DEFAULT_MAX_CONVERSION_LENGTH`.
+    pub fn set_max_conversion_length(&self, length: usize) {
+        self.state.max_conversion_length.set(length);
+    }
+
+    /// Has `callback` called whenever the engine reports running out of
+    /// memory, e.g. so the embedder can shed load. It runs in the middle of
+    /// the failed operation, so it must not use the runtime; the operation
+    /// itself then fails with an `ErrorKind::OutOfMemory` error.
+    pub fn set_oom_callback(&self, callback: Box<FnMut()>) {
+        *self.state.oom_callback.borrow_mut() = Some(callback);
+    }
+
+    /// Sets the embedder's GC callback, which the crate's own calls at the
+    /// start and end of every collection, and returns the previous one.
+    /// Don't call `JS_SetGCCallback` directly: it would replace the crate's.
+    pub fn set_gc_callback(&self, callback: JSGCCallback) -> JSGCCallback {
+        let previous = self.state.gc_callback.get();
+        self.state.gc_callback.set(callback);
+        previous
+    }
+
+    /// Sets the embedder's finalize callback, which the crate's own calls
+    /// at each stage of finalization, and returns the previous one. Don't
+    /// call `JS_SetFinalizeCallback` directly: it would replace the crate's.
+    pub fn set_finalize_callback(&self, callback: JSFinalizeCallback) -> JSFinalizeCallback {
+        let previous = self.state.finalize_callback.get();
+        self.state.finalize_callback.set(callback);
+        previous
+    }
+
+    /// Collects the errors the engine reports until the returned guard is
+    /// dropped, e.g. to check whether a snippet compiles without anything
+    /// reaching the log. The guard installs an error reporter of its own in
+    /// place of the context's current one, whether the crate's or one the
+    /// embedder set with `JS_SetErrorReporter`, and puts that back when
+    /// it's dropped. Guards nest: each report goes to the innermost one
+    /// only. Warnings still go to the reporter that was replaced.
+    ///
+    /// Exceptions that escape an evaluation are only reported with
+    /// `set_report_uncaught(true)`; the evaluation's error describes them
+    /// either way.
+    #[must_use]
+    pub fn capture_errors(&self) -> ErrorReporterGuard {
+        let reports = Rc::new(RefCell::new(vec![]));
+        let previous = unsafe {
+            JS_SetErrorReporter(self.cx, Some(capture_error as unsafe extern "C"
+                                              fn(*mut JSContext, *const c_char, *mut JSErrorReport)))
+        };
+        self.state.error_captures.borrow_mut().push(ErrorCapture {
+            reports: reports.clone(),
+            previous: previous,
+        });
+        ErrorReporterGuard { rt: self, reports: reports }
+    }
+
+    /// Turns the method JIT and type inference on or off; both start out
+    /// on. Only scripts compiled afterwards are affected. The script cache
+    /// keys on these options, so cached bytecode isn't mixed up either.
+    pub fn set_jit_enabled(&self, methodjit: bool, type_inference: bool) {
+        self.set_options(ContextFlags::METHODJIT, methodjit);
+        self.set_options(ContextFlags::TYPE_INFERENCE, type_inference);
+    }
+
+    /// The context's options, including any the crate has no name for.
+    pub fn context_flags(&self) -> ContextFlags {
+        ContextFlags::from_bits(unsafe { JS_GetOptions(self.cx) })
+    }
+
+    /// Replaces the context's options. To change some flags and leave the
+    /// rest, modify what `context_flags` returns.
+    pub fn set_context_flags(&self, flags: ContextFlags) {
+        unsafe { JS_SetOptions(self.cx, flags.bits()) };
+    }
+
+    /// Sets or clears `flags` in the context's options, leaving the others.
+    fn set_options(&self, flags: ContextFlags, enable: bool) {
+        let mut options = self.context_flags();
+        options.set(flags, enable);
+        self.set_context_flags(options);
+    }
+
+    /// Calls `hook` whenever a scripted function is entered or left.
+    ///
+    /// Call hooks only fire in debug mode, which slows script down, so this
+    /// turns debug mode on for compartments created from now on; create the
+    /// globals to observe after installing the hook. Calls made by the hook
+    /// itself are not reported.
+    ///
+    /// Functions already running when the hook is replaced or cleared are
+    /// still reported to the hook that saw them entered, which is only
+    /// dropped once they have all returned.
+    pub fn set_call_hook(&self, hook: Box<FnMut(FunctionCallEvent)>) {
+        let hook = CallHook::new(hook);
+        unsafe {
+            JS_SetRuntimeDebugMode(self.rt, 1);
+            JS_SetCallHook(self.rt, Some(call_hook), &*hook as *const Rc<CallHook> as *mut c_void);
+        }
+        *self.call_hook.borrow_mut() = Some(hook);
+    }
+
+    /// Removes the hook installed by `set_call_hook`. Compartments stay in
+    /// debug mode.
+    pub fn clear_call_hook(&self) {
+        let hook = self.call_hook.borrow_mut().take();
+        // The engine only passes a frame's reference back to the hook
+        // installed when the frame is left, so `call_hook` stays installed
+        // until hooked frames are gone.
+        let hooked_frames = hook.map_or(false, |hook| Rc::strong_count(&*hook) > 1);
+        unsafe {
+            if hooked_frames {
+                JS_SetCallHook(self.rt, Some(call_hook), ptr::null_mut());
+            } else {
+                JS_SetCallHook(self.rt, None, ptr::null_mut());
+            }
+        }
+    }
+
+    /// Starts recording which lines of script run.
+    ///
+    /// Only scripts compiled from now on, in compartments created from now
+    /// on, are covered: single-stepping needs debug mode, which this turns on
+    /// for new compartments. Execution is slow until `take_coverage`.
+    pub fn enable_coverage(&self) {
+        if self.coverage.borrow().is_some() {
+            return;
+        }
+        let collector = CoverageCollector::new();
+        unsafe {
+            JS_SetRuntimeDebugMode(self.rt, 1);
+            JS_SetNewScriptHook(self.rt, Some(coverage_new_script), collector.as_closure());
+            JS_SetDestroyScriptHook(self.rt, Some(coverage_destroy_script), collector.as_closure());
+            JS_SetInterrupt(self.rt, Some(coverage_step), collector.as_closure());
+        }
+        *self.coverage.borrow_mut() = Some(collector);
+    }
+
+    /// Stops recording coverage and returns the executed lines, by filename.
+    /// Returns an empty map if coverage wasn't enabled.
+    pub fn take_coverage(&self) -> HashMap<String, BTreeSet<u32>> {
+        let collector = match self.coverage.borrow_mut().take() {
+            Some(collector) => collector,
+            None => return HashMap::new(),
+        };
+        unsafe {
+            let mut handler = None;
+            let mut closure = ptr::null_mut();
+            JS_ClearInterrupt(self.rt, &mut handler, &mut closure);
+            JS_SetNewScriptHook(self.rt, None, ptr::null_mut());
+            JS_SetDestroyScriptHook(self.rt, None, ptr::null_mut());
+        }
+        collector.into_lines()
+    }
+
+    /// Makes the locale-sensitive string methods of this runtime's context
+    /// use `provider`, which is kept alive as long as the runtime. Must not
+    /// be called from inside a `LocaleProvider` method.
+    pub fn set_locale_provider(&self, provider: Box<LocaleProvider>) {
+        let callbacks = LocaleCallbacks::new(provider);
+        unsafe { JS_SetLocaleCallbacks(self.cx, callbacks.as_raw()) };
+        // The previous provider is only dropped once the engine no longer
+        // points at it.
+        *self.locale.borrow_mut() = Some(callbacks);
+    }
+
+    /// Installs (or, with `None`, removes) the cache used by
+    /// `evaluate_cached`, returning the previous one.
+    pub fn set_script_cache(&self, cache: Option<ScriptCache>) -> Option<ScriptCache> {
+        mem::replace(&mut *self.script_cache.borrow_mut(), cache)
+    }
+
+    /// The statistics of the script cache, if one is installed.
+    pub fn script_cache_stats(&self) -> Option<ScriptCacheStats> {
+        self.script_cache.borrow().as_ref().map(|cache| cache.stats())
+    }
+
+    /// Evaluates `source` against `global` and returns the completion value,
+    /// reusing bytecode from the script cache when possible. Without a cache
+    /// this just compiles and runs the script. The caller must already be in
+    /// `global`'s compartment.
+    ///
+    /// Cached bytecode that fails to decode, e.g. because it was produced by
+    /// a different engine build, is dropped and the script recompiled.
+    ///
+    /// An exception already pending on the context, which some native
+    /// should have propagated, is reported and cleared first; see
+    /// `error::report_stale_exception`.
+    pub fn evaluate_cached(&self, global: *mut JSObject, source: &str, filename: &str)
+                           -> Result<JSVal, JSError> {
+        self.clear_stale_exception();
+        let cx = self.cx;
+        let key = unsafe {
+            ScriptCache::key(source, filename, JS_GetOptions(cx), JS_GetVersion(cx) as i32)
+        };
+        let cached = match *self.script_cache.borrow_mut() {
+            Some(ref mut cache) => cache.lookup(key),
+            None => None,
+        };
+
+        let mut script = ptr::null_mut();
+        if let Some(bytecode) = cached {
+            // Bytecode too long for the engine's length parameter is
+            // treated like bytecode that fails to decode.
+            if bytecode.len() <= u32::MAX as usize {
+                script = unsafe {
+                    JS_DecodeScript(cx, bytecode.as_ptr() as *const c_void, bytecode.len() as u32,
+                                    ptr::null_mut(), ptr::null_mut())
+                };
+            }
+            if script.is_null() {
+                unsafe { JS_ClearPendingException(cx) };
+                if let Some(ref mut cache) = *self.script_cache.borrow_mut() {
+                    cache.reject(key);
+                }
+            }
+        }
+
+        if script.is_null() {
+            // Compile-and-go scripts can't be XDR-encoded.
+            let options = EvaluateOptions::new().filename(filename).compile_and_go(false);
+            script = try!(self.compile_raw(global, source, &options));
+            if self.script_cache.borrow().is_some() {
+                let mut length = 0;
+                let data = unsafe { JS_EncodeScript(cx, script, &mut length) };
+                if data.is_null() {
+                    // Not being able to cache isn't an error.
+                    unsafe { JS_ClearPendingException(cx) };
+                } else {
+                    let bytecode = unsafe {
+                        slice::from_raw_parts(data as *const u8, length as usize).to_vec()
+                    };
+                    unsafe { JS_free(cx, data) };
+                    if let Some(ref mut cache) = *self.script_cache.borrow_mut() {
+                        cache.insert(key, bytecode);
+                    }
+                }
+            }
+        }
+
+        self.record_source_map(Some(filename), source, None);
+        let mut rval = UndefinedValue();
+        let ok = self.run_limited(|| unsafe { JS_ExecuteScript(cx, global, script, &mut rval) });
+        if ok == ERR {
+            return Err(self.evaluation_error());
+        }
+        Ok(rval)
+    }
+
+    /// Runs `f` with `JSOPTION_COMPILE_N_GO` set or cleared, which is how
+    /// the engine's compile and evaluate calls are told about it.
+    fn with_compile_and_go<R, F: FnOnce() -> R>(&self, compile_and_go: bool, f: F) -> R {
+        let saved = SavedOptions {
+            cx: self.cx,
+            options: unsafe { JS_GetOptions(self.cx) },
+        };
+        let mut options = ContextFlags::from_bits(saved.options);
+        options.set(ContextFlags::COMPILE_N_GO, compile_and_go);
+        self.set_context_flags(options);
+        f()
+    }
+
+    /// Checks that `global`, and the scope in `options` if any, belong to
+    /// this runtime.
+    fn check_globals(&self, global: *mut JSObject, options: &EvaluateOptions) -> Result<(), JSError> {
+        try!(check_same_runtime(self.cx, global, "global"));
+        match options.scope {
+            Some(scope) => check_same_runtime(self.cx, scope, "scope"),
+            None => Ok(()),
+        }
+    }
+
+    /// Compiles `source` without rooting the script. `no_rval` doesn't
+    /// apply to compilation and is ignored.
+    fn compile_raw(&self, global: *mut JSObject, source: &str, options: &EvaluateOptions)
+                   -> Result<*mut JSScript, JSError> {
+        try!(self.check_globals(global, options));
+        let mut filename_buf = take_scratch(&self.scratch_filename);
+        let filename = try!(c_filename(options.filename_str(), &mut filename_buf));
+        let obj = options.scope.unwrap_or(global);
+        let version = options.version.unwrap_or_else(|| unsafe { JS_GetVersion(self.cx) });
+        let line = options.line as c_uint;
+        *self.state.reported_error.borrow_mut() = None;
+        let mut chars = take_scratch(&self.scratch_chars);
+        if !options.utf8 {
+            to_utf16_into(source, &mut chars);
+        }
+        let script = self.with_compile_and_go(options.compile_and_go, || unsafe {
+            if options.utf8 {
+                JS_CompileScriptForPrincipalsVersion(self.cx, obj, options.principals,
+                                                     source.as_ptr() as *const c_char,
+                                                     source.len() as size_t, filename, line, version)
+            } else {
+                JS_CompileUCScriptForPrincipalsVersion(self.cx, obj, options.principals,
+                                                       chars.as_ptr(), chars.len() as size_t,
+                                                       filename, line, version)
+            }
+        });
+        return_scratch(&self.scratch_chars, chars);
+        return_scratch(&self.scratch_filename, filename_buf);
+        if script.is_null() {
+            return Err(self.take_exception());
+        }
+        Ok(script)
+    }
+
+    /// Compiles `source` against `global` for running later, possibly
+    /// repeatedly. The caller must already be in `global`'s compartment.
+    ///
+    /// With `compile_and_go`, the default, the script runs faster but only
+    /// against `global`; without it, it can also run against other globals.
+    pub fn compile_script<'a>(&'a self, global: *mut JSObject, source: &str, options: &EvaluateOptions)
+                              -> Result<CompiledScript<'a>, JSError> {
+        self.clear_stale_exception();
+        let script = try!(self.compile_raw(global, source, options));
+        let mut script = unsafe { CompiledScript::from_raw(self, global, script, options.compile_and_go) };
+        script.set_source_map_url(find_source_map_url(source));
+        Ok(script)
+    }
+
+    /// Like `compile_script`, but the script runs with `principals`, which
+    /// it keeps alive.
+    pub fn compile_script_for_principals<'a>(&'a self, global: *mut JSObject, source: &str,
+                                             options: &EvaluateOptions, principals: &Principals<'a>)
+                                             -> Result<CompiledScript<'a>, JSError> {
+        let options = options.clone().principals(principals.raw());
+        let mut script = try!(self.compile_script(global, source, &options));
+        script.hold_principals(principals);
+        Ok(script)
+    }
+
+    /// Compiles `body` as a function taking `argnames`, scoped to `global`
+    /// or `options`' scope, and returns the function object, which isn't
+    /// rooted. The caller must already be in `global`'s compartment. Of
+    /// `options`, only the filename, line, version, scope and principals
+    /// apply.
+    pub fn compile_function(&self, global: *mut JSObject, name: Option<&str>, argnames: &[&str], body: &str,
+                            options: &EvaluateOptions) -> Result<*mut JSObject, JSError> {
+        try!(self.check_globals(global, options));
+        self.clear_stale_exception();
+        let name = match name {
+            Some(name) => Some(try!(ffi::CString::new(name).map_err(|_| {
+                JSError::new(format!("invalid function name {:?}", name))
+            }))),
+            None => None,
+        };
+        let mut names = vec!();
+        for argname in argnames {
+            names.push(try!(ffi::CString::new(*argname).map_err(|_| {
+                JSError::new(format!("invalid argument name {:?}", argname))
+            })));
+        }
+        let mut name_ptrs: Vec<*const c_char> = names.iter().map(|name| name.as_ptr()).collect();
+        let name_count = try!(check_argument_count(name_ptrs.len()));
+        let mut filename_buf = take_scratch(&self.scratch_filename);
+        let filename = try!(c_filename(options.filename_str(), &mut filename_buf));
+        let obj = options.scope.unwrap_or(global);
+        let version = options.version.unwrap_or_else(|| unsafe { JS_GetVersion(self.cx) });
+        *self.state.reported_error.borrow_mut() = None;
+        let mut chars = take_scratch(&self.scratch_chars);
+        to_utf16_into(body, &mut chars);
+        let fun = unsafe {
+            JS_CompileUCFunctionForPrincipalsVersion(self.cx, obj, options.principals,
+                                                     name.as_ref().map_or(ptr::null(), |name| name.as_ptr()),
+                                                     name_count, name_ptrs.as_mut_ptr(),
+                                                     chars.as_ptr(), chars.len() as size_t,
+                                                     filename, options.line as c_uint, version)
+        };
+        return_scratch(&self.scratch_chars, chars);
+        return_scratch(&self.scratch_filename, filename_buf);
+        if fun.is_null() {
+            return Err(self.take_exception());
+        }
+        Ok(unsafe { JS_GetFunctionObject(fun) })
+    }
+
+    /// Like `compile_function`, but the function runs with `principals`.
+    /// The engine keeps them alive for as long as the function.
+    pub fn compile_function_for_principals(&self, global: *mut JSObject, name: Option<&str>,
+                                           argnames: &[&str], body: &str, options: &EvaluateOptions,
+                                           principals: &Principals) -> Result<*mut JSObject, JSError> {
+        let options = options.clone().principals(principals.raw());
+        self.compile_function(global, name, argnames, body, &options)
+    }
+
+    /// Evaluates `source` against `global` as `options` describe and
+    /// returns the completion value, or `undefined` with `no_rval`. The
+    /// caller must already be in `global`'s compartment.
+    ///
+    /// Like `evaluate_cached`, the evaluation runs under the limits set with
+    /// `set_limits`, and a stale pending exception is reported and cleared
+    /// first.
+    pub fn evaluate<O: AsObjectPtr>(&self, global: O, source: &str, options: &EvaluateOptions)
+                                    -> Result<JSVal, JSError> {
+        self.evaluate_named(global.object_ptr().as_raw(), source, options.filename_str(), options)
+    }
+
+    /// `evaluate` for a raw `global`, which must be live.
+    #[deprecated(note = "use evaluate with an ObjectPtr")]
+    pub unsafe fn evaluate_raw(&self, global: *mut JSObject, source: &str, options: &EvaluateOptions)
+                               -> Result<JSVal, JSError> {
+        self.evaluate(ObjectPtr::from_raw_nonnull(global), source, options)
+    }
+
+    /// `evaluate`, with the filename given apart from `options`, whose own
+    /// is ignored, so that callers holding a `&str` needn't copy it into
+    /// them.
+    fn evaluate_named(&self, global: *mut JSObject, source: &str, filename: Option<&str>,
+                      options: &EvaluateOptions) -> Result<JSVal, JSError> {
+        try!(self.check_globals(global, options));
+        if options.save_frame_chain {
+            let _saved = try!(SavedFrameChain::new(self.cx));
+            let options = options.clone().save_frame_chain(false);
+            let scope = unsafe { ObjectPtr::from_raw_nonnull(options.scope.unwrap_or(global)) };
+            return with_compartment(self.context(), scope,
+                                    || self.evaluate_named(global, source, filename, &options));
+        }
+        self.clear_stale_exception();
+        let name = filename;
+        let mut filename_buf = take_scratch(&self.scratch_filename);
+        let filename = try!(c_filename(name, &mut filename_buf));
+        let obj = options.scope.unwrap_or(global);
+        let version = options.version.unwrap_or_else(|| unsafe { JS_GetVersion(self.cx) });
+        let line = options.line as c_uint;
+
+        let mut rval = UndefinedValue();
+        let rval_ptr: *mut JSVal = if options.no_rval { ptr::null_mut() } else { &mut rval };
+        let mut chars = take_scratch(&self.scratch_chars);
+        if !options.utf8 {
+            to_utf16_into(source, &mut chars);
+        }
+        let length = match check_source_length(if options.utf8 { source.len() } else { chars.len() }) {
+            Ok(length) => length,
+            Err(error) => {
+                return_scratch(&self.scratch_chars, chars);
+                return_scratch(&self.scratch_filename, filename_buf);
+                return Err(error);
+            }
+        };
+        let result = self.with_compile_and_go(options.compile_and_go, || self.run_limited(|| unsafe {
+            if options.utf8 {
+                JS_EvaluateScriptForPrincipalsVersion(self.cx, obj, options.principals,
+                                                      source.as_ptr() as *const c_char,
+                                                      length, filename, line, rval_ptr, version)
+            } else {
+                JS_EvaluateUCScriptForPrincipalsVersion(self.cx, obj, options.principals,
+                                                        chars.as_ptr(), length,
+                                                        filename, line, rval_ptr, version)
+            }
+        }));
+        return_scratch(&self.scratch_chars, chars);
+        return_scratch(&self.scratch_filename, filename_buf);
+        if result == ERR {
+            let mut error = self.evaluation_error();
+            self.record_source_map(name, source, Some(&mut error));
+            return Err(error);
+        }
+        self.record_source_map(name, source, None);
+        Ok(rval)
+    }
+
+    /// Like `evaluate`, but the script runs with `principals`.
+    pub fn evaluate_for_principals(&self, global: *mut JSObject, source: &str, options: &EvaluateOptions,
+                                   principals: &Principals) -> Result<JSVal, JSError> {
+        let options = options.clone().principals(principals.raw());
+        self.evaluate_named(global, source, options.filename_str(), &options)
+    }
+
+    /// Evaluates `source` against `global` and converts the completion
+    /// value to `T`, e.g. `rt.evaluate_into::<f64>(global, "6 * 7", "inline", 1)`.
+    /// The caller must already be in `global`'s compartment.
+    pub fn evaluate_into<T: FromJSValConvertible>(&self, global: *mut JSObject, source: &str,
+                                                  filename: &str, line: u32) -> Result<T, EvalError> {
+        let options = EvaluateOptions::new().line(line);
+        let value = try!(self.evaluate_named(global, source, Some(filename), &options)
+                             .map_err(EvalError::Script));
+        T::from_jsval(self.cx, value).map_err(EvalError::Conversion)
+    }
+
+    /// Creates a global set up as `template` describes; see
+    /// `GlobalTemplate::instantiate`.
+    pub fn instantiate_global(&self, template: &GlobalTemplate) -> Result<*mut JSObject, JSError> {
+        template.instantiate(self)
+    }
+
+    /// Evaluates `source` against a fresh global with lazily resolved
+    /// standard classes, so nothing it does persists or can be seen by
+    /// other code. A string or object result is structured-cloned into
+    /// `target`'s compartment, and anything that can't be cloned, like a
+    /// function, is an error. An error has no `exception`, as it would
+    /// belong to the fresh global's compartment. The fresh global is
+    /// unrooted before this returns, so its compartment goes at the next
+    /// collection.
+    pub fn evaluate_isolated(&self, target: *mut JSObject, source: &str, filename: &str)
+                             -> Result<IsolatedResult, JSError> {
+        let options = GlobalOptions { standard_classes: StandardClasses::Lazy };
+        let global = try!(RootedObject::new(self.cx, try!(new_global_object(self.cx, None, &options))));
+        let result = with_compartment(self.context(), &global, || {
+            let options = EvaluateOptions::new().filename(filename);
+            let value = try!(self.evaluate(&global, source, &options));
+            if value.is_primitive() && !value.is_string() {
+                return Ok(IsolatedResult::Primitive(value));
+            }
+            clone_value_into(self.cx, value, target).map(IsolatedResult::Cloned)
+        });
+        // The exception would keep the fresh compartment alive, and can't be
+        // used from the caller's.
+        result.map_err(|mut error| {
+            error.drop_exception();
+            error
+        })
+    }
+
+    /// Evaluates `script` against `global`, discarding the completion value.
+    #[deprecated(note = "use run_script, or evaluate, to get the error")]
+    pub fn evaluate_script(&self, global: *mut JSObject, script: String,
+                           filename: String, line_num: usize)
+                           -> Result<(), ()> {
+        let line = try!(check_line_number(line_num).map_err(|_| ()));
+        self.run_script(global, &script, &filename, line).map_err(|_| ())
+    }
+
+    /// Evaluates `script` against `global`, discarding the completion value.
+    /// On failure the exception is taken off the context and returned.
+    ///
+    /// Once the runtime's scratch buffers have grown to fit, a successful
+    /// call doesn't allocate.
+    pub fn run_script(&self, global: *mut JSObject, script: &str, filename: &str, line: u32)
+                      -> Result<(), JSError> {
+        debug!("Evaluating script from {}", filename);
+        let options = EvaluateOptions::new().line(line).no_rval(true);
+        match self.evaluate_named(global, script, Some(filename), &options) {
+            Ok(_) => {
+                debug!("...ok!");
+                Ok(())
+            }
+            Err(error) => {
+                debug!("...err! {}", error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Evaluates `source`, bytes from anywhere, e.g. a fuzzer, against
+    /// `global`, turning everything that can go wrong into an error rather
+    /// than a panic. Invalid UTF-8 is replaced with U+FFFD. The script runs
+    /// in a request and `global`'s compartment, on a fuel budget: it fails
+    /// after a million loop iterations, or once the heap grows past 64 MiB,
+    /// at the same point on every run. Converting what it throws can run
+    /// more of its code, e.g. a `toString`, and happens on the same budget.
+    ///
+    /// Script that never loops, e.g. recursion that keeps catching its own
+    /// stack overflows, isn't metered; limits set with `set_limits` still
+    /// apply to bound it in time. Deeply nested source only fails cleanly
+    /// on a runtime with a `RuntimeOptions::native_stack_quota`.
+    pub fn evaluate_untrusted(&self, global: *mut JSObject, source: &[u8]) -> Result<(), JSError> {
+        let fuel = Fuel {
+            remaining: UNTRUSTED_FUEL,
+            max_heap_bytes: UNTRUSTED_MAX_HEAP_BYTES,
+        };
+        let previous = self.state.fuel.replace(Some(fuel));
+        unsafe { JS_TriggerOperationCallback(self.rt) };
+        let request = Request::new(self.cx);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let object = match unsafe { ObjectPtr::from_raw(global) } {
+                Some(object) => object,
+                None => return Err(JSError::new("evaluate_untrusted needs a global")),
+            };
+            try!(check_same_runtime(self.cx, global, "global"));
+            let source = String::from_utf8_lossy(source);
+            with_compartment(self.context(), object, || {
+                let mut result = Ok(());
+                // `evaluate` nested in a limited run of our own, so that a
+                // limit it exceeds stays noted until the error has been made.
+                self.run_limited(|| {
+                    let options = EvaluateOptions::new().filename("untrusted").no_rval(true);
+                    result = self.evaluate(object, &source, &options).map(|_| ());
+                    1
+                });
+                result
+            })
+        }));
+        unsafe { JS_ClearPendingException(self.cx) };
+        drop(request);
+        self.state.fuel.set(previous);
+        match result {
+            Ok(result) => result,
+            Err(payload) => Err(JSError::new(format!("Rust panic: {}", panic_message(&*payload)))),
+        }
+    }
+
+    /// Evaluates each `(source, filename, line)` of `scripts` against
+    /// `global` in turn, entering its compartment and a request once for
+    /// the whole batch. `result[i]` is the outcome of `scripts[i]`; with
+    /// `StopOnError` the results end at the first failure.
+    ///
+    /// Earlier completion values are rooted while later scripts run, but
+    /// not once the batch returns.
+    pub fn evaluate_batch(&self, global: *mut JSObject, scripts: &[(&str, &str, u32)], mode: BatchMode)
+                          -> Vec<Result<JSVal, JSError>> {
+        let mut results = Vec::with_capacity(scripts.len());
+        // Boxed so the roots' addresses stay put.
+        let mut roots: Vec<Box<JSVal>> = vec![];
+        let _request = Request::new(self.cx);
+        let object = unsafe { ObjectPtr::from_raw_nonnull(global) };
+        with_compartment(self.context(), object, || {
+            for &(source, filename, line) in scripts {
+                let options = EvaluateOptions::new().line(line);
+                let result = self.evaluate_named(global, source, Some(filename), &options);
+                let failed = result.is_err();
+                if let Ok(value) = result {
+                    let mut root = Box::new(value);
+                    unsafe {
+                        JS_AddNamedValueRoot(self.cx, &mut *root,
+                                             b"evaluate_batch\0".as_ptr() as *const c_char);
+                    }
+                    roots.push(root);
+                }
+                results.push(result);
+                if failed && mode == BatchMode::StopOnError {
+                    break;
+                }
+            }
+        });
+        for root in &mut roots {
+            unsafe { JS_RemoveValueRoot(self.cx, &mut **root) };
+        }
+        results
+    }
+
+    /// Sets the loader `require` gets module sources from, replacing any
+    /// earlier one for every global.
+    pub fn set_module_loader(&self, loader: Box<ModuleLoader>) {
+        self.modules.set_loader(loader);
+    }
+
+    /// Gives `global` a `require(specifier)` function that loads modules
+    /// through the loader set with `set_module_loader`; see `loader`. The
+    /// caller must already be in `global`'s compartment.
+    pub fn define_require(&self, global: *mut JSObject) -> Result<(), JSError> {
+        define_require(self.cx, global, self.modules.clone())
+    }
+}
 
 impl Drop for Runtime {
     fn drop(&mut self) {
+        self.check_thread();
+        *self.state.interrupt.rt.lock().unwrap() = ptr::null_mut();
+        self.stop_watchdog();
+        self.clear_default_global();
+        for root in self.named_globals.values_mut() {
+            unsafe { JS_RemoveObjectRoot(self.cx, &mut **root) };
+        }
+        self.named_globals.clear();
+        // Errors may outlive the runtime, and so must no longer touch it.
+        self.state.exception_roots.release(self.cx);
         unsafe {
             JS_DestroyContext(self.cx);
             JS_Finish(self.rt);
         }
-    }
+        // `state` is dropped after this, once the engine can no longer call
+        // back into it.
+        LIVE_RUNTIMES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+thread_local!(static THREAD_MARKER: u8 = 0);
+
+/// Identifies the calling thread by the address of a thread-local.
+fn current_thread() -> usize {
+    THREAD_MARKER.with(|marker| marker as *const u8 as usize)
+}
+
+/// The GC zeal mode and frequency runtimes start with under the
+/// `debugmozjs` feature: a collection every thousand allocations, often
+/// enough to catch unrooted values without slowing tests to a crawl.
+#[cfg(feature = "debugmozjs")]
+pub const DEBUG_GC_ZEAL: (u8, u32) = (2, 1000);
+
+/// Checks that a source of `length` code units (or bytes, for UTF-8) fits
+/// the engine's `unsigned` length parameters, and converts it. Longer
+/// sources would otherwise be silently cut short.
+pub fn check_source_length(length: usize) -> Result<c_uint, JSError> {
+    if length > c_uint::max_value() as usize {
+        return Err(JSError::new(format!("source of length {} is too long to compile", length)));
+    }
+    Ok(length as c_uint)
+}
+
+/// Checks that `line` fits the engine's `unsigned` line numbers, and
+/// converts it.
+pub fn check_line_number(line: usize) -> Result<c_uint, JSError> {
+    if line > c_uint::max_value() as usize {
+        return Err(JSError::new(format!("line number {} is too large", line)));
+    }
+    Ok(line as c_uint)
+}
+
+/// Checks that `count` arguments, or argument names, fit the engine's
+/// `unsigned` counts, and converts it.
+pub fn check_argument_count(count: usize) -> Result<c_uint, JSError> {
+    if count > c_uint::max_value() as usize {
+        return Err(JSError::new(format!("{} arguments are too many to pass", count)));
+    }
+    Ok(count as c_uint)
+}
+
+/// The engine's description of itself, like `JavaScript-C 1.8.5+ 2011-04-16`.
+pub fn engine_version_string() -> String {
+    unsafe {
+        let version = JS_GetImplementationVersion();
+        String::from_utf8_lossy(ffi::CStr::from_ptr(version).to_bytes()).into_owned()
+    }
+}
+
+/// How the SpiderMonkey the crate is linked against was built; see
+/// `Runtime::build_info`.
+///
+/// Only `threadsafe` is found out from the linked engine. This engine
+/// version has no way to ask whether it's a debug build, and its headers
+/// don't say either, so `debug` and `gc_zeal` are the crate's feature
+/// flags: what the crate was built to expect. `mozjs_sys` builds the engine
+/// with the same `debugmozjs` feature, so they only differ from the linked
+/// engine if it was swapped for another build.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// Whether the crate was built with the `debugmozjs` feature, for a
+    /// debug build of the engine.
+    pub debug: bool,
+    /// Whether the crate offers `Runtime::set_gc_zeal`, which it does with
+    /// the `debugmozjs` feature, as only debug builds export `JS_SetGCZeal`.
+    pub gc_zeal: bool,
+    /// Built with `JS_THREADSAFE`, which tracks requests.
+    pub threadsafe: bool,
+}
+
+#[cfg(feature = "debugmozjs")]
+fn set_debug_gc_zeal(cx: *mut JSContext) {
+    let (zeal, frequency) = DEBUG_GC_ZEAL;
+    unsafe { JS_SetGCZeal(cx, zeal, frequency) }
+}
+
+// Release builds of SpiderMonkey don't export `JS_SetGCZeal`.
+#[cfg(not(feature = "debugmozjs"))]
+fn set_debug_gc_zeal(_cx: *mut JSContext) {}
+
+/// A live `ErrorReporterGuard`, as `RuntimeState` keeps it.
+struct ErrorCapture {
+    reports: Rc<RefCell<Vec<JSError>>>,
+    /// The reporter to restore once the guard is gone.
+    previous: JSErrorReporter,
+}
+
+/// Collects reported errors until dropped; see `Runtime::capture_errors`.
+/// Dropping it, even while unwinding, restores the error reporter it
+/// replaced, and hands reports not taken to the guard it was nested in, or
+/// to the log.
+#[must_use]
+pub struct ErrorReporterGuard<'a> {
+    rt: &'a Runtime,
+    reports: Rc<RefCell<Vec<JSError>>>,
+}
+
+impl<'a> ErrorReporterGuard<'a> {
+    /// The errors reported so far, oldest first, leaving none.
+    pub fn take_reports(&self) -> Vec<JSError> {
+        mem::replace(&mut *self.reports.borrow_mut(), vec![])
+    }
+}
+
+impl<'a> Drop for ErrorReporterGuard<'a> {
+    fn drop(&mut self) {
+        // Usually the innermost, but guards may be dropped out of order, in
+        // which case the one nested in this takes over its reporter.
+        let mut captures = self.rt.state.error_captures.borrow_mut();
+        let i = match captures.iter().rposition(|capture| Rc::ptr_eq(&capture.reports, &self.reports)) {
+            Some(i) => i,
+            None => return,
+        };
+        let capture = captures.remove(i);
+        if i == captures.len() {
+            unsafe { JS_SetErrorReporter(self.rt.cx, capture.previous) };
+        } else {
+            captures[i].previous = capture.previous;
+        }
+        let reports = self.take_reports();
+        if i > 0 {
+            captures[i - 1].reports.borrow_mut().extend(reports);
+        } else {
+            for report in &reports {
+                log_reported_error(report);
+            }
+        }
+    }
+}
+
+/// The error reporter an `ErrorReporterGuard` installs: hands errors to the
+/// innermost guard, and warnings to the reporter it replaced.
+unsafe extern "C" fn capture_error(cx: *mut JSContext, msg: *const c_char, report: *mut JSErrorReport) {
+    catch_panic(ptr::null_mut(), || {
+        let state = runtime_state(JS_GetRuntime(cx));
+        let (reports, previous) = match state.error_captures.borrow().last() {
+            Some(capture) => (capture.reports.clone(), capture.previous),
+            None => return,
+        };
+        if (*report).flags & JSREPORT_WARNING != 0 {
+            if let Some(previous) = previous {
+                previous(cx, msg, report);
+            }
+            return;
+        }
+        let error = error_from_report(cx, msg, report);
+        reports.borrow_mut().push(error.clone());
+        remember_reported_error(state, error);
+    });
 }
 
-pub unsafe extern fn reportError(_cx: *mut JSContext, msg: *const c_char, report: *mut JSErrorReport) {
+/// The `JSError` for `report`, whose message is `msg`.
+unsafe fn error_from_report(cx: *mut JSContext, msg: *const c_char, report: *mut JSErrorReport) -> JSError {
     let fnptr = (*report).filename;
-    let fname = if !fnptr.is_null() {
-        let c_str = ffi::CStr::from_ptr(fnptr);
-        str::from_utf8(c_str.to_bytes()).ok().unwrap().to_string()
+    let filename = if fnptr.is_null() {
+        None
+    } else {
+        Some(String::from_utf8_lossy(ffi::CStr::from_ptr(fnptr).to_bytes()).into_owned())
+    };
+    let msg = if msg.is_null() {
+        "unknown error".to_string()
     } else {
-        "none".to_string()
+        String::from_utf8_lossy(ffi::CStr::from_ptr(msg).to_bytes()).into_owned()
     };
-    let lineno = (*report).lineno;
-    let c_str = ffi::CStr::from_ptr(msg);
-    let msg = str::from_utf8(c_str.to_bytes()).ok().unwrap().to_string();
-    error!("Error at {}:{}: {}\n", fname, lineno, msg);
+    // The engine reports running out of memory without an exception.
+    let out_of_memory = (*report).errorNumber == JSMSG_OUT_OF_MEMORY;
+    JSError {
+        kind: if out_of_memory { ErrorKind::OutOfMemory } else { error_kind_for_report(report) },
+        source_map_url: filename.as_ref().and_then(|filename| source_map_url(cx, filename)),
+        filename: filename,
+        lineno: (*report).lineno as u32,
+        ..JSError::new(msg)
+    }
+}
+
+fn log_reported_error(error: &JSError) {
+    let filename = error.filename.as_ref().map_or("none", |filename| &filename[..]);
+    error!("Error at {}:{}: {}\n", filename, error.lineno, error.message);
+}
+
+/// Remembers `error`, just reported, for `Runtime::evaluation_error`, first
+/// calling the out-of-memory callback if that's what it is.
+fn remember_reported_error(state: &RuntimeState, error: JSError) {
+    if error.is_out_of_memory() {
+        // Taken out of its cell while it runs, so that reentering the
+        // reporter from it can't borrow the cell twice.
+        let callback = state.oom_callback.borrow_mut().take();
+        if let Some(mut callback) = callback {
+            callback();
+            let mut slot = state.oom_callback.borrow_mut();
+            if slot.is_none() {
+                *slot = Some(callback);
+            }
+        }
+    }
+    *state.reported_error.borrow_mut() = Some(error);
+}
+
+pub unsafe extern fn reportError(cx: *mut JSContext, msg: *const c_char, report: *mut JSErrorReport) {
+    // The reporter runs while an error is being reported, so a panic here
+    // is only logged.
+    catch_panic(ptr::null_mut(), || {
+        let error = error_from_report(cx, msg, report);
+        log_reported_error(&error);
+        // Remember errors, not warnings, for `Runtime::evaluation_error`.
+        let rt = JS_GetRuntime(cx);
+        if (*report).flags & JSREPORT_WARNING == 0 && !JS_GetRuntimePrivate(rt).is_null() {
+            remember_reported_error(runtime_state(rt), error);
+        }
+    });
+}
+
+/// `Runtime::gc_bytes` for the runtime `cx` belongs to, for natives that
+/// only have the context.
+pub fn gc_bytes(cx: *mut JSContext) -> usize {
+    unsafe { JS_GetGCParameter(JS_GetRuntime(cx), JSGC_BYTES) as usize }
+}
+
+/// `Runtime::set_gc_zeal` for the runtime `cx` belongs to, for natives
+/// that only have the context.
+#[cfg(feature = "debugmozjs")]
+pub fn set_gc_zeal(cx: *mut JSContext, zeal: u8, frequency: u32) {
+    unsafe { JS_SetGCZeal(cx, zeal, frequency) }
 }
 
-pub fn with_compartment<R, F: FnMut() -> R>(cx: *mut JSContext, object: *mut JSObject, mut cb: F) -> R {
+/// Collects only the compartment of `global`, which is much cheaper than a
+/// full collection when the runtime has many. Does nothing from inside a
+/// collection.
+///
+/// While an incremental collection is under way a compartment can't be
+/// collected on its own; a full, non-incremental collection runs instead,
+/// with a warning, and this returns false.
+pub fn gc_compartment(cx: *mut JSContext, global: *mut JSObject) -> bool {
     unsafe {
-        let call = JS_EnterCrossCompartmentCall(cx, object);
-        let result = cb();
-        JS_LeaveCrossCompartmentCall(call);
-        result
+        let rt = JS_GetRuntime(cx);
+        if runtime_state(rt).in_gc.get() {
+            return false;
+        }
+        if CompartmentGC(rt, global) {
+            return true;
+        }
+        warn!("cannot collect a single compartment during an incremental GC; running a full GC");
+        JS_GC(rt);
+        false
+    }
+}
+
+/// The limit set with `Runtime::set_max_conversion_length` on the runtime
+/// `cx` belongs to, or `DEFAULT_MAX_CONVERSION_LENGTH` if the runtime
+/// wasn't created by a `Runtime`.
+pub fn max_conversion_length(cx: *mut JSContext) -> usize {
+    unsafe {
+        let rt = JS_GetRuntime(cx);
+        if JS_GetRuntimePrivate(rt).is_null() {
+            return DEFAULT_MAX_CONVERSION_LENGTH;
+        }
+        runtime_state(rt).max_conversion_length.get()
+    }
+}
+
+/// The roots `JSError`s take for their exceptions on the runtime `cx`
+/// belongs to, or `None` if it isn't a `Runtime`'s.
+pub fn exception_roots(cx: *mut JSContext) -> Option<Arc<ExceptionRoots>> {
+    unsafe {
+        let rt = JS_GetRuntime(cx);
+        if JS_GetRuntimePrivate(rt).is_null() {
+            return None;
+        }
+        Some(runtime_state(rt).exception_roots.clone())
+    }
+}
+
+/// Whether the last error reported on the runtime `cx` belongs to was the
+/// engine running out of memory, taking it if so. Always false if the
+/// runtime isn't a `Runtime`.
+pub fn take_reported_out_of_memory(cx: *mut JSContext) -> bool {
+    unsafe {
+        let rt = JS_GetRuntime(cx);
+        if JS_GetRuntimePrivate(rt).is_null() {
+            return false;
+        }
+        let mut reported = runtime_state(rt).reported_error.borrow_mut();
+        if reported.as_ref().map_or(false, |error| error.is_out_of_memory()) {
+            *reported = None;
+            return true;
+        }
+        false
+    }
+}
+
+/// The source map URL of the last script evaluated from `filename` on the
+/// runtime `cx` belongs to, if it had one and is still remembered; see
+/// `SourceMaps`.
+pub fn source_map_url(cx: *mut JSContext, filename: &str) -> Option<String> {
+    unsafe {
+        let rt = JS_GetRuntime(cx);
+        if JS_GetRuntimePrivate(rt).is_null() {
+            return None;
+        }
+        runtime_state(rt).source_maps.borrow().get(filename).cloned()
+    }
+}
+
+/// Tells the GC that `nbytes` were allocated outside its heap on behalf of
+/// JS objects, e.g. buffers owned by natives. Once enough has been reported
+/// since the last collection, the engine schedules one, which runs at the
+/// next `JS_MaybeGC` or operation callback.
+///
+/// The count can only go up: memory freed when those objects are
+/// finalized can't be reported back, but the counter starts from zero
+/// after every collection anyway.
+pub fn update_malloc_counter(cx: *mut JSContext, nbytes: usize) {
+    unsafe { JS_updateMallocCounter(cx, nbytes as size_t) }
+}
+
+/// Runs `cb` inside `object`'s compartment; see `pointers` for what `cx`
+/// and `object` can be.
+pub fn with_compartment<C, O, R, F>(cx: C, object: O, mut cb: F) -> R
+    where C: AsContextPtr, O: AsObjectPtr, F: FnMut() -> R {
+    let (cx, object) = (cx.context_ptr().as_raw(), object.object_ptr().as_raw());
+    let _call = CompartmentCall(unsafe { JS_EnterCrossCompartmentCall(cx, object) });
+    cb()
+}
+
+/// `with_compartment` for raw pointers, which must be live.
+#[deprecated(note = "use with_compartment with typed pointers")]
+pub unsafe fn with_compartment_raw<R, F>(cx: *mut JSContext, object: *mut JSObject, cb: F) -> R
+    where F: FnMut() -> R {
+    with_compartment(ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(object), cb)
+}
+
+/// Leaves the compartment `with_compartment` entered when dropped, so a
+/// panicking callback doesn't leave the context inside it.
+struct CompartmentCall(*mut JSCrossCompartmentCall);
+
+impl Drop for CompartmentCall {
+    fn drop(&mut self) {
+        unsafe { JS_LeaveCrossCompartmentCall(self.0) };
     }
 }
 
 #[cfg(test)]
-pub mod test {
-    use super::Runtime;
+mod test {
+    use libc::{c_char, c_uint};
+    use std::fs;
+    use std::io::Read;
+    use std::cell::RefCell;
+    use std::iter;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::ptr;
+    use std::rc::Rc;
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+    use super::{Runtime, RuntimeCreationError, RuntimesStillAlive, with_compartment, gc_compartment};
+    use super::{Limits, LimitExceeded, EvaluateOptions, IncrementalGCProgress, BatchMode};
+    use super::{shutdown_engine, MAX_SCRATCH_CAPACITY, EvalError, ContextFlags, engine_version_string};
+    use super::{check_source_length, check_line_number, check_argument_count};
+    use super::{IsolatedResult, RuntimeOptions, NATIVE_STACK_QUOTA};
+    use super::{max_conversion_length, DEFAULT_MAX_CONVERSION_LENGTH, Request};
+    use super::{source_map_url, MAX_SOURCE_MAPS};
+    use strings::new_string;
+    use error::ErrorKind;
+    use glue::IsIncrementalGCInProgress;
+    use {JSOPTION_METHODJIT, JSOPTION_TYPE_INFERENCE, JSOPTION_STRICT, JSOPTION_DONT_REPORT_UNCAUGHT};
+    use jsapi::*;
+    use jsval::{JSVal, UndefinedValue, Int32Value};
+    use global::GlobalOptions;
+    use conversions::{to_string, check_length};
+    use testing::{new_global, evaluate, with_test_runtime, object_ptr};
+    use object::{get_path, call_method, call_function};
+    use closure::define_closure;
+    use hooks::FunctionCallEvent;
+    use hooks::CallEventKind::{Enter, Leave};
 
     #[test]
     pub fn dummy() {
         let _rt = Runtime::new();
     }
 
+    #[test]
+    fn concurrent_runtime_creation() {
+        let threads: Vec<_> = (0..8).map(|_| {
+            thread::spawn(|| {
+                for _ in 0..16 {
+                    let rt = Runtime::new();
+                    let global = new_global(&rt);
+                    with_compartment(rt.context(), object_ptr(global), || {
+                        assert_eq!(evaluate(&rt, global, "1 + 1").unwrap().to_number(), 2.0);
+                    });
+                }
+            })
+        }).collect();
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn scripts_run_simultaneously_on_four_runtimes() {
+        let barrier = Arc::new(Barrier::new(4));
+        let threads: Vec<_> = (0..4).map(|i| {
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                let rt = Runtime::new();
+                let global = new_global(&rt);
+                with_compartment(rt.context(), object_ptr(global), || {
+                    let setup = format!("var id = {}; var total = 0;", i);
+                    evaluate(&rt, global, &setup).unwrap();
+                    // Start all the loops at once so the runtimes really overlap.
+                    barrier.wait();
+                    let total = evaluate(&rt, global,
+                                         "for (var j = 0; j < 100000; j++) { total += id; } \
+                                          total").unwrap();
+                    assert_eq!(total.to_number(), 100000.0 * i as f64);
+                });
+            })
+        }).collect();
+        for thread in threads.into_iter() {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn low_memory_notification_frees_garbage() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            evaluate(&rt, global, "(function() { \
+                                    var junk = []; \
+                                    for (var i = 0; i < 100000; i++) { junk.push({ i: i }); } \
+                                  })()").unwrap();
+        });
+        let before = rt.gc_bytes();
+        rt.notify_low_memory();
+        assert!(rt.gc_bytes() < before, "{} bytes before, {} after", before, rt.gc_bytes());
+        // With nothing to do, idle notifications are harmless.
+        rt.notify_idle(Duration::from_millis(5));
+        rt.notify_idle(Duration::from_millis(0));
+    }
+
+    #[test]
+    fn incremental_gc_interleaved_with_script() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        rt.set_gc_slice_budget(1);
+        let mode = unsafe { JS_GetGCParameter(rt.rt(), JSGC_MODE) };
+        assert!(mode != JSGC_MODE_INCREMENTAL);
+        with_compartment(rt.context(), object_ptr(global), || {
+            evaluate(&rt, global, "var live = []; \
+                                   for (var i = 0; i < 10000; i++) live.push({i: i}); \
+                                   (function() { \
+                                     for (var j = 0; j < 100000; j++) ({j: j}); \
+                                   })()").unwrap();
+            let mut progress = rt.begin_incremental_gc();
+            for slice in 0..5 {
+                // Script mutates the heap between slices, behind the marker's back.
+                evaluate(&rt, global, &format!("for (var i = 0; i < 1000; i++) \
+                                                  live[i * 10] = {{i: i * 10, slice: {}}};", slice))
+                    .unwrap();
+                if progress == IncrementalGCProgress::NotIncremental {
+                    continue;
+                }
+                progress = rt.incremental_gc_slice(1);
+            }
+            rt.finish_incremental_gc();
+            assert!(!unsafe { IsIncrementalGCInProgress(rt.rt()) });
+            assert_eq!(unsafe { JS_GetGCParameter(rt.rt(), JSGC_MODE) }, mode);
+
+            unsafe { JS_GC(rt.rt()) };
+            let check = "live.length == 10000 && \
+                         live.every(function(o, i) { return o.i === i; }) && \
+                         live[0].slice === 4";
+            assert!(evaluate(&rt, global, check).unwrap().to_boolean());
+        });
+    }
+
+    #[test]
+    fn cancel_infinite_loop_from_another_thread() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let token = rt.cancellation_token();
+        let canceller = {
+            let token = token.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                token.cancel();
+            })
+        };
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert!(evaluate(&rt, global, "while (true) {}").is_err());
+            canceller.join().unwrap();
+
+            // Still cancelled: the next loop doesn't get to run either.
+            assert!(token.is_cancelled());
+            assert!(evaluate(&rt, global, "for (;;) {}").is_err());
+
+            token.reset();
+            let ten = evaluate(&rt, global, "var i = 0; while (i < 10) { i++; } i").unwrap();
+            assert_eq!(ten.to_number(), 10.0);
+        });
+    }
+
+    #[test]
+    fn time_limit_stops_evaluation() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        rt.set_limits(Limits { max_duration: Some(Duration::from_millis(50)), .. Limits::default() });
+        with_compartment(rt.context(), object_ptr(global), || {
+            let err = rt.evaluate_cached(global, "while (true) {}", "loop.js").unwrap_err();
+            assert_eq!(err.message, LimitExceeded::Time.to_string());
+            // The next evaluation gets a fresh budget.
+            assert_eq!(rt.evaluate_cached(global, "6 * 7", "quick.js").unwrap().to_number(), 42.0);
+            assert!(rt.run_script(global, "for (;;) {}", "loop.js", 1)
+                      .is_err());
+        });
+    }
+
+    #[test]
+    fn heap_limit_stops_evaluation() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let limit = rt.gc_bytes() + 8 * 1024 * 1024;
+        rt.set_limits(Limits { max_heap_bytes: Some(limit), .. Limits::default() });
+        with_compartment(rt.context(), object_ptr(global), || {
+            let err = rt.evaluate_cached(global, "var a = []; for (;;) { a.push({}); }", "grow.js")
+                        .unwrap_err();
+            assert_eq!(err.message, LimitExceeded::Heap.to_string());
+        });
+    }
+
+    #[test]
+    fn objects_from_another_runtime_are_rejected() {
+        let first = Runtime::new();
+        let second = Runtime::new();
+        let global = new_global(&first);
+        let error = second.run_script(global, "1", "crossed.js", 1).unwrap_err();
+        assert!(error.is_runtime_mismatch(), "{}", error.message);
+        assert!(error.message.contains("global"), "{}", error.message);
+        assert!(second.compile_script(global, "1", &EvaluateOptions::new()).is_err());
+        assert!(second.evaluate_untrusted(global, b"1").unwrap_err().is_runtime_mismatch());
+        let other_global = new_global(&second);
+        let options = EvaluateOptions::new().scope(global);
+        assert!(second.evaluate(object_ptr(other_global), "1", &options).unwrap_err().is_runtime_mismatch());
+
+        let (object, fun) = with_compartment(first.context(), object_ptr(global), || {
+            let object = evaluate(&first, global, "({ f: function () { return 1; } })").unwrap().to_object();
+            (object, get_path(&first, object_ptr(object), "f").unwrap())
+        });
+        let object = object_ptr(object);
+        assert!(get_path(&second, object, "f").unwrap_err().is_runtime_mismatch());
+        assert!(call_method(&second, object, "f", &[]).unwrap_err().is_runtime_mismatch());
+        with_compartment(second.context(), object_ptr(other_global), || {
+            let no_args: &[JSVal] = &[];
+            let error = call_function(&second, Some(object_ptr(other_global)), fun, no_args).unwrap_err();
+            assert!(error.is_runtime_mismatch());
+        });
+        // Both still work with their own objects.
+        with_compartment(first.context(), object_ptr(global), || {
+            assert_eq!(call_method(&first, object, "f", &[]).unwrap().to_int32(), 1);
+        });
+    }
+
+    #[test]
+    fn untrusted_input_never_panics() {
+        let options = RuntimeOptions {
+            native_stack_quota: Some(NATIVE_STACK_QUOTA),
+            .. RuntimeOptions::default()
+        };
+        let rt = Runtime::with_options(&options).unwrap();
+        let limits = Limits { max_duration: Some(Duration::from_secs(30)), .. Limits::default() };
+        rt.set_limits(limits);
+        let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus/evaluate_untrusted");
+        let mut entries: Vec<_> = fs::read_dir(corpus).unwrap().map(|entry| entry.unwrap().path()).collect();
+        entries.sort();
+        assert!(!entries.is_empty());
+        for path in entries {
+            let mut source = vec!();
+            fs::File::open(&path).unwrap().read_to_end(&mut source).unwrap();
+            let global = new_global(&rt);
+            let result = rt.evaluate_untrusted(global, &source);
+            if let Err(ref error) = result {
+                assert!(!error.message.starts_with("Rust panic"), "{:?}: {}", path, error.message);
+            }
+            // The runtime is still usable, with its own limits back.
+            assert_eq!(rt.limits(), limits);
+            with_compartment(rt.context(), object_ptr(global), || {
+                assert_eq!(evaluate(&rt, global, "6 * 7").unwrap().to_number(), 42.0, "after {:?}", path);
+            });
+            unsafe { JS_GC(rt.rt()) };
+        }
+        assert!(rt.evaluate_untrusted(new_global(&rt), b"var x = 1; x").is_ok());
+        assert!(rt.evaluate_untrusted(ptr::null_mut(), b"1").is_err());
+    }
+
+    #[test]
+    fn untrusted_evaluation_leaves_no_watchdog_behind() {
+        let rt = Runtime::new();
+        assert!(rt.evaluate_untrusted(new_global(&rt), b"1").is_ok());
+        assert_eq!(rt.limits(), Limits::default());
+        assert!(rt.watchdog.borrow().is_none());
+    }
+
+    #[test]
+    fn untrusted_script_runs_out_of_fuel() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let mut iterations = vec!();
+        for _ in 0..2 {
+            let error = rt.evaluate_untrusted(global, b"var i = 0; while (true) { i++; }").unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Termination);
+            assert_eq!(error.message, LimitExceeded::Fuel.to_string());
+            with_compartment(rt.context(), object_ptr(global), || {
+                iterations.push(evaluate(&rt, global, "i").unwrap().to_number());
+            });
+        }
+        // Stopped at the same iteration every time.
+        assert!(iterations[0] > 0.0);
+        assert_eq!(iterations[0], iterations[1]);
+        assert!(rt.state.fuel.get().is_none());
+        assert!(rt.evaluate_untrusted(global, b"for (var j = 0; j < 1000; j++) {}").is_ok());
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert_eq!(evaluate(&rt, global, "for (var k = 0; k < 2000000; k++) {} k").unwrap().to_number(),
+                       2000000.0);
+        });
+    }
+
+    #[test]
+    fn cancelling_after_the_runtime_is_gone_is_harmless() {
+        let token = Runtime::new().cancellation_token();
+        token.cancel();
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn evaluate_options_set_filename_and_line() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let options = EvaluateOptions::new().filename("options.js").line(10);
+            let err = rt.evaluate(object_ptr(global), "var a = 1;\n\nthrow new Error('boom');", &options)
+                        .unwrap_err();
+            assert_eq!(err.filename, Some("options.js".to_string()));
+            assert_eq!(err.lineno, 12);
+
+            let options = options.utf8(true);
+            assert_eq!(rt.evaluate(object_ptr(global), "6 * 7", &options).unwrap().to_number(), 42.0);
+            assert!(rt.evaluate(object_ptr(global), "6 * 7", &options.no_rval(true)).unwrap().is_undefined());
+        });
+    }
+
+    #[test]
+    fn source_maps_are_recorded_once_compiled_and_bounded() {
+        with_test_runtime(|rt, global| {
+            let options = EvaluateOptions::new().filename("app.js");
+            rt.evaluate(object_ptr(global), "1;\n//# sourceMappingURL=good.map", &options).unwrap();
+            let error = rt.evaluate(object_ptr(global), "(\n//# sourceMappingURL=broken.map", &options)
+                          .unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Syntax);
+            assert_eq!(source_map_url(rt.cx(), "app.js"), Some("good.map".to_string()));
+            let source = "throw new Error('late');\n//# sourceMappingURL=late.map";
+            let error = rt.evaluate(object_ptr(global), source, &options).unwrap_err();
+            assert_eq!(error.source_map_url, Some("late.map".to_string()));
+
+            for i in 0..MAX_SOURCE_MAPS {
+                let options = EvaluateOptions::new().filename(&format!("file{}.js", i));
+                rt.evaluate(object_ptr(global), "//# sourceMappingURL=file.map", &options).unwrap();
+            }
+            assert_eq!(rt.state.source_maps.borrow().urls.len(), MAX_SOURCE_MAPS);
+            assert_eq!(source_map_url(rt.cx(), "app.js"), None);
+            assert_eq!(source_map_url(rt.cx(), "file0.js"), Some("file.map".to_string()));
+        });
+    }
+
+    #[test]
+    fn requests_end_when_their_code_panics() {
+        let rt = Runtime::new();
+        // Only a threadsafe build tracks requests.
+        if !rt.build_info().threadsafe {
+            return;
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _request = Request::new(rt.cx());
+            assert!(unsafe { JS_IsInRequest(rt.rt()) } != 0);
+            panic!("in a request");
+        }));
+        assert!(result.is_err());
+        assert!(unsafe { JS_IsInRequest(rt.rt()) } == 0);
+    }
+
+    #[test]
+    fn evaluate_batch_keeps_results_aligned() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let sources: Vec<String> = (0..100).map(|i| {
+            if i == 49 {
+                "throw new Error('step 50');".to_string()
+            } else {
+                format!("var steps = (this.steps || 0) + 1; {}", i)
+            }
+        }).collect();
+        let filenames: Vec<String> = (0..100).map(|i| format!("step{}.js", i)).collect();
+        let scripts: Vec<(&str, &str, u32)> = sources.iter().zip(filenames.iter())
+                                                     .map(|(source, filename)| (&source[..], &filename[..], 1))
+                                                     .collect();
+
+        let results = rt.evaluate_batch(global, &scripts, BatchMode::StopOnError);
+        assert_eq!(results.len(), 50);
+        for (i, result) in results[..49].iter().enumerate() {
+            assert_eq!(result.as_ref().unwrap().to_int32(), i as i32);
+        }
+        let err = results[49].as_ref().unwrap_err();
+        assert_eq!(err.filename, Some("step49.js".to_string()));
+        assert!(err.message.contains("step 50"), "{}", err.message);
+
+        let results = rt.evaluate_batch(global, &scripts, BatchMode::ContinueOnError);
+        assert_eq!(results.len(), 100);
+        for (i, result) in results.iter().enumerate() {
+            if i == 49 {
+                assert!(result.is_err());
+            } else {
+                assert_eq!(result.as_ref().unwrap().to_int32(), i as i32);
+            }
+        }
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert_eq!(evaluate(&rt, global, "steps").unwrap().to_int32(), 49 + 99);
+        });
+    }
+
+    #[test]
+    fn panic_inside_compartment_leaves_it() {
+        let rt = Runtime::new();
+        let first = new_global(&rt);
+        let second = new_global(&rt);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            with_compartment(rt.context(), object_ptr(first), || {
+                evaluate(&rt, first, "var where = 'first'").unwrap();
+                panic!("boom");
+            })
+        }));
+        assert!(result.is_err());
+        assert_eq!(rt.evaluation_depth.get(), 0);
+        with_compartment(rt.context(), object_ptr(second), || {
+            evaluate(&rt, second, "var where = 'second'").unwrap();
+            rt.run_script(second, "where += '!'", "second.js", 1).unwrap();
+            assert!(evaluate(&rt, second, "where == 'second!'").unwrap().to_boolean());
+        });
+        with_compartment(rt.context(), object_ptr(first), || {
+            assert!(evaluate(&rt, first, "where == 'first'").unwrap().to_boolean());
+        });
+    }
+
+    /// Dropping the runtime while unwinding out of a compartment must not
+    /// trip the engine's assertions.
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn panic_inside_compartment_unwinds_cleanly() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            evaluate(&rt, global, "1 + 1").unwrap();
+            panic!("boom");
+        });
+    }
+
+    #[test]
+    fn evaluation_reuses_scratch_buffers() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let options = EvaluateOptions::new().filename("handler.js");
+            rt.evaluate(object_ptr(global), "var frames = 0;", &options).unwrap();
+            let chars = rt.scratch_chars.borrow().as_ptr();
+            let filename = rt.scratch_filename.borrow().as_ptr();
+            for _ in 0..100 {
+                rt.evaluate(object_ptr(global), "frames++;", &options).unwrap();
+                assert_eq!(rt.scratch_chars.borrow().as_ptr(), chars);
+                assert_eq!(rt.scratch_filename.borrow().as_ptr(), filename);
+            }
+            assert_eq!(rt.evaluate(object_ptr(global), "frames", &options).unwrap().to_int32(), 100);
+
+            // A script bigger than the cap doesn't stay allocated.
+            let padding: String = iter::repeat(' ').take(MAX_SCRATCH_CAPACITY).collect();
+            let big = format!("frames{}", padding);
+            assert_eq!(rt.evaluate(object_ptr(global), &big, &options).unwrap().to_int32(), 100);
+            assert_eq!(rt.scratch_chars.borrow().capacity(), 0);
+
+            let options = EvaluateOptions::new().filename("a\0b");
+            let err = rt.evaluate(object_ptr(global), "1", &options).unwrap_err();
+            assert!(err.message.contains("invalid filename"), "{}", err.message);
+        });
+    }
+
+    #[test]
+    fn evaluate_options_override_the_version() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            // `let` is only a keyword from JavaScript 1.7 on.
+            let source = "var let = 3; let";
+            assert!(rt.evaluate(object_ptr(global), source, &EvaluateOptions::new()).is_err());
+            let options = EvaluateOptions::new().version(JSVersion::JSVERSION_1_6);
+            assert_eq!(rt.evaluate(object_ptr(global), source, &options).unwrap().to_int32(), 3);
+        });
+    }
+
+    #[test]
+    fn jit_toggles_leave_other_options_alone() {
+        let rt = Runtime::new();
+        let jit = JSOPTION_METHODJIT | JSOPTION_TYPE_INFERENCE;
+        let before = unsafe { JS_GetOptions(rt.cx()) };
+        assert_eq!(before & jit, jit);
+
+        rt.set_jit_enabled(false, true);
+        assert_eq!(unsafe { JS_GetOptions(rt.cx()) }, before & !JSOPTION_METHODJIT);
+
+        rt.set_jit_enabled(true, false);
+        assert_eq!(unsafe { JS_GetOptions(rt.cx()) }, before & !JSOPTION_TYPE_INFERENCE);
+
+        rt.set_jit_enabled(true, true);
+        assert_eq!(unsafe { JS_GetOptions(rt.cx()) }, before);
+    }
+
+    #[test]
+    fn oversized_sources_are_rejected() {
+        assert_eq!(check_source_length(0).unwrap(), 0);
+        assert_eq!(check_source_length(c_uint::max_value() as usize).unwrap(), c_uint::max_value());
+        if cfg!(target_pointer_width = "64") {
+            let error = check_source_length(c_uint::max_value() as usize + 1).unwrap_err();
+            assert!(error.message.contains("too long"), "{}", error.message);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn oversized_line_numbers_and_counts_are_rejected() {
+        assert_eq!(check_line_number(1).unwrap(), 1);
+        assert_eq!(check_line_number(c_uint::max_value() as usize).unwrap(), c_uint::max_value());
+        assert_eq!(check_argument_count(3).unwrap(), 3);
+        if cfg!(target_pointer_width = "64") {
+            let error = check_line_number(c_uint::max_value() as usize + 1).unwrap_err();
+            assert!(error.message.contains("too large"), "{}", error.message);
+            assert!(check_argument_count(c_uint::max_value() as usize + 1).is_err());
+            with_test_runtime(|rt, global| {
+                let line = c_uint::max_value() as usize + 1;
+                assert_eq!(rt.evaluate_script(global, "1".to_string(), "big.js".to_string(), line), Err(()));
+            });
+        }
+    }
+
+    #[test]
+    fn engine_describes_itself() {
+        let version = engine_version_string();
+        assert!(version.contains("JavaScript"), "{}", version);
+
+        let rt = Runtime::new();
+        assert_eq!(rt.version(), JSVersion::JSVERSION_LATEST);
+        let info = rt.build_info();
+        assert_eq!(info.debug, cfg!(feature = "debugmozjs"));
+        assert_eq!(info.gc_zeal, info.debug);
+        // Probing leaves the context outside any request.
+        assert_eq!(unsafe { JS_IsInRequest(rt.rt()) }, 0);
+    }
+
+    #[test]
+    fn context_flags_change_one_flag_at_a_time() {
+        let rt = Runtime::new();
+        // JSOPTION_PRIVATE_IS_NSISUPPORTS, which the crate has no name for.
+        let unnamed = ContextFlags::from_bits(1 << 3);
+        let mut flags = rt.context_flags();
+        assert!(flags.contains(ContextFlags::VAROBJFIX | ContextFlags::DONT_REPORT_UNCAUGHT));
+        flags.insert(unnamed);
+        rt.set_context_flags(flags);
+        let before = rt.context_flags();
+        assert!(before.contains(unnamed));
+
+        let mut strict = before;
+        strict.insert(ContextFlags::STRICT);
+        rt.set_context_flags(strict);
+        assert_eq!(rt.context_flags().bits(), before.bits() | JSOPTION_STRICT);
+        let mut cleared = rt.context_flags();
+        cleared.remove(ContextFlags::STRICT);
+        rt.set_context_flags(cleared);
+        assert_eq!(rt.context_flags(), before);
+
+        rt.set_report_uncaught(true);
+        assert_eq!(rt.context_flags().bits(), before.bits() & !JSOPTION_DONT_REPORT_UNCAUGHT);
+        assert!(rt.context_flags().contains(unnamed));
+        rt.set_report_uncaught(false);
+        assert_eq!(rt.context_flags(), before);
+    }
+
+    #[test]
+    fn uncaught_exception_is_returned_when_not_reported() {
+        let options = RuntimeOptions { report_uncaught: false, .. RuntimeOptions::default() };
+        let rt = Runtime::with_options(&options).unwrap();
+        let global = new_global(&rt);
+        assert!(!rt.reports_uncaught());
+        with_compartment(rt.context(), object_ptr(global), || {
+            let reporter = rt.capture_errors();
+            let err = rt.run_script(global, "throw new Error('boom');",
+                                    "throw.js", 1).unwrap_err();
+            assert_eq!(err.message, "Error: boom");
+            assert_eq!(err.filename, Some("throw.js".to_string()));
+            assert_eq!(unsafe { JS_IsExceptionPending(rt.cx()) }, 0);
+            // The reporter never saw it.
+            assert!(reporter.take_reports().is_empty());
+        });
+    }
+
+    #[test]
+    fn uncaught_exception_is_reported_when_enabled() {
+        let options = RuntimeOptions { report_uncaught: true, .. RuntimeOptions::default() };
+        let rt = Runtime::with_options(&options).unwrap();
+        let global = new_global(&rt);
+        assert!(rt.reports_uncaught());
+        with_compartment(rt.context(), object_ptr(global), || {
+            let reporter = rt.capture_errors();
+            let err = rt.run_script(global, "throw new Error('boom');",
+                                    "throw.js", 1).unwrap_err();
+            let reports = reporter.take_reports();
+            assert_eq!(reports.len(), 1);
+            assert_eq!(reports[0].message, "Error: boom");
+            assert_eq!(reports[0].lineno, 1);
+            // The evaluation's error is the reported one.
+            assert_eq!(err.message, reports[0].message);
+            assert_eq!(err.filename, Some("throw.js".to_string()));
+            assert_eq!(unsafe { JS_IsExceptionPending(rt.cx()) }, 0);
+
+            rt.set_report_uncaught(false);
+            let err = rt.evaluate_cached(global, "throw 'again';", "again.js").unwrap_err();
+            assert_eq!(err.message, "again");
+        });
+    }
+
+    /// A native that fails to propagate its error.
+    unsafe extern "C" fn leave_exception(cx: *mut JSContext, _argc: c_uint, _vp: *mut JSVal) -> JSBool {
+        JS_SetPendingException(cx, Int32Value(7));
+        1
+    }
+
+    #[test]
+    fn stale_exception_is_reported_before_evaluating() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut vp = [UndefinedValue(); 2];
+            unsafe {
+                leave_exception(rt.cx(), 0, vp.as_mut_ptr());
+                assert!(JS_IsExceptionPending(rt.cx()) != 0);
+            }
+            assert_eq!(rt.evaluate_cached(global, "6 * 7", "quick.js").unwrap().to_number(), 42.0);
+            assert_eq!(unsafe { JS_IsExceptionPending(rt.cx()) }, 0);
+
+            unsafe { leave_exception(rt.cx(), 0, vp.as_mut_ptr()) };
+            let err = rt.run_script(global, "throw 'fresh';",
+                                    "fresh.js", 1).unwrap_err();
+            assert_eq!(err.message, "fresh");
+
+            rt.set_report_uncaught(true);
+            unsafe { leave_exception(rt.cx(), 0, vp.as_mut_ptr()) };
+            let err = rt.run_script(global, "throw 'reported';",
+                                    "fresh.js", 1).unwrap_err();
+            assert!(err.message.contains("reported"), "{}", err.message);
+        });
+    }
+
+    #[test]
+    fn coverage_skips_untaken_branch() {
+        let rt = Runtime::new();
+        rt.enable_coverage();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            rt.run_script(global, "var x = 1;\n\
+                                   if (x > 5) {\n\
+                                     x = 100;\n\
+                                   }\n\
+                                   x;\n", "branch.js", 1).unwrap();
+        });
+        let coverage = rt.take_coverage();
+        let lines = &coverage["branch.js"];
+        assert!(lines.contains(&1));
+        assert!(lines.contains(&2));
+        assert!(!lines.contains(&3));
+        assert!(lines.contains(&5));
+        assert!(rt.take_coverage().is_empty());
+    }
+
+    #[test]
+    fn conversions_outside_a_runtime_use_the_default_limit() {
+        let rt = Runtime::new();
+        rt.set_max_conversion_length(4);
+        unsafe {
+            let state = JS_GetRuntimePrivate(rt.rt());
+            JS_SetRuntimePrivate(rt.rt(), ptr::null_mut());
+            assert_eq!(max_conversion_length(rt.cx()), DEFAULT_MAX_CONVERSION_LENGTH);
+            assert!(check_length(rt.cx(), 10));
+            assert!(new_string(rt.cx(), "longer than four").is_ok());
+            JS_SetRuntimePrivate(rt.rt(), state);
+        }
+        assert!(!check_length(rt.cx(), 10));
+        unsafe { JS_ClearPendingException(rt.cx()) };
+    }
+
+    #[test]
+    fn call_hook_replaced_mid_frame() {
+        let rt = Runtime::new();
+        let events = Rc::new(RefCell::new(vec![]));
+        let first = events.clone();
+        rt.set_call_hook(Box::new(move |event: FunctionCallEvent| {
+            first.borrow_mut().push((1, event.name.unwrap(), event.kind))
+        }));
+        let global = new_global(&rt);
+        let runtime = &rt as *const Runtime;
+        let recorded = events.clone();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_closure(rt.cx(), global, "replaceHook", 0, 0, move |_| {
+                let second = recorded.clone();
+                unsafe {
+                    (*runtime).set_call_hook(Box::new(move |event: FunctionCallEvent| {
+                        second.borrow_mut().push((2, event.name.unwrap(), event.kind))
+                    }));
+                }
+                Ok(UndefinedValue())
+            }).unwrap();
+            evaluate(&rt, global, "function inner() {} \
+                                   function outer() { replaceHook(); inner(); } \
+                                   outer(); outer();").unwrap();
+        });
+        rt.clear_call_hook();
+
+        let expected = [(1, "outer", Enter), (2, "inner", Enter), (2, "inner", Leave), (1, "outer", Leave),
+                        (2, "outer", Enter), (2, "inner", Enter), (2, "inner", Leave), (2, "outer", Leave)];
+        let events = events.borrow();
+        assert_eq!(events.len(), expected.len());
+        for (event, &(hook, name, kind)) in events.iter().zip(expected.iter()) {
+            assert_eq!((event.0, &*event.1, event.2), (hook, name, kind));
+        }
+    }
+
+    #[test]
+    fn default_global_keeps_state() {
+        let mut rt = Runtime::new();
+        rt.init_default_global(None).unwrap();
+        let global = rt.global();
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert!(evaluate(&rt, global, "var counter = 0;").is_ok());
+            for _ in 0..3 {
+                assert!(evaluate(&rt, rt.global(), "counter += 1;").is_ok());
+            }
+            unsafe { JS_GC(rt.rt()) };
+            assert_eq!(evaluate(&rt, rt.global(), "counter").unwrap().to_number(), 3.0);
+            assert!(evaluate(&rt, rt.global(), "JSON.stringify({})").is_ok());
+        });
+    }
+
+    #[inline(never)]
+    fn fill_named_globals(rt: &mut Runtime) {
+        for name in ["ui", "worker", "plugins"].iter() {
+            rt.create_named_global(name, &GlobalOptions::default()).unwrap();
+            let global = rt.global_named(name).unwrap();
+            with_compartment(rt.context(), object_ptr(global), || {
+                let source = "var data = []; for (var i = 0; i < 100000; i++) data.push({ i: i });";
+                assert!(evaluate(rt, global, source).is_ok());
+            });
+        }
+    }
+
+    #[test]
+    fn isolated_evaluations_share_nothing() {
+        with_test_runtime(|rt, global| {
+            match rt.evaluate_isolated(global, "var leaked = 1; leaked", "first.js").unwrap() {
+                IsolatedResult::Primitive(value) => assert_eq!(value.to_number(), 1.0),
+                result => panic!("expected a primitive, got {:?}", result),
+            }
+            let result = rt.evaluate_isolated(global, "typeof leaked", "second.js").unwrap();
+            assert_eq!(to_string(rt.cx(), result.value()).unwrap(), "undefined");
+            let outside = evaluate(rt, global, "typeof leaked").unwrap();
+            assert_eq!(to_string(rt.cx(), outside).unwrap(), "undefined");
+
+            let error = rt.evaluate_isolated(global, "null.x", "third.js").unwrap_err();
+            assert!(error.message.starts_with("TypeError"), "{}", error.message);
+            assert_eq!(error.exception(), None);
+            assert!(rt.evaluate_isolated(global, "(function() {})", "fourth.js").unwrap_err().exception().is_none());
+        });
+    }
+
+    #[test]
+    fn isolated_objects_are_cloned_into_the_target() {
+        with_test_runtime(|rt, global| {
+            let result = rt.evaluate_isolated(global, "({list: [1, 2], name: 'x'})", "clone.js").unwrap();
+            let object = match result {
+                IsolatedResult::Cloned(value) => value.to_object(),
+                result => panic!("expected a clone, got {:?}", result),
+            };
+            assert_eq!(unsafe { JS_GetGlobalForObject(rt.cx(), object) }, global);
+        });
+    }
+
+    #[test]
+    fn isolated_compartments_are_collected() {
+        with_test_runtime(|rt, global| {
+            let source = "var data = []; for (var i = 0; i < 10000; i++) data.push({ i: i }); data.length";
+            let throwing = "var data = []; for (var i = 0; i < 10000; i++) data.push({ i: i }); throw data";
+            assert!(rt.evaluate_isolated(global, source, "warmup.js").is_ok());
+            unsafe { JS_GC(rt.rt()) };
+            let baseline = rt.gc_bytes();
+            let mut errors = vec![];
+            for _ in 0..200 {
+                assert!(rt.evaluate_isolated(global, source, "repeat.js").is_ok());
+                // Errors kept alive don't keep their compartments alive.
+                errors.push(rt.evaluate_isolated(global, throwing, "throw.js").unwrap_err());
+            }
+            unsafe { JS_GC(rt.rt()) };
+            assert!(rt.gc_bytes() <= baseline * 2, "{} bytes at first, {} after", baseline, rt.gc_bytes());
+            assert_eq!(errors.len(), 200);
+        });
+    }
+
+    #[test]
+    fn named_globals() {
+        let mut rt = Runtime::new();
+        fill_named_globals(&mut rt);
+        assert!(rt.create_named_global("ui", &GlobalOptions::default()).is_err());
+        assert!(rt.global_named("ui") != rt.global_named("worker"));
+        assert_eq!(rt.global_named("missing"), None);
+
+        unsafe { JS_GC(rt.rt()) };
+        let before = rt.gc_bytes();
+        assert!(rt.drop_global("worker"));
+        assert!(!rt.drop_global("worker"));
+        assert_eq!(rt.global_named("worker"), None);
+        unsafe { JS_GC(rt.rt()) };
+        assert!(rt.gc_bytes() < before, "{} bytes before, {} after", before, rt.gc_bytes());
+
+        let ui = rt.global_named("ui").unwrap();
+        with_compartment(rt.context(), object_ptr(ui), || {
+            assert_eq!(evaluate(&rt, ui, "data.length").unwrap().to_number(), 100000.0);
+        });
+    }
+
+    #[test]
+    fn dropped_global_is_collected_alone() {
+        let mut rt = Runtime::new();
+        fill_named_globals(&mut rt);
+        let ui = rt.global_named("ui").unwrap();
+        let kept = with_compartment(rt.context(), object_ptr(ui), || {
+            evaluate(&rt, ui, "data[5]").unwrap().to_object()
+        });
+        unsafe { JS_GC(rt.rt()) };
+
+        let before = rt.gc_bytes();
+        assert!(rt.drop_global_and_gc("worker"));
+        assert!(!rt.drop_global_and_gc("worker"));
+        assert!(rt.gc_bytes() < before, "{} bytes before, {} after", before, rt.gc_bytes());
+
+        // Collecting the other global's compartment didn't touch this one.
+        assert!(gc_compartment(rt.cx(), rt.global_named("plugins").unwrap()));
+        with_compartment(rt.context(), object_ptr(ui), || {
+            assert_eq!(evaluate(&rt, ui, "data[5]").unwrap().to_object(), kept);
+            assert_eq!(evaluate(&rt, ui, "data[5].i").unwrap().to_number(), 5.0);
+            assert_eq!(evaluate(&rt, ui, "data.length").unwrap().to_number(), 100000.0);
+        });
+    }
+
+    #[test]
+    fn heap_dump_lists_objects() {
+        let mut rt = Runtime::new();
+        rt.init_default_global(None).unwrap();
+        let global = rt.global();
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert!(evaluate(&rt, global, "var kept = [{}, {}, {}];").is_ok());
+        });
+        let mut dump = vec![];
+        rt.dump_heap(&mut dump, None).unwrap();
+        assert!(String::from_utf8_lossy(&dump).contains("Object"));
+    }
+
+    #[test]
+    #[should_panic(expected = "before init_default_global")]
+    fn no_default_global() {
+        Runtime::new().global();
+    }
+
+    #[test]
+    fn shutdown_refused_while_runtimes_alive() {
+        let _rt = Runtime::new();
+        match shutdown_engine() {
+            Err(RuntimesStillAlive(n)) => assert!(n >= 1),
+            Ok(()) => panic!("engine shut down under a live runtime"),
+        }
+    }
+
+    #[test]
+    fn context_creation_failure_cleans_up() {
+        super::FAIL_NEXT_CONTEXT.with(|fail| fail.set(true));
+        match Runtime::try_new() {
+            Err(e) => assert_eq!(e, RuntimeCreationError::ContextCreationFailed),
+            Ok(_) => panic!("context creation should have failed"),
+        }
+        // The injected fault is one-shot and the failed runtime was finished,
+        // so creating another one works.
+        let rt = Runtime::try_new().unwrap();
+        assert!(!rt.cx().is_null());
+    }
+
+    #[test]
+    fn evaluate_into_converts_the_result() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert_eq!(rt.evaluate_into::<f64>(global, "6*7", "inline", 1).unwrap(), 42.0);
+            assert_eq!(rt.evaluate_into::<String>(global, "({}).toString()", "inline", 1).unwrap(),
+                       "[object Object]");
+            assert_eq!(rt.evaluate_into::<Option<i32>>(global, "null", "inline", 1).unwrap(), None);
+            match rt.evaluate_into::<i32>(global, "'hello'", "inline", 1) {
+                Err(EvalError::Conversion(error)) => assert_eq!(error.message, "expected an i32, got string"),
+                other => panic!("expected a conversion error, got {:?}", other),
+            }
+            match rt.evaluate_into::<i32>(global, "2.5", "inline", 1) {
+                Err(EvalError::Conversion(error)) => assert_eq!(error.message, "2.5 is not an i32"),
+                other => panic!("expected a conversion error, got {:?}", other),
+            }
+            match rt.evaluate_into::<f64>(global, "throw new Error('nope')", "inline", 1) {
+                Err(EvalError::Script(error)) => assert_eq!(error.message, "Error: nope"),
+                other => panic!("expected a script error, got {:?}", other),
+            }
+        });
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn evaluate_script_keeps_its_signature() {
+        with_test_runtime(|rt, global| {
+            let source = "var kept = 1;".to_string();
+            assert_eq!(rt.evaluate_script(global, source, "kept.js".to_string(), 1), Ok(()));
+            assert_eq!(evaluate(rt, global, "kept").unwrap().to_int32(), 1);
+            let source = "throw 'old';".to_string();
+            assert_eq!(rt.evaluate_script(global, source, "kept.js".to_string(), 1), Err(()));
+            assert_eq!(unsafe { JS_IsExceptionPending(rt.cx()) }, 0);
+        });
+    }
+
+    #[test]
+    fn evaluate_script_names_the_rust_location() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let global = object_ptr(global);
+        with_compartment(rt.context(), global, || {
+            let line = line!() + 1;
+            let error = evaluate_script!(rt, global, "throw new Error('embedded');").unwrap_err();
+            let filename = error.filename.unwrap();
+            assert!(filename.ends_with("rust.rs"), "{}", filename);
+            assert_eq!(error.lineno, line);
+
+            let error = evaluate_script!(rt, global, "\nthrow new Error('named');", "named.js").unwrap_err();
+            assert_eq!((error.filename.unwrap(), error.lineno), ("named.js".to_string(), 2));
+            let error = evaluate_script!(rt, global, "throw new Error('named');", "named.js", 7).unwrap_err();
+            assert_eq!(error.lineno, 7);
+            assert_eq!(evaluate_script!(rt, global, "6 * 7").unwrap().to_number(), 42.0);
+        });
+    }
+
+    static EMBEDDER_GCS: AtomicUsize = ATOMIC_USIZE_INIT;
+    static EMBEDDER_FINALIZES: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    unsafe extern "C" fn count_gcs(_rt: *mut JSRuntime, status: JSGCStatus) {
+        if status == JSGC_END {
+            EMBEDDER_GCS.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    unsafe extern "C" fn count_finalizes(_fop: *mut JSFreeOp, status: JSFinalizeStatus, _is_compartment: JSBool) {
+        if status == JSFINALIZE_START {
+            EMBEDDER_FINALIZES.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn embedder_gc_callbacks_are_chained() {
+        let rt = Runtime::new();
+        assert!(rt.set_gc_callback(Some(count_gcs)).is_none());
+        assert!(rt.set_finalize_callback(Some(count_finalizes)).is_none());
+        unsafe { JS_GC(rt.rt()) };
+        assert_eq!(EMBEDDER_GCS.load(Ordering::SeqCst), 1);
+        assert!(EMBEDDER_FINALIZES.load(Ordering::SeqCst) >= 1);
+        // The crate's own callback still ran.
+        assert!(rt.state.bytes_after_gc.get() > 0);
+        assert!(!rt.state.in_gc.get());
+
+        assert!(rt.set_gc_callback(None).is_some());
+        unsafe { JS_GC(rt.rt()) };
+        assert_eq!(EMBEDDER_GCS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn nested_error_reporter_guards() {
+        with_test_runtime(|rt, global| {
+            rt.set_report_uncaught(true);
+            let outer = rt.capture_errors();
+            assert!(evaluate(rt, global, "(").is_err());
+            {
+                let inner = rt.capture_errors();
+                assert!(evaluate(rt, global, "null.x").is_err());
+                let reports = inner.take_reports();
+                assert_eq!(reports.len(), 1);
+                assert_eq!(reports[0].kind, ErrorKind::Type);
+                assert!(inner.take_reports().is_empty());
+            }
+            assert!(evaluate(rt, global, "undeclaredName").is_err());
+            let kinds: Vec<_> = outer.take_reports().iter().map(|report| report.kind).collect();
+            assert_eq!(kinds, vec![ErrorKind::Syntax, ErrorKind::Reference]);
+
+            // Reports an inner guard doesn't take are handed to the outer.
+            {
+                let _inner = rt.capture_errors();
+                assert!(evaluate(rt, global, "(").is_err());
+            }
+            assert_eq!(outer.take_reports().len(), 1);
+
+            // Dropped out of order, each guard still removes only itself.
+            let first = rt.capture_errors();
+            let second = rt.capture_errors();
+            drop(first);
+            assert!(evaluate(rt, global, "throw 1").is_err());
+            assert_eq!(second.take_reports().len(), 1);
+            drop(second);
+            drop(outer);
+            assert!(rt.state.error_captures.borrow().is_empty());
+            // The crate's reporter is back in place.
+            let error = evaluate(rt, global, "null.x").unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+        });
+    }
+
+    static EMBEDDER_REPORTS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    unsafe extern "C" fn count_reports(_cx: *mut JSContext, _msg: *const c_char,
+                                       _report: *mut JSErrorReport) {
+        EMBEDDER_REPORTS.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn error_reporter_is_restored_after_a_panic() {
+        with_test_runtime(|rt, global| {
+            rt.set_report_uncaught(true);
+            let reporter = Some(count_reports as unsafe extern "C"
+                                fn(*mut JSContext, *const c_char, *mut JSErrorReport));
+            let crate_reporter = unsafe { JS_SetErrorReporter(rt.cx(), reporter) };
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let guard = rt.capture_errors();
+                assert!(evaluate(rt, global, "(").is_err());
+                assert_eq!(guard.take_reports().len(), 1);
+                panic!("inside the scope");
+            }));
+            assert!(result.is_err());
+            assert!(rt.state.error_captures.borrow().is_empty());
+            // The embedder's reporter is back, and never saw the report
+            // the guard captured.
+            assert_eq!(EMBEDDER_REPORTS.load(Ordering::SeqCst), 0);
+            assert!(evaluate(rt, global, "null.x").is_err());
+            assert_eq!(EMBEDDER_REPORTS.load(Ordering::SeqCst), 1);
+            assert!(unsafe { JS_SetErrorReporter(rt.cx(), crate_reporter) } == reporter);
+
+            // So is the crate's, and the evaluation's error is still taken
+            // from its reports.
+            let error = evaluate(rt, global, "null.x").unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Type);
+            let guard = rt.capture_errors();
+            assert!(evaluate(rt, global, "(").is_err());
+            assert_eq!(guard.take_reports().len(), 1);
+        });
+    }
 }
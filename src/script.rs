@@ -0,0 +1,373 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Compiled scripts that can be run repeatedly and debugged.
+
+use libc::{c_char, c_uint, c_void};
+use std::cell::RefCell;
+use std::ffi::CStr;
+use std::ptr;
+use jsapi::{JSContext, JSObject, JSScript, JS_ExecuteScript};
+use jsapi::{JS_AddNamedScriptRoot, JS_RemoveScriptRoot};
+use jsdbgapi::{JSStackFrame, JSTrapStatus, jsbytecode};
+use jsdbgapi::{JS_SetDebugMode, JS_GetDebugMode, JS_SetTrap, JS_ClearScriptTraps};
+use jsdbgapi::{JS_LineNumberToPC, JS_PCToLineNumber, JS_FrameIterator, JS_GetFrameThis};
+use jsdbgapi::{JS_EvaluateUCInStackFrame, JS_GetScriptFilename};
+use jsval::{JSVal, UndefinedValue, PrivateValue};
+use error::{JSError, report_stale_exception, catch_panic};
+use principals::Principals;
+use rust::{Runtime, EvaluateOptions, check_source_length};
+use utf16::to_utf16;
+use ERR;
+
+/// The state of the program when a breakpoint is hit.
+pub struct TrapContext {
+    pub cx: *mut JSContext,
+    /// The frame the breakpoint is in.
+    pub frame: *mut JSStackFrame,
+    pub line: u32,
+    /// `this` in that frame.
+    pub this_value: JSVal,
+}
+
+impl TrapContext {
+    /// Evaluates `source` in the paused frame, with access to its locals.
+    pub fn evaluate(&self, source: &str) -> Result<JSVal, JSError> {
+        let source = to_utf16(source);
+        let length = try!(check_source_length(source.len()));
+        let mut rval = UndefinedValue();
+        unsafe {
+            if JS_EvaluateUCInStackFrame(self.cx, self.frame, source.as_ptr(), length,
+                                         b"breakpoint\0".as_ptr() as *const c_char, 1,
+                                         &mut rval) == ERR {
+                return Err(JSError::from_pending_exception(self.cx));
+            }
+        }
+        Ok(rval)
+    }
+
+    /// The `arguments` of the paused function; fails at the top level of a
+    /// script, where there are none.
+    pub fn arguments(&self) -> Result<JSVal, JSError> {
+        self.evaluate("arguments")
+    }
+}
+
+/// Finds the source map URL in a `//# sourceMappingURL=<url>` comment, or
+/// the older `//@` form, on a line of its own. The last one wins, as in
+/// browsers.
+pub fn find_source_map_url(source: &str) -> Option<String> {
+    source.lines().rev().filter_map(|line| {
+        let line = line.trim();
+        if !line.starts_with("//# sourceMappingURL=") && !line.starts_with("//@ sourceMappingURL=") {
+            return None;
+        }
+        line["//# sourceMappingURL=".len()..].split_whitespace().next().map(|url| url.to_string())
+    }).next()
+}
+
+/// What to do after a breakpoint handler returns.
+pub enum TrapAction {
+    /// Resume execution.
+    Continue,
+    /// Throw the value from the paused frame.
+    Throw(JSVal),
+    /// Return the value from the paused frame.
+    Return(JSVal),
+}
+
+struct Breakpoint {
+    handler: RefCell<Box<FnMut(TrapContext) -> TrapAction>>,
+}
+
+unsafe extern "C" fn trap_handler(cx: *mut JSContext, script: *mut JSScript, pc: *mut jsbytecode,
+                                  rval: *mut JSVal, closure: JSVal) -> JSTrapStatus {
+    let breakpoint = &*(closure.to_private() as *const Breakpoint);
+    let mut iter = ptr::null_mut();
+    let frame = JS_FrameIterator(cx, &mut iter);
+    let mut this_value = UndefinedValue();
+    if frame.is_null() || JS_GetFrameThis(cx, frame, &mut this_value) == ERR {
+        return JSTrapStatus::JSTRAP_ERROR;
+    }
+    let context = TrapContext {
+        cx: cx,
+        frame: frame,
+        line: JS_PCToLineNumber(cx, script, pc),
+        this_value: this_value,
+    };
+    match catch_panic(cx, || (&mut *breakpoint.handler.borrow_mut())(context)) {
+        Some(TrapAction::Continue) => JSTrapStatus::JSTRAP_CONTINUE,
+        Some(TrapAction::Throw(value)) => {
+            *rval = value;
+            JSTrapStatus::JSTRAP_THROW
+        }
+        Some(TrapAction::Return(value)) => {
+            *rval = value;
+            JSTrapStatus::JSTRAP_RETURN
+        }
+        None => JSTrapStatus::JSTRAP_ERROR,
+    }
+}
+
+/// A script compiled against a global, rooted until dropped.
+pub struct CompiledScript<'a> {
+    rt: &'a Runtime,
+    global: *mut JSObject,
+    // Boxed so the root's address stays put.
+    script: Box<*mut JSScript>,
+    compile_and_go: bool,
+    breakpoints: Vec<Box<Breakpoint>>,
+    principals: Option<Principals<'a>>,
+    source_map_url: Option<String>,
+}
+
+impl<'a> CompiledScript<'a> {
+    /// Compiles `source` so that it can run against `global` or any other
+    /// global. The caller must already be in `global`'s compartment. See
+    /// `Runtime::compile_script` for more options.
+    pub fn compile(rt: &'a Runtime, global: *mut JSObject, source: &str, filename: &str, line: u32)
+                   -> Result<CompiledScript<'a>, JSError> {
+        let options = EvaluateOptions::new().filename(filename).line(line).compile_and_go(false);
+        rt.compile_script(global, source, &options)
+    }
+
+    /// Roots `script`, compiled against `global` in `rt`, until dropped.
+    pub unsafe fn from_raw(rt: &'a Runtime, global: *mut JSObject, script: *mut JSScript,
+                           compile_and_go: bool) -> CompiledScript<'a> {
+        let mut script = Box::new(script);
+        JS_AddNamedScriptRoot(rt.cx(), &mut *script, b"CompiledScript\0".as_ptr() as *const c_char);
+        CompiledScript {
+            rt: rt,
+            global: global,
+            script: script,
+            compile_and_go: compile_and_go,
+            breakpoints: vec!(),
+            principals: None,
+            source_map_url: None,
+        }
+    }
+
+    pub fn raw(&self) -> *mut JSScript {
+        *self.script
+    }
+
+    /// Keeps `principals`, which the script must have been compiled with,
+    /// alive as long as the script. `Runtime::compile_script_for_principals`
+    /// does this.
+    pub fn hold_principals(&mut self, principals: &Principals<'a>) {
+        self.principals = Some(principals.clone());
+    }
+
+    /// The principals held by `hold_principals`, if any.
+    pub fn principals(&self) -> Option<&Principals<'a>> {
+        self.principals.as_ref()
+    }
+
+    /// The URL of the script's source map, from its `sourceMappingURL`
+    /// comment, if it has one.
+    pub fn source_map_url(&self) -> Option<String> {
+        self.source_map_url.clone()
+    }
+
+    /// Sets the URL `source_map_url` returns and errors raised in the
+    /// script's file while it runs report. `Runtime::compile_script` sets
+    /// it from the source.
+    pub fn set_source_map_url(&mut self, url: Option<String>) {
+        self.source_map_url = url;
+    }
+
+    fn filename(&self) -> Option<String> {
+        let filename = unsafe { JS_GetScriptFilename(self.rt.cx(), *self.script) };
+        if filename.is_null() {
+            return None;
+        }
+        let filename = unsafe { CStr::from_ptr(filename) };
+        Some(String::from_utf8_lossy(filename.to_bytes()).into_owned())
+    }
+
+    /// Runs the script against the global it was compiled for and returns
+    /// the completion value. The caller must be in that global's compartment.
+    /// An exception already pending is reported and cleared first.
+    pub fn execute(&self) -> Result<JSVal, JSError> {
+        self.execute_in(self.global)
+    }
+
+    /// Like `execute`, but runs the script against `global`, which the
+    /// caller must be in the compartment of. Fails for a compile-and-go
+    /// script and any global but the one it was compiled for.
+    pub fn execute_in(&self, global: *mut JSObject) -> Result<JSVal, JSError> {
+        if self.compile_and_go && global != self.global {
+            return Err(JSError::new("a compile-and-go script can only run against the global \
+                                     it was compiled for"));
+        }
+        report_stale_exception(self.rt.cx());
+        let mut rval = UndefinedValue();
+        unsafe {
+            if JS_ExecuteScript(self.rt.cx(), global, *self.script, &mut rval) == ERR {
+                let mut error = JSError::from_pending_exception(self.rt.cx());
+                if error.filename.is_some() && error.filename == self.filename() {
+                    error.source_map_url = self.source_map_url.clone();
+                }
+                return Err(error);
+            }
+        }
+        Ok(rval)
+    }
+
+    /// Calls `handler` each time execution reaches `line`. The caller must be
+    /// in the script's compartment.
+    ///
+    /// Breakpoints need the compartment in debug mode, which this turns on.
+    /// The engine can't switch a compartment while any of its code is on the
+    /// stack, so this fails when called from script running there; set
+    /// breakpoints before executing.
+    pub fn set_breakpoint(&mut self, line: u32, handler: Box<FnMut(TrapContext) -> TrapAction>)
+                          -> Result<(), JSError> {
+        let cx = self.rt.cx();
+        unsafe {
+            JS_SetDebugMode(cx, 1);
+            if JS_GetDebugMode(cx) == 0 {
+                return Err(JSError::new("cannot enable debug mode while the compartment is running"));
+            }
+            let pc = JS_LineNumberToPC(cx, *self.script, line as c_uint);
+            if pc.is_null() || JS_PCToLineNumber(cx, *self.script, pc) != line {
+                return Err(JSError::new(format!("no code on line {}", line)));
+            }
+            let breakpoint = Box::new(Breakpoint { handler: RefCell::new(handler) });
+            let closure = PrivateValue(&*breakpoint as *const Breakpoint as *const c_void);
+            if JS_SetTrap(cx, *self.script, pc, Some(trap_handler), closure) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            self.breakpoints.push(breakpoint);
+        }
+        Ok(())
+    }
+
+    /// Removes every breakpoint.
+    pub fn clear_breakpoints(&mut self) {
+        unsafe { JS_ClearScriptTraps(self.rt.cx(), *self.script) };
+        self.breakpoints.clear();
+    }
+}
+
+impl<'a> Drop for CompiledScript<'a> {
+    fn drop(&mut self) {
+        if !self.breakpoints.is_empty() {
+            self.clear_breakpoints();
+        }
+        unsafe { JS_RemoveScriptRoot(self.rt.cx(), &mut *self.script) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use jsval::Int32Value;
+    use super::{CompiledScript, TrapAction, find_source_map_url};
+    use rust::{Runtime, EvaluateOptions, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+
+    const SCRIPT: &'static str = "var x = 1;\n\
+                                  x += 1;\n\
+                                  x *= 10;\n\
+                                  x += 1;\n\
+                                  x;\n";
+
+    #[test]
+    fn breakpoint_fires_once() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut script = CompiledScript::compile(&rt, global, SCRIPT, "five.js", 1).unwrap();
+            let hits = Rc::new(Cell::new(0));
+            let seen = hits.clone();
+            script.set_breakpoint(3, Box::new(move |context| {
+                assert_eq!(context.line, 3);
+                // Line 3 hasn't run yet.
+                assert_eq!(context.evaluate("x").unwrap().to_number(), 2.0);
+                seen.set(seen.get() + 1);
+                TrapAction::Continue
+            })).unwrap();
+            assert_eq!(script.execute().unwrap().to_number(), 21.0);
+            assert_eq!(hits.get(), 1);
+            assert!(script.set_breakpoint(40, Box::new(|_| TrapAction::Continue)).is_err());
+        });
+    }
+
+    #[test]
+    fn breakpoint_can_return_and_throw() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let mut script = CompiledScript::compile(&rt, global, SCRIPT, "five.js", 1).unwrap();
+            script.set_breakpoint(3, Box::new(|_| TrapAction::Return(Int32Value(7)))).unwrap();
+            assert_eq!(script.execute().unwrap().to_number(), 7.0);
+
+            script.clear_breakpoints();
+            script.set_breakpoint(4, Box::new(|_| TrapAction::Throw(Int32Value(13)))).unwrap();
+            let err = script.execute().unwrap_err();
+            assert_eq!(err.message, "13");
+        });
+    }
+
+    #[test]
+    fn reusable_script_runs_against_other_globals() {
+        let rt = Runtime::new();
+        let first = new_global(&rt);
+        let second = new_global(&rt);
+        let script = with_compartment(rt.context(), object_ptr(first), || {
+            evaluate(&rt, first, "var x = 1;").unwrap();
+            CompiledScript::compile(&rt, first, "x * 2", "double.js", 1).unwrap()
+        });
+        with_compartment(rt.context(), object_ptr(first), || {
+            assert_eq!(script.execute().unwrap().to_number(), 2.0);
+        });
+        with_compartment(rt.context(), object_ptr(second), || {
+            evaluate(&rt, second, "var x = 21;").unwrap();
+            assert_eq!(script.execute_in(second).unwrap().to_number(), 42.0);
+        });
+    }
+
+    #[test]
+    fn compile_and_go_script_refuses_other_globals() {
+        let rt = Runtime::new();
+        let first = new_global(&rt);
+        let second = new_global(&rt);
+        let script = with_compartment(rt.context(), object_ptr(first), || {
+            evaluate(&rt, first, "var x = 1;").unwrap();
+            let options = EvaluateOptions::new().filename("double.js");
+            rt.compile_script(first, "x * 2", &options).unwrap()
+        });
+        with_compartment(rt.context(), object_ptr(first), || {
+            assert_eq!(script.execute_in(first).unwrap().to_number(), 2.0);
+        });
+        with_compartment(rt.context(), object_ptr(second), || {
+            let err = script.execute_in(second).unwrap_err();
+            assert!(err.message.contains("compile-and-go"), "{}", err.message);
+        });
+    }
+
+    #[test]
+    fn source_map_url_reaches_script_and_error() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let source = "throw new Error('mapped');\n//# sourceMappingURL=app.js.map\n\
+                          //# sourceMappingURL=https://example.com/ma\u{f1}ana.js.map  \n";
+            let script = CompiledScript::compile(&rt, global, source, "app.js", 1).unwrap();
+            let url = "https://example.com/ma\u{f1}ana.js.map".to_string();
+            assert_eq!(script.source_map_url(), Some(url.clone()));
+            let error = script.execute().unwrap_err();
+            assert_eq!(error.filename, Some("app.js".to_string()));
+            assert_eq!(error.source_map_url, Some(url));
+
+            let plain = CompiledScript::compile(&rt, global, "throw 1;", "plain.js", 1).unwrap();
+            assert_eq!(plain.source_map_url(), None);
+            assert_eq!(plain.execute().unwrap_err().source_map_url, None);
+            assert_eq!(find_source_map_url("//@ sourceMappingURL=old.map"), Some("old.map".to_string()));
+            assert_eq!(find_source_map_url("var s = '//# sourceMappingURL=no.map';"), None);
+        });
+    }
+}
@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A cache of XDR-encoded bytecode, used by `Runtime::evaluate_cached`.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher, SipHasher};
+use jsapi::JS_GetImplementationVersion;
+
+/// Hit and size statistics of a `ScriptCache`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ScriptCacheStats {
+    /// Evaluations that decoded cached bytecode.
+    pub hits: usize,
+    /// Evaluations that had to compile.
+    pub misses: usize,
+    pub entries: usize,
+    /// The total size of the cached bytecode.
+    pub bytes: usize,
+}
+
+struct Entry {
+    bytecode: Vec<u8>,
+    last_used: u64,
+}
+
+/// Maps scripts to their encoded bytecode, evicting the least recently used
+/// entries once the total size exceeds a bound.
+///
+/// Entries are keyed on a hash of the source, the filename, the compile
+/// options and the engine version, so bytecode produced by another build of
+/// the engine is never looked up.
+pub struct ScriptCache {
+    entries: HashMap<u64, Entry>,
+    max_bytes: usize,
+    bytes: usize,
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+impl ScriptCache {
+    /// Creates an empty cache holding at most `max_bytes` of bytecode.
+    pub fn new(max_bytes: usize) -> ScriptCache {
+        ScriptCache {
+            entries: HashMap::new(),
+            max_bytes: max_bytes,
+            bytes: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn stats(&self) -> ScriptCacheStats {
+        ScriptCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+        }
+    }
+
+    /// Removes every entry, keeping the statistics.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    /// Computes the key of a script.
+    pub fn key(source: &str, filename: &str, options: u32, version: i32) -> u64 {
+        let mut hasher = SipHasher::new();
+        source.hash(&mut hasher);
+        filename.hash(&mut hasher);
+        options.hash(&mut hasher);
+        version.hash(&mut hasher);
+        let engine = unsafe { CStr::from_ptr(JS_GetImplementationVersion()) };
+        engine.to_bytes().hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns a copy of the bytecode cached under `key`, counting a hit or
+    /// a miss.
+    pub fn lookup(&mut self, key: u64) -> Option<Vec<u8>> {
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.hits += 1;
+                Some(entry.bytecode.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches `bytecode` under `key`, evicting old entries to make room.
+    /// Bytecode larger than the whole cache is not stored.
+    pub fn insert(&mut self, key: u64, bytecode: Vec<u8>) {
+        self.remove(key);
+        if bytecode.len() > self.max_bytes {
+            return;
+        }
+        while self.bytes + bytecode.len() > self.max_bytes {
+            let oldest = *self.entries.iter()
+                .min_by(|&(_, a), &(_, b)| a.last_used.cmp(&b.last_used))
+                .unwrap().0;
+            self.remove(oldest);
+        }
+        self.clock += 1;
+        self.bytes += bytecode.len();
+        self.entries.insert(key, Entry {
+            bytecode: bytecode,
+            last_used: self.clock,
+        });
+    }
+
+    /// Takes back the hit `lookup` just counted for `key`, whose bytecode
+    /// turned out not to decode: counts a miss instead and drops the entry.
+    pub fn reject(&mut self, key: u64) {
+        self.hits -= 1;
+        self.misses += 1;
+        self.remove(key);
+    }
+
+    /// Drops the entry for `key`.
+    pub fn remove(&mut self, key: u64) {
+        if let Some(entry) = self.entries.remove(&key) {
+            self.bytes -= entry.bytecode.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jsapi::{JSObject, JS_GetOptions, JS_GetVersion};
+    use super::ScriptCache;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, object_ptr};
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut cache = ScriptCache::new(10);
+        cache.insert(1, vec![0; 4]);
+        cache.insert(2, vec![0; 4]);
+        assert!(cache.lookup(1).is_some());
+        // Evicts 2, which hasn't been used since 1 was looked up.
+        cache.insert(3, vec![0; 4]);
+        assert!(cache.lookup(2).is_none());
+        assert!(cache.lookup(1).is_some());
+        assert!(cache.lookup(3).is_some());
+        assert_eq!(cache.stats().bytes, 8);
+
+        cache.insert(4, vec![0; 11]);
+        assert!(cache.lookup(4).is_none());
+        assert_eq!(cache.stats().entries, 2);
+    }
+
+    #[test]
+    fn second_evaluation_hits_the_cache() {
+        let rt = Runtime::new();
+        rt.set_script_cache(Some(ScriptCache::new(1 << 20)));
+        let source = "var calls = (typeof calls == 'number' ? calls : 0) + 1; calls * 10";
+        let run = |global: *mut JSObject, filename: &str| {
+            with_compartment(rt.context(), object_ptr(global), || {
+                rt.evaluate_cached(global, source, filename).unwrap().to_number()
+            })
+        };
+
+        let first = new_global(&rt);
+        assert_eq!(run(first, "cached.js"), 10.0);
+        let stats = rt.script_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (0, 1, 1));
+
+        // The decoded script runs against whichever global it's given.
+        let second = new_global(&rt);
+        assert_eq!(run(second, "cached.js"), 10.0);
+        assert_eq!(run(second, "cached.js"), 20.0);
+        assert_eq!(rt.script_cache_stats().unwrap().hits, 2);
+
+        // A different filename is a different script.
+        assert_eq!(run(second, "other.js"), 30.0);
+        assert_eq!(rt.script_cache_stats().unwrap().misses, 2);
+    }
+
+    #[test]
+    fn undecodable_bytecode_is_a_miss() {
+        let rt = Runtime::new();
+        let source = "6 * 7";
+        let key = unsafe {
+            ScriptCache::key(source, "bad.js", JS_GetOptions(rt.cx()), JS_GetVersion(rt.cx()) as i32)
+        };
+        let mut cache = ScriptCache::new(1 << 20);
+        cache.insert(key, vec![0xff; 16]);
+        rt.set_script_cache(Some(cache));
+
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert_eq!(rt.evaluate_cached(global, source, "bad.js").unwrap().to_number(), 42.0);
+        });
+        let stats = rt.script_cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (0, 1));
+        // The garbage was replaced by freshly encoded bytecode.
+        assert_eq!(stats.entries, 1);
+        assert!(stats.bytes != 16);
+    }
+}
@@ -0,0 +1,922 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Converting Rust data to and from JS values with serde, behind the
+//! `serde` feature.
+//!
+//! Values map the way `JSON.stringify` would see them:
+//!
+//! * `()`, unit structs and `None` are `null`; `Some(v)` is just `v`.
+//! * Numbers are JS numbers, so 64-bit integers beyond 2^53 lose precision.
+//! * Sequences, tuples and tuple structs are arrays; structs and maps are
+//!   plain objects. Map keys that are numbers, booleans, chars or unit
+//!   variants become their string form and are parsed back when reading.
+//! * Enums are externally tagged: a unit variant is its name as a string,
+//!   any other variant an object with the name as its only property.
+//!
+//! Reading runs property getters, and reports exceptions they throw.
+
+use libc::size_t;
+use std::fmt;
+use std::ptr;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::de::value::StringDeserializer;
+use serde::ser::{self, Impossible, Serialize};
+use jsapi::{JSContext, JSObject, jsid, JS_NewObject, JS_NewArrayObject, JS_IsArrayObject};
+use jsapi::{JS_GetArrayLength, JS_GetElement, JS_SetElement, JS_DefineUCProperty};
+use jsapi::{JS_Enumerate, JS_IdArrayLength, JS_IdArrayGet, JS_DestroyIdArray};
+use jsapi::{JS_IdToValue, JS_GetPropertyById};
+use jsval::{JSVal, NullValue, BooleanValue, Int32Value, DoubleValue, NumberValue, StringValue};
+use jsval::UndefinedValue;
+use conversions::{jsstring_to_string, check_length};
+use error::JSError;
+use object::RootedObject;
+use strings::new_string;
+use utf16::to_utf16;
+use {ERR, JSPROP_ENUMERATE};
+
+impl ser::Error for JSError {
+    fn custom<T: fmt::Display>(msg: T) -> JSError {
+        JSError::new(msg.to_string())
+    }
+}
+
+impl de::Error for JSError {
+    fn custom<T: fmt::Display>(msg: T) -> JSError {
+        JSError::new(msg.to_string())
+    }
+}
+
+/// Converts `value` to a JS value built from fresh objects, arrays,
+/// strings and numbers in `cx`'s current compartment.
+pub fn to_jsval_serde<T: Serialize + ?Sized>(cx: *mut JSContext, value: &T) -> Result<JSVal, JSError> {
+    value.serialize(Serializer { cx: cx })
+}
+
+/// Reads a `T` out of `value`.
+pub fn from_jsval_serde<T: DeserializeOwned>(cx: *mut JSContext, value: JSVal) -> Result<T, JSError> {
+    T::deserialize(Deserializer { cx: cx, value: value })
+}
+
+fn string_value(cx: *mut JSContext, s: &str) -> Result<JSVal, JSError> {
+    let string = try!(new_string(cx, s));
+    Ok(StringValue(unsafe { &*string }))
+}
+
+fn new_plain_object(cx: *mut JSContext) -> Result<RootedObject, JSError> {
+    RootedObject::new(cx, unsafe { JS_NewObject(cx, ptr::null(), ptr::null(), ptr::null()) })
+}
+
+fn define_property(cx: *mut JSContext, obj: *mut JSObject, name: &str, value: JSVal) -> Result<(), JSError> {
+    let name = to_utf16(name);
+    unsafe {
+        if JS_DefineUCProperty(cx, obj, name.as_ptr(), name.len() as size_t, value,
+                               None, None, JSPROP_ENUMERATE) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+/// `{variant: value}`, for enum variants that carry data.
+fn tagged_value(cx: *mut JSContext, variant: &str, value: JSVal) -> Result<JSVal, JSError> {
+    let object = try!(new_plain_object(cx));
+    try!(define_property(cx, object.get(), variant, value));
+    Ok(object.value())
+}
+
+// ___________________________________________________________________________
+// Rust to JS
+
+struct Serializer {
+    cx: *mut JSContext,
+}
+
+impl ser::Serializer for Serializer {
+    type Ok = JSVal;
+    type Error = JSError;
+    type SerializeSeq = ArraySerializer;
+    type SerializeTuple = ArraySerializer;
+    type SerializeTupleStruct = ArraySerializer;
+    type SerializeTupleVariant = VariantSerializer<ArraySerializer>;
+    type SerializeMap = ObjectSerializer;
+    type SerializeStruct = ObjectSerializer;
+    type SerializeStructVariant = VariantSerializer<ObjectSerializer>;
+
+    fn serialize_bool(self, v: bool) -> Result<JSVal, JSError> {
+        Ok(BooleanValue(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<JSVal, JSError> {
+        Ok(Int32Value(v as i32))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<JSVal, JSError> {
+        Ok(Int32Value(v as i32))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<JSVal, JSError> {
+        Ok(Int32Value(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<JSVal, JSError> {
+        Ok(NumberValue(v as f64))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<JSVal, JSError> {
+        Ok(Int32Value(v as i32))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<JSVal, JSError> {
+        Ok(Int32Value(v as i32))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<JSVal, JSError> {
+        Ok(NumberValue(v as f64))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<JSVal, JSError> {
+        Ok(NumberValue(v as f64))
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<JSVal, JSError> {
+        Ok(DoubleValue(v as f64))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<JSVal, JSError> {
+        Ok(DoubleValue(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<JSVal, JSError> {
+        string_value(self.cx, &v.to_string())
+    }
+
+    fn serialize_str(self, v: &str) -> Result<JSVal, JSError> {
+        string_value(self.cx, v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<JSVal, JSError> {
+        let mut array = try!(ArraySerializer::with_length(self.cx, v.len()));
+        for byte in v {
+            try!(ser::SerializeSeq::serialize_element(&mut array, byte));
+        }
+        ser::SerializeSeq::end(array)
+    }
+
+    fn serialize_none(self) -> Result<JSVal, JSError> {
+        Ok(NullValue())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<JSVal, JSError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<JSVal, JSError> {
+        Ok(NullValue())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<JSVal, JSError> {
+        Ok(NullValue())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+                              -> Result<JSVal, JSError> {
+        string_value(self.cx, variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+                                                       -> Result<JSVal, JSError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                        variant: &'static str, value: &T)
+                                                        -> Result<JSVal, JSError> {
+        let cx = self.cx;
+        let value = try!(value.serialize(self));
+        tagged_value(cx, variant, value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ArraySerializer, JSError> {
+        ArraySerializer::with_length(self.cx, len.unwrap_or(0))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ArraySerializer, JSError> {
+        ArraySerializer::with_length(self.cx, len)
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<ArraySerializer, JSError> {
+        ArraySerializer::new(self.cx)
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                               _len: usize) -> Result<VariantSerializer<ArraySerializer>, JSError> {
+        Ok(VariantSerializer {
+            variant: variant,
+            inner: try!(ArraySerializer::new(self.cx)),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<ObjectSerializer, JSError> {
+        ObjectSerializer::new(self.cx)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<ObjectSerializer, JSError> {
+        ObjectSerializer::new(self.cx)
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str,
+                                _len: usize) -> Result<VariantSerializer<ObjectSerializer>, JSError> {
+        Ok(VariantSerializer {
+            variant: variant,
+            inner: try!(ObjectSerializer::new(self.cx)),
+        })
+    }
+}
+
+struct ArraySerializer {
+    array: RootedObject,
+    length: u32,
+}
+
+impl ArraySerializer {
+    fn new(cx: *mut JSContext) -> Result<ArraySerializer, JSError> {
+        ArraySerializer::with_length(cx, 0)
+    }
+
+    /// Fails up front if `length` elements would be more than
+    /// `check_length` allows.
+    fn with_length(cx: *mut JSContext, length: usize) -> Result<ArraySerializer, JSError> {
+        if !check_length(cx, length) {
+            return Err(unsafe { JSError::from_pending_exception(cx) });
+        }
+        let array = try!(RootedObject::new(cx, unsafe { JS_NewArrayObject(cx, 0, ptr::null_mut()) }));
+        Ok(ArraySerializer {
+            array: array,
+            length: 0,
+        })
+    }
+
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        let cx = self.array.cx();
+        let mut value = try!(value.serialize(Serializer { cx: cx }));
+        unsafe {
+            if JS_SetElement(cx, self.array.get(), self.length, &mut value) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        self.length += 1;
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ArraySerializer {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        Ok(self.array.value())
+    }
+}
+
+impl ser::SerializeTuple for ArraySerializer {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        Ok(self.array.value())
+    }
+}
+
+impl ser::SerializeTupleStruct for ArraySerializer {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        Ok(self.array.value())
+    }
+}
+
+struct ObjectSerializer {
+    object: RootedObject,
+    /// Set by `serialize_key`, used by the following `serialize_value`.
+    key: Option<String>,
+}
+
+impl ObjectSerializer {
+    fn new(cx: *mut JSContext) -> Result<ObjectSerializer, JSError> {
+        Ok(ObjectSerializer {
+            object: try!(new_plain_object(cx)),
+            key: None,
+        })
+    }
+
+    fn define<T: Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), JSError> {
+        let cx = self.object.cx();
+        let value = try!(value.serialize(Serializer { cx: cx }));
+        define_property(cx, self.object.get(), key, value)
+    }
+}
+
+impl ser::SerializeMap for ObjectSerializer {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), JSError> {
+        self.key = Some(try!(key.serialize(KeySerializer)));
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        let key = self.key.take().expect("serialize_value called before serialize_key");
+        self.define(&key, value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        Ok(self.object.value())
+    }
+}
+
+impl ser::SerializeStruct for ObjectSerializer {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+                                              -> Result<(), JSError> {
+        self.define(key, value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        Ok(self.object.value())
+    }
+}
+
+/// Builds the data of a tuple or struct variant, then tags it.
+struct VariantSerializer<S> {
+    variant: &'static str,
+    inner: S,
+}
+
+impl ser::SerializeTupleVariant for VariantSerializer<ArraySerializer> {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), JSError> {
+        self.inner.push(value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        let cx = self.inner.array.cx();
+        tagged_value(cx, self.variant, self.inner.array.value())
+    }
+}
+
+impl ser::SerializeStructVariant for VariantSerializer<ObjectSerializer> {
+    type Ok = JSVal;
+    type Error = JSError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, key: &'static str, value: &T)
+                                              -> Result<(), JSError> {
+        self.inner.define(key, value)
+    }
+
+    fn end(self) -> Result<JSVal, JSError> {
+        let cx = self.inner.object.cx();
+        tagged_value(cx, self.variant, self.inner.object.value())
+    }
+}
+
+/// Turns map keys into property names.
+struct KeySerializer;
+
+fn unsupported_key() -> JSError {
+    JSError::new("map keys must be strings, numbers, booleans, chars or unit variants")
+}
+
+macro_rules! serialize_key_to_string {
+    ($($method:ident($ty:ty),)*) => {$(
+        fn $method(self, v: $ty) -> Result<String, JSError> {
+            Ok(v.to_string())
+        }
+    )*}
+}
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = JSError;
+    type SerializeSeq = Impossible<String, JSError>;
+    type SerializeTuple = Impossible<String, JSError>;
+    type SerializeTupleStruct = Impossible<String, JSError>;
+    type SerializeTupleVariant = Impossible<String, JSError>;
+    type SerializeMap = Impossible<String, JSError>;
+    type SerializeStruct = Impossible<String, JSError>;
+    type SerializeStructVariant = Impossible<String, JSError>;
+
+    serialize_key_to_string! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+    }
+
+    fn serialize_bytes(self, _v: &[u8]) -> Result<String, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_none(self) -> Result<String, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String, JSError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<String, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<String, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str)
+                              -> Result<String, JSError> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T)
+                                                       -> Result<String, JSError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32,
+                                                        _variant: &'static str, _value: &T)
+                                                        -> Result<String, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, _len: usize)
+                              -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_tuple_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                               _len: usize) -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize)
+                        -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+
+    fn serialize_struct_variant(self, _name: &'static str, _index: u32, _variant: &'static str,
+                                _len: usize) -> Result<Impossible<String, JSError>, JSError> {
+        Err(unsupported_key())
+    }
+}
+
+// ___________________________________________________________________________
+// JS to Rust
+
+struct Deserializer {
+    cx: *mut JSContext,
+    value: JSVal,
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer {
+    type Error = JSError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JSError> {
+        let value = self.value;
+        if value.is_null_or_undefined() {
+            visitor.visit_unit()
+        } else if value.is_boolean() {
+            visitor.visit_bool(value.to_boolean())
+        } else if value.is_int32() {
+            visitor.visit_i32(value.to_int32())
+        } else if value.is_double() {
+            // Integral doubles are how 64-bit integers come back.
+            let n = value.to_double();
+            if n.fract() == 0.0 && n.abs() < 9007199254740992.0 {
+                visitor.visit_i64(n as i64)
+            } else {
+                visitor.visit_f64(n)
+            }
+        } else if value.is_string() {
+            visitor.visit_string(try!(unsafe { jsstring_to_string(self.cx, value.to_string()) }))
+        } else if value.is_object() {
+            let object = try!(RootedObject::new(self.cx, value.to_object()));
+            if unsafe { JS_IsArrayObject(self.cx, object.get()) } != 0 {
+                visitor.visit_seq(try!(ArrayAccess::new(object)))
+            } else {
+                visitor.visit_map(try!(ObjectAccess::new(object)))
+            }
+        } else {
+            Err(JSError::new("unsupported JS value"))
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JSError> {
+        if self.value.is_null_or_undefined() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+                                                   -> Result<V::Value, JSError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value, JSError> {
+        let value = self.value;
+        if value.is_string() {
+            let variant = try!(unsafe { jsstring_to_string(self.cx, value.to_string()) });
+            let variant: StringDeserializer<JSError> = variant.into_deserializer();
+            return visitor.visit_enum(variant);
+        }
+        if !value.is_object() {
+            return Err(JSError::new("expected a string or an object for an enum"));
+        }
+        let access = try!(ObjectAccess::new(try!(RootedObject::new(self.cx, value.to_object()))));
+        if access.ids.len() != 1 {
+            return Err(JSError::new("expected an object with a single property for an enum"));
+        }
+        let variant = try!(access.key_string(0));
+        let data = try!(access.value_at(0));
+        visitor.visit_enum(EnumAccess {
+            cx: self.cx,
+            variant: variant,
+            data: data,
+        })
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ArrayAccess {
+    array: RootedObject,
+    index: u32,
+    length: u32,
+}
+
+impl ArrayAccess {
+    fn new(array: RootedObject) -> Result<ArrayAccess, JSError> {
+        let mut length = 0;
+        unsafe {
+            if JS_GetArrayLength(array.cx(), array.get(), &mut length) == ERR {
+                return Err(JSError::from_pending_exception(array.cx()));
+            }
+        }
+        Ok(ArrayAccess {
+            array: array,
+            index: 0,
+            length: length,
+        })
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for ArrayAccess {
+    type Error = JSError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>, JSError> {
+        if self.index == self.length {
+            return Ok(None);
+        }
+        let cx = self.array.cx();
+        let mut value = UndefinedValue();
+        unsafe {
+            if JS_GetElement(cx, self.array.get(), self.index, &mut value) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        self.index += 1;
+        seed.deserialize(Deserializer { cx: cx, value: value }).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some((self.length - self.index) as usize)
+    }
+}
+
+/// Walks an object's own enumerable properties.
+struct ObjectAccess {
+    object: RootedObject,
+    // The object keeps the ids' atoms alive.
+    ids: Vec<jsid>,
+    index: usize,
+}
+
+impl ObjectAccess {
+    fn new(object: RootedObject) -> Result<ObjectAccess, JSError> {
+        let cx = object.cx();
+        let mut ids = vec![];
+        unsafe {
+            let array = JS_Enumerate(cx, object.get());
+            if array.is_null() {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            for i in 0..JS_IdArrayLength(cx, array) {
+                ids.push(JS_IdArrayGet(cx, array, i));
+            }
+            JS_DestroyIdArray(cx, array);
+        }
+        Ok(ObjectAccess {
+            object: object,
+            ids: ids,
+            index: 0,
+        })
+    }
+
+    fn key_string(&self, index: usize) -> Result<String, JSError> {
+        let cx = self.object.cx();
+        let mut key = UndefinedValue();
+        unsafe {
+            if JS_IdToValue(cx, self.ids[index], &mut key) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+            if key.is_string() {
+                jsstring_to_string(cx, key.to_string())
+            } else {
+                // Index properties have integer ids.
+                Ok(key.to_int32().to_string())
+            }
+        }
+    }
+
+    fn value_at(&self, index: usize) -> Result<JSVal, JSError> {
+        let cx = self.object.cx();
+        let mut value = UndefinedValue();
+        unsafe {
+            if JS_GetPropertyById(cx, self.object.get(), self.ids[index], &mut value) == ERR {
+                return Err(JSError::from_pending_exception(cx));
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for ObjectAccess {
+    type Error = JSError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, JSError> {
+        if self.index == self.ids.len() {
+            return Ok(None);
+        }
+        let key = try!(self.key_string(self.index));
+        seed.deserialize(KeyDeserializer { key: key }).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, JSError> {
+        let value = try!(self.value_at(self.index));
+        self.index += 1;
+        seed.deserialize(Deserializer { cx: self.object.cx(), value: value })
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.ids.len() - self.index)
+    }
+}
+
+/// The single property of an object standing for an enum variant.
+struct EnumAccess {
+    cx: *mut JSContext,
+    variant: String,
+    data: JSVal,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = JSError;
+    type Variant = Deserializer;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Deserializer), JSError> {
+        let variant: StringDeserializer<JSError> = self.variant.into_deserializer();
+        let variant = try!(seed.deserialize(variant));
+        Ok((variant, Deserializer { cx: self.cx, value: self.data }))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Deserializer {
+    type Error = JSError;
+
+    fn unit_variant(self) -> Result<(), JSError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, JSError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, JSError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(self, _fields: &'static [&'static str], visitor: V)
+                                       -> Result<V::Value, JSError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+/// Reads a property name back as whatever type the map's keys have.
+struct KeyDeserializer {
+    key: String,
+}
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {$(
+        fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JSError> {
+            match self.key.parse() {
+                Ok(v) => visitor.$visit(v),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(&self.key), &visitor)),
+            }
+        }
+    )*}
+}
+
+impl<'de> de::Deserializer<'de> for KeyDeserializer {
+    type Error = JSError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JSError> {
+        visitor.visit_string(self.key)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, JSError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V)
+                                                   -> Result<V::Value, JSError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value, JSError> {
+        let variant: StringDeserializer<JSError> = self.key.into_deserializer();
+        visitor.visit_enum(variant)
+    }
+
+    forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_char;
+    use std::collections::{BTreeMap, HashMap};
+    use jsapi::JS_DefineProperty;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{to_jsval_serde, from_jsval_serde};
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Shape {
+        Dot,
+        Circle(f64),
+        Segment(i32, i32),
+        Rect { width: u32, height: u32 },
+        Group(Vec<Shape>),
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    enum Layer {
+        Background,
+        Foreground,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Scene {
+        name: String,
+        shapes: Vec<Shape>,
+        by_id: BTreeMap<u32, Shape>,
+        by_layer: BTreeMap<Layer, Vec<String>>,
+        flags: HashMap<String, Option<bool>>,
+        big: u64,
+        offset: (i8, f32),
+        parent: Option<Box<Scene>>,
+    }
+
+    fn scene() -> Scene {
+        let mut by_id = BTreeMap::new();
+        by_id.insert(7, Shape::Rect { width: 3, height: 4 });
+        by_id.insert(1000, Shape::Group(vec![Shape::Dot, Shape::Circle(0.5)]));
+        let mut by_layer = BTreeMap::new();
+        by_layer.insert(Layer::Background, vec!["sky".to_string()]);
+        by_layer.insert(Layer::Foreground, vec![]);
+        let mut flags = HashMap::new();
+        flags.insert("visible".to_string(), Some(true));
+        flags.insert("unknown".to_string(), None);
+        Scene {
+            name: "main \u{1F600}".to_string(),
+            shapes: vec![Shape::Dot, Shape::Circle(2.5), Shape::Segment(-1, 1)],
+            by_id: by_id,
+            by_layer: by_layer,
+            flags: flags,
+            big: 1 << 40,
+            offset: (-3, 0.25),
+            parent: Some(Box::new(Scene {
+                name: "parent".to_string(),
+                shapes: vec![],
+                by_id: BTreeMap::new(),
+                by_layer: BTreeMap::new(),
+                flags: HashMap::new(),
+                big: 0,
+                offset: (0, 0.0),
+                parent: None,
+            })),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let original = scene();
+            let value = to_jsval_serde(rt.cx(), &original).unwrap();
+            unsafe {
+                assert!(JS_DefineProperty(rt.cx(), global, b"scene\0".as_ptr() as *const c_char,
+                                          value, None, None, 0) != 0);
+            }
+            let check = "scene.shapes[0] === 'Dot' && scene.shapes[1].Circle === 2.5 && \
+                         scene.shapes[2].Segment[0] === -1 && scene.by_id['7'].Rect.height === 4 && \
+                         scene.by_layer.Background[0] === 'sky' && scene.flags.unknown === null && \
+                         scene.parent.parent === null";
+            assert!(evaluate(&rt, global, check).unwrap().to_boolean());
+            let back: Scene = from_jsval_serde(rt.cx(), value).unwrap();
+            assert_eq!(back, original);
+        });
+    }
+
+    #[test]
+    fn reads_script_objects() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let value = evaluate(&rt, global, "[{Rect: {width: 1, height: 2}}, 'Dot', {Group: []}]").unwrap();
+            let shapes: Vec<Shape> = from_jsval_serde(rt.cx(), value).unwrap();
+            assert_eq!(shapes, vec![Shape::Rect { width: 1, height: 2 }, Shape::Dot, Shape::Group(vec![])]);
+
+            let value = evaluate(&rt, global, "({Circle: 1, Dot: null})").unwrap();
+            assert!(from_jsval_serde::<Shape>(rt.cx(), value).is_err());
+            let value = evaluate(&rt, global, "({'x': 1})").unwrap();
+            assert!(from_jsval_serde::<BTreeMap<u32, u32>>(rt.cx(), value).is_err());
+        });
+    }
+}
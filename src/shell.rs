@@ -0,0 +1,84 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The SpiderMonkey shell's GC testing functions, for JS test suites.
+//! Nothing here is installed unless asked for; don't expose it to
+//! untrusted script.
+
+use jsapi::{JSContext, JSObject, JS_GC, JS_GetRuntime};
+use jsval::{NumberValue, UndefinedValue};
+use closure::define_closure;
+#[cfg(feature = "debugmozjs")]
+use conversions::to_number;
+use error::JSError;
+use rust::gc_bytes;
+#[cfg(feature = "debugmozjs")]
+use rust::set_gc_zeal;
+
+/// The shell's default for `gczeal`'s frequency.
+#[cfg(feature = "debugmozjs")]
+const DEFAULT_ZEAL_FREQUENCY: u32 = 100;
+
+/// Defines `gc()`, which runs a full collection, and `gcbytes()`, which
+/// returns the size of the GC heap, on `global`. With the `debugmozjs`
+/// feature, `gczeal(mode, frequency)` is defined too.
+pub fn install_test_functions(cx: *mut JSContext, global: *mut JSObject) -> Result<(), JSError> {
+    try!(define_closure(cx, global, "gc", 0, 0, |call| {
+        unsafe { JS_GC(JS_GetRuntime(call.cx())) };
+        Ok(UndefinedValue())
+    }));
+    try!(define_closure(cx, global, "gcbytes", 0, 0, |call| {
+        Ok(NumberValue(gc_bytes(call.cx()) as f64))
+    }));
+    try!(define_gczeal(cx, global));
+    Ok(())
+}
+
+#[cfg(feature = "debugmozjs")]
+fn define_gczeal(cx: *mut JSContext, global: *mut JSObject) -> Result<(), JSError> {
+    try!(define_closure(cx, global, "gczeal", 2, 0, |call| {
+        let mode = try!(to_number(call.cx(), call.arg(0)));
+        let frequency = if call.argc() > 1 {
+            try!(to_number(call.cx(), call.arg(1))) as u32
+        } else {
+            DEFAULT_ZEAL_FREQUENCY
+        };
+        set_gc_zeal(call.cx(), mode as u8, frequency);
+        Ok(UndefinedValue())
+    }));
+    Ok(())
+}
+
+#[cfg(not(feature = "debugmozjs"))]
+fn define_gczeal(_cx: *mut JSContext, _global: *mut JSObject) -> Result<(), JSError> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::install_test_functions;
+
+    #[test]
+    fn gc_frees_garbage_made_by_script() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            assert!(evaluate(&rt, global, "gc()").is_err());
+            install_test_functions(rt.cx(), global).unwrap();
+            if cfg!(feature = "debugmozjs") {
+                evaluate(&rt, global, "gczeal(0)").unwrap();
+            }
+            let freed = evaluate(&rt, global, "gc(); \
+                                               var garbage = []; \
+                                               for (var i = 0; i < 10000; i++) garbage.push({i: i}); \
+                                               garbage = null; \
+                                               var before = gcbytes(); \
+                                               gc(); \
+                                               gcbytes() < before").unwrap();
+            assert!(freed.to_boolean());
+        });
+    }
+}
@@ -0,0 +1,295 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Builders for the null-terminated spec tables taken by `JS_DefineFunctions`
+//! and friends.
+
+use std::ffi::CString;
+use std::ptr;
+use jsapi::{JSContext, JSObject, JSFunctionSpec, JSNative, JSNativeWrapper};
+use jsapi::{JSPropertySpec, JSPropertyOpWrapper, JSStrictPropertyOpWrapper};
+use jsapi::{JS_DefineFunctions, JS_DefineProperties};
+use error::JSError;
+use {JSPROP_SHARED, JSPROP_NATIVE_ACCESSORS};
+use ERR;
+
+/// A null-terminated `JSFunctionSpec` table that owns its names.
+pub struct FunctionSpecs {
+    specs: Vec<JSFunctionSpec>,
+    // The specs point into these, so they must not be dropped before them.
+    #[allow(dead_code)]
+    names: Vec<CString>,
+}
+
+impl FunctionSpecs {
+    /// The table, including its terminating entry.
+    pub fn as_slice(&self) -> &[JSFunctionSpec] {
+        &self.specs
+    }
+
+    /// The number of functions in the table, not counting the terminator.
+    pub fn len(&self) -> usize {
+        self.specs.len() - 1
+    }
+}
+
+/// Builds a `FunctionSpecs` table.
+pub struct FunctionSpecBuilder {
+    entries: Vec<(CString, JSNative, u16, u16)>,
+}
+
+impl FunctionSpecBuilder {
+    pub fn new() -> FunctionSpecBuilder {
+        FunctionSpecBuilder {
+            entries: vec!(),
+        }
+    }
+
+    /// Adds a function `name` taking `nargs` arguments.
+    pub fn function(self, name: &str, call: JSNative, nargs: u16) -> FunctionSpecBuilder {
+        self.function_with_flags(name, call, nargs, 0)
+    }
+
+    /// Adds a function with the given `JSPROP_*`/`JSFUN_*` flags.
+    pub fn function_with_flags(mut self, name: &str, call: JSNative, nargs: u16, flags: u16)
+                               -> FunctionSpecBuilder {
+        assert!(call.is_some(), "function spec {} needs a native", name);
+        self.entries.push((CString::new(name).unwrap(), call, nargs, flags));
+        self
+    }
+
+    /// Creates the table and appends the terminating entry.
+    pub fn build(self) -> FunctionSpecs {
+        let mut specs = vec!();
+        let mut names = vec!();
+        for (name, call, nargs, flags) in self.entries.into_iter() {
+            specs.push(JSFunctionSpec {
+                name: name.as_ptr(),
+                call: JSNativeWrapper { op: call, info: ptr::null() },
+                nargs: nargs,
+                flags: flags,
+                selfHostedName: ptr::null(),
+            });
+            names.push(name);
+        }
+        specs.push(JSFunctionSpec {
+            name: ptr::null(),
+            call: JSNativeWrapper { op: None, info: ptr::null() },
+            nargs: 0,
+            flags: 0,
+            selfHostedName: ptr::null(),
+        });
+        FunctionSpecs {
+            specs: specs,
+            names: names,
+        }
+    }
+}
+
+/// Defines every function in `specs` on `obj`.
+pub fn define_functions(cx: *mut JSContext, obj: *mut JSObject, specs: &FunctionSpecs)
+                        -> Result<(), JSError> {
+    unsafe {
+        if JS_DefineFunctions(cx, obj, specs.as_slice().as_ptr()) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+/// A null-terminated `JSPropertySpec` table that owns its names.
+pub struct PropertySpecs {
+    specs: Vec<JSPropertySpec>,
+    #[allow(dead_code)]
+    names: Vec<CString>,
+}
+
+impl PropertySpecs {
+    /// The table, including its terminating entry.
+    pub fn as_slice(&self) -> &[JSPropertySpec] {
+        &self.specs
+    }
+
+    /// The number of properties in the table, not counting the terminator.
+    pub fn len(&self) -> usize {
+        self.specs.len() - 1
+    }
+}
+
+/// Builds a `PropertySpecs` table of native accessor properties.
+///
+/// Getters and setters are `JSNative`s: a getter sets the return value, a
+/// setter receives the new value as its only argument.
+pub struct PropertySpecBuilder {
+    entries: Vec<(CString, i8, u8, JSNative, JSNative)>,
+}
+
+impl PropertySpecBuilder {
+    pub fn new() -> PropertySpecBuilder {
+        PropertySpecBuilder {
+            entries: vec!(),
+        }
+    }
+
+    /// Adds a read-only property.
+    ///
+    /// The property simply has no setter: flagging an accessor property
+    /// `JSPROP_READONLY` trips engine assertions. Assigning to it is ignored,
+    /// or throws in strict mode code.
+    pub fn getter(self, name: &str, getter: JSNative, flags: u8) -> PropertySpecBuilder {
+        self.property(name, 0, flags, getter, None)
+    }
+
+    /// Adds a read-write property.
+    pub fn accessor(self, name: &str, getter: JSNative, setter: JSNative, flags: u8)
+                    -> PropertySpecBuilder {
+        assert!(setter.is_some(), "read-write property {} needs a setter", name);
+        self.property(name, 0, flags, getter, setter)
+    }
+
+    /// Adds a property with full control over the spec entry. `flags` are
+    /// `JSPROP_*` values; the native accessor flags are always added.
+    pub fn property(mut self, name: &str, tinyid: i8, flags: u8, getter: JSNative, setter: JSNative)
+                    -> PropertySpecBuilder {
+        assert!(getter.is_some(), "property spec {} needs a getter", name);
+        let flags = flags | (JSPROP_SHARED | JSPROP_NATIVE_ACCESSORS) as u8;
+        self.entries.push((CString::new(name).unwrap(), tinyid, flags, getter, setter));
+        self
+    }
+
+    /// Creates the table and appends the terminating entry.
+    pub fn build(self) -> PropertySpecs {
+        let mut specs = vec!();
+        let mut names = vec!();
+        for (name, tinyid, flags, getter, setter) in self.entries.into_iter() {
+            specs.push(JSPropertySpec {
+                name: name.as_ptr(),
+                tinyid: tinyid,
+                flags: flags,
+                getter: JSPropertyOpWrapper { op: getter, info: ptr::null() },
+                setter: JSStrictPropertyOpWrapper { op: setter, info: ptr::null() },
+            });
+            names.push(name);
+        }
+        specs.push(JSPropertySpec {
+            name: ptr::null(),
+            tinyid: 0,
+            flags: 0,
+            getter: JSPropertyOpWrapper { op: None, info: ptr::null() },
+            setter: JSStrictPropertyOpWrapper { op: None, info: ptr::null() },
+        });
+        PropertySpecs {
+            specs: specs,
+            names: names,
+        }
+    }
+}
+
+/// Defines every property in `specs` on `obj`.
+pub fn define_properties(cx: *mut JSContext, obj: *mut JSObject, specs: &PropertySpecs)
+                         -> Result<(), JSError> {
+    unsafe {
+        if JS_DefineProperties(cx, obj, specs.as_slice().as_ptr()) == ERR {
+            return Err(JSError::from_pending_exception(cx));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_uint;
+    use std::cell::Cell;
+    use jsapi::{JSBool, JSContext};
+    use jsval::{JSVal, DoubleValue, Int32Value, BooleanValue};
+    use conversions::{to_number, to_boolean, to_string};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{FunctionSpecBuilder, define_functions};
+    use super::{PropertySpecBuilder, define_properties};
+    use {JS_ARGV, JS_SET_RVAL, JSPROP_ENUMERATE};
+
+    unsafe fn binary_op<F: Fn(f64, f64) -> f64>(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal,
+                                                 op: F) -> JSBool {
+        assert_eq!(argc, 2);
+        let argv = JS_ARGV(cx, vp);
+        let a = to_number(cx, *argv.offset(0)).unwrap();
+        let b = to_number(cx, *argv.offset(1)).unwrap();
+        JS_SET_RVAL(cx, vp, DoubleValue(op(a, b)));
+        1
+    }
+
+    unsafe extern "C" fn add(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal) -> JSBool {
+        binary_op(cx, argc, vp, |a, b| a + b)
+    }
+
+    unsafe extern "C" fn mul(cx: *mut JSContext, argc: c_uint, vp: *mut JSVal) -> JSBool {
+        binary_op(cx, argc, vp, |a, b| a * b)
+    }
+
+    #[test]
+    fn define_two_functions() {
+        let specs = FunctionSpecBuilder::new()
+            .function("add", Some(add), 2)
+            .function("mul", Some(mul), 2)
+            .build();
+        assert_eq!(specs.len(), 2);
+        assert!(specs.as_slice().last().unwrap().name.is_null());
+
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let obj = evaluate(&rt, global, "var math = {}; math").unwrap().to_object();
+            define_functions(rt.cx(), obj, &specs).unwrap();
+            let result = evaluate(&rt, global, "math.add(2, 3) + math.mul(4, 5)").unwrap();
+            assert_eq!(result.to_number(), 25.0);
+            assert_eq!(evaluate(&rt, global, "math.add.length").unwrap().to_number(), 2.0);
+        });
+    }
+
+    thread_local!(static DEBUG: Cell<bool> = Cell::new(false));
+
+    unsafe extern "C" fn get_version(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        JS_SET_RVAL(cx, vp, Int32Value(3));
+        1
+    }
+
+    unsafe extern "C" fn get_debug(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        JS_SET_RVAL(cx, vp, BooleanValue(DEBUG.with(|d| d.get())));
+        1
+    }
+
+    unsafe extern "C" fn set_debug(cx: *mut JSContext, _argc: c_uint, vp: *mut JSVal) -> JSBool {
+        let value = to_boolean(cx, *JS_ARGV(cx, vp));
+        DEBUG.with(|d| d.set(value));
+        1
+    }
+
+    #[test]
+    fn define_read_only_and_read_write_properties() {
+        let specs = PropertySpecBuilder::new()
+            .getter("version", Some(get_version), JSPROP_ENUMERATE as u8)
+            .accessor("debug", Some(get_debug), Some(set_debug), JSPROP_ENUMERATE as u8)
+            .build();
+        assert_eq!(specs.len(), 2);
+
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            let obj = evaluate(&rt, global, "var engine = {}; engine").unwrap().to_object();
+            define_properties(rt.cx(), obj, &specs).unwrap();
+
+            let version = evaluate(&rt, global, "engine.version = 5; engine.version").unwrap();
+            assert_eq!(version.to_number(), 3.0);
+            assert!(evaluate(&rt, global, "'use strict'; engine.version = 5").is_err());
+
+            assert!(!evaluate(&rt, global, "engine.debug").unwrap().to_boolean());
+            assert!(evaluate(&rt, global, "engine.debug = true; engine.debug").unwrap().to_boolean());
+            assert!(DEBUG.with(|d| d.get()));
+
+            let keys = evaluate(&rt, global, "Object.keys(engine).join()").unwrap();
+            assert_eq!(to_string(rt.cx(), keys).unwrap(), "version,debug");
+        });
+    }
+}
@@ -0,0 +1,227 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Inspecting the JS stack from native code.
+
+use libc::c_uint;
+use std::fmt::Write;
+use std::ptr;
+use jsapi::{JSContext, JS_DescribeScriptedCaller, JS_GetFunctionId};
+use jsapi::{JS_SaveFrameChain, JS_RestoreFrameChain};
+use jsdbgapi::{JS_FrameIterator, JS_GetFrameScript, JS_GetFramePC, JS_GetFrameFunction};
+use jsdbgapi::JS_PCToLineNumber;
+use conversions::jsstring_to_string;
+use error::JSError;
+use hooks::script_location;
+use ERR;
+
+/// Where the innermost script on the stack currently is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallerDescription {
+    pub filename: String,
+    pub line: u32,
+}
+
+/// Describes the script that (directly or through other natives) called the
+/// running native, or returns `None` if no script is running, e.g. when
+/// called directly from Rust.
+pub fn describe_scripted_caller(cx: *mut JSContext) -> Option<CallerDescription> {
+    let mut script = ptr::null_mut();
+    let mut line: c_uint = 0;
+    unsafe {
+        if JS_DescribeScriptedCaller(cx, &mut script, &mut line) == 0 || script.is_null() {
+            return None;
+        }
+        let (filename, _) = script_location(cx, script);
+        Some(CallerDescription {
+            filename: filename.unwrap_or(String::new()),
+            line: line,
+        })
+    }
+}
+
+/// Formats the JS stack, innermost frame first, one line per frame:
+///
+/// ```text
+/// #0 inner (file.js:12)
+/// #1 <anonymous> (file.js:3)
+/// #2 <top-level> (file.js:20)
+/// ```
+///
+/// Frames without a script show as `<native>`. This only reads the stack
+/// and never runs script or triggers a GC, so it's safe to call from a
+/// native or an operation callback, e.g. to write the stack to stderr when
+/// something goes wrong.
+pub fn dump_js_stack(cx: *mut JSContext) -> String {
+    let mut out = String::new();
+    let mut iter = ptr::null_mut();
+    let mut depth = 0;
+    unsafe {
+        loop {
+            let frame = JS_FrameIterator(cx, &mut iter);
+            if frame.is_null() {
+                break;
+            }
+            let script = JS_GetFrameScript(cx, frame);
+            if script.is_null() {
+                let _ = writeln!(out, "#{} <native>", depth);
+            } else {
+                let fun = JS_GetFrameFunction(cx, frame);
+                let name = if fun.is_null() {
+                    "<top-level>".to_string()
+                } else {
+                    let id = JS_GetFunctionId(fun);
+                    let name = if id.is_null() { None } else { jsstring_to_string(cx, id).ok() };
+                    name.unwrap_or("<anonymous>".to_string())
+                };
+                let (filename, _) = script_location(cx, script);
+                let line = JS_PCToLineNumber(cx, script, JS_GetFramePC(cx, frame));
+                let _ = writeln!(out, "#{} {} ({}:{})", depth, name,
+                                 filename.as_ref().map_or("<unknown>", |s| &**s), line);
+            }
+            depth += 1;
+        }
+    }
+    out
+}
+
+/// Sets the JS stack aside until dropped, even by a panic, so that script
+/// run in the meantime, e.g. by a native, starts from an empty stack: its
+/// errors' stacks and `describe_scripted_caller` don't see the frames of
+/// whatever called the native. Saving leaves the context in no
+/// compartment; enter one before running anything.
+/// `EvaluateOptions::save_frame_chain` does all of this.
+pub struct SavedFrameChain {
+    cx: *mut JSContext,
+}
+
+impl SavedFrameChain {
+    /// Saves the frame chain, which can fail on OOM.
+    pub fn new(cx: *mut JSContext) -> Result<SavedFrameChain, JSError> {
+        unsafe {
+            if JS_SaveFrameChain(cx) == ERR {
+                return Err(JSError::from_failed_allocation(cx, "saving the frame chain"));
+            }
+        }
+        Ok(SavedFrameChain { cx: cx })
+    }
+}
+
+impl Drop for SavedFrameChain {
+    fn drop(&mut self) {
+        unsafe { JS_RestoreFrameChain(self.cx) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_uint;
+    use std::cell::RefCell;
+    use jsapi::{JSBool, JSContext, JSObject};
+    use jsval::{JSVal, StringValue};
+    use closure::define_closure;
+    use rust::{Runtime, EvaluateOptions, with_compartment};
+    use strings::new_string;
+    use testing::{new_global, evaluate, object_ptr};
+    use specs::{FunctionSpecBuilder, define_functions};
+    use super::{CallerDescription, describe_scripted_caller, dump_js_stack};
+
+    thread_local!(static CALLER: RefCell<Option<CallerDescription>> = RefCell::new(None));
+    thread_local!(static STACK: RefCell<String> = RefCell::new(String::new()));
+
+    unsafe extern "C" fn record_stack(cx: *mut JSContext, _argc: c_uint, _vp: *mut JSVal) -> JSBool {
+        STACK.with(|stack| *stack.borrow_mut() = dump_js_stack(cx));
+        1
+    }
+
+    unsafe extern "C" fn record_caller(cx: *mut JSContext, _argc: c_uint, _vp: *mut JSVal) -> JSBool {
+        CALLER.with(|caller| *caller.borrow_mut() = describe_scripted_caller(cx));
+        1
+    }
+
+    #[test]
+    fn native_sees_its_caller() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let specs = FunctionSpecBuilder::new().function("recordCaller", Some(record_caller), 0).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            assert!(describe_scripted_caller(rt.cx()).is_none());
+            assert!(rt.run_script(global, "var a = 1;\nrecordCaller();",
+                                  "caller.js", 10).is_ok());
+        });
+        let caller = CALLER.with(|caller| caller.borrow().clone());
+        assert_eq!(caller, Some(CallerDescription { filename: "caller.js".to_string(), line: 11 }));
+    }
+
+    #[test]
+    fn stack_dump_lists_frames_innermost_first() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let specs = FunctionSpecBuilder::new().function("recordStack", Some(record_stack), 0).build();
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_functions(rt.cx(), global, &specs).unwrap();
+            assert_eq!(dump_js_stack(rt.cx()), "");
+            let source = "function outer() { middle(); }\n\
+                          function middle() { inner(); }\n\
+                          function inner() { recordStack(); }\n\
+                          outer();";
+            assert!(rt.run_script(global, source, "stack.js", 1).is_ok());
+        });
+        let stack = STACK.with(|stack| stack.borrow().clone());
+        let lines: Vec<&str> = stack.lines().collect();
+        assert_eq!(lines, vec!["#0 inner (stack.js:3)",
+                               "#1 middle (stack.js:2)",
+                               "#2 outer (stack.js:1)",
+                               "#3 <top-level> (stack.js:4)"]);
+    }
+
+    /// Defines `runInner()`, which evaluates a throwing script, saving the
+    /// frame chain if `save`, and returns the stack of what it threw.
+    fn define_run_inner(rt: &Runtime, global: *mut JSObject, save: bool) {
+        let rt_ptr = rt as *const Runtime;
+        define_closure(rt.cx(), global, "runInner", 0, 0, move |call| {
+            let rt = unsafe { &*rt_ptr };
+            let options = EvaluateOptions::new().filename("inner.js").save_frame_chain(save);
+            let source = "(function innerFn() { throw new Error('inner'); })()";
+            let error = rt.evaluate(object_ptr(global), source, &options).unwrap_err();
+            assert!(error.message.contains("inner"), "{}", error.message);
+            Ok(StringValue(unsafe { &*try!(new_string(call.cx(), &error.js_stack.unwrap())) }))
+        }).unwrap();
+    }
+
+    const OUTER: &'static str = "function outerFn() {\n\
+                                     var inner = runInner();\n\
+                                     try { throw new Error('outer'); } catch (e) { return [inner, e.stack]; }\n\
+                                 }\n\
+                                 var stacks = outerFn();";
+
+    #[test]
+    fn saving_the_frame_chain_hides_the_callers_frames() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_run_inner(&rt, global, true);
+            assert!(evaluate(&rt, global, OUTER).is_ok());
+            assert!(evaluate(&rt, global, "/innerFn/.test(stacks[0]) && !/outerFn/.test(stacks[0])")
+                .unwrap().to_boolean());
+            // The outer script's own stack is intact afterwards.
+            assert!(evaluate(&rt, global, "/outerFn/.test(stacks[1]) && !/innerFn/.test(stacks[1])")
+                .unwrap().to_boolean());
+            assert!(describe_scripted_caller(rt.cx()).is_none());
+        });
+    }
+
+    #[test]
+    fn without_saving_the_callers_frames_leak_in() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            define_run_inner(&rt, global, false);
+            assert!(evaluate(&rt, global, OUTER).is_ok());
+            assert!(evaluate(&rt, global, "/innerFn/.test(stacks[0]) && /outerFn/.test(stacks[0])")
+                .unwrap().to_boolean());
+        });
+    }
+}
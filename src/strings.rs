@@ -0,0 +1,483 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Working with `JSString`s without copying them into Rust.
+//!
+//! Strings returned as raw pointers aren't explicitly rooted; like any GC
+//! pointer they stay alive while on the stack, where the conservative
+//! scanner finds them, and need rooting, e.g. with `RootedString`, when
+//! stored anywhere else. `concat_strings` and `substring`, whose results are
+//! typically built up over many calls, return them rooted.
+
+use libc::{c_char, size_t};
+use std::cmp::Ordering;
+use std::ffi::CString;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::slice;
+use std::sync::Arc;
+use jsapi::{JSBool, jschar, JSStringFinalizer, JS_NewExternalString, JSContext, JSString, JS_GetStringCharsAndLength, JS_ClearPendingException};
+use jsapi::{JS_AddNamedStringRoot, JS_RemoveStringRoot, JS_CompareStrings, JS_StringEqualsAscii};
+use jsapi::{JS_NewUCStringCopyN, JS_ConcatStrings, JS_NewDependentString, JS_GetStringLength};
+use jsapi::JS_ValueToString;
+use jsval::{JSVal, StringValue};
+use conversions::{check_length, jsstring_to_string};
+use error::{JSError, injected_allocation_failure};
+use utf16::{self, Utf16Chars, to_utf16};
+
+/// Borrows the characters of a `JSString` in place.
+///
+/// The string is rooted for as long as the view lives, and the engine's GC
+/// doesn't move strings, so the characters stay valid even if a GC runs in
+/// the meantime. The borrowed slices can't outlive the view. The one thing
+/// to avoid is handing the view's string to an API that mutates strings in
+/// place; none in the crate do.
+pub struct JSStringView {
+    cx: *mut JSContext,
+    // Boxed so the root's address stays put.
+    string: Box<*mut JSString>,
+    chars: *const u16,
+    length: usize,
+}
+
+impl JSStringView {
+    /// Flattens `s` if it's a rope, which can fail on OOM.
+    pub fn new(cx: *mut JSContext, s: *mut JSString) -> Result<JSStringView, JSError> {
+        let mut string = Box::new(s);
+        let mut length: size_t = 0;
+        unsafe {
+            JS_AddNamedStringRoot(cx, &mut *string, b"JSStringView\0".as_ptr() as *const c_char);
+            let chars = JS_GetStringCharsAndLength(cx, s, &mut length);
+            if chars.is_null() {
+                JS_RemoveStringRoot(cx, &mut *string);
+                JS_ClearPendingException(cx);
+                return Err(JSError::out_of_memory("reading a string"));
+            }
+            Ok(JSStringView {
+                cx: cx,
+                string: string,
+                chars: chars,
+                length: length as usize,
+            })
+        }
+    }
+
+    pub fn string(&self) -> *mut JSString {
+        *self.string
+    }
+
+    /// The string's UTF-16 code units, unpaired surrogates included.
+    pub fn as_utf16(&self) -> &[u16] {
+        unsafe { slice::from_raw_parts(self.chars, self.length) }
+    }
+
+    /// The same as `as_utf16`.
+    pub fn code_units(&self) -> &[u16] {
+        self.as_utf16()
+    }
+
+    /// Decodes the string as it's iterated over, without copying it, turning
+    /// each unpaired surrogate into U+FFFD.
+    pub fn chars(&self) -> Utf16Chars {
+        utf16::decode(self.as_utf16())
+    }
+
+    /// Compares with `s` without allocating.
+    pub fn eq_str(&self, s: &str) -> bool {
+        let units = self.as_utf16();
+        // A UTF-16 unit takes one to three bytes of UTF-8.
+        s.len() >= units.len() && s.len() <= 3 * units.len() &&
+            utf16::units(s).eq(units.iter().cloned())
+    }
+
+    /// Hashes the code units, e.g. to look the string up in a table keyed by
+    /// a hash of `&str`s' UTF-16.
+    pub fn hash(&self) -> u64 {
+        let mut hasher = SipHasher::new();
+        self.as_utf16().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Drop for JSStringView {
+    fn drop(&mut self) {
+        unsafe { JS_RemoveStringRoot(self.cx, &mut *self.string) };
+    }
+}
+
+/// Keeps a string alive while nothing the GC scans refers to it, e.g. while
+/// building property names or output across calls that may collect. The
+/// root goes away when this is dropped.
+pub struct RootedString {
+    cx: *mut JSContext,
+    // Boxed so the root's address stays put.
+    string: Box<*mut JSString>,
+}
+
+impl RootedString {
+    /// Roots `s`. A null `s` is taken to come from a failed allocation, and
+    /// the pending exception is returned instead.
+    pub fn new(cx: *mut JSContext, s: *mut JSString) -> Result<RootedString, JSError> {
+        if s.is_null() {
+            return Err(unsafe { JSError::from_failed_allocation(cx, "creating a string") });
+        }
+        let mut string = Box::new(s);
+        unsafe {
+            JS_AddNamedStringRoot(cx, &mut *string, b"RootedString\0".as_ptr() as *const c_char);
+        }
+        Ok(RootedString {
+            cx: cx,
+            string: string,
+        })
+    }
+
+    /// Copies `s` into a new rooted JS string; see `new_string`.
+    pub fn from_str(cx: *mut JSContext, s: &str) -> Result<RootedString, JSError> {
+        RootedString::new(cx, try!(new_string(cx, s)))
+    }
+
+    /// Converts `v` with the ECMAScript ToString algorithm, which may run
+    /// script, e.g. an object's `toString`.
+    pub fn from_jsval(cx: *mut JSContext, v: JSVal) -> Result<RootedString, JSError> {
+        RootedString::new(cx, unsafe { JS_ValueToString(cx, v) })
+    }
+
+    pub fn cx(&self) -> *mut JSContext {
+        self.cx
+    }
+
+    pub fn as_ptr(&self) -> *mut JSString {
+        *self.string
+    }
+
+    /// The string as a value, e.g. to pass to `object::call_method` or
+    /// `JS_SetProperty`.
+    pub fn value(&self) -> JSVal {
+        StringValue(unsafe { &*self.as_ptr() })
+    }
+
+    /// The number of UTF-16 code units.
+    pub fn len(&self) -> usize {
+        unsafe { JS_GetStringLength(self.as_ptr()) as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the string into Rust, replacing unpaired surrogates.
+    pub fn to_string(&self) -> Result<String, JSError> {
+        unsafe { jsstring_to_string(self.cx, self.as_ptr()) }
+    }
+}
+
+/// Compares without copying, through a `JSStringView`. A rope that can't be
+/// flattened for lack of memory compares unequal.
+impl PartialEq<str> for RootedString {
+    fn eq(&self, other: &str) -> bool {
+        match JSStringView::new(self.cx, self.as_ptr()) {
+            Ok(view) => view.eq_str(other),
+            Err(_) => false,
+        }
+    }
+}
+
+impl<'a> PartialEq<&'a str> for RootedString {
+    fn eq(&self, other: &&'a str) -> bool {
+        *self == **other
+    }
+}
+
+impl Drop for RootedString {
+    fn drop(&mut self) {
+        unsafe { JS_RemoveStringRoot(self.cx, &mut *self.string) };
+    }
+}
+
+/// Compares code unit by code unit, like `<` on JS strings. Ropes are
+/// flattened, which can fail on OOM.
+pub fn compare_strings(cx: *mut JSContext, a: *mut JSString, b: *mut JSString) -> Result<Ordering, JSError> {
+    let mut result = 0;
+    unsafe {
+        if JS_CompareStrings(cx, a, b, &mut result) == 0 {
+            JS_ClearPendingException(cx);
+            return Err(JSError::out_of_memory("comparing strings"));
+        }
+    }
+    Ok(result.cmp(&0))
+}
+
+/// Whether `s` equals `lit`, which must be ASCII. Meant for checking a
+/// string against a known keyword; literals under 64 bytes don't allocate.
+pub fn string_equals_ascii(cx: *mut JSContext, s: *mut JSString, lit: &str) -> Result<bool, JSError> {
+    if lit.bytes().any(|b| b == 0 || b >= 0x80) {
+        return Err(JSError::new(format!("{:?} is not a NUL-free ASCII literal", lit)));
+    }
+    let mut buffer = [0u8; 64];
+    let owned;
+    let bytes = if lit.len() < buffer.len() {
+        for (dst, &src) in buffer.iter_mut().zip(lit.as_bytes()) {
+            *dst = src;
+        }
+        &buffer[..]
+    } else {
+        owned = CString::new(lit).unwrap();
+        owned.as_bytes_with_nul()
+    };
+    let mut matched: JSBool = 0;
+    unsafe {
+        if JS_StringEqualsAscii(cx, s, bytes.as_ptr() as *const c_char, &mut matched) == 0 {
+            JS_ClearPendingException(cx);
+            return Err(JSError::out_of_memory("comparing strings"));
+        }
+    }
+    Ok(matched != 0)
+}
+
+/// Copies `s` into a new JS string. Fails if it is longer than
+/// `conversions::check_length` allows.
+pub fn new_string(cx: *mut JSContext, s: &str) -> Result<*mut JSString, JSError> {
+    let chars = to_utf16(s);
+    unsafe {
+        if !check_length(cx, chars.len()) {
+            return Err(JSError::from_pending_exception(cx));
+        }
+        let string = JS_NewUCStringCopyN(cx, chars.as_ptr(), chars.len() as size_t);
+        if string.is_null() || injected_allocation_failure(cx) {
+            return Err(JSError::from_failed_allocation(cx, "creating a string"));
+        }
+        Ok(string)
+    }
+}
+
+/// Returns `a + b`, rooted. The engine builds a rope rather than copying,
+/// so appending repeatedly is linear overall; the rope is flattened once,
+/// the first time its characters are read.
+pub fn concat_strings(cx: *mut JSContext, a: *mut JSString, b: *mut JSString) -> Result<RootedString, JSError> {
+    unsafe {
+        let string = JS_ConcatStrings(cx, a, b);
+        if string.is_null() || injected_allocation_failure(cx) {
+            return Err(JSError::from_failed_allocation(cx, "concatenating strings"));
+        }
+        RootedString::new(cx, string)
+    }
+}
+
+/// Returns the `len` code units of `s` starting at `start`, rooted, sharing
+/// its characters where the engine can.
+pub fn substring(cx: *mut JSContext, s: *mut JSString, start: usize, len: usize) -> Result<RootedString, JSError> {
+    let length = unsafe { JS_GetStringLength(s) } as usize;
+    if start > length || len > length - start {
+        return Err(JSError::new(format!("substring {}..{}+{} out of range for a string of length {}",
+                                        start, start, len, length)));
+    }
+    unsafe {
+        let string = JS_NewDependentString(cx, s, start as size_t, len as size_t);
+        if string.is_null() || injected_allocation_failure(cx) {
+            return Err(JSError::from_failed_allocation(cx, "creating a substring"));
+        }
+        RootedString::new(cx, string)
+    }
+}
+
+/// What the engine holds on to for an external string. The finalizer has
+/// to come first: the engine hands back a pointer to it.
+#[repr(C)]
+struct ExternalBuffer {
+    finalizer: JSStringFinalizer,
+    buffer: Arc<Vec<u16>>,
+}
+
+unsafe extern "C" fn finalize_external(finalizer: *mut JSStringFinalizer, _chars: *mut jschar) {
+    drop(Box::from_raw(finalizer as *mut ExternalBuffer));
+}
+
+/// Creates a JS string whose characters are `buffer`'s, without copying.
+///
+/// The string keeps a clone of the `Arc` until the GC finalizes it, so the
+/// buffer can't be freed while script may still read it, and the buffer is
+/// released as soon as the last holder lets go of it. To be told when the
+/// engine is done with it, watch `Arc::strong_count` or wrap the data in a
+/// type whose `Drop` notifies you.
+pub fn new_external_string(cx: *mut JSContext, buffer: Arc<Vec<u16>>) -> Result<*mut JSString, JSError> {
+    let chars = buffer.as_ptr();
+    let length = buffer.len();
+    let external = Box::into_raw(Box::new(ExternalBuffer {
+        finalizer: JSStringFinalizer { finalize: Some(finalize_external) },
+        buffer: buffer,
+    }));
+    unsafe {
+        let string = JS_NewExternalString(cx, chars, length as size_t, &(*external).finalizer);
+        if string.is_null() {
+            drop(Box::from_raw(external));
+            return Err(JSError::from_failed_allocation(cx, "creating a string"));
+        }
+        Ok(string)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cmp::Ordering;
+    use std::hash::{Hash, Hasher, SipHasher};
+    use std::iter;
+    use std::sync::Arc;
+    use libc::c_char;
+    use jsapi::{JSContext, JSObject, JS_SetProperty};
+    use jsval::StringValue;
+    use jsapi::JS_GC;
+    use rust::{Runtime, with_compartment};
+    use testing::{with_test_runtime, new_global, evaluate, assert_all_collected, object_ptr};
+    use super::{JSStringView, compare_strings, string_equals_ascii};
+    use super::{new_string, concat_strings, substring, new_external_string, RootedString};
+    use object::call_method;
+
+    #[test]
+    fn view_of_a_large_string() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let expected: String = iter::repeat('a').take(1 << 20).collect();
+        with_compartment(rt.context(), object_ptr(global), || {
+            let big = assert_evaluates!(rt, global, "new Array((1 << 20) + 1).join('a')");
+            let view = JSStringView::new(rt.cx(), big.to_string()).unwrap();
+            assert_eq!(view.as_utf16().len(), 1 << 20);
+            assert!(view.eq_str(&expected));
+            assert!(!view.eq_str(&expected[1..]));
+            assert!(!view.eq_str("a"));
+
+            unsafe { JS_GC(rt.rt()) };
+            assert!(view.eq_str(&expected));
+        });
+    }
+
+    #[test]
+    fn decoding_chars_in_place() {
+        with_test_runtime(|rt, global| {
+            let s = assert_evaluates!(rt, global, "'caf\\u00e9 \\ud83d\\ude00 ' + String.fromCharCode(0xD800) + '!'");
+            let view = JSStringView::new(rt.cx(), s.to_string()).unwrap();
+            assert_eq!(view.code_units().len(), 10);
+            assert_eq!(view.code_units()[8], 0xD800);
+            let decoded: String = view.chars().collect();
+            assert_eq!(decoded, "caf\u{e9} \u{1F600} \u{FFFD}!");
+            assert_eq!(view.chars().position(|c| c == '\u{1F600}'), Some(5));
+
+            let mut chars = view.chars().skip(8);
+            assert_eq!(chars.next(), Some('!'));
+            assert_eq!(chars.next(), None);
+            assert_eq!(chars.next(), None);
+        });
+    }
+
+    #[test]
+    fn non_ascii_and_hashing() {
+        with_test_runtime(|rt, global| {
+            // A rope, flattened by the view.
+            let s = assert_evaluates!(rt, global, "var s = 'gr'; s + '\\u00fc\\u00df'");
+            let view = JSStringView::new(rt.cx(), s.to_string()).unwrap();
+            assert!(view.eq_str("grüß"));
+            assert!(!view.eq_str("gruss"));
+
+            let units: Vec<u16> = "grüß".utf16_units().collect();
+            let mut hasher = SipHasher::new();
+            (&units[..]).hash(&mut hasher);
+            assert_eq!(view.hash(), hasher.finish());
+        });
+    }
+
+    #[test]
+    fn comparisons() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let directive = assert_evaluates!(rt, global, "var u = 'use '; u + 'strict'").to_string();
+            assert!(string_equals_ascii(cx, directive, "use strict").unwrap());
+            assert!(!string_equals_ascii(cx, directive, "use asm").unwrap());
+            let accented = assert_evaluates!(rt, global, "'use str\u00efct'").to_string();
+            assert!(!string_equals_ascii(cx, accented, "use strict").unwrap());
+            assert!(string_equals_ascii(cx, accented, "use strïct").is_err());
+
+            assert_eq!(compare_strings(cx, directive, accented).unwrap(), Ordering::Less);
+            assert_eq!(compare_strings(cx, accented, directive).unwrap(), Ordering::Greater);
+            assert_eq!(compare_strings(cx, directive, directive).unwrap(), Ordering::Equal);
+        });
+    }
+
+    #[test]
+    fn building_a_string_from_pieces() {
+        with_test_runtime(|rt, _| {
+            let cx = rt.cx();
+            let mut report = RootedString::from_str(cx, "").unwrap();
+            for i in 0..1000 {
+                let piece = new_string(cx, &format!("{:03}|", i)).unwrap();
+                report = concat_strings(cx, report.as_ptr(), piece).unwrap();
+            }
+            unsafe { JS_GC(rt.rt()) };
+            let view = JSStringView::new(cx, report.as_ptr()).unwrap();
+            assert_eq!(view.as_utf16().len(), 4000);
+            assert_eq!(view.as_utf16()[4 * 512 + 1], '1' as u16);
+
+            let piece = substring(cx, report.as_ptr(), 4 * 512, 3).unwrap();
+            unsafe { JS_GC(rt.rt()) };
+            assert!(string_equals_ascii(cx, piece.as_ptr(), "512").unwrap());
+            let empty = substring(cx, report.as_ptr(), 4000, 0).unwrap();
+            assert!(string_equals_ascii(cx, empty.as_ptr(), "").unwrap());
+            assert!(substring(cx, report.as_ptr(), 3999, 2).is_err());
+            assert!(substring(cx, report.as_ptr(), 4001, 0).is_err());
+        });
+    }
+
+    #[test]
+    fn rooted_strings_survive_gc() {
+        with_test_runtime(|rt, global| {
+            let cx = rt.cx();
+            let built = RootedString::from_str(cx, &format!("{}-{}", "prefix", 42)).unwrap();
+            let converted = RootedString::from_jsval(cx, assert_evaluates!(rt, global, "({ toString: \
+                                                         function() { return 'gr' + '\u00fc\u00df'; } })"))
+                .unwrap();
+            assert!(evaluate(rt, global, "var junk = []; for (var i = 0; i < 10000; i++) junk.push('x' + i); \
+                                          junk = null;").is_ok());
+            unsafe { JS_GC(rt.rt()) };
+
+            assert!(built == "prefix-42");
+            assert!(built != "prefix-43");
+            assert_eq!(built.len(), 9);
+            assert_eq!(converted.to_string().unwrap(), "grüß");
+            assert!(converted == "grüß");
+            assert_eq!(converted.len(), 4);
+            assert!(RootedString::from_str(cx, "").unwrap().is_empty());
+
+            let object = assert_evaluates!(rt, global, "({ shout: function(s) { return s.toUpperCase(); } })");
+            let result = call_method(rt, object_ptr(object.to_object()), "shout", &[built.value()]).unwrap();
+            assert!(RootedString::from_jsval(cx, result).unwrap() == "PREFIX-42");
+        });
+    }
+
+    #[inline(never)]
+    fn expose_copies(cx: *mut JSContext, global: *mut JSObject, asset: &Arc<Vec<u16>>, count: usize) {
+        for _ in 0..count {
+            let string = new_external_string(cx, asset.clone()).unwrap();
+            let mut value = unsafe { StringValue(&*string) };
+            unsafe { JS_SetProperty(cx, global, b"asset\0".as_ptr() as *const c_char, &mut value) };
+        }
+    }
+
+    #[test]
+    fn external_strings_release_their_buffer() {
+        const COUNT: usize = 100;
+        let asset = Arc::new("héllo".utf16_units().collect::<Vec<u16>>());
+        {
+            let rt = Runtime::new();
+            let global = new_global(&rt);
+            with_compartment(rt.context(), object_ptr(global), || {
+                expose_copies(rt.cx(), global, &asset, COUNT);
+                assert_eq!(assert_evaluates!(rt, global, "asset.length").to_number(), 5.0);
+                let view = JSStringView::new(rt.cx(), evaluate(&rt, global, "asset.charAt(1)")
+                                                          .unwrap().to_string()).unwrap();
+                assert!(view.eq_str("é"));
+                assert!(evaluate(&rt, global, "asset = undefined").is_ok());
+            });
+            assert_all_collected(&rt, "external strings", || Arc::strong_count(&asset) - 1);
+        }
+        // Tearing down the runtime finalizes whatever was left.
+        assert_eq!(Arc::strong_count(&asset), 1);
+    }
+}
@@ -0,0 +1,218 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Structured clone data, for copying values between compartments or,
+//! once detached, between runtimes on different threads.
+
+use libc::{c_void, size_t};
+use std::mem;
+use std::ptr;
+use std::slice;
+use jsapi::{JSContext, JS_WriteStructuredClone, JS_ReadStructuredClone, JS_free};
+use jsval::{JSVal, UndefinedValue};
+use error::{JSError, injected_allocation_failure};
+use glue::JS_STRUCTURED_CLONE_VERSION;
+use ERR;
+
+enum Storage {
+    /// Written by `JS_WriteStructuredClone` in `cx`, to be freed there.
+    Attached(*mut JSContext, *mut u64, size_t),
+    /// Copied out of the engine's memory.
+    Detached(Vec<u64>),
+    /// Already read.
+    Consumed,
+}
+
+/// A value serialized with the structured clone algorithm: a deep copy of
+/// plain objects, arrays, dates, regexps, typed arrays and primitives.
+/// Each buffer can be read once.
+///
+/// A buffer starts out attached to the context that wrote it. `detach`
+/// turns it into plain bytes that don't depend on that context, which can
+/// be sent to another thread and read by another runtime.
+pub struct StructuredCloneBuffer {
+    storage: Storage,
+}
+
+// Only detached buffers may be read by another context, and an attached
+// buffer's memory comes from the engine's malloc, which `JS_free` returns
+// without touching the context.
+unsafe impl Send for StructuredCloneBuffer {}
+
+impl StructuredCloneBuffer {
+    /// Serializes `value`, which must be in the current compartment.
+    /// Anything the algorithm can't copy, like a function, is a
+    /// `DataCloneError`.
+    pub fn write(cx: *mut JSContext, value: JSVal) -> Result<StructuredCloneBuffer, JSError> {
+        let mut data = ptr::null_mut();
+        let mut nbytes = 0;
+        unsafe {
+            if JS_WriteStructuredClone(cx, value, &mut data, &mut nbytes, ptr::null(), ptr::null_mut()) == ERR {
+                return Err(JSError::from_failed_allocation(cx, "writing a structured clone"));
+            }
+            if injected_allocation_failure(cx) {
+                JS_free(cx, data as *mut c_void);
+                return Err(JSError::from_failed_allocation(cx, "writing a structured clone"));
+            }
+        }
+        Ok(StructuredCloneBuffer { storage: Storage::Attached(cx, data, nbytes) })
+    }
+
+    /// Wraps bytes from `to_bytes`. Bytes that aren't valid clone data are
+    /// an error when read, not before.
+    pub fn from_bytes(bytes: &[u8]) -> Result<StructuredCloneBuffer, JSError> {
+        if bytes.len() % mem::size_of::<u64>() != 0 {
+            return Err(JSError::new(format!("structured clone data must be a whole number of \
+                                             64-bit words, not {} bytes", bytes.len())));
+        }
+        let mut words = vec![0u64; bytes.len() / mem::size_of::<u64>()];
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), words.as_mut_ptr() as *mut u8, bytes.len());
+        }
+        Ok(StructuredCloneBuffer { storage: Storage::Detached(words) })
+    }
+
+    /// Copies the data out of the writing context's memory and releases
+    /// that, so the buffer can outlive the context or move to another
+    /// thread.
+    pub fn detach(mut self) -> StructuredCloneBuffer {
+        if let Storage::Attached(..) = self.storage {
+            let words = self.words().to_vec();
+            self.release();
+            self.storage = Storage::Detached(words);
+        }
+        self
+    }
+
+    pub fn is_detached(&self) -> bool {
+        match self.storage {
+            Storage::Attached(..) => false,
+            _ => true,
+        }
+    }
+
+    /// The serialized data, which is empty once the buffer has been read.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let words = self.words();
+        unsafe {
+            slice::from_raw_parts(words.as_ptr() as *const u8, words.len() * mem::size_of::<u64>()).to_vec()
+        }
+    }
+
+    /// Deserializes a copy into the current compartment of `cx`. An attached
+    /// buffer can only be read by the context that wrote it.
+    pub fn read(&mut self, cx: *mut JSContext) -> Result<JSVal, JSError> {
+        match self.storage {
+            Storage::Attached(owner, _, _) if owner != cx => {
+                return Err(JSError::new("a structured clone buffer must be detached before \
+                                         another context reads it"));
+            }
+            Storage::Consumed => {
+                return Err(JSError::new("this structured clone buffer has already been read"));
+            }
+            _ => {}
+        }
+        let mut value = UndefinedValue();
+        let ok = {
+            let words = self.words();
+            unsafe {
+                JS_ReadStructuredClone(cx, words.as_ptr(), (words.len() * mem::size_of::<u64>()) as size_t,
+                                       JS_STRUCTURED_CLONE_VERSION, &mut value, ptr::null(), ptr::null_mut())
+            }
+        };
+        self.release();
+        self.storage = Storage::Consumed;
+        if ok == ERR || injected_allocation_failure(cx) {
+            return Err(unsafe { JSError::from_failed_allocation(cx, "reading a structured clone") });
+        }
+        Ok(value)
+    }
+
+    fn words(&self) -> &[u64] {
+        match self.storage {
+            Storage::Attached(_, data, nbytes) => unsafe {
+                slice::from_raw_parts(data, nbytes as usize / mem::size_of::<u64>())
+            },
+            Storage::Detached(ref words) => words,
+            Storage::Consumed => &[],
+        }
+    }
+
+    fn release(&mut self) {
+        if let Storage::Attached(cx, data, _) = self.storage {
+            unsafe { JS_free(cx, data as *mut c_void) };
+            self.storage = Storage::Consumed;
+        }
+    }
+}
+
+impl Drop for StructuredCloneBuffer {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use object::get_path_into;
+    use testing::{with_test_runtime, evaluate, object_ptr};
+    use super::StructuredCloneBuffer;
+
+    #[test]
+    fn values_move_between_threads() {
+        let (sender, receiver) = channel();
+        let writer = thread::spawn(move || {
+            with_test_runtime(|rt, global| {
+                let value = evaluate(rt, global, "({ name: 'result', data: [1, 2, { nested: [true, null] }], \
+                                                    when: new Date(0) })").unwrap();
+                let buffer = StructuredCloneBuffer::write(rt.cx(), value).unwrap();
+                sender.send(buffer.detach()).unwrap();
+            });
+        });
+        let reader = thread::spawn(move || {
+            let mut buffer = receiver.recv().unwrap();
+            assert!(buffer.is_detached());
+            with_test_runtime(|rt, _| {
+                let value = object_ptr(buffer.read(rt.cx()).unwrap().to_object());
+                assert_eq!(get_path_into::<String, _, _>(rt, value, "name").unwrap(), "result");
+                assert_eq!(get_path_into::<bool, _, _>(rt, value, "data.2.nested.0"), Ok(true));
+                assert_eq!(get_path_into::<f64, _, _>(rt, value, "data.length"), Ok(3.0));
+                assert!(buffer.read(rt.cx()).is_err());
+            });
+        });
+        writer.join().unwrap();
+        reader.join().unwrap();
+    }
+
+    #[test]
+    fn buffers_are_read_once() {
+        with_test_runtime(|rt, global| {
+            let value = assert_evaluates!(rt, global, "[1, 2, 3]");
+            let mut buffer = StructuredCloneBuffer::write(rt.cx(), value).unwrap();
+            assert!(!buffer.is_detached());
+            assert!(buffer.read(rt.cx()).is_ok());
+            let error = buffer.read(rt.cx()).unwrap_err();
+            assert!(error.message.contains("already been read"), "{}", error.message);
+            assert!(buffer.to_bytes().is_empty());
+        });
+    }
+
+    #[test]
+    fn corrupted_buffers_are_errors() {
+        with_test_runtime(|rt, global| {
+            let value = assert_evaluates!(rt, global, "({list: [1, 2, 3], text: 'hello'})");
+            let bytes = StructuredCloneBuffer::write(rt.cx(), value).unwrap().to_bytes();
+            let mut round_trip = StructuredCloneBuffer::from_bytes(&bytes).unwrap();
+            assert!(round_trip.read(rt.cx()).is_ok());
+
+            assert!(StructuredCloneBuffer::from_bytes(&bytes[1..]).is_err());
+            let mut garbage = StructuredCloneBuffer::from_bytes(&[0xff; 64]).unwrap();
+            assert!(garbage.read(rt.cx()).is_err());
+            let mut truncated = StructuredCloneBuffer::from_bytes(&bytes[..bytes.len() - 8]).unwrap();
+            assert!(truncated.read(rt.cx()).is_err());
+        });
+    }
+}
@@ -0,0 +1,151 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for tests that run script, available to other crates with the
+//! `test-utils` feature:
+//!
+//! ```ignore
+//! with_test_runtime(|rt, global| {
+//!     assert_eq!(assert_evaluates!(rt, global, "6 * 7").to_number(), 42.0);
+//!     assert_throws!(rt, global, "null.x", "TypeError");
+//! });
+//! ```
+
+use libc::c_char;
+#[cfg(feature = "debugmozjs")]
+use std::env;
+use std::ptr;
+use jsapi::{JSObject, JS_EvaluateUCScript, JS_GC};
+#[cfg(feature = "debugmozjs")]
+use jsapi::JS_DEFAULT_ZEAL_FREQ;
+use jsval::{JSVal, UndefinedValue};
+use error::JSError;
+use global::{GlobalOptions, new_global_object};
+use pointers::ObjectPtr;
+use rust::{Runtime, with_compartment, check_source_length};
+use utf16::to_utf16;
+
+/// The environment variable that makes tests run under GC zeal, as
+/// `mode` or `mode,frequency`: `MOZJS_TEST_GC_ZEAL=2,10` collects every
+/// ten allocations. It takes effect when `new_global` or `with_test_runtime`
+/// sets a runtime up, and only with the `debugmozjs` feature, as release
+/// builds of the engine have no zeal.
+pub const GC_ZEAL_VAR: &'static str = "MOZJS_TEST_GC_ZEAL";
+
+/// Applies the GC zeal `GC_ZEAL_VAR` asks for to `rt`, if any.
+#[cfg(feature = "debugmozjs")]
+pub fn set_test_gc_zeal(rt: &Runtime) {
+    let setting = match env::var(GC_ZEAL_VAR) {
+        Ok(setting) => setting,
+        Err(_) => return,
+    };
+    let mut parts = setting.splitn(2, ',');
+    let mode = parts.next().unwrap().trim().parse::<u8>();
+    let frequency = parts.next().map_or(Ok(JS_DEFAULT_ZEAL_FREQ), |f| f.trim().parse::<u32>());
+    match (mode, frequency) {
+        (Ok(mode), Ok(frequency)) => rt.set_gc_zeal(mode, frequency),
+        _ => panic!("{}={:?} isn't `mode` or `mode,frequency`", GC_ZEAL_VAR, setting),
+    }
+}
+
+#[cfg(not(feature = "debugmozjs"))]
+pub fn set_test_gc_zeal(_rt: &Runtime) {}
+
+/// Creates a global object with the standard classes initialized, after
+/// applying `GC_ZEAL_VAR` to `rt`.
+pub fn new_global(rt: &Runtime) -> *mut JSObject {
+    set_test_gc_zeal(rt);
+    new_global_object(rt.cx(), None, &GlobalOptions::default()).unwrap()
+}
+
+/// `obj`, an object a test got from the engine, such as a global
+/// `new_global` made, as an `ObjectPtr`. Panics if it's null.
+pub fn object_ptr(obj: *mut JSObject) -> ObjectPtr {
+    unsafe { ObjectPtr::from_raw_nonnull(obj) }
+}
+
+/// Evaluates `source` against `global` and returns the completion value.
+/// The caller must already be in `global`'s compartment.
+pub fn evaluate(rt: &Runtime, global: *mut JSObject, source: &str) -> Result<JSVal, JSError> {
+    let source = to_utf16(source);
+    let length = try!(check_source_length(source.len()));
+    let mut rval: JSVal = UndefinedValue();
+    unsafe {
+        if JS_EvaluateUCScript(rt.cx(), global, source.as_ptr(), length,
+                               b"test\0".as_ptr() as *const c_char, 1, &mut rval) == 0 {
+            return Err(JSError::from_pending_exception(rt.cx()));
+        }
+    }
+    Ok(rval)
+}
+
+/// Runs `f` with a new runtime and a global made by `new_global`, inside
+/// the global's compartment. The compartment is left before the runtime is
+/// destroyed, even if `f` panics.
+pub fn with_test_runtime<R, F: FnOnce(&Runtime, *mut JSObject) -> R>(f: F) -> R {
+    let rt = Runtime::new();
+    let global = new_global(&rt);
+    let mut f = Some(f);
+    with_compartment(&rt, object_ptr(global), || (f.take().unwrap())(&rt, global))
+}
+
+/// How many collections `assert_all_collected` runs before giving up.
+pub const MAX_COLLECTIONS: usize = 10;
+
+/// Overwrites a stretch of the stack below the caller, so that stale
+/// pointers left there by earlier calls don't keep things alive through the
+/// conservative stack scanner.
+#[inline(never)]
+fn clear_stack() {
+    let mut junk = [0usize; 4096];
+    for slot in junk.iter_mut() {
+        unsafe { ptr::write_volatile(slot, 0) };
+    }
+}
+
+/// Collects garbage until `remaining`, the number of `what` the test
+/// expects to have been finalized but that are still alive, reaches 0,
+/// panicking if it hasn't after `MAX_COLLECTIONS` collections.
+pub fn assert_all_collected<F: Fn() -> usize>(rt: &Runtime, what: &str, remaining: F) {
+    for _ in 0..MAX_COLLECTIONS {
+        if remaining() == 0 {
+            return;
+        }
+        clear_stack();
+        unsafe { JS_GC(rt.rt()) };
+    }
+    let left = remaining();
+    assert!(left == 0, "{} {} still alive after {} collections", left, what, MAX_COLLECTIONS);
+}
+
+/// Evaluates `$source` with `testing::evaluate` and returns the completion
+/// value, panicking with the error if it throws.
+#[macro_export]
+macro_rules! assert_evaluates {
+    ($rt:expr, $global:expr, $source:expr) => {
+        match $crate::testing::evaluate(&$rt, $global, $source) {
+            Ok(value) => value,
+            Err(error) => panic!("{:?} threw {}", $source, error),
+        }
+    }
+}
+
+/// Evaluates `$source` with `testing::evaluate`, panicking unless it
+/// throws an exception whose string form starts with `$name`, such as
+/// `"TypeError"`. Returns the error.
+#[macro_export]
+macro_rules! assert_throws {
+    ($rt:expr, $global:expr, $source:expr, $name:expr) => {
+        match $crate::testing::evaluate(&$rt, $global, $source) {
+            Ok(_) => panic!("{:?} didn't throw", $source),
+            Err(error) => {
+                let name: &str = $name;
+                if error.message != name && !error.message.starts_with(&format!("{}:", name)) {
+                    panic!("{:?} threw {}, not {}", $source, error.message, name);
+                }
+                error
+            }
+        }
+    }
+}
@@ -0,0 +1,275 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! `setTimeout` and friends, driven by the embedder's own event loop.
+//!
+//! Times are milliseconds on whatever clock the embedder passes to
+//! `TimerQueue::run_due`; a timer set from script is due its delay after
+//! the last `now` the queue was given.
+
+use libc::{c_char, c_uint};
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::mem;
+use std::rc::Rc;
+use jsapi::{JSContext, JSObject, JS_AddNamedValueRoot, JS_RemoveValueRoot, JS_CallFunctionValue};
+use jsapi::JS_GetGlobalForObject;
+use jsapi::{JS_ObjectIsCallable, JS_IsExceptionPending, JS_ReportPendingException, JS_ClearPendingException};
+use jsval::{JSVal, Int32Value, ObjectValue, UndefinedValue};
+use closure::{CallContext, define_closure};
+use conversions::to_number;
+use error::JSError;
+use pointers::{ContextPtr, ObjectPtr};
+use rust::with_compartment;
+use ERR;
+
+/// A scheduled callback. Its global, function and arguments stay rooted
+/// until it is dropped.
+struct Timer {
+    cx: *mut JSContext,
+    due: u64,
+    /// The period of an interval; `None` for a timeout.
+    interval: Option<u64>,
+    /// The global, the function, then the arguments. Boxed so the roots'
+    /// addresses stay put.
+    values: Vec<Box<JSVal>>,
+}
+
+impl Timer {
+    fn new(cx: *mut JSContext, due: u64, interval: Option<u64>, values: &[JSVal]) -> Timer {
+        let mut timer = Timer {
+            cx: cx,
+            due: due,
+            interval: interval,
+            values: vec![],
+        };
+        for &value in values {
+            let mut root = Box::new(value);
+            unsafe { JS_AddNamedValueRoot(cx, &mut *root, b"Timer\0".as_ptr() as *const c_char) };
+            timer.values.push(root);
+        }
+        timer
+    }
+
+    fn global(&self) -> *mut JSObject {
+        self.values[0].to_object()
+    }
+
+    /// Calls the function, reporting anything it throws.
+    fn fire(&self) {
+        let cx = self.cx;
+        let global = self.global();
+        let mut args: Vec<JSVal> = self.values[2..].iter().map(|value| **value).collect();
+        let (context, object) = unsafe {
+            (ContextPtr::from_raw_nonnull(cx), ObjectPtr::from_raw_nonnull(global))
+        };
+        with_compartment(context, object, || unsafe {
+            let mut rval = UndefinedValue();
+            if JS_CallFunctionValue(cx, global, *self.values[1], args.len() as c_uint,
+                                    args.as_mut_ptr(), &mut rval) == ERR &&
+               JS_IsExceptionPending(cx) != 0 {
+                JS_ReportPendingException(cx);
+                JS_ClearPendingException(cx);
+            }
+        });
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        for root in &mut self.values {
+            unsafe { JS_RemoveValueRoot(self.cx, &mut **root) };
+        }
+    }
+}
+
+struct TimerState {
+    /// The last time given to `run_due`.
+    now: Cell<u64>,
+    next_id: Cell<u32>,
+    timers: RefCell<BTreeMap<u32, Timer>>,
+    /// The timer `run_due` is firing, and whether its callback cleared it.
+    running: Cell<Option<u32>>,
+    running_cleared: Cell<bool>,
+}
+
+/// The timers set by script in the globals given `install_timers`.
+///
+/// The queue must be dropped before the runtime whose globals it serves;
+/// dropping it releases the timers still scheduled.
+pub struct TimerQueue {
+    state: Rc<TimerState>,
+}
+
+impl TimerQueue {
+    pub fn new() -> TimerQueue {
+        TimerQueue {
+            state: Rc::new(TimerState {
+                now: Cell::new(0),
+                next_id: Cell::new(1),
+                timers: RefCell::new(BTreeMap::new()),
+                running: Cell::new(None),
+                running_cleared: Cell::new(false),
+            }),
+        }
+    }
+
+    /// Runs the timers due at `now`, earliest first, and returns how many
+    /// ran. Timers set by those callbacks wait for the next call, even if
+    /// they are already due, so a callback that keeps rescheduling itself
+    /// can't stall the loop. Must not be called from a timer callback.
+    pub fn run_due(&self, now: u64) -> usize {
+        let state = &self.state;
+        state.now.set(cmp::max(now, state.now.get()));
+        let last_id = state.next_id.get();
+        let mut ran = 0;
+        loop {
+            let next = state.timers.borrow().iter()
+                            .filter(|&(&id, timer)| id < last_id && timer.due <= now)
+                            .min_by_key(|&(&id, timer)| (timer.due, id))
+                            .map(|(&id, _)| id);
+            let id = match next {
+                Some(id) => id,
+                None => break,
+            };
+            let mut timer = state.timers.borrow_mut().remove(&id).unwrap();
+            state.running.set(Some(id));
+            state.running_cleared.set(false);
+            timer.fire();
+            state.running.set(None);
+            ran += 1;
+            if let Some(interval) = timer.interval {
+                if !state.running_cleared.get() {
+                    timer.due = now + cmp::max(interval, 1);
+                    state.timers.borrow_mut().insert(id, timer);
+                }
+            }
+        }
+        ran
+    }
+
+    /// When the next timer is due, if any is scheduled.
+    pub fn next_due(&self) -> Option<u64> {
+        self.state.timers.borrow().values().map(|timer| timer.due).min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.timers.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.timers.borrow().is_empty()
+    }
+}
+
+impl Default for TimerQueue {
+    fn default() -> TimerQueue {
+        TimerQueue::new()
+    }
+}
+
+impl Drop for TimerQueue {
+    fn drop(&mut self) {
+        // The natives share the state, but the timers' roots must go now.
+        let timers = mem::replace(&mut *self.state.timers.borrow_mut(), BTreeMap::new());
+        drop(timers);
+    }
+}
+
+impl TimerState {
+    fn schedule(&self, call: &CallContext, interval: bool) -> Result<JSVal, JSError> {
+        let cx = call.cx();
+        let callback = call.arg(0);
+        if !callback.is_object() || unsafe { JS_ObjectIsCallable(cx, callback.to_object()) } == 0 {
+            return Err(JSError::new("timer callback is not a function"));
+        }
+        let delay = try!(to_number(cx, call.arg(1)));
+        let delay = if delay.is_finite() && delay > 0.0 { delay as u64 } else { 0 };
+        let global = unsafe { JS_GetGlobalForObject(cx, call.callee()) };
+        let mut values = vec![ObjectValue(unsafe { &*global }), callback];
+        for i in 2..call.argc() {
+            values.push(call.arg(i));
+        }
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        let timer = Timer::new(cx, self.now.get() + delay, if interval { Some(delay) } else { None },
+                               &values);
+        self.timers.borrow_mut().insert(id, timer);
+        Ok(Int32Value(id as i32))
+    }
+
+    fn clear(&self, call: &CallContext) -> Result<JSVal, JSError> {
+        let id = try!(to_number(call.cx(), call.arg(0)));
+        if id >= 1.0 && id < self.next_id.get() as f64 {
+            let id = id as u32;
+            self.timers.borrow_mut().remove(&id);
+            if self.running.get() == Some(id) {
+                self.running_cleared.set(true);
+            }
+        }
+        Ok(UndefinedValue())
+    }
+}
+
+/// Defines `setTimeout`, `clearTimeout`, `setInterval` and `clearInterval`
+/// on `global`, scheduling into `queue`. Extra arguments to the setters are
+/// passed to the callback; string callbacks aren't supported.
+pub fn install_timers(cx: *mut JSContext, global: *mut JSObject, queue: &TimerQueue) -> Result<(), JSError> {
+    let state = queue.state.clone();
+    try!(define_closure(cx, global, "setTimeout", 2, 0, move |call| state.schedule(call, false)));
+    let state = queue.state.clone();
+    try!(define_closure(cx, global, "setInterval", 2, 0, move |call| state.schedule(call, true)));
+    let state = queue.state.clone();
+    try!(define_closure(cx, global, "clearTimeout", 1, 0, move |call| state.clear(call)));
+    let state = queue.state.clone();
+    try!(define_closure(cx, global, "clearInterval", 1, 0, move |call| state.clear(call)));
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use conversions::to_string;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{TimerQueue, install_timers};
+
+    #[test]
+    fn nested_timers_run_in_order() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let queue = TimerQueue::new();
+        with_compartment(rt.context(), object_ptr(global), || {
+            install_timers(rt.cx(), global, &queue).unwrap();
+            evaluate(&rt, global, "var log = []; \
+                                   setTimeout(function(tag) { \
+                                       log.push(tag); \
+                                       setTimeout(function() { log.push('nested'); }, 0); \
+                                   }, 20, 'slow'); \
+                                   setTimeout(function() { log.push('fast'); }, 10); \
+                                   var cancelled = setTimeout(function() { log.push('cancelled'); }, 5); \
+                                   clearTimeout(cancelled); \
+                                   var ticks = 0; \
+                                   var interval = setInterval(function() { \
+                                       if (++ticks == 3) clearInterval(interval); \
+                                       log.push('tick' + ticks); \
+                                   }, 15); \
+                                   setTimeout(function() { throw new Error('ignored'); }, 1);").unwrap();
+        });
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.next_due(), Some(1));
+        assert_eq!(queue.run_due(9), 1);
+        assert_eq!(queue.run_due(20), 3);
+        // The nested timeout is due, but waits for the next run.
+        assert_eq!(queue.run_due(20), 1);
+        assert_eq!(queue.run_due(100), 1);
+        assert_eq!(queue.run_due(100), 0);
+        assert_eq!(queue.run_due(200), 1);
+        assert!(queue.is_empty());
+        with_compartment(rt.context(), object_ptr(global), || {
+            let log = to_string(rt.cx(), evaluate(&rt, global, "log.join()").unwrap()).unwrap();
+            assert_eq!(log, "fast,tick1,slow,nested,tick2,tick3");
+        });
+    }
+}
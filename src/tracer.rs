@@ -0,0 +1,193 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Reporting GC pointers held by Rust data to the engine's tracer.
+
+use libc::{c_char, c_void, size_t};
+use std::cmp;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ptr;
+use jsapi::{JSObject, JSString, JSTracer, JS_CallTracer};
+use jsval::JSVal;
+use heap::{GCPointer, Heap};
+
+/// A `JSTracer` handed to a trace hook.
+pub struct Tracer {
+    trc: *mut JSTracer,
+}
+
+impl Tracer {
+    /// Wraps the tracer passed to a trace hook. It must stay valid for as
+    /// long as the `Tracer` is used.
+    pub unsafe fn from_raw(trc: *mut JSTracer) -> Tracer {
+        assert!(!trc.is_null());
+        Tracer {
+            trc: trc,
+        }
+    }
+
+    pub fn raw(&self) -> *mut JSTracer {
+        self.trc
+    }
+
+    /// Reports the value in `value` if it is an object or string. `name`
+    /// labels the edge in heap dumps.
+    pub fn trace_value(&mut self, value: &Heap<JSVal>, name: &str) {
+        self.trace_heap(value, name);
+    }
+
+    pub fn trace_object(&mut self, object: &Heap<*mut JSObject>, name: &str) {
+        self.trace_heap(object, name);
+    }
+
+    pub fn trace_string(&mut self, string: &Heap<*mut JSString>, name: &str) {
+        self.trace_heap(string, name);
+    }
+
+    /// Reports whatever `heap` holds, if anything.
+    pub fn trace_heap<T: GCPointer>(&mut self, heap: &Heap<T>, name: &str) {
+        if let Some((thing, kind)) = heap.get().gc_thing() {
+            // The engine only asks for the name while `JS_CallTracer` runs,
+            // so pointing it at `name` on our stack is enough.
+            unsafe {
+                (*self.trc).debugPrinter = Some(print_edge_name);
+                (*self.trc).debugPrintArg = &name as *const &str as *const c_void;
+                (*self.trc).debugPrintIndex = !0;
+                JS_CallTracer(self.trc, thing, kind);
+                (*self.trc).debugPrintArg = ptr::null();
+            }
+        }
+    }
+}
+
+/// Copies the `&str` behind `debugPrintArg` into `buf` as a C string.
+unsafe extern "C" fn print_edge_name(trc: *mut JSTracer, buf: *mut c_char, bufsize: size_t) {
+    if bufsize == 0 {
+        return;
+    }
+    let name = *((*trc).debugPrintArg as *const &str);
+    let len = cmp::min(name.len(), bufsize as usize - 1);
+    ptr::copy_nonoverlapping(name.as_ptr() as *const c_char, buf, len);
+    *buf.offset(len as isize) = 0;
+}
+
+/// Rust data that may hold GC pointers, which a trace hook reports with
+/// `trace`.
+pub trait Traceable {
+    fn trace(&self, trc: &mut Tracer);
+}
+
+impl<T: GCPointer> Traceable for Heap<T> {
+    fn trace(&self, trc: &mut Tracer) {
+        trc.trace_heap(self, "Heap");
+    }
+}
+
+impl<T: Traceable> Traceable for Option<T> {
+    fn trace(&self, trc: &mut Tracer) {
+        if let Some(ref value) = *self {
+            value.trace(trc);
+        }
+    }
+}
+
+impl<T: Traceable> Traceable for Vec<T> {
+    fn trace(&self, trc: &mut Tracer) {
+        for value in self {
+            value.trace(trc);
+        }
+    }
+}
+
+impl<K: Eq + Hash, T: Traceable> Traceable for HashMap<K, T> {
+    fn trace(&self, trc: &mut Tracer) {
+        for value in self.values() {
+            value.trace(trc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::c_char;
+    use std::collections::HashMap;
+    use jsapi::{JSObject, JS_GC, JS_GetElement, JS_DefineProperty};
+    use jsval::{UndefinedValue, ObjectValue};
+    use heap::Heap;
+    use private::{NativeClass, new_object_with_native, native_from_object};
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, object_ptr};
+    use super::{Tracer, Traceable};
+
+    struct Holder {
+        objects: Vec<Heap<*mut JSObject>>,
+        named: HashMap<String, Option<Heap<*mut JSObject>>>,
+    }
+
+    impl Traceable for Holder {
+        fn trace(&self, trc: &mut Tracer) {
+            self.objects.trace(trc);
+            self.named.trace(trc);
+        }
+    }
+
+    /// Leaves the holder reachable from `global` and its objects reachable
+    /// only through the holder's trace hook.
+    #[inline(never)]
+    fn fill_holder(rt: &Runtime, global: *mut JSObject, class: &NativeClass<Holder>) {
+        let cx = rt.cx();
+        // Script keeps the objects alive until the holder owns them.
+        let array = evaluate(rt, global, "var objects = []; \
+                                          for (var i = 0; i < 100; i++) objects.push({index: i}); \
+                                          objects").unwrap().to_object();
+        let mut objects = vec![];
+        for i in 0..100 {
+            let mut element = UndefinedValue();
+            assert!(unsafe { JS_GetElement(cx, array, i, &mut element) } != 0);
+            objects.push(Heap::new(element.to_object()));
+        }
+        let mut named = HashMap::new();
+        named.insert("first".to_string(), Some(Heap::new(objects[0].get())));
+        named.insert("empty".to_string(), None);
+
+        let holder = new_object_with_native(cx, class, Holder {
+            objects: objects,
+            named: named,
+        }).unwrap();
+        unsafe {
+            assert!(JS_DefineProperty(cx, global, b"holder\0".as_ptr() as *const c_char,
+                                      ObjectValue(&*holder), None, None, 0) != 0);
+        }
+        evaluate(rt, global, "objects = null").unwrap();
+    }
+
+    #[test]
+    fn traced_vec_of_heaps_survives_gc() {
+        let class = NativeClass::<Holder>::new_traced("Holder");
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            fill_holder(&rt, global, &class);
+            unsafe {
+                JS_GC(rt.rt());
+                JS_GC(rt.rt());
+            }
+
+            let holder = evaluate(&rt, global, "holder").unwrap().to_object();
+            let holder = unsafe { native_from_object::<Holder>(holder) }.unwrap();
+            assert_eq!(holder.objects.len(), 100);
+            for (i, object) in holder.objects.iter().enumerate() {
+                unsafe {
+                    assert!(JS_DefineProperty(rt.cx(), global, b"probe\0".as_ptr() as *const c_char,
+                                              ObjectValue(&*object.get()), None, None, 0) != 0);
+                }
+                assert_eq!(evaluate(&rt, global, "probe.index").unwrap().to_int32(), i as i32);
+            }
+            let first = holder.named["first"].as_ref().unwrap().get();
+            assert_eq!(first, holder.objects[0].get());
+            assert!(holder.named["empty"].is_none());
+        });
+    }
+}
@@ -0,0 +1,222 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Conversions between Rust's UTF-8 and the engine's UTF-16. Everything in
+//! the crate that widens or narrows strings goes through here.
+//!
+//! A `&str` is always valid Unicode, so widening it can't fail: characters
+//! outside the Basic Multilingual Plane become surrogate pairs. JS strings
+//! can hold unpaired surrogates, which have no UTF-8 form; narrowing
+//! replaces each with U+FFFD.
+
+use std::iter::FusedIterator;
+use std::ptr;
+
+const REPLACEMENT_CHARACTER: char = '\u{FFFD}';
+
+fn is_lead_surrogate(unit: u16) -> bool {
+    unit >= 0xD800 && unit <= 0xDBFF
+}
+
+fn is_trail_surrogate(unit: u16) -> bool {
+    unit >= 0xDC00 && unit <= 0xDFFF
+}
+
+/// The UTF-16 code units of a `&str`, made by `units`.
+pub struct Utf16Units<'a> {
+    chars: ::std::str::Chars<'a>,
+    trail: Option<u16>,
+}
+
+impl<'a> Iterator for Utf16Units<'a> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        if let Some(trail) = self.trail.take() {
+            return Some(trail);
+        }
+        self.chars.next().map(|c| {
+            let c = c as u32;
+            if c < 0x10000 {
+                c as u16
+            } else {
+                let c = c - 0x10000;
+                self.trail = Some(0xDC00 | (c & 0x3FF) as u16);
+                0xD800 | (c >> 10) as u16
+            }
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        // Each character is one or two units, and takes at least as many
+        // bytes of UTF-8.
+        let (chars, bytes) = self.chars.size_hint();
+        let pending = if self.trail.is_some() { 1 } else { 0 };
+        (chars + pending, bytes.map(|bytes| bytes + pending))
+    }
+}
+
+/// Iterates over the UTF-16 code units of `s` without allocating.
+pub fn units(s: &str) -> Utf16Units {
+    Utf16Units { chars: s.chars(), trail: None }
+}
+
+/// Encodes `s` as UTF-16.
+pub fn to_utf16(s: &str) -> Vec<u16> {
+    let mut units = Vec::new();
+    to_utf16_into(s, &mut units);
+    units
+}
+
+/// Encodes `s` as UTF-16 into `buffer`, replacing its contents, so a buffer
+/// can be reused across conversions.
+pub fn to_utf16_into(s: &str, buffer: &mut Vec<u16>) {
+    buffer.clear();
+    // There are never more units than bytes.
+    buffer.reserve(s.len());
+    if is_ascii_fast(s) {
+        buffer.extend(s.bytes().map(|b| b as u16));
+    } else {
+        buffer.extend(units(s));
+    }
+}
+
+/// The characters of UTF-16 code units, made by `decode`.
+#[derive(Clone)]
+pub struct Utf16Chars<'a> {
+    units: &'a [u16],
+}
+
+impl<'a> Iterator for Utf16Chars<'a> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let units = self.units;
+        if units.is_empty() {
+            return None;
+        }
+        let (c, used) = if units.len() > 1 && is_lead_surrogate(units[0]) && is_trail_surrogate(units[1]) {
+            let c = 0x10000 + (((units[0] as u32) & 0x3FF) << 10) + ((units[1] as u32) & 0x3FF);
+            (::std::char::from_u32(c).unwrap(), 2)
+        } else {
+            (::std::char::from_u32(units[0] as u32).unwrap_or(REPLACEMENT_CHARACTER), 1)
+        };
+        self.units = &units[used..];
+        Some(c)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.units.len();
+        ((len + 1) / 2, Some(len))
+    }
+}
+
+impl<'a> FusedIterator for Utf16Chars<'a> {}
+
+/// Decodes `units` as it's iterated over, turning each unpaired surrogate
+/// into U+FFFD.
+pub fn decode(units: &[u16]) -> Utf16Chars {
+    Utf16Chars { units: units }
+}
+
+/// Decodes `units`, replacing each unpaired surrogate with U+FFFD.
+pub fn from_utf16_lossy(units: &[u16]) -> String {
+    let mut s = String::with_capacity(units.len());
+    s.extend(decode(units));
+    s
+}
+
+/// Whether `s` is all ASCII, in which case each byte is one code unit.
+/// Checks eight bytes at a time, reading them as one word.
+pub fn is_ascii_fast(s: &str) -> bool {
+    const HIGH_BITS: u64 = 0x8080808080808080;
+    const WORD: usize = 8;
+    let bytes = s.as_bytes();
+    let words = bytes.len() / WORD;
+    for i in 0..words {
+        // The bytes needn't be aligned for a u64.
+        let word = unsafe { ptr::read_unaligned(bytes[i * WORD..].as_ptr() as *const u64) };
+        if word & HIGH_BITS != 0 {
+            return false;
+        }
+    }
+    bytes[words * WORD..].iter().all(|&b| b < 0x80)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{to_utf16, to_utf16_into, from_utf16_lossy, is_ascii_fast, units, decode};
+
+    #[test]
+    fn empty_strings() {
+        assert_eq!(to_utf16(""), Vec::<u16>::new());
+        assert_eq!(from_utf16_lossy(&[]), "");
+        assert!(is_ascii_fast(""));
+        assert_eq!(units("").count(), 0);
+    }
+
+    #[test]
+    fn astral_characters_are_surrogate_pairs() {
+        // U+1F600 GRINNING FACE and U+10FFFF, the last code point.
+        assert_eq!(to_utf16("a\u{1F600}b"), vec![0x61, 0xD83D, 0xDE00, 0x62]);
+        assert_eq!(to_utf16("\u{10FFFF}"), vec![0xDBFF, 0xDFFF]);
+        assert_eq!(to_utf16("\u{10000}"), vec![0xD800, 0xDC00]);
+        assert_eq!(from_utf16_lossy(&[0x61, 0xD83D, 0xDE00, 0x62]), "a\u{1F600}b");
+        assert_eq!(from_utf16_lossy(&[0xDBFF, 0xDFFF]), "\u{10FFFF}");
+    }
+
+    #[test]
+    fn lone_surrogates_are_replaced() {
+        assert_eq!(from_utf16_lossy(&[0xD83D]), "\u{FFFD}");
+        assert_eq!(from_utf16_lossy(&[0xDE00, 0x61]), "\u{FFFD}a");
+        assert_eq!(from_utf16_lossy(&[0x61, 0xD83D, 0x62]), "a\u{FFFD}b");
+        // A reversed pair is two unpaired surrogates.
+        assert_eq!(from_utf16_lossy(&[0xDE00, 0xD83D]), "\u{FFFD}\u{FFFD}");
+        // A lead followed by a valid pair.
+        assert_eq!(from_utf16_lossy(&[0xD83D, 0xD83D, 0xDE00]), "\u{FFFD}\u{1F600}");
+    }
+
+    #[test]
+    fn round_trips_match_std() {
+        for s in &["", "plain ascii text", "grüß", "日本語", "mixed \u{1F600} and \u{FFFF} \u{10000}",
+                   "\u{7F}\u{80}\u{7FF}\u{800}\u{D7FF}\u{E000}"] {
+            let encoded = to_utf16(s);
+            assert_eq!(encoded, s.utf16_units().collect::<Vec<u16>>());
+            assert_eq!(units(s).collect::<Vec<u16>>(), encoded);
+            assert_eq!(from_utf16_lossy(&encoded), *s);
+        }
+    }
+
+    #[test]
+    fn decoding_is_fused() {
+        let units = [0x61, 0xD800];
+        let mut chars = decode(&units);
+        assert_eq!(chars.next(), Some('a'));
+        assert_eq!(chars.next(), Some('\u{FFFD}'));
+        assert_eq!(chars.next(), None);
+        assert_eq!(chars.next(), None);
+    }
+
+    #[test]
+    fn buffers_are_reused() {
+        let mut buffer = to_utf16("a much longer string than the next one");
+        let capacity = buffer.capacity();
+        to_utf16_into("h\u{1F600}", &mut buffer);
+        assert_eq!(buffer, vec![0x68, 0xD83D, 0xDE00]);
+        assert_eq!(buffer.capacity(), capacity);
+    }
+
+    #[test]
+    fn ascii_detection() {
+        assert!(is_ascii_fast("exactly8"));
+        assert!(is_ascii_fast("longer than eight bytes, all ascii\x7f"));
+        assert!(!is_ascii_fast("é"));
+        assert!(!is_ascii_fast("seven7\u{80}"));
+        assert!(!is_ascii_fast("0123456789abcdef\u{1F600}"));
+        assert!(!is_ascii_fast("\u{1F600}0123456789abcdef"));
+        // Words read from an odd offset.
+        assert!(is_ascii_fast(&"x0123456789abcdef"[1..]));
+        assert!(!is_ascii_fast(&"x012\u{e9}3456789abcdef"[1..]));
+    }
+}
@@ -0,0 +1,144 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Rust data attached to JS objects without keeping them alive.
+
+use libc::c_void;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use jsapi::{JSObject, JSRuntime, JS_IsAboutToBeFinalized};
+use glue::{IsIncrementalBarrierNeeded, IncrementalReferenceBarrier};
+use rust::Runtime;
+
+/// Weak references to GC things, which the runtime sweeps at the start of
+/// every finalization, once the collector knows what is going to die.
+pub trait Sweep {
+    /// Forgets every thing `JS_IsAboutToBeFinalized` reports as dying.
+    fn sweep(&self);
+}
+
+type Entries<V> = RefCell<HashMap<*mut JSObject, V>>;
+
+impl<V> Sweep for Entries<V> {
+    fn sweep(&self) {
+        let mut entries = self.borrow_mut();
+        let dead: Vec<*mut JSObject> = entries.keys().cloned().filter(|&key| unsafe {
+            JS_IsAboutToBeFinalized(key as *mut c_void) != 0
+        }).collect();
+        for key in dead {
+            entries.remove(&key);
+        }
+    }
+}
+
+/// A map from JS objects to `V` that doesn't keep its keys alive: an entry
+/// goes away when the GC collects its key.
+///
+/// Dead entries are dropped from inside the collector, so `V`'s `Drop`
+/// implementation must not touch the JS engine. The map must not outlive
+/// the runtime it was created for.
+pub struct WeakObjectMap<V> {
+    rt: *mut JSRuntime,
+    entries: Rc<Entries<V>>,
+}
+
+impl<V: 'static> WeakObjectMap<V> {
+    pub fn new(rt: &Runtime) -> WeakObjectMap<V> {
+        let entries: Rc<Entries<V>> = Rc::new(RefCell::new(HashMap::new()));
+        rt.register_weak_references(Rc::downgrade(&entries) as Weak<Sweep>);
+        WeakObjectMap {
+            rt: rt.rt(),
+            entries: entries,
+        }
+    }
+
+    /// Associates `value` with `key`, which must be a live object, and
+    /// returns the value it replaces.
+    pub fn insert(&self, key: *mut JSObject, value: V) -> Option<V> {
+        assert!(!key.is_null());
+        self.entries.borrow_mut().insert(key, value)
+    }
+
+    pub fn remove(&self, key: *mut JSObject) -> Option<V> {
+        self.entries.borrow_mut().remove(&key)
+    }
+
+    pub fn contains_key(&self, key: *mut JSObject) -> bool {
+        self.entries.borrow().contains_key(&key)
+    }
+
+    /// The value for `key`. As `key` is alive to be passed here, so is the
+    /// entry, if there is one.
+    pub fn get(&self, key: *mut JSObject) -> Option<V> where V: Clone {
+        self.entries.borrow().get(&key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().is_empty()
+    }
+
+    /// A snapshot of the entries.
+    ///
+    /// While an incremental collection is under way a key may not have been
+    /// marked yet; handing it out is what keeps it alive, so each one gets
+    /// the same barrier as an overwritten `Heap`.
+    pub fn entries(&self) -> Vec<(*mut JSObject, V)> where V: Clone {
+        let entries: Vec<_> = self.entries.borrow().iter()
+                                  .map(|(&key, value)| (key, value.clone()))
+                                  .collect();
+        unsafe {
+            if IsIncrementalBarrierNeeded(self.rt) {
+                for &(key, _) in &entries {
+                    IncrementalReferenceBarrier(key as *mut c_void);
+                }
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jsapi::{JSObject, JS_GC, JS_GetElement};
+    use jsval::UndefinedValue;
+    use rust::{Runtime, with_compartment};
+    use testing::{new_global, evaluate, assert_all_collected, object_ptr};
+    use super::WeakObjectMap;
+
+    #[inline(never)]
+    fn fill_map(rt: &Runtime, global: *mut JSObject, map: &WeakObjectMap<u32>) {
+        let array = evaluate(rt, global, "var objects = []; \
+                                          for (var i = 0; i < 100; i++) objects.push({index: i}); \
+                                          objects").unwrap().to_object();
+        for i in 0..100 {
+            let mut element = UndefinedValue();
+            assert!(unsafe { JS_GetElement(rt.cx(), array, i, &mut element) } != 0);
+            map.insert(element.to_object(), i);
+        }
+    }
+
+    #[test]
+    fn entries_go_away_with_their_keys() {
+        let rt = Runtime::new();
+        let global = new_global(&rt);
+        let map = WeakObjectMap::new(&rt);
+        with_compartment(rt.context(), object_ptr(global), || {
+            fill_map(&rt, global, &map);
+            unsafe { JS_GC(rt.rt()) };
+            // Script still holds every key.
+            assert_eq!(map.len(), 100);
+            let entries = map.entries();
+            assert_eq!(entries.len(), 100);
+            assert!(entries.iter().all(|&(key, value)| map.get(key) == Some(value)));
+
+            evaluate(&rt, global, "objects = null").unwrap();
+            assert_all_collected(&rt, "weak map keys", || map.len());
+        });
+    }
+}
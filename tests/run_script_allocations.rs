@@ -0,0 +1,70 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this file,
+ * You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Checks that `Runtime::run_script` doesn't allocate once warmed up.
+//! This is its own test crate so that the counting allocator sees no other
+//! test's allocations.
+
+extern crate js;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT, ATOMIC_USIZE_INIT};
+use js::global::{GlobalOptions, new_global_object};
+use js::pointers::ObjectPtr;
+use js::rust::{Runtime, with_compartment};
+
+struct CountingAllocator;
+
+static COUNTING: AtomicBool = ATOMIC_BOOL_INIT;
+static ALLOCATIONS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if COUNTING.load(Ordering::SeqCst) {
+            ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// How many times the Rust heap is allocated from while `f` runs.
+fn count_allocations<F: FnOnce()>(f: F) -> usize {
+    ALLOCATIONS.store(0, Ordering::SeqCst);
+    COUNTING.store(true, Ordering::SeqCst);
+    f();
+    COUNTING.store(false, Ordering::SeqCst);
+    ALLOCATIONS.load(Ordering::SeqCst)
+}
+
+#[test]
+fn run_script_does_not_allocate_once_warm() {
+    let rt = Runtime::new();
+    let global = new_global_object(rt.cx(), None, &GlobalOptions::default()).unwrap();
+    let object = unsafe { ObjectPtr::from_raw_nonnull(global) };
+    with_compartment(&rt, object, || {
+        let script = "var total = (typeof total == 'number' ? total : 0) + 1;";
+        // The first call grows the runtime's scratch buffers to fit.
+        rt.run_script(global, script, "warm.js", 1).unwrap();
+        let allocations = count_allocations(|| {
+            for line in 1..100 {
+                rt.run_script(global, script, "warm.js", line).unwrap();
+            }
+        });
+        assert_eq!(allocations, 0);
+    });
+}